@@ -11,6 +11,11 @@ pub struct AppUi {
     peer: RefCell<Option<Arc<PeerUi>>>,
     app_div: HtmlDivElement,
     tracker_address_input: HtmlInputElement,
+    ice_servers_input: HtmlInputElement,
+    host_only_input: HtmlInputElement,
+    label_input: HtmlInputElement,
+    room_input: HtmlInputElement,
+    max_connections_input: HtmlInputElement,
     connect_button: HtmlButtonElement,
     //upload_speed_handler: ClosureCell1<Event>,
     connect_click_handler: ClosureCell1<Event>,
@@ -18,8 +23,9 @@ pub struct AppUi {
 
 impl AppUi {
     pub fn new() -> Arc<Self> {
-        use crate::default_tracker_address;
         use crate::{body, ElementExt};
+        use crate::{default_tracker_address, DEFAULT_ICE_SERVERS, DEFAULT_MAX_CONNECTIONS};
+        use tracker_protocol::DEFAULT_ROOM;
 
         let app_div: HtmlDivElement = body().unwrap().add_child("div").unwrap();
 
@@ -27,6 +33,23 @@ impl AppUi {
             .add_input("server address", &default_tracker_address())
             .unwrap();
 
+        let ice_servers_input = app_div
+            .add_input("ICE servers ([user:pass@]url, ...)", DEFAULT_ICE_SERVERS)
+            .unwrap();
+
+        let host_only_input = app_div
+            .add_input("host candidates only, no ICE servers (LAN)", "")
+            .unwrap();
+        host_only_input.set_type("checkbox");
+
+        let label_input = app_div.add_input("peer label (optional)", "").unwrap();
+
+        let room_input = app_div.add_input("room", DEFAULT_ROOM).unwrap();
+
+        let max_connections_input = app_div
+            .add_input("max connections", DEFAULT_MAX_CONNECTIONS)
+            .unwrap();
+
         let connect_button: HtmlButtonElement = app_div.add_child("button").unwrap();
         connect_button.add_text("Connect to server").unwrap();
 
@@ -34,6 +57,11 @@ impl AppUi {
             peer: RefCell::new(None),
             app_div,
             tracker_address_input,
+            ice_servers_input,
+            host_only_input,
+            label_input,
+            room_input,
+            max_connections_input,
             //upload_speed_limit_input,
             //max_channel_buffer_input,
             //peer_send_interval_input,
@@ -62,6 +90,11 @@ impl AppUi {
 
     fn set_connect_buttons_inactive(&self) {
         self.tracker_address_input.set_read_only(true);
+        self.ice_servers_input.set_read_only(true);
+        self.host_only_input.set_disabled(true);
+        self.label_input.set_read_only(true);
+        self.room_input.set_read_only(true);
+        self.max_connections_input.set_read_only(true);
         //self.upload_speed_limit_input.set_read_only(true);
         //self.max_channel_buffer_input.set_read_only(true);
         //self.peer_send_interval_input.set_read_only(true);
@@ -69,14 +102,47 @@ impl AppUi {
     }
 
     fn on_connect_click(self: &Arc<Self>, _: Event) {
+        use peer::{IceCandidatePolicy, IceServerConfig};
         use wasm_bindgen_futures::spawn_local;
 
+        let ice_servers = match IceServerConfig::parse_list(&self.ice_servers_input.value()) {
+            Ok(ice_servers) => ice_servers,
+            Err(err) => {
+                log::error!("ICE servers parse failed: {}", err);
+                return;
+            }
+        };
+
+        let ice_candidate_policy = if self.host_only_input.checked() {
+            IceCandidatePolicy::HostOnly
+        } else {
+            IceCandidatePolicy::All
+        };
+
+        let max_connections: usize = match self.max_connections_input.value().parse() {
+            Ok(max_connections) => max_connections,
+            Err(err) => {
+                log::error!("max connections parse failed: {}", err);
+                return;
+            }
+        };
+
         self.set_connect_buttons_inactive();
         let tracker_addr = self.fix_and_get_tracker_address();
+        let room = self.room_input.value();
+        let label = Some(self.label_input.value().trim().to_owned()).filter(|s| !s.is_empty());
 
         let self_arc = Arc::clone(self);
         spawn_local(async move {
-            let peer = PeerUi::new(tracker_addr).await;
+            let peer = PeerUi::new(
+                tracker_addr,
+                ice_servers,
+                ice_candidate_policy,
+                room,
+                label,
+                max_connections,
+            )
+            .await;
             let prev = self_arc.peer.replace(Some(peer));
             assert!(prev.is_none());
         });
@@ -2,17 +2,28 @@ use core::cell::RefCell;
 use std::sync::Arc;
 
 use async_std::sync::RwLock;
-use peer::JsSharedFile;
-use web_sys::{Event, HtmlButtonElement, HtmlCanvasElement, HtmlDivElement, HtmlInputElement};
+use peer::{FileDiscoveryStatus, JsSharedFile};
+use tracker_protocol::FileSha256;
+use web_sys::{
+    Blob, Event, HtmlButtonElement, HtmlCanvasElement, HtmlDivElement, HtmlInputElement,
+};
 
 use crate::{ClosureCell1, Time};
 
+const CANVAS_WIDTH: u32 = 1024;
+const CANVAS_HEIGHT: u32 = 256;
+
+// Pieces of larger files would take less than a quarter of a pixel.
+const MAX_CANVAS_NUM_PIECES: usize = 1024 * 1024;
+
 #[derive(Debug)]
 pub struct FileUi {
+    sha256: FileSha256,
     shared_file: Arc<RwLock<JsSharedFile<Time>>>,
     file_div: HtmlDivElement,
     download_button: HtmlButtonElement,
     download_button_handler: ClosureCell1<Event>,
+    congestion_div: HtmlDivElement,
     canvas: Option<HtmlCanvasElement>,
 }
 
@@ -21,7 +32,8 @@ impl FileUi {
         use crate::{body, ElementExt};
 
         let shared_file_ref = shared_file.read().await;
-        let metadata = shared_file_ref.file().metadata();
+        let metadata = shared_file_ref.file().metadata().clone();
+        let sha256 = metadata.sha256();
 
         let file_div: HtmlDivElement = body().unwrap().add_div().unwrap();
 
@@ -36,9 +48,19 @@ impl FileUi {
                 metadata.sha256()
             ))
             .unwrap();
+        if let Some(mime_type) = metadata.mime_type() {
+            file_div
+                .add_div()
+                .unwrap()
+                .add_text(&format!("type = {}", mime_type))
+                .unwrap();
+        }
+        if let Some(description) = metadata.description() {
+            file_div.add_div().unwrap().add_text(description).unwrap();
+        }
 
         let magnet_input: HtmlInputElement = file_div
-            .add_input("magnet", &metadata.encode_base64().unwrap())
+            .add_input("magnet", &metadata.encode_magnet())
             .unwrap();
         magnet_input.class_list().add_1("magnet").unwrap();
         magnet_input.set_read_only(true);
@@ -49,11 +71,13 @@ impl FileUi {
         download_button.add_text("Loading").unwrap();
         download_button.set_disabled(true);
 
+        let congestion_div: HtmlDivElement = file_div.add_div().unwrap();
+
         let shared_file_ref = shared_file.read().await;
-        let canvas = if shared_file_ref.num_pieces() <= 1024 * 1024 {
+        let canvas = if shared_file_ref.num_pieces() <= MAX_CANVAS_NUM_PIECES {
             let canvas: HtmlCanvasElement = file_div.add_child("canvas").unwrap();
-            canvas.set_width(1024);
-            canvas.set_height(256);
+            canvas.set_width(CANVAS_WIDTH);
+            canvas.set_height(CANVAS_HEIGHT);
             Some(canvas)
         } else {
             None
@@ -61,10 +85,12 @@ impl FileUi {
         drop(shared_file_ref);
 
         let file_ui = Arc::new(Self {
+            sha256,
             shared_file,
             file_div,
             download_button,
             download_button_handler: RefCell::new(None),
+            congestion_div,
             canvas,
         });
 
@@ -86,10 +112,14 @@ impl FileUi {
         );
     }
 
+    pub fn sha256(&self) -> FileSha256 {
+        self.sha256
+    }
+
     fn on_download_click(self: &Arc<Self>, _: Event) {
         use crate::{body, ElementExt};
         use wasm_bindgen_futures::spawn_local;
-        use web_sys::{HtmlAnchorElement, Url};
+        use web_sys::HtmlAnchorElement;
 
         let file_ui = Arc::clone(&self);
         spawn_local(async move {
@@ -102,40 +132,90 @@ impl FileUi {
                     return;
                 }
             };
-            let url = Url::create_object_url_with_blob(&blob).unwrap();
+            let url = match create_object_url(&blob) {
+                Some(url) => url,
+                None => return,
+            };
 
             let link: HtmlAnchorElement = body().unwrap().add_child("a").unwrap();
-            let name = shared_file.file().metadata().name();
+            let file = shared_file.file();
+            let name = file.metadata().name();
             link.set_href(&url);
             link.set_target("_blank");
             link.set_download(name);
             link.click();
-            Url::revoke_object_url(&url).unwrap();
+            revoke_object_url(&url);
         })
     }
 
-    pub async fn update(self: &Arc<Self>) {
+    pub async fn update(
+        self: &Arc<Self>,
+        discovery_status: Option<FileDiscoveryStatus>,
+        now: Time,
+    ) {
         use crate::ElementExt;
         use wasm_bindgen::{Clamped, JsCast};
         use web_sys::{CanvasRenderingContext2d, ImageData};
 
         let shared_file = self.shared_file.read().await;
-        let state = shared_file.file().state();
+        let state = shared_file.file().state().clone();
+
+        let mut blocked_peers: Vec<_> = shared_file
+            .send_blocked_peers()
+            .map(|(peer_id, _)| peer_id.0)
+            .collect();
+        blocked_peers.sort_unstable();
+        let congestion_text: String = blocked_peers
+            .iter()
+            .map(|peer_id| format!("peer {} congested", peer_id))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if self.congestion_div.text_content().unwrap_or_default() != congestion_text {
+            self.congestion_div.replace_text(&congestion_text).unwrap();
+        }
 
         if state.is_complete() {
+            let text = if shared_file.is_fully_distributed() {
+                "Download (seeding complete)"
+            } else {
+                "Download"
+            };
+            if self.download_button.text_content().as_deref() != Some(text) {
+                self.download_button.replace_text(text).unwrap();
+            }
             if self.download_button.disabled() {
-                self.download_button.replace_text("Download").unwrap();
                 self.download_button.set_disabled(false);
                 if let Some(canvas) = self.canvas.as_ref() {
                     canvas.remove();
                 }
             }
+        } else if discovery_status == Some(FileDiscoveryStatus::NoSeedersFound) {
+            let text = "No seeders found, still searching";
+            if self.download_button.text_content().as_deref() != Some(text) {
+                self.download_button.replace_text(text).unwrap();
+            }
         } else {
+            let availability = shared_file.availability();
+            let warning = if availability < 1.0 {
+                ", file may never complete"
+            } else {
+                ""
+            };
+            let eta = match shared_file.estimated_completion(now) {
+                Some(completion) => format!("{:.0}s", (completion - now).as_secs_f64()),
+                None => "unknown".to_owned(),
+            };
             self.download_button
                 .replace_text(&format!(
-                    "Loading: {}/{}",
+                    "Loading: {} of {} ({}/{} pieces), ETA {}, availability {:.2} (rarest piece {}){}",
+                    format_megabytes(shared_file.bytes_available()),
+                    format_megabytes(shared_file.file().len().0),
                     state.num_available(),
-                    state.len()
+                    state.len(),
+                    eta,
+                    availability,
+                    shared_file.min_availability(),
+                    warning
                 ))
                 .unwrap();
             if let Some(canvas) = self.canvas.as_ref() {
@@ -144,13 +224,12 @@ impl FileUi {
                 let mut data = vec![0; (width * height * 4) as usize];
 
                 let shared_file = self.shared_file.read().await;
-                let num_lines = (shared_file.num_pieces() + 1023) / 256;
+                let num_pieces = shared_file.num_pieces();
 
                 for (j, bit) in shared_file.file().state().raw().iter().enumerate() {
-                    let x = j % 1024;
-                    let y = j / 1024;
-                    for k in y * 1024 / num_lines..(y + 1) * 1024 / num_lines {
-                        let offset = (k * 1024 + x) * 4;
+                    let offsets =
+                        piece_pixel_offsets(j, num_pieces, width as usize, height as usize);
+                    for offset in offsets {
                         if *bit {
                             data[offset] = 58;
                             data[offset + 1] = 151;
@@ -185,3 +264,75 @@ impl Drop for FileUi {
         self.file_div.remove();
     }
 }
+
+/// Returns byte offsets of the piece pixels in RGBA canvas data.
+///
+/// Pieces are laid out in rows of the canvas width,
+/// rows are scaled to the canvas height and are at least one pixel high.
+// Object urls may be unavailable in restricted environments,
+// so failures are logged instead of panicking in the download handler.
+fn create_object_url(blob: &Blob) -> Option<String> {
+    use web_sys::Url;
+
+    match Url::create_object_url_with_blob(blob) {
+        Ok(url) => Some(url),
+        Err(err) => {
+            log::error!("Can not create object url: {:?}", err);
+            None
+        }
+    }
+}
+
+fn revoke_object_url(url: &str) {
+    use web_sys::Url;
+
+    if let Err(err) = Url::revoke_object_url(url) {
+        log::warn!("Can not revoke object url: {:?}", err);
+    }
+}
+
+fn format_megabytes(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+fn piece_pixel_offsets(
+    piece_idx: usize,
+    num_pieces: usize,
+    width: usize,
+    height: usize,
+) -> impl Iterator<Item = usize> {
+    let num_rows = num_pieces.div_ceil(width).max(1);
+    let x = piece_idx % width;
+    let row = piece_idx / width;
+    let top = (row * height / num_rows).min(height - 1);
+    let bottom = ((row + 1) * height / num_rows).clamp(top + 1, height);
+    (top..bottom).map(move |y| (y * width + x) * 4)
+}
+
+#[test]
+fn map_pieces_into_canvas() {
+    let width = CANVAS_WIDTH as usize;
+    let height = CANVAS_HEIGHT as usize;
+    let len = width * height * 4;
+
+    for num_pieces in [1, 256, 1024, 262_144, MAX_CANVAS_NUM_PIECES] {
+        let mut num_drawn_rows = 0;
+        for piece_idx in 0..num_pieces {
+            let offsets: Vec<_> =
+                piece_pixel_offsets(piece_idx, num_pieces, width, height).collect();
+            assert!(!offsets.is_empty());
+            assert!(offsets.iter().all(|offset| offset + 3 < len));
+            if piece_idx % width == 0 {
+                num_drawn_rows += offsets.len();
+            }
+        }
+        // Piece rows fill the whole canvas height.
+        assert!(num_drawn_rows >= height);
+    }
+
+    // A single row of pieces is stretched over the whole canvas.
+    let offsets: Vec<_> = piece_pixel_offsets(3, 256, width, height).collect();
+    assert_eq!(offsets.len(), height);
+    assert_eq!(offsets[0], 3 * 4);
+    assert_eq!(*offsets.last().unwrap(), ((height - 1) * width + 3) * 4);
+}
@@ -31,14 +31,16 @@ use file_ui::FileUi;
 use html::{body, ElementExt};
 use interval_handler::{IntervalHandler, NewIntervalHandlerError};
 use params::{
-    default_tracker_address, DEFAULT_MAX_DATACHANNEL_BUFFER_BYTES, DEFAULT_PEER_DATA_SEND_INTERVAL,
-    DEFAULT_PIECE_RESEND_INTERVAL, DEFAULT_STATE_RESEND_INTERVAL,
-    DEFAULT_UPLOAD_SPEED_BYTES_PER_SECOND,
+    default_tracker_address, DEFAULT_ICE_SERVERS, DEFAULT_MAX_CONNECTIONS,
+    DEFAULT_MAX_DATACHANNEL_BUFFER_BYTES, DEFAULT_MAX_FILES, DEFAULT_MAX_PIECES_PER_PEER,
+    DEFAULT_PEER_DATA_SEND_INTERVAL, DEFAULT_PIECES_BATCH_SIZE, DEFAULT_PIECE_RESEND_INTERVAL,
+    DEFAULT_STATE_RESEND_INTERVAL, DEFAULT_UPLOAD_SPEED_BYTES_PER_SECOND, IDLE_PEER_PRUNE_INTERVAL,
+    PEER_PING_INTERVAL, UNRESPONSIVE_PEER_TIMEOUT,
 };
 use peer_ui::PeerUi;
 use rand_ext::JsRandom;
 use sender::{Sender, SenderParams};
-use time::{monotonic_time, Time};
+use time::{MonotonicClock, Time};
 
 fn main() {
     console_error_panic_hook::set_once();
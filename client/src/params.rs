@@ -1,8 +1,18 @@
+use core::time::Duration;
+
 pub const DEFAULT_UPLOAD_SPEED_BYTES_PER_SECOND: &str = "1048576";
 pub const DEFAULT_MAX_DATACHANNEL_BUFFER_BYTES: &str = "2097152";
 pub const DEFAULT_PEER_DATA_SEND_INTERVAL: &str = "0.1";
 pub const DEFAULT_STATE_RESEND_INTERVAL: &str = "10";
 pub const DEFAULT_PIECE_RESEND_INTERVAL: &str = "0.5";
+pub const DEFAULT_PIECES_BATCH_SIZE: &str = "64";
+pub const DEFAULT_MAX_PIECES_PER_PEER: &str = "0";
+pub const DEFAULT_ICE_SERVERS: &str = "stun:stun.l.google.com:19302";
+pub const DEFAULT_MAX_CONNECTIONS: &str = "32";
+pub const DEFAULT_MAX_FILES: &str = "16";
+pub const IDLE_PEER_PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+pub const PEER_PING_INTERVAL: Duration = Duration::from_secs(5);
+pub const UNRESPONSIVE_PEER_TIMEOUT: Duration = Duration::from_secs(15);
 
 pub fn default_tracker_address() -> String {
     const FALLBACK_ADDRESS: &str = "ws://localhost:9010";
@@ -2,13 +2,17 @@ use core::cell::RefCell;
 use std::sync::Arc;
 
 use async_std::sync::RwLock;
-use peer::LocalPeer;
-use web_sys::{Event, HtmlButtonElement, HtmlDivElement, HtmlInputElement};
+use peer::{
+    DataChannelConfig, IceCandidatePolicy, IceServerConfig, LocalPeer, PeerChangeEvent, SendPhases,
+};
+use web_sys::{Event, HtmlButtonElement, HtmlDivElement, HtmlInputElement, HtmlSpanElement};
 
 use crate::{
     ClosureCell1, FileUi, Sender, SenderParams, Time, DEFAULT_MAX_DATACHANNEL_BUFFER_BYTES,
-    DEFAULT_PEER_DATA_SEND_INTERVAL, DEFAULT_PIECE_RESEND_INTERVAL, DEFAULT_STATE_RESEND_INTERVAL,
-    DEFAULT_UPLOAD_SPEED_BYTES_PER_SECOND,
+    DEFAULT_MAX_FILES, DEFAULT_MAX_PIECES_PER_PEER, DEFAULT_PEER_DATA_SEND_INTERVAL,
+    DEFAULT_PIECES_BATCH_SIZE, DEFAULT_PIECE_RESEND_INTERVAL, DEFAULT_STATE_RESEND_INTERVAL,
+    DEFAULT_UPLOAD_SPEED_BYTES_PER_SECOND, IDLE_PEER_PRUNE_INTERVAL, PEER_PING_INTERVAL,
+    UNRESPONSIVE_PEER_TIMEOUT,
 };
 
 #[derive(Debug)]
@@ -17,36 +21,77 @@ pub struct PeerUi {
     local_files: RwLock<Vec<Arc<FileUi>>>,
     peer_sender: RwLock<Option<Sender>>,
     peer_div: HtmlDivElement,
+    stats_div: HtmlDivElement,
+    connected_peers_div: HtmlDivElement,
     recv_div: HtmlDivElement,
     send_div: HtmlDivElement,
     file_input: HtmlInputElement,
     magnet_input: HtmlInputElement,
     recv_button: HtmlButtonElement,
+    swarm_button: HtmlButtonElement,
+    swarm_span: HtmlSpanElement,
     send_button: HtmlButtonElement,
     upload_speed_limit_input: HtmlInputElement,
     max_channel_buffer_input: HtmlInputElement,
     peer_send_interval_input: HtmlInputElement,
     state_resend_interval_input: HtmlInputElement,
     piece_resend_interval_input: HtmlInputElement,
+    pieces_batch_size_input: HtmlInputElement,
+    max_pieces_per_peer_input: HtmlInputElement,
+    adaptive_send_interval_input: HtmlInputElement,
+    piece_passphrase_input: HtmlInputElement,
+    max_files_input: HtmlInputElement,
     file_input_handler: ClosureCell1<Event>,
     recv_button_handler: ClosureCell1<Event>,
+    swarm_button_handler: ClosureCell1<Event>,
     send_button_handler: ClosureCell1<Event>,
     upload_speed_limit_handler: ClosureCell1<Event>,
     max_channel_buffer_handler: ClosureCell1<Event>,
     peer_send_interval_handler: ClosureCell1<Event>,
     state_resend_interval_handler: ClosureCell1<Event>,
     piece_resend_interval_handler: ClosureCell1<Event>,
+    pieces_batch_size_handler: ClosureCell1<Event>,
+    max_pieces_per_peer_handler: ClosureCell1<Event>,
+    adaptive_send_interval_handler: ClosureCell1<Event>,
+    piece_passphrase_handler: ClosureCell1<Event>,
+    max_files_handler: ClosureCell1<Event>,
 }
 
 impl PeerUi {
-    pub async fn new(tracker_addr: String) -> Arc<Self> {
+    pub async fn new(
+        tracker_addr: String,
+        ice_servers: Vec<IceServerConfig>,
+        ice_candidate_policy: IceCandidatePolicy,
+        room: String,
+        label: Option<String>,
+        max_connections: usize,
+    ) -> Arc<Self> {
         use crate::{body, ElementExt};
+        use tracker_protocol::PeerTrackerMessage;
 
         let peer_div: HtmlDivElement = body().unwrap().add_div().unwrap();
 
-        let local_peer = LocalPeer::new(tracker_addr).await;
-
-        peer_div.add_div().unwrap().add_text("Peer:").unwrap();
+        let local_peer = LocalPeer::new(
+            tracker_addr,
+            ice_servers,
+            ice_candidate_policy,
+            DataChannelConfig::default(),
+            room,
+            max_connections,
+        )
+        .await;
+
+        match &label {
+            Some(label) => peer_div
+                .add_div()
+                .unwrap()
+                .add_text(&format!("Peer ({}):", label))
+                .unwrap(),
+            None => peer_div.add_div().unwrap().add_text("Peer:").unwrap(),
+        }
+        if let Some(label) = label {
+            local_peer.send(PeerTrackerMessage::SetLabel { label });
+        }
 
         let upload_speed_limit_input = peer_div
             .add_div()
@@ -93,6 +138,44 @@ impl PeerUi {
             )
             .unwrap();
 
+        let pieces_batch_size_input = peer_div
+            .add_div()
+            .unwrap()
+            .add_input("pieces send batch size:", DEFAULT_PIECES_BATCH_SIZE)
+            .unwrap();
+
+        let max_pieces_per_peer_input = peer_div
+            .add_div()
+            .unwrap()
+            .add_input(
+                "max pieces per peer per send (0 for unlimited):",
+                DEFAULT_MAX_PIECES_PER_PEER,
+            )
+            .unwrap();
+
+        let adaptive_send_interval_input = peer_div
+            .add_div()
+            .unwrap()
+            .add_input("adapt send interval to throughput:", "")
+            .unwrap();
+        adaptive_send_interval_input.set_type("checkbox");
+
+        let piece_passphrase_input = peer_div
+            .add_div()
+            .unwrap()
+            .add_input("piece encryption passphrase (empty to disable):", "")
+            .unwrap();
+        piece_passphrase_input.set_type("password");
+
+        let max_files_input = peer_div
+            .add_div()
+            .unwrap()
+            .add_input("max shared files (0 for unlimited):", DEFAULT_MAX_FILES)
+            .unwrap();
+
+        let stats_div: HtmlDivElement = peer_div.add_div().unwrap();
+        let connected_peers_div: HtmlDivElement = peer_div.add_div().unwrap();
+        connected_peers_div.add_text("Connected peers: 0").unwrap();
         let recv_div: HtmlDivElement = peer_div.add_div().unwrap();
         let send_div: HtmlDivElement = peer_div.add_div().unwrap();
 
@@ -103,6 +186,10 @@ impl PeerUi {
         let recv_button: HtmlButtonElement = recv_div.add_child("button").unwrap();
         recv_button.add_text("Receive file by magnet").unwrap();
 
+        let swarm_button: HtmlButtonElement = recv_div.add_child("button").unwrap();
+        swarm_button.add_text("Query swarm size").unwrap();
+        let swarm_span: HtmlSpanElement = recv_div.add_child("span").unwrap();
+
         send_div.add_div().unwrap().add_text("Send:").unwrap();
         let file_input: HtmlInputElement = send_div.add_child("input").unwrap();
         file_input.set_type("file");
@@ -117,26 +204,41 @@ impl PeerUi {
             local_files: RwLock::new(Vec::new()),
             peer_sender: RwLock::new(None),
             peer_div,
+            stats_div,
+            connected_peers_div,
             recv_div,
             send_div,
             file_input,
             magnet_input,
             recv_button,
+            swarm_button,
+            swarm_span,
             send_button,
             upload_speed_limit_input,
             max_channel_buffer_input,
             peer_send_interval_input,
             state_resend_interval_input,
             piece_resend_interval_input,
+            pieces_batch_size_input,
+            max_pieces_per_peer_input,
+            adaptive_send_interval_input,
+            piece_passphrase_input,
+            max_files_input,
             //peer_sender_handler: RefCell::new(None),
             file_input_handler: RefCell::new(None),
             recv_button_handler: RefCell::new(None),
+            swarm_button_handler: RefCell::new(None),
             send_button_handler: RefCell::new(None),
             upload_speed_limit_handler: RefCell::new(None),
             max_channel_buffer_handler: RefCell::new(None),
             peer_send_interval_handler: RefCell::new(None),
             state_resend_interval_handler: RefCell::new(None),
             piece_resend_interval_handler: RefCell::new(None),
+            pieces_batch_size_handler: RefCell::new(None),
+            max_pieces_per_peer_handler: RefCell::new(None),
+            adaptive_send_interval_handler: RefCell::new(None),
+            piece_passphrase_handler: RefCell::new(None),
+            max_files_handler: RefCell::new(None),
         });
 
         peer_ui.init();
@@ -144,10 +246,29 @@ impl PeerUi {
         peer_ui
     }
 
+    fn on_peer_change(&self, event: PeerChangeEvent) {
+        use crate::ElementExt;
+
+        log::debug!("peer change: {:?}", event);
+        self.connected_peers_div
+            .replace_text(&format!(
+                "Connected peers: {}",
+                self.local_peer.num_connected_peers()
+            ))
+            .unwrap();
+    }
+
     fn init(self: &Arc<Self>) {
         use crate::init_weak_callback;
         use web_sys::HtmlElement;
 
+        let self_weak = Arc::downgrade(self);
+        self.local_peer.set_on_peer_change(move |event| {
+            if let Some(peer_ui) = self_weak.upgrade() {
+                peer_ui.on_peer_change(event);
+            }
+        });
+
         init_weak_callback(
             &self,
             Self::on_file_input,
@@ -164,6 +285,14 @@ impl PeerUi {
             &self.recv_button,
         );
 
+        init_weak_callback(
+            &self,
+            Self::on_swarm_click,
+            &self.swarm_button_handler,
+            HtmlElement::set_onclick,
+            &self.swarm_button,
+        );
+
         init_weak_callback(
             &self,
             Self::on_send_click,
@@ -212,15 +341,87 @@ impl PeerUi {
             &self.piece_resend_interval_input,
         );
 
+        init_weak_callback(
+            &self,
+            Self::on_update_peer_sender,
+            &self.pieces_batch_size_handler,
+            HtmlElement::set_onchange,
+            &self.pieces_batch_size_input,
+        );
+
+        init_weak_callback(
+            &self,
+            Self::on_update_peer_sender,
+            &self.max_pieces_per_peer_handler,
+            HtmlElement::set_onchange,
+            &self.max_pieces_per_peer_input,
+        );
+
+        init_weak_callback(
+            &self,
+            Self::on_update_peer_sender,
+            &self.adaptive_send_interval_handler,
+            HtmlElement::set_onchange,
+            &self.adaptive_send_interval_input,
+        );
+
+        init_weak_callback(
+            &self,
+            Self::on_piece_passphrase_change,
+            &self.piece_passphrase_handler,
+            HtmlElement::set_onchange,
+            &self.piece_passphrase_input,
+        );
+
+        init_weak_callback(
+            &self,
+            Self::on_max_files_change,
+            &self.max_files_handler,
+            HtmlElement::set_onchange,
+            &self.max_files_input,
+        );
+
+        self.update_max_files();
         self.update_peer_sender();
     }
 
+    // The passphrase is shared out-of-band and applies to peers connected afterwards.
+    fn on_piece_passphrase_change(self: &Arc<Self>, _: Event) {
+        use peer::PieceCipher;
+
+        let passphrase = self.piece_passphrase_input.value();
+        let piece_cipher = if passphrase.is_empty() {
+            None
+        } else {
+            Some(PieceCipher::from_passphrase(&passphrase))
+        };
+        self.local_peer.set_piece_cipher(piece_cipher);
+    }
+
+    fn on_max_files_change(self: &Arc<Self>, _: Event) {
+        self.update_max_files();
+    }
+
+    // Already shared files are kept, the limit only applies to files added afterwards.
+    fn update_max_files(&self) {
+        let max_files: usize = match self.max_files_input.value().parse() {
+            Ok(max_files) => max_files,
+            Err(err) => {
+                log::error!("max files parse failed: {:?}", err);
+                return;
+            }
+        };
+        self.local_peer
+            .set_max_files(Some(max_files).filter(|&max_files| max_files > 0));
+    }
+
     fn on_update_peer_sender(self: &Arc<Self>, _: Event) {
         self.update_peer_sender();
     }
 
     fn update_peer_sender(self: &Arc<Self>) {
-        use peer::FILE_PIECE_SIZE;
+        use crate::{ElementExt, MonotonicClock};
+        use peer::{AdaptiveSendInterval, Clock, FILE_PIECE_SIZE};
         use std::time::Duration;
         use wasm_bindgen_futures::spawn_local;
 
@@ -231,6 +432,8 @@ impl PeerUi {
             self.state_resend_interval_input.value().parse();
         let piece_resend_interval: Result<f64, _> =
             self.piece_resend_interval_input.value().parse();
+        let pieces_batch_size: Result<usize, _> = self.pieces_batch_size_input.value().parse();
+        let max_pieces_per_peer: Result<usize, _> = self.max_pieces_per_peer_input.value().parse();
 
         let (
             upload_speed_limit,
@@ -238,34 +441,78 @@ impl PeerUi {
             peer_send_interval,
             state_resend_interval,
             piece_resend_interval,
+            pieces_batch_size,
+            max_pieces_per_peer,
         ) = match (
             upload_speed_limit,
             max_channel_buffer,
             peer_send_interval,
             state_resend_interval,
             piece_resend_interval,
+            pieces_batch_size,
+            max_pieces_per_peer,
         ) {
-            (Ok(v1), Ok(v2), Ok(v3), Ok(v4), Ok(v5)) => (v1, v2, v3, v4, v5),
-            (v1, v2, v3, v4, v5) => {
+            (Ok(v1), Ok(v2), Ok(v3), Ok(v4), Ok(v5), Ok(v6), Ok(v7)) => {
+                (v1, v2, v3, v4, v5, v6, v7)
+            }
+            (v1, v2, v3, v4, v5, v6, v7) => {
                 log::error!(
-                    "PeerSender params parse failed: {:?} {:?} {:?} {:?} {:?}",
+                    "PeerSender params parse failed: {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
                     v1,
                     v2,
                     v3,
                     v4,
-                    v5
+                    v5,
+                    v6,
+                    v7
                 );
                 return;
             }
         };
 
+        // The buffer is kept half full so that it neither overflows nor runs empty.
+        let adaptive_send_interval = if self.adaptive_send_interval_input.checked() {
+            Some(AdaptiveSendInterval::new(
+                Duration::from_secs_f64(peer_send_interval),
+                max_channel_buffer / 2,
+            ))
+        } else {
+            None
+        };
+
+        let clock = match MonotonicClock::new() {
+            Ok(clock) => clock,
+            Err(err) => {
+                log::error!("clock initialization failed: {}", err);
+                return;
+            }
+        };
+
         let peer_ui = Arc::clone(&self);
+        let update_clock = clock.clone();
 
         let update_callback = move || {
             let peer_ui = Arc::clone(&peer_ui);
+            let now = update_clock.now();
             spawn_local(async move {
+                let stats = peer_ui.local_peer.stats().await;
+                peer_ui
+                    .stats_div
+                    .replace_text(&format!(
+                        "Files: {} ({} complete), peers: {}, uploaded: {} bytes, downloaded: {} bytes",
+                        stats.num_files,
+                        stats.files_complete,
+                        stats.num_peers,
+                        stats.total_bytes_up,
+                        stats.total_bytes_down
+                    ))
+                    .unwrap();
                 for file_ui in peer_ui.local_files.read().await.iter() {
-                    file_ui.update().await;
+                    let discovery_status = peer_ui
+                        .local_peer
+                        .file_discovery_status(&file_ui.sha256())
+                        .await;
+                    file_ui.update(discovery_status, now).await;
                 }
             })
         };
@@ -275,6 +522,7 @@ impl PeerUi {
             let _: Option<_> = peer_ui.peer_sender.write().await.replace(
                 Sender::new(
                     Arc::clone(&peer_ui.local_peer),
+                    clock,
                     SenderParams {
                         data_send_interval: Duration::from_secs_f64(peer_send_interval),
                         state_resend_interval: Duration::from_secs_f64(state_resend_interval),
@@ -284,6 +532,14 @@ impl PeerUi {
                             / FILE_PIECE_SIZE as u64)
                             as usize,
                         max_buffer_bytes: Some(max_channel_buffer),
+                        pieces_batch_size: Some(pieces_batch_size),
+                        max_pieces_per_peer: Some(max_pieces_per_peer)
+                            .filter(|&max_pieces| max_pieces > 0),
+                        idle_peer_prune_interval: IDLE_PEER_PRUNE_INTERVAL,
+                        peer_ping_interval: PEER_PING_INTERVAL,
+                        unresponsive_peer_timeout: UNRESPONSIVE_PEER_TIMEOUT,
+                        adaptive_send_interval,
+                        send_phases: SendPhases::default(),
                     },
                     update_callback,
                 )
@@ -298,7 +554,7 @@ impl PeerUi {
 
         let magnet = self.magnet_input.value();
         let magnet = magnet.trim();
-        let metadata = FileMetadata::decode_base64(magnet);
+        let metadata = FileMetadata::parse_magnet(magnet);
         let metadata = match metadata {
             Ok(metadata) => metadata,
             Err(err) => {
@@ -311,11 +567,15 @@ impl PeerUi {
         spawn_local(async move {
             let file = File::new(metadata);
             match file {
-                Ok(file) => {
-                    let shared_file = peer_ui.local_peer.add_file(file).await.unwrap();
-                    let file_ui = FileUi::new(shared_file).await;
-                    peer_ui.local_files.write().await.push(file_ui);
-                }
+                Ok(file) => match peer_ui.local_peer.add_file(file).await {
+                    Ok(shared_file) => {
+                        let file_ui = FileUi::new(shared_file).await;
+                        peer_ui.local_files.write().await.push(file_ui);
+                    }
+                    Err(err) => {
+                        log::error!("LocalPeer::add_file error: {}", err);
+                    }
+                },
                 Err(err) => {
                     log::error!("LocalFile::new error: {}", err);
                 }
@@ -323,6 +583,31 @@ impl PeerUi {
         });
     }
 
+    fn on_swarm_click(self: &Arc<Self>, _: Event) {
+        use crate::ElementExt;
+        use peer::FileMetadata;
+        use wasm_bindgen_futures::spawn_local;
+
+        let magnet = self.magnet_input.value();
+        let metadata = match FileMetadata::parse_magnet(magnet.trim()) {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                log::error!("error on magnet decode {}", err);
+                return;
+            }
+        };
+
+        self.swarm_span.replace_text("querying...").unwrap();
+        let peer_ui = Arc::clone(&self);
+        spawn_local(async move {
+            let peer_count = peer_ui.local_peer.query_swarm(metadata.sha256()).await;
+            peer_ui
+                .swarm_span
+                .replace_text(&format!("{} peers share the file", peer_count))
+                .unwrap();
+        });
+    }
+
     fn on_file_input(self: &Arc<Self>, _: Event) {
         let num_files = self.file_input.files().map_or(0, |files| files.length());
 
@@ -334,7 +619,8 @@ impl PeerUi {
     }
 
     fn on_send_click(self: &Arc<Self>, _: Event) {
-        use peer::File;
+        use crate::ElementExt;
+        use peer::{File, FileLen};
         use wasm_bindgen_futures::spawn_local;
 
         let files = self.file_input.files();
@@ -343,13 +629,24 @@ impl PeerUi {
                 let file = files.get(j).unwrap();
                 let peer_ui = Arc::clone(&self);
                 spawn_local(async move {
-                    let file = File::from_file(file).await;
+                    let send_button = peer_ui.send_button.clone();
+                    let on_progress = move |hashed: FileLen, total: FileLen| {
+                        send_button
+                            .replace_text(&format!("Hashing {}%...", hashed.0 * 100 / total.0))
+                            .unwrap();
+                    };
+                    let file = File::from_file_with_progress(file, on_progress).await;
+                    peer_ui.send_button.replace_text("Send file").unwrap();
                     match file {
-                        Ok(file) => {
-                            let shared_file = peer_ui.local_peer.add_file(file).await.unwrap();
-                            let file_ui = FileUi::new(shared_file).await;
-                            peer_ui.local_files.write().await.push(file_ui);
-                        }
+                        Ok(file) => match peer_ui.local_peer.add_file(file).await {
+                            Ok(shared_file) => {
+                                let file_ui = FileUi::new(shared_file).await;
+                                peer_ui.local_files.write().await.push(file_ui);
+                            }
+                            Err(err) => {
+                                log::error!("LocalPeer::add_file error: {}", err);
+                            }
+                        },
                         Err(err) => {
                             log::error!("LocalFile::from_file error: {}", err);
                         }
@@ -1,7 +1,9 @@
+use core::cell::Cell;
+use std::rc::Rc;
 use std::sync::Arc;
 use std::time::Duration;
 
-use peer::LocalPeer;
+use peer::{AdaptiveSendInterval, Clock, LocalPeer, SendPhases};
 use thiserror::Error;
 
 use crate::{IntervalHandler, NewIntervalHandlerError, Time};
@@ -13,6 +15,18 @@ pub struct SenderParams {
     pub piece_resend_interval: Duration,
     pub num_pieces_to_be_sent: usize,
     pub max_buffer_bytes: Option<u64>,
+    pub pieces_batch_size: Option<usize>,
+    /// Maximum number of pieces assigned to a single peer per send interval.
+    pub max_pieces_per_peer: Option<usize>,
+    pub idle_peer_prune_interval: Duration,
+    pub peer_ping_interval: Duration,
+    /// Peers that have not answered pings for this long are skipped in piece selection.
+    pub unresponsive_peer_timeout: Duration,
+    /// Adapts the send interval to the measured throughput instead of `data_send_interval`,
+    /// the number of pieces sent is scaled with the interval.
+    pub adaptive_send_interval: Option<AdaptiveSendInterval>,
+    /// Data send phases run on each send interval, in order.
+    pub send_phases: SendPhases,
 }
 
 #[derive(Debug)]
@@ -21,49 +35,145 @@ pub struct Sender {
 }
 
 impl Sender {
-    pub fn new<F>(
+    pub fn new<C, F>(
         peer: Arc<LocalPeer<Time>>,
+        clock: C,
         params: SenderParams,
         update_callback: F,
     ) -> Result<Self, NewPeerSenderError>
     where
+        C: 'static + Clock<Time = Time>,
         F: 'static + Fn(),
     {
-        use crate::{monotonic_time, JsRandom};
+        use crate::JsRandom;
+        use peer::SendPhase;
         use rand_chacha::ChaCha8Rng;
         use wasm_bindgen_futures::spawn_local;
 
         let update_callback = Arc::new(update_callback);
+        let clock = Arc::new(clock);
+        let prev_time = Rc::new(Cell::new(None));
+        let prev_prune_time = Rc::new(Cell::new(None));
+        let prev_ping_time = Rc::new(Cell::new(None));
+        let adaptive_send_interval = Rc::new(Cell::new(params.adaptive_send_interval));
+        let next_send_time = Rc::new(Cell::new(None));
+        let prev_bytes_up = Rc::new(Cell::new(None));
         let callback = move || {
             let update_callback = Arc::clone(&update_callback);
             let peer = Arc::clone(&peer);
+            let clock = Arc::clone(&clock);
+            let prev_time = Rc::clone(&prev_time);
+            let prev_prune_time = Rc::clone(&prev_prune_time);
+            let prev_ping_time = Rc::clone(&prev_ping_time);
+            let adaptive_send_interval = Rc::clone(&adaptive_send_interval);
+            let next_send_time = Rc::clone(&next_send_time);
+            let prev_bytes_up = Rc::clone(&prev_bytes_up);
             spawn_local(async move {
-                let time = monotonic_time().unwrap();
-                let rng = ChaCha8Rng::new();
+                let time = clock.now();
 
-                peer.send_state_to_remote_peers(
-                    time.saturating_sub(params.state_resend_interval),
-                    time,
-                )
-                .await;
+                // In adaptive mode the handler ticks at the minimum interval
+                // and sends are skipped until the adapted interval elapses.
+                let mut num_pieces_to_be_sent = params.num_pieces_to_be_sent;
+                if let Some(adaptive) = adaptive_send_interval.get() {
+                    if next_send_time
+                        .get()
+                        .is_some_and(|next_send_time| time < next_send_time)
+                    {
+                        return;
+                    }
+                    next_send_time.set(Some(time + adaptive.interval()));
+                    num_pieces_to_be_sent = (num_pieces_to_be_sent as f64
+                        * adaptive.interval().as_secs_f64()
+                        / params.data_send_interval.as_secs_f64())
+                    .ceil() as usize;
+                }
 
-                peer.send_recently_received_to_remote_peers().await;
+                if let Some(prev_time) = prev_time.replace(Some(time)) {
+                    peer.update_peer_rates(time.0.saturating_sub(prev_time.0))
+                        .await;
+                }
+                match prev_prune_time.get() {
+                    Some(prev_prune_time)
+                        if time.saturating_sub(params.idle_peer_prune_interval)
+                            < prev_prune_time => {}
+                    _ => {
+                        prev_prune_time.set(Some(time));
+                        peer.prune_idle_peers().await;
+                    }
+                }
+                match prev_ping_time.get() {
+                    Some(prev_ping_time)
+                        if time.saturating_sub(params.peer_ping_interval) < prev_ping_time => {}
+                    _ => {
+                        prev_ping_time.set(Some(time));
+                        peer.check_peers_liveness(
+                            time,
+                            time.saturating_sub(params.unresponsive_peer_timeout),
+                        )
+                        .await;
+                    }
+                }
 
-                peer.resend_pieces_before(time.saturating_sub(params.piece_resend_interval))
-                    .await;
+                peer.admit_pending_connections().await;
 
-                peer.send_pieces_to_remote_peers(
-                    params.num_pieces_to_be_sent,
-                    params.max_buffer_bytes,
-                    time,
-                    rng,
-                )
-                .await;
+                peer.poll_control_queues(time).await;
+
+                peer.retry_offer_requests(time).await;
+
+                peer.update_file_discoveries(time).await;
+
+                peer.stamp_piece_arrivals(time).await;
+
+                for phase in params.send_phases.iter() {
+                    match phase {
+                        SendPhase::State => {
+                            peer.send_state_to_remote_peers(
+                                time.saturating_sub(params.state_resend_interval),
+                                time,
+                            )
+                            .await;
+                        }
+                        SendPhase::RecentlyReceived => {
+                            peer.send_recently_received_to_remote_peers().await;
+                        }
+                        SendPhase::ResendPieces => {
+                            peer.resend_pieces_before(
+                                time.saturating_sub(params.piece_resend_interval),
+                            )
+                            .await;
+                        }
+                        SendPhase::Pieces => {
+                            peer.request_pieces_from_remote_peers(time).await;
+                            peer.send_pieces_to_remote_peers(
+                                num_pieces_to_be_sent,
+                                params.max_buffer_bytes,
+                                params.pieces_batch_size,
+                                params.max_pieces_per_peer,
+                                time,
+                                ChaCha8Rng::new(),
+                            )
+                            .await;
+                        }
+                    }
+                }
+
+                if let Some(mut adaptive) = adaptive_send_interval.get() {
+                    let bytes_up = peer.stats().await.total_bytes_up;
+                    let acked_bytes =
+                        bytes_up.saturating_sub(prev_bytes_up.replace(Some(bytes_up)).unwrap_or(0));
+                    let interval = adaptive.update(acked_bytes, peer.max_buffered_bytes().await);
+                    adaptive_send_interval.set(Some(adaptive));
+                    next_send_time.set(Some(time + interval));
+                }
 
                 update_callback();
             });
         };
-        let handler = IntervalHandler::new(callback, params.data_send_interval)?;
+        let handler_interval = match params.adaptive_send_interval {
+            Some(adaptive) => adaptive.min_interval(),
+            None => params.data_send_interval,
+        };
+        let handler = IntervalHandler::new(callback, handler_interval)?;
 
         Ok(Self { _handler: handler })
     }
@@ -1,17 +1,34 @@
-use core::ops::Add;
+use core::ops::{Add, Sub};
 use std::time::Duration;
 
+use peer::Clock;
 use thiserror::Error;
+use web_sys::Performance;
 
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Time(pub Duration);
 
-pub fn monotonic_time() -> Result<Time, NowError> {
-    let window = web_sys::window().ok_or_else(|| NowError::UndefinedWindow)?;
-    let performance = window
-        .performance()
-        .ok_or_else(|| NowError::UndefinedPerformance)?;
-    Ok(Time(Duration::from_secs_f64(performance.now() * 0.001)))
+#[derive(Clone, Debug)]
+pub struct MonotonicClock {
+    performance: Performance,
+}
+
+impl MonotonicClock {
+    pub fn new() -> Result<Self, NowError> {
+        let window = web_sys::window().ok_or_else(|| NowError::UndefinedWindow)?;
+        let performance = window
+            .performance()
+            .ok_or_else(|| NowError::UndefinedPerformance)?;
+        Ok(Self { performance })
+    }
+}
+
+impl Clock for MonotonicClock {
+    type Time = Time;
+
+    fn now(&self) -> Time {
+        Time(Duration::from_secs_f64(self.performance.now() * 0.001))
+    }
 }
 
 impl Time {
@@ -27,6 +44,13 @@ impl Add<Duration> for Time {
     }
 }
 
+impl Sub for Time {
+    type Output = Duration;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self.0.saturating_sub(rhs.0)
+    }
+}
+
 #[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
 pub enum NowError {
     #[error("js window is undefined")]
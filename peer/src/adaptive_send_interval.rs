@@ -0,0 +1,117 @@
+use core::time::Duration;
+
+pub const MIN_ADAPTIVE_SEND_INTERVAL: Duration = Duration::from_millis(50);
+pub const MAX_ADAPTIVE_SEND_INTERVAL: Duration = Duration::from_secs(4);
+
+/// Send interval adjusted to keep the data channel buffer near the target occupancy.
+///
+/// The next send is scheduled once the bytes buffered above the target
+/// are drained at the throughput measured from acknowledged bytes,
+/// an underfilled buffer halves the interval.
+/// The interval changes at most twice per update and stays within the bounds.
+#[derive(Clone, Copy, Debug)]
+pub struct AdaptiveSendInterval {
+    interval: Duration,
+    min_interval: Duration,
+    max_interval: Duration,
+    target_buffer_bytes: u64,
+}
+
+impl AdaptiveSendInterval {
+    pub fn new(interval: Duration, target_buffer_bytes: u64) -> Self {
+        Self {
+            interval: interval.clamp(MIN_ADAPTIVE_SEND_INTERVAL, MAX_ADAPTIVE_SEND_INTERVAL),
+            min_interval: MIN_ADAPTIVE_SEND_INTERVAL,
+            max_interval: MAX_ADAPTIVE_SEND_INTERVAL,
+            target_buffer_bytes,
+        }
+    }
+
+    pub fn with_bounds(self, min_interval: Duration, max_interval: Duration) -> Self {
+        Self {
+            interval: self.interval.clamp(min_interval, max_interval),
+            min_interval,
+            max_interval,
+            ..self
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+
+    pub fn max_interval(&self) -> Duration {
+        self.max_interval
+    }
+
+    pub fn target_buffer_bytes(&self) -> u64 {
+        self.target_buffer_bytes
+    }
+
+    /// Updates the interval from bytes acknowledged during the last interval
+    /// and bytes currently buffered, returns the new interval.
+    pub fn update(&mut self, acked_bytes: u64, buffered_bytes: u64) -> Duration {
+        let interval = self.interval.as_secs_f64();
+        let next_interval = if buffered_bytes <= self.target_buffer_bytes {
+            interval / 2.0
+        } else if acked_bytes == 0 {
+            // The link is stalled, so the buffer is not drained at all.
+            interval * 2.0
+        } else {
+            let throughput = acked_bytes as f64 / interval;
+            (buffered_bytes - self.target_buffer_bytes) as f64 / throughput
+        };
+        let next_interval = next_interval.clamp(interval / 2.0, interval * 2.0);
+        self.interval =
+            Duration::from_secs_f64(next_interval).clamp(self.min_interval, self.max_interval);
+        self.interval
+    }
+}
+
+#[test]
+fn adapt_send_interval_to_throughput() {
+    let mut interval = AdaptiveSendInterval::new(Duration::from_secs(1), 64 * 1024);
+    assert_eq!(interval.interval(), Duration::from_secs(1));
+
+    // 96 KiB above the target are drained in 1.5 s at 64 KiB/s.
+    assert_eq!(
+        interval.update(64 * 1024, 160 * 1024),
+        Duration::from_millis(1500)
+    );
+    // 16 KiB above the target are drained in 0.125 s at 128 KiB/s,
+    // but the interval is at most halved.
+    assert_eq!(
+        interval.update(192 * 1024, 80 * 1024),
+        Duration::from_millis(750)
+    );
+    // Underfilled buffer is topped up sooner.
+    assert_eq!(interval.update(48 * 1024, 0), Duration::from_millis(375));
+    // Stalled link backs off.
+    assert_eq!(interval.update(0, 128 * 1024), Duration::from_millis(750));
+
+    for _ in 0..16 {
+        let _: Duration = interval.update(0, 0);
+    }
+    assert_eq!(interval.interval(), MIN_ADAPTIVE_SEND_INTERVAL);
+    for _ in 0..16 {
+        let _: Duration = interval.update(0, u64::MAX);
+    }
+    assert_eq!(interval.interval(), MAX_ADAPTIVE_SEND_INTERVAL);
+}
+
+#[test]
+fn keep_adaptive_send_interval_within_bounds() {
+    let mut interval = AdaptiveSendInterval::new(Duration::from_secs(10), 1024)
+        .with_bounds(Duration::from_millis(100), Duration::from_millis(200));
+    assert_eq!(interval.interval(), Duration::from_millis(200));
+    assert_eq!(
+        interval.update(1024, 1024 * 1024),
+        Duration::from_millis(200)
+    );
+    assert_eq!(interval.update(1024, 0), Duration::from_millis(100));
+    assert_eq!(interval.update(1024, 0), Duration::from_millis(100));
+}
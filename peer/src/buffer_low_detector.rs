@@ -0,0 +1,101 @@
+use core::ops::Add;
+use core::time::Duration;
+
+pub const BUFFER_LOW_EVENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Decides whether queued messages can be sent now
+/// based on `bufferedamountlow` events with a fallback to polling the buffered amount.
+///
+/// Some browsers never fire `bufferedamountlow`, so if the buffer is drained
+/// and the event is not fired within the timeout, the detector switches to polling.
+#[derive(Clone, Debug)]
+pub struct BufferLowDetector<T> {
+    low_threshold: u64,
+    timeout: Duration,
+    /// The buffer was filled above the threshold, so the event is expected.
+    is_event_expected: bool,
+    /// Time when the buffer was first seen drained while the event is expected.
+    drained_since: Option<T>,
+    is_polling: bool,
+}
+
+impl<T> BufferLowDetector<T> {
+    pub fn new(low_threshold: u64, timeout: Duration) -> Self {
+        Self {
+            low_threshold,
+            timeout,
+            is_event_expected: false,
+            drained_since: None,
+            is_polling: false,
+        }
+    }
+
+    pub fn is_polling(&self) -> bool {
+        self.is_polling
+    }
+
+    /// Records the `bufferedamountlow` event, switches back from polling if it was used.
+    pub fn on_event(&mut self) {
+        self.is_event_expected = false;
+        self.drained_since = None;
+        self.is_polling = false;
+    }
+
+    /// Returns `true` if more messages can be sent now.
+    pub fn can_send_more(&mut self, now: T, buffered_amount: u64) -> bool
+    where
+        T: Clone + Ord + Add<Duration, Output = T>,
+    {
+        if buffered_amount > self.low_threshold {
+            self.is_event_expected = true;
+            self.drained_since = None;
+            return false;
+        }
+        if self.is_polling || !self.is_event_expected {
+            return true;
+        }
+
+        let drained_since = self.drained_since.get_or_insert_with(|| now.clone());
+        if now >= drained_since.clone() + self.timeout {
+            self.is_event_expected = false;
+            self.drained_since = None;
+            self.is_polling = true;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[test]
+fn wait_for_buffer_low_event() {
+    let secs = Duration::from_secs;
+    let mut detector = BufferLowDetector::new(100, secs(2));
+    assert!(detector.can_send_more(secs(0), 50));
+    assert!(!detector.can_send_more(secs(1), 200));
+
+    // The buffer is drained, but the event is expected to be fired soon.
+    assert!(!detector.can_send_more(secs(2), 50));
+    detector.on_event();
+    assert!(detector.can_send_more(secs(3), 50));
+    assert!(!detector.is_polling());
+}
+
+#[test]
+fn poll_buffered_amount_if_buffer_low_event_timed_out() {
+    let secs = Duration::from_secs;
+    let mut detector = BufferLowDetector::new(100, secs(2));
+    assert!(!detector.can_send_more(secs(0), 200));
+    assert!(!detector.can_send_more(secs(1), 50));
+    assert!(!detector.can_send_more(secs(2), 50));
+    assert!(detector.can_send_more(secs(3), 50));
+    assert!(detector.is_polling());
+
+    // Once polling, a drained buffer is used right away.
+    assert!(!detector.can_send_more(secs(4), 200));
+    assert!(detector.can_send_more(secs(5), 50));
+
+    // A late event switches back to the event-driven strategy.
+    detector.on_event();
+    assert!(!detector.is_polling());
+}
@@ -8,6 +8,12 @@ use wasm_bindgen::convert::FromWasmAbi;
 pub type ClosureCell0 = RefCell<Option<Closure<dyn FnMut()>>>;
 pub type ClosureCell1<T1> = RefCell<Option<Closure<dyn FnMut(T1)>>>;
 
+/// Closure cell which can be cleared regardless of the closure argument types.
+pub trait ClearClosureCell {
+    /// Drops the saved closure, returns `true` if it was set.
+    fn clear_closure(&self) -> bool;
+}
+
 pub trait Callback<F> {
     fn with_callback(callback: F) -> Self;
 }
@@ -16,6 +22,12 @@ pub trait WeakCallback<T, F> {
     fn with_weak_callback(arc: &Arc<T>, callback: F) -> Self;
 }
 
+impl<T> ClearClosureCell for RefCell<Option<T>> {
+    fn clear_closure(&self) -> bool {
+        self.take().is_some()
+    }
+}
+
 impl<F, T1> Callback<F> for Closure<dyn FnMut(T1)>
 where
     F: 'static + FnMut(T1),
@@ -65,3 +77,23 @@ pub fn init_weak_callback<T, F, G, S, T1>(
     let prev = cell.replace(Some(closure));
     assert!(prev.is_none());
 }
+
+/// Drops closures saved in the cells, returns the number of dropped closures.
+///
+/// The web-sys callbacks using these closures need to be cleared beforehand.
+pub fn clear_closure_cells(cells: &[&dyn ClearClosureCell]) -> usize {
+    cells.iter().filter(|cell| cell.clear_closure()).count()
+}
+
+#[test]
+fn clear_every_closure_cell() {
+    let first = RefCell::new(Some(1_u32));
+    let second = RefCell::new(Some("handler"));
+    let third: RefCell<Option<u8>> = RefCell::new(None);
+
+    assert_eq!(clear_closure_cells(&[&first, &second, &third]), 2);
+    assert!(first.borrow().is_none());
+    assert!(second.borrow().is_none());
+    assert!(third.borrow().is_none());
+    assert_eq!(clear_closure_cells(&[&first, &second, &third]), 0);
+}
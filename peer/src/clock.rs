@@ -0,0 +1,9 @@
+/// Source of the current time.
+///
+/// Resend decisions only depend on the time passed to `LocalPeer`,
+/// so a mock clock allows to test them without a browser.
+pub trait Clock {
+    type Time;
+
+    fn now(&self) -> Self::Time;
+}
@@ -0,0 +1,105 @@
+use std::collections::{HashMap, VecDeque};
+
+use tracker_protocol::PeerId;
+
+/// Admission queue for new peer connections limited by the maximum number of connections.
+///
+/// Messages of queued peers are kept in order of arrival
+/// so that the connection can be opened later as if they were received just now.
+#[derive(Clone, Debug)]
+pub struct ConnectionQueue<M> {
+    max_connections: usize,
+    peers: VecDeque<PeerId>,
+    messages: HashMap<PeerId, Vec<M>>,
+}
+
+impl<M> ConnectionQueue<M> {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            max_connections,
+            peers: VecDeque::new(),
+            messages: HashMap::new(),
+        }
+    }
+
+    pub fn max_connections(&self) -> usize {
+        self.max_connections
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_queued(&self, peer_id: PeerId) -> bool {
+        self.messages.contains_key(&peer_id)
+    }
+
+    /// Returns the message back if it can be handled right now, otherwise queues it.
+    ///
+    /// Messages of already queued peers are always queued,
+    /// other messages are queued only if they open a connection above the limit.
+    pub fn admit(
+        &mut self,
+        peer_id: PeerId,
+        message: M,
+        opens_connection: bool,
+        num_connections: usize,
+    ) -> Option<M> {
+        if let Some(messages) = self.messages.get_mut(&peer_id) {
+            messages.push(message);
+            return None;
+        }
+        if !opens_connection || num_connections < self.max_connections {
+            return Some(message);
+        }
+        self.peers.push_back(peer_id);
+        let _: Option<_> = self.messages.insert(peer_id, vec![message]);
+        None
+    }
+
+    /// Removes the earliest queued peer with its messages if a connection can be opened.
+    pub fn pop(&mut self, num_connections: usize) -> Option<(PeerId, Vec<M>)> {
+        if num_connections >= self.max_connections {
+            return None;
+        }
+        let peer_id = self.peers.pop_front()?;
+        let messages = self.messages.remove(&peer_id).unwrap();
+        Some((peer_id, messages))
+    }
+}
+
+#[test]
+fn queue_connections_above_limit() {
+    let mut queue = ConnectionQueue::new(2);
+
+    assert_eq!(queue.admit(PeerId(1), "offer 1", true, 1), Some("offer 1"));
+    assert_eq!(queue.admit(PeerId(2), "offer 2", true, 2), None);
+    assert_eq!(queue.admit(PeerId(3), "request 3", true, 2), None);
+    // Messages of queued peers are queued even if they do not open connections.
+    assert_eq!(queue.admit(PeerId(2), "candidate 2", false, 2), None);
+    // Messages of connected peers are handled right away.
+    assert_eq!(
+        queue.admit(PeerId(1), "candidate 1", false, 2),
+        Some("candidate 1")
+    );
+    assert_eq!(queue.len(), 2);
+    assert!(queue.is_queued(PeerId(2)));
+    assert!(!queue.is_queued(PeerId(1)));
+
+    assert_eq!(queue.pop(2), None);
+    assert_eq!(
+        queue.pop(1),
+        Some((PeerId(2), vec!["offer 2", "candidate 2"]))
+    );
+    assert_eq!(
+        queue.admit(PeerId(2), "candidate 2", false, 2),
+        Some("candidate 2")
+    );
+    assert_eq!(queue.pop(0), Some((PeerId(3), vec!["request 3"])));
+    assert_eq!(queue.pop(0), None);
+    assert!(queue.is_empty());
+}
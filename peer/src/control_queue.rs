@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+use crate::{PeerPeerMessage, MAX_PEER_MESSAGE_SIZE};
+
+pub const CONTROL_QUEUE_CAPACITY: usize = 64;
+
+// Queued control messages are retried once the buffer is drained below a single message.
+pub const CONTROL_QUEUE_BUFFER_LOW_THRESHOLD: u32 = MAX_PEER_MESSAGE_SIZE as u32;
+
+/// Outbound queue of control messages that could not be sent yet.
+///
+/// File pieces are not queued because they are resent by the piece resend logic anyway.
+/// Only the latest file state of each file is kept.
+#[derive(Clone, Debug, Default)]
+pub struct ControlQueue {
+    messages: VecDeque<PeerPeerMessage>,
+}
+
+impl ControlQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_control_message(message: &PeerPeerMessage) -> bool {
+        !matches!(
+            message,
+            PeerPeerMessage::FilePiece { .. }
+                | PeerPeerMessage::FilePieceBatch { .. }
+                | PeerPeerMessage::CompressedFilePieceBatch { .. }
+                | PeerPeerMessage::EncryptedFilePieceBatch { .. }
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    /// Pushes the message and returns the dropped oldest message if the queue is full.
+    pub fn push(&mut self, message: PeerPeerMessage) -> Option<PeerPeerMessage> {
+        let starts_new_state = match &message {
+            PeerPeerMessage::FileMissing { .. }
+            | PeerPeerMessage::FileComplete { .. }
+            | PeerPeerMessage::FileState { .. } => true,
+            PeerPeerMessage::FileStateChunk { offset, .. } => *offset == 0,
+            _ => false,
+        };
+        if starts_new_state {
+            let sha256 = message.sha256();
+            self.messages
+                .retain(|queued| !(is_state_message(queued) && queued.sha256() == sha256));
+        }
+
+        let dropped = if self.messages.len() >= CONTROL_QUEUE_CAPACITY {
+            self.messages.pop_front()
+        } else {
+            None
+        };
+        self.messages.push_back(message);
+        dropped
+    }
+
+    pub fn front(&self) -> Option<&PeerPeerMessage> {
+        self.messages.front()
+    }
+
+    pub fn pop_front(&mut self) -> Option<PeerPeerMessage> {
+        self.messages.pop_front()
+    }
+}
+
+fn is_state_message(message: &PeerPeerMessage) -> bool {
+    matches!(
+        message,
+        PeerPeerMessage::FileMissing { .. }
+            | PeerPeerMessage::FileComplete { .. }
+            | PeerPeerMessage::FileState { .. }
+            | PeerPeerMessage::FileStateChunk { .. }
+    )
+}
+
+#[test]
+fn coalesce_file_state_messages() {
+    use bitvec::bits;
+    use bitvec::order::Lsb0;
+    use tracker_protocol::FileSha256;
+
+    use crate::{FilePieceIdx, FileState};
+
+    let first = FileSha256([1; 32]);
+    let second = FileSha256([2; 32]);
+
+    let mut queue = ControlQueue::new();
+    assert_eq!(
+        queue.push(PeerPeerMessage::FileMissing {
+            sha256: first,
+            seq: 0,
+        }),
+        None
+    );
+    assert_eq!(
+        queue.push(PeerPeerMessage::FileStateReceived { sha256: first }),
+        None
+    );
+    assert_eq!(
+        queue.push(PeerPeerMessage::FileState {
+            sha256: second,
+            seq: 0,
+            state: FileState::bits_to_bytes(bits![0, 1]),
+        }),
+        None
+    );
+    assert_eq!(
+        queue.push(PeerPeerMessage::FilePiecesReceived {
+            sha256: first,
+            pieces: vec![FilePieceIdx(0)],
+        }),
+        None
+    );
+    assert_eq!(
+        queue.push(PeerPeerMessage::FileState {
+            sha256: first,
+            seq: 1,
+            state: FileState::bits_to_bytes(bits![1, 0]),
+        }),
+        None
+    );
+    assert_eq!(queue.len(), 4);
+
+    assert_eq!(
+        queue.push(PeerPeerMessage::FileComplete {
+            sha256: second,
+            seq: 1,
+        }),
+        None
+    );
+    assert_eq!(
+        queue.push(PeerPeerMessage::FileStateChunk {
+            sha256: first,
+            seq: 2,
+            num_pieces: 4,
+            offset: 0,
+            state: FileState::bits_to_bytes(bits![1, 1]),
+        }),
+        None
+    );
+    assert_eq!(
+        queue.push(PeerPeerMessage::FileStateChunk {
+            sha256: first,
+            seq: 2,
+            num_pieces: 4,
+            offset: 2,
+            state: FileState::bits_to_bytes(bits![0, 1]),
+        }),
+        None
+    );
+
+    let messages: Vec<_> = core::iter::from_fn(|| queue.pop_front()).collect();
+    assert_eq!(
+        messages,
+        [
+            PeerPeerMessage::FileStateReceived { sha256: first },
+            PeerPeerMessage::FilePiecesReceived {
+                sha256: first,
+                pieces: vec![FilePieceIdx(0)],
+            },
+            PeerPeerMessage::FileComplete {
+                sha256: second,
+                seq: 1,
+            },
+            PeerPeerMessage::FileStateChunk {
+                sha256: first,
+                seq: 2,
+                num_pieces: 4,
+                offset: 0,
+                state: FileState::bits_to_bytes(bits![1, 1]),
+            },
+            PeerPeerMessage::FileStateChunk {
+                sha256: first,
+                seq: 2,
+                num_pieces: 4,
+                offset: 2,
+                state: FileState::bits_to_bytes(bits![0, 1]),
+            },
+        ]
+    );
+}
+
+#[test]
+fn drop_oldest_control_message() {
+    use tracker_protocol::FileSha256;
+
+    let mut queue = ControlQueue::new();
+    for j in 0..CONTROL_QUEUE_CAPACITY {
+        let sha256 = FileSha256([j as u8; 32]);
+        assert_eq!(
+            queue.push(PeerPeerMessage::FileStateReceived { sha256 }),
+            None
+        );
+    }
+
+    let sha256 = FileSha256([0xFF; 32]);
+    assert_eq!(
+        queue.push(PeerPeerMessage::FileRemoved { sha256 }),
+        Some(PeerPeerMessage::FileStateReceived {
+            sha256: FileSha256([0; 32])
+        })
+    );
+    assert_eq!(queue.len(), CONTROL_QUEUE_CAPACITY);
+    assert_eq!(
+        queue.front(),
+        Some(&PeerPeerMessage::FileStateReceived {
+            sha256: FileSha256([1; 32])
+        })
+    );
+}
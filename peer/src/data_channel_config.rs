@@ -0,0 +1,213 @@
+use thiserror::Error;
+
+pub const DEFAULT_DATA_CHANNEL_LABEL: &str = "data";
+
+/// Maximum data channel label and protocol length in bytes.
+pub const MAX_DATA_CHANNEL_STRING_LEN: usize = 65535;
+
+/// Maximum negotiated data channel id, 65535 is reserved.
+pub const MAX_DATA_CHANNEL_ID: u16 = 65534;
+
+/// Label, SCTP sub-protocol, negotiated id and reliability of the peer data channel.
+///
+/// Negotiated channels are matched by id only,
+/// so peers exchange the label and the protocol once the channel is open.
+///
+/// Lost messages are not retransmitted by default, lost pieces are resent
+/// by the piece resend logic anyway, which is preferable on low-loss links.
+/// A maximum number of retransmits or a maximum packet lifetime
+/// reduces the number of resent pieces on moderate-loss links at the cost of latency,
+/// the lifetime bounds the latency regardless of the round-trip time.
+/// Only one of them may be set.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct DataChannelConfig {
+    label: String,
+    protocol: String,
+    id: u16,
+    max_retransmits: Option<u16>,
+    max_packet_life_time: Option<u16>,
+}
+
+impl Default for DataChannelConfig {
+    fn default() -> Self {
+        Self::new(DEFAULT_DATA_CHANNEL_LABEL.to_owned())
+    }
+}
+
+impl DataChannelConfig {
+    pub fn new(label: String) -> Self {
+        Self {
+            label,
+            protocol: String::new(),
+            id: 0,
+            max_retransmits: None,
+            max_packet_life_time: None,
+        }
+    }
+
+    pub fn with_protocol(self, protocol: String) -> Self {
+        Self { protocol, ..self }
+    }
+
+    pub fn with_id(self, id: u16) -> Self {
+        Self { id, ..self }
+    }
+
+    pub fn with_max_retransmits(self, max_retransmits: u16) -> Self {
+        Self {
+            max_retransmits: Some(max_retransmits),
+            ..self
+        }
+    }
+
+    /// Sets the time in milliseconds during which lost messages are retransmitted.
+    pub fn with_max_packet_life_time(self, max_packet_life_time: u16) -> Self {
+        Self {
+            max_packet_life_time: Some(max_packet_life_time),
+            ..self
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Returns the SCTP sub-protocol, empty if not specified.
+    pub fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn max_retransmits(&self) -> Option<u16> {
+        self.max_retransmits
+    }
+
+    pub fn max_packet_life_time(&self) -> Option<u16> {
+        self.max_packet_life_time
+    }
+
+    pub fn validate(&self) -> Result<(), DataChannelConfigError> {
+        if self.label.len() > MAX_DATA_CHANNEL_STRING_LEN {
+            return Err(DataChannelConfigError::LabelIsTooLong {
+                len: self.label.len(),
+            });
+        }
+        if self.protocol.len() > MAX_DATA_CHANNEL_STRING_LEN {
+            return Err(DataChannelConfigError::ProtocolIsTooLong {
+                len: self.protocol.len(),
+            });
+        }
+        if self.id > MAX_DATA_CHANNEL_ID {
+            return Err(DataChannelConfigError::ReservedId { id: self.id });
+        }
+        if self.max_retransmits.is_some() && self.max_packet_life_time.is_some() {
+            return Err(DataChannelConfigError::BothRetransmitsAndPacketLifeTime);
+        }
+        Ok(())
+    }
+
+    /// Checks that the label and the protocol announced by the remote peer match the local ones.
+    pub fn check_remote(
+        &self,
+        label: &str,
+        protocol: &str,
+    ) -> Result<(), DataChannelMismatchError> {
+        if label != self.label {
+            return Err(DataChannelMismatchError::Label);
+        }
+        if protocol != self.protocol {
+            return Err(DataChannelMismatchError::Protocol);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum DataChannelConfigError {
+    #[error("data channel label of {len} bytes is too long")]
+    LabelIsTooLong { len: usize },
+    #[error("data channel protocol of {len} bytes is too long")]
+    ProtocolIsTooLong { len: usize },
+    #[error("data channel id {id} is reserved")]
+    ReservedId { id: u16 },
+    #[error("data channel max retransmits and max packet lifetime are mutually exclusive")]
+    BothRetransmitsAndPacketLifeTime,
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum DataChannelMismatchError {
+    #[error("remote data channel label does not match the local one")]
+    Label,
+    #[error("remote data channel protocol does not match the local one")]
+    Protocol,
+}
+
+#[test]
+fn build_data_channel_config() {
+    let config = DataChannelConfig::default();
+    assert_eq!(config.label(), DEFAULT_DATA_CHANNEL_LABEL);
+    assert_eq!(config.protocol(), "");
+    assert_eq!(config.id(), 0);
+    assert_eq!(config.validate(), Ok(()));
+
+    let config = DataChannelConfig::new("files".to_owned())
+        .with_protocol("webtorrent-lite/1".to_owned())
+        .with_id(3);
+    assert_eq!(config.label(), "files");
+    assert_eq!(config.protocol(), "webtorrent-lite/1");
+    assert_eq!(config.id(), 3);
+    assert_eq!(config.validate(), Ok(()));
+
+    assert_eq!(
+        config.clone().with_id(u16::MAX).validate(),
+        Err(DataChannelConfigError::ReservedId { id: u16::MAX })
+    );
+    let long = "x".repeat(MAX_DATA_CHANNEL_STRING_LEN + 1);
+    assert_eq!(
+        DataChannelConfig::new(long.clone()).validate(),
+        Err(DataChannelConfigError::LabelIsTooLong { len: long.len() })
+    );
+    assert_eq!(
+        config.with_protocol(long.clone()).validate(),
+        Err(DataChannelConfigError::ProtocolIsTooLong { len: long.len() })
+    );
+}
+
+#[test]
+fn reject_both_retransmits_and_packet_life_time() {
+    let config = DataChannelConfig::default();
+    assert_eq!(config.max_retransmits(), None);
+    assert_eq!(config.max_packet_life_time(), None);
+
+    let config = config.with_max_packet_life_time(150);
+    assert_eq!(config.max_packet_life_time(), Some(150));
+    assert_eq!(config.validate(), Ok(()));
+    assert_eq!(
+        DataChannelConfig::default()
+            .with_max_retransmits(2)
+            .validate(),
+        Ok(())
+    );
+
+    assert_eq!(
+        config.with_max_retransmits(2).validate(),
+        Err(DataChannelConfigError::BothRetransmitsAndPacketLifeTime)
+    );
+}
+
+#[test]
+fn check_remote_data_channel_config() {
+    let config = DataChannelConfig::new("files".to_owned()).with_protocol("v1".to_owned());
+    assert_eq!(config.check_remote("files", "v1"), Ok(()));
+    assert_eq!(
+        config.check_remote("data", "v1"),
+        Err(DataChannelMismatchError::Label)
+    );
+    assert_eq!(
+        config.check_remote("files", ""),
+        Err(DataChannelMismatchError::Protocol)
+    );
+}
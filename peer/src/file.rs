@@ -1,3 +1,5 @@
+use bitvec::boxed::BitBox;
+use bitvec::slice::BitSlice;
 use js_sys::Uint8Array;
 use thiserror::Error;
 use tracker_protocol::FileSha256;
@@ -5,7 +7,7 @@ use web_sys::{Blob, File as WebSysFile};
 
 use crate::{
     FileChunk, FileLen, FileMetadata, FilePieceIdx, FileState, FileStatePieceError,
-    FileStateSetStatus, FILE_PIECE_SIZE,
+    FileStateSetStatus, FileStateUnsetStatus, FileStorage, FileStorageError, FILE_PIECE_SIZE,
 };
 
 // Chrome does not support creating an array buffer of 2 GB or more.
@@ -32,6 +34,12 @@ pub struct File<C, const CHUNK_SIZE: usize> {
 
     /// Local file available pieces mask.
     state: FileState,
+
+    /// Chunks with contents dropped after their pieces were distributed.
+    released_chunks: BitBox,
+
+    /// Storage of file contents, chunks are left empty if set.
+    storage: Option<Box<dyn FileStorage>>,
 }
 
 impl<C, const CHUNK_SIZE: usize> File<C, CHUNK_SIZE> {
@@ -39,6 +47,30 @@ impl<C, const CHUNK_SIZE: usize> File<C, CHUNK_SIZE> {
     where
         C: FileChunk,
     {
+        Self::new_with_storage(metadata, None)
+    }
+
+    /// Creates an empty file with contents kept in the storage instead of wasm memory.
+    ///
+    /// Pieces of stored files are accessed with `read_piece` and `write_piece`.
+    pub fn new_with_backend(
+        metadata: FileMetadata,
+        storage: Box<dyn FileStorage>,
+    ) -> Result<Self, NewFileError>
+    where
+        C: FileChunk,
+    {
+        Self::new_with_storage(metadata, Some(storage))
+    }
+
+    fn new_with_storage(
+        metadata: FileMetadata,
+        storage: Option<Box<dyn FileStorage>>,
+    ) -> Result<Self, NewFileError>
+    where
+        C: FileChunk,
+    {
+        use bitvec::bitbox;
         use core::cmp::min;
 
         assert_eq!(CHUNK_SIZE as u64 % FILE_PIECE_SIZE_U64, 0);
@@ -53,28 +85,221 @@ impl<C, const CHUNK_SIZE: usize> File<C, CHUNK_SIZE> {
             .try_into()
             .map_err(|_| NewFileError::SizeIsTooLarge { len })?;
 
-        let chunks = (0..num_chunks)
+        let chunks: Vec<_> = (0..num_chunks)
             .map(|j| {
                 let len = min(len.0 - j * FILE_CHUNK_SIZE_U64, FILE_CHUNK_SIZE_U64)
                     .try_into()
                     .unwrap();
-                C::with_len(len)
+                C::with_len(if storage.is_some() { 0 } else { len })
             })
             .collect();
 
         let state = FileState::from_missing(num_pieces);
+        let released_chunks = bitbox![0; chunks.len()];
 
         Ok(Self {
             metadata,
             chunks,
             num_pieces,
             state,
+            released_chunks,
+            storage,
         })
     }
+
+    /// Creates a partially available file from its chunks.
+    ///
+    /// Only pieces marked in `have_mask` are treated as available.
+    /// Complete chunks are verified against metadata chunk hashes if present,
+    /// pieces of mismatched chunks are left missing.
+    pub fn from_partial_chunks(
+        metadata: FileMetadata,
+        chunks: Vec<C>,
+        have_mask: FileState,
+    ) -> Result<Self, FileFromPartialError>
+    where
+        C: FileChunk,
+    {
+        let mut file = Self::new(metadata).map_err(|err| match err {
+            NewFileError::SizeIsTooLarge { len } => FileFromPartialError::SizeIsTooLarge { len },
+        })?;
+
+        if chunks.len() != file.chunks.len() {
+            return Err(FileFromPartialError::InvalidNumChunks {
+                len: chunks.len(),
+                expected: file.chunks.len(),
+            });
+        }
+        if have_mask.len() != file.num_pieces {
+            return Err(FileFromPartialError::InvalidStateLen {
+                len: have_mask.len(),
+                expected: file.num_pieces,
+            });
+        }
+        file.chunks = chunks;
+        file.state = have_mask;
+
+        for chunk_idx in 0..file.num_chunks() {
+            let expected = match file.metadata.chunk_hashes().get(chunk_idx) {
+                Some(expected) => *expected,
+                None => break,
+            };
+            if file.has_chunk(chunk_idx) && file.chunk_sha256(chunk_idx) != expected {
+                let pieces = file.unset_chunk(chunk_idx);
+                log::warn!(
+                    "file {} chunk {} hash mismatch, {} pieces dropped",
+                    file.name(),
+                    chunk_idx,
+                    pieces.len()
+                );
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Creates a file with pieces marked in `state` available but released,
+    /// e.g. to reproduce a reported state without the file contents.
+    ///
+    /// Chunks with all pieces available are released, so their pieces are never read
+    /// or sent to peers. Pieces of partially available chunks are marked as missing,
+    /// since their contents are not known, and are received again.
+    pub fn from_released_state(
+        metadata: FileMetadata,
+        state: FileState,
+    ) -> Result<Self, FileFromPartialError>
+    where
+        C: FileChunk,
+    {
+        let mut file = Self::new(metadata).map_err(|err| match err {
+            NewFileError::SizeIsTooLarge { len } => FileFromPartialError::SizeIsTooLarge { len },
+        })?;
+
+        if state.len() != file.num_pieces {
+            return Err(FileFromPartialError::InvalidStateLen {
+                len: state.len(),
+                expected: file.num_pieces,
+            });
+        }
+        file.state = state;
+
+        for chunk_idx in 0..file.num_chunks() {
+            let mut pieces = file.chunk_pieces(chunk_idx);
+            if pieces.all(|piece_idx| file.state.has(&piece_idx).unwrap()) {
+                file.chunks[chunk_idx] = C::with_len(0);
+                file.released_chunks.set(chunk_idx, true);
+            } else {
+                let _: Vec<_> = file.unset_chunk(chunk_idx);
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Creates a complete file from an in-memory buffer, hashing it chunk by chunk.
+    pub fn from_bytes(name: String, bytes: &[u8]) -> Self
+    where
+        C: FileChunk,
+    {
+        use bitvec::bitbox;
+        use sha2::{Digest, Sha256};
+
+        let len = FileLen(bytes.len() as u64);
+        let num_pieces = (bytes.len() + FILE_PIECE_SIZE - 1) / FILE_PIECE_SIZE;
+
+        let mut chunks = Vec::new();
+        let mut chunk_hashes = Vec::new();
+        for chunk_bytes in bytes.chunks(FILE_CHUNK_SIZE) {
+            let mut chunk = C::with_len(chunk_bytes.len());
+            chunk.set(0, chunk_bytes);
+            chunk_hashes.push(FileSha256(Sha256::digest(chunk_bytes).into()));
+            chunks.push(chunk);
+        }
+
+        let sha256 = FileSha256(Sha256::digest(bytes).into());
+        let metadata = FileMetadata::new(sha256, name, len).with_chunk_hashes(chunk_hashes);
+        let state = FileState::from_complete(num_pieces);
+        let released_chunks = bitbox![0; chunks.len()];
+
+        Self {
+            metadata,
+            chunks,
+            num_pieces,
+            state,
+            released_chunks,
+            storage: None,
+        }
+    }
 }
 
 impl<const CHUNK_SIZE: usize> File<Uint8Array, CHUNK_SIZE> {
+    /// Reads a previously partially downloaded file to resume or seed it.
+    ///
+    /// Only chunks with pieces marked in `have_mask` are read.
+    pub async fn from_partial_file(
+        metadata: FileMetadata,
+        file: WebSysFile,
+        have_mask: FileState,
+    ) -> Result<Self, FileFromPartialError> {
+        use js_sys::ArrayBuffer;
+        use wasm_bindgen::JsCast;
+        use wasm_bindgen_futures::JsFuture;
+
+        pub const FILE_CHUNK_SIZE_U64: u64 = FILE_CHUNK_SIZE as u64;
+
+        let len = metadata.len();
+        let file_len = FileLen(file.size() as u64);
+        if file_len != len {
+            return Err(FileFromPartialError::SizeMismatch {
+                len: file_len,
+                expected: len,
+            });
+        }
+
+        let mut chunks = Vec::new();
+        for (chunk_idx, start) in (0..len.0).step_by(FILE_CHUNK_SIZE).enumerate() {
+            let end = (start + FILE_CHUNK_SIZE_U64).min(len.0);
+            let first_piece = chunk_idx * NUM_PIECES_IN_CHUNK;
+            let last_piece = (first_piece + NUM_PIECES_IN_CHUNK).min(have_mask.len());
+            let has_pieces = have_mask
+                .raw()
+                .get(first_piece..last_piece)
+                .is_some_and(BitSlice::any);
+
+            if has_pieces {
+                let chunk = file
+                    .slice_with_f64_and_f64(start as f64, end as f64)
+                    .unwrap();
+                let array_buffer: ArrayBuffer = JsFuture::from(chunk.array_buffer())
+                    .await
+                    .unwrap()
+                    .dyn_into()
+                    .unwrap();
+                chunks.push(Uint8Array::new(&array_buffer));
+            } else {
+                chunks.push(Uint8Array::with_len((end - start).try_into().unwrap()));
+            }
+        }
+
+        Self::from_partial_chunks(metadata, chunks, have_mask)
+    }
+
     pub async fn from_file(file: WebSysFile) -> Result<Self, FileFromError> {
+        Self::from_file_with_progress(file, |_, _| {}).await
+    }
+
+    /// Reads and hashes the file chunk by chunk.
+    ///
+    /// `on_progress` is called after each chunk with the number of bytes hashed so far
+    /// and the total file length.
+    pub async fn from_file_with_progress<F>(
+        file: WebSysFile,
+        mut on_progress: F,
+    ) -> Result<Self, FileFromError>
+    where
+        F: FnMut(FileLen, FileLen),
+    {
+        use bitvec::bitbox;
         use js_sys::{ArrayBuffer, Number};
         use sha2::{Digest, Sha256};
         use wasm_bindgen::JsCast;
@@ -97,6 +322,7 @@ impl<const CHUNK_SIZE: usize> File<Uint8Array, CHUNK_SIZE> {
             .map_err(|_| FileFromError::SizeIsTooLarge { len })?;
 
         let mut chunks = Vec::new();
+        let mut chunk_hashes = Vec::new();
         let mut hasher = Sha256::new();
         for start in (0..len.0).step_by(FILE_CHUNK_SIZE) {
             let end = start + FILE_CHUNK_SIZE_U64;
@@ -111,15 +337,22 @@ impl<const CHUNK_SIZE: usize> File<Uint8Array, CHUNK_SIZE> {
                 .unwrap();
 
             let u8_array = Uint8Array::new(&array_buffer);
-            hasher.update(&u8_array.to_vec());
+            let bytes = u8_array.to_vec();
+            hasher.update(&bytes);
+            chunk_hashes.push(FileSha256(Sha256::digest(&bytes).into()));
             chunks.push(u8_array);
             log::debug!("adding file {} ... {}/{}bytes", file.name(), start, len.0);
+            on_progress(FileLen(end.min(len.0)), len);
         }
 
         let sha256 = FileSha256(hasher.finalize().into());
-        let metadata = FileMetadata::new(sha256, name, len);
+        let mime_type = Some(file.type_()).filter(|mime_type| !mime_type.is_empty());
+        let metadata = FileMetadata::new(sha256, name, len)
+            .with_chunk_hashes(chunk_hashes)
+            .with_mime_type(mime_type);
 
         let state = FileState::from_complete(num_pieces);
+        let released_chunks = bitbox![0; chunks.len()];
 
         log::info!("adding file {} ... OK", file.name());
         Ok(Self {
@@ -127,15 +360,37 @@ impl<const CHUNK_SIZE: usize> File<Uint8Array, CHUNK_SIZE> {
             chunks,
             num_pieces,
             state,
+            released_chunks,
+            storage: None,
         })
     }
 
+    /// Joins file chunks into a blob, empty files have no chunks and produce an empty blob.
     pub async fn to_blob(&self) -> Result<Blob, FileToBlobError> {
         use js_sys::Array;
+        use web_sys::BlobPropertyBag;
 
-        if self.state.is_complete() {
-            let blob_args: Array = self.chunks.iter().collect();
-            Ok(Blob::new_with_u8_array_sequence(&blob_args).unwrap())
+        if self.released_chunks.any() {
+            Err(FileToBlobError::ChunksReleased {
+                num_released: self.released_chunks.count_ones(),
+            })
+        } else if self.state.is_complete() {
+            let blob_args: Array = match &self.storage {
+                // Chunks are read one by one, so only one of them is kept in wasm memory.
+                Some(storage) => {
+                    let blob_args = Array::new();
+                    for chunk_idx in 0..self.chunks.len() {
+                        let (offset, len) = self.chunk_range(chunk_idx);
+                        let bytes = storage.read(offset, len).await?;
+                        let _: u32 = blob_args.push(&Uint8Array::from(&*bytes));
+                    }
+                    blob_args
+                }
+                None => self.chunks.iter().collect(),
+            };
+            let mut options = BlobPropertyBag::new();
+            let _: &mut _ = options.type_(self.blob_type());
+            Ok(Blob::new_with_u8_array_sequence_and_options(&blob_args, &options).unwrap())
         } else {
             Err(FileToBlobError::NotComplete {
                 available: self.state.num_available(),
@@ -162,6 +417,11 @@ impl<C, const CHUNK_SIZE: usize> File<C, CHUNK_SIZE> {
         self.metadata.sha256()
     }
 
+    /// Returns the type of blobs created by `to_blob`, empty if the MIME type is unknown.
+    pub fn blob_type(&self) -> &str {
+        self.metadata.mime_type().unwrap_or("")
+    }
+
     pub fn num_pieces(&self) -> usize {
         self.num_pieces
     }
@@ -178,6 +438,128 @@ impl<C, const CHUNK_SIZE: usize> File<C, CHUNK_SIZE> {
             .unwrap()
     }
 
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn piece_chunk_idx(&self, piece_idx: &FilePieceIdx) -> usize {
+        piece_idx.0 / NUM_PIECES_IN_CHUNK
+    }
+
+    /// Returns indices of the pieces stored in the chunk.
+    pub fn chunk_pieces(&self, chunk_idx: usize) -> impl Iterator<Item = FilePieceIdx> {
+        let start = chunk_idx * NUM_PIECES_IN_CHUNK;
+        let end = (start + NUM_PIECES_IN_CHUNK).min(self.num_pieces);
+        (start..end).map(FilePieceIdx)
+    }
+
+    /// Returns the chunk offset and length in bytes.
+    fn chunk_range(&self, chunk_idx: usize) -> (u64, usize) {
+        let offset = (chunk_idx * FILE_CHUNK_SIZE) as u64;
+        let len = (self.len().0 - offset).min(FILE_CHUNK_SIZE as u64) as usize;
+        (offset, len)
+    }
+
+    /// Returns `true` if the file contents are kept in the storage instead of wasm memory.
+    pub fn is_stored(&self) -> bool {
+        self.storage.is_some()
+    }
+
+    /// Replaces the file state with a known-good one and returns the previous state.
+    ///
+    /// Chunks that are complete in the new state are verified against the chunk hashes.
+    pub fn replace_state(&mut self, state: FileState) -> Result<FileState, FileReplaceStateError>
+    where
+        C: FileChunk,
+    {
+        use core::mem::replace;
+
+        if self.storage.is_some() {
+            return Err(FileReplaceStateError::FileIsStored);
+        }
+        if state.len() != self.num_pieces {
+            return Err(FileReplaceStateError::InvalidStateLen {
+                len: state.len(),
+                expected: self.num_pieces,
+            });
+        }
+
+        let prev_state = replace(&mut self.state, state);
+        for (chunk_idx, expected) in self.metadata.chunk_hashes().iter().enumerate() {
+            if self.has_chunk(chunk_idx) && self.chunk_sha256(chunk_idx) != *expected {
+                self.state = prev_state;
+                return Err(FileReplaceStateError::ChunkHashMismatch { chunk_idx });
+            }
+        }
+        Ok(prev_state)
+    }
+
+    pub fn has_chunk(&self, chunk_idx: usize) -> bool {
+        let mut pieces = self.chunk_pieces(chunk_idx);
+        pieces.all(|piece_idx| self.state.has(&piece_idx).unwrap())
+    }
+
+    pub fn chunk_sha256(&self, chunk_idx: usize) -> FileSha256
+    where
+        C: FileChunk,
+    {
+        use sha2::{Digest, Sha256};
+
+        let bytes = self.chunks[chunk_idx].get(0, FILE_CHUNK_SIZE);
+        FileSha256(Sha256::digest(&bytes).into())
+    }
+
+    /// Marks all chunk pieces as missing and returns the pieces that were available.
+    pub fn unset_chunk(&mut self, chunk_idx: usize) -> Vec<FilePieceIdx> {
+        self.chunk_pieces(chunk_idx)
+            .filter(|piece_idx| {
+                self.state.unset(piece_idx).unwrap() == FileStateUnsetStatus::JustUnset
+            })
+            .collect()
+    }
+
+    /// Marks the piece as missing so that it is received again.
+    pub fn unset_piece(
+        &mut self,
+        piece_idx: &FilePieceIdx,
+    ) -> Result<FileStateUnsetStatus, FileStatePieceError> {
+        self.state.unset(piece_idx)
+    }
+
+    pub fn is_chunk_released(&self, chunk_idx: usize) -> bool {
+        self.released_chunks[chunk_idx]
+    }
+
+    pub fn is_piece_released(&self, piece_idx: &FilePieceIdx) -> bool {
+        self.released_chunks
+            .get(self.piece_chunk_idx(piece_idx))
+            .is_some_and(|released| *released)
+    }
+
+    /// Drops the contents of an available chunk to reduce memory usage.
+    ///
+    /// The chunk pieces remain available in the file state,
+    /// but they can no longer be read, so the file can not be seeded or saved afterwards.
+    pub fn release_chunk(&mut self, chunk_idx: usize) -> Result<(), FileReleaseChunkError>
+    where
+        C: FileChunk,
+    {
+        if chunk_idx >= self.chunks.len() {
+            return Err(FileReleaseChunkError::ChunkIndexOutOfRange {
+                len: self.chunks.len(),
+            });
+        }
+        if self.released_chunks[chunk_idx] {
+            return Err(FileReleaseChunkError::ChunkIsAlreadyReleased);
+        }
+        if !self.has_chunk(chunk_idx) {
+            return Err(FileReleaseChunkError::ChunkIsNotAvailable);
+        }
+        self.chunks[chunk_idx] = C::with_len(0);
+        self.released_chunks.set(chunk_idx, true);
+        Ok(())
+    }
+
     pub fn has_piece(&self, piece_idx: &FilePieceIdx) -> Result<bool, FileHasPieceError> {
         Ok(self.state.has(piece_idx)?)
     }
@@ -193,7 +575,11 @@ impl<C, const CHUNK_SIZE: usize> File<C, CHUNK_SIZE> {
         let chunk_piece_idx = piece_idx.0 % NUM_PIECES_IN_CHUNK;
 
         let has_piece = self.state.has(piece_idx)?;
-        if has_piece {
+        if self.released_chunks[chunk_idx] {
+            Err(FileGetPieceError::ChunkIsReleased { chunk_idx })
+        } else if has_piece && self.storage.is_some() {
+            Err(FileGetPieceError::ChunkIsStored { chunk_idx })
+        } else if has_piece {
             let chunk = &self.chunks[chunk_idx];
             let offset = chunk_piece_idx * FILE_PIECE_SIZE;
             let len = self.piece_len(piece_idx);
@@ -211,19 +597,132 @@ impl<C, const CHUNK_SIZE: usize> File<C, CHUNK_SIZE> {
     where
         C: FileChunk,
     {
+        self.check_piece_len(piece_idx, data)?;
+        let chunk_idx = piece_idx.0 / NUM_PIECES_IN_CHUNK;
+        let chunk_piece_idx = piece_idx.0 % NUM_PIECES_IN_CHUNK;
+        if self.released_chunks[chunk_idx] {
+            return self.released_piece_status(piece_idx, chunk_idx);
+        }
+        if self.storage.is_some() {
+            return Err(FileSetPieceError::ChunkIsStored { chunk_idx });
+        }
+        let chunk = &mut self.chunks[chunk_idx];
+        let offset = chunk_piece_idx * FILE_PIECE_SIZE;
+
+        chunk.set(offset, data);
+        Ok(self.state.set(piece_idx)?)
+    }
+
+    /// Reads the piece either from the storage or from the in-memory chunk.
+    pub async fn read_piece(
+        &self,
+        piece_idx: &FilePieceIdx,
+    ) -> Result<Option<Box<[u8]>>, FileGetPieceError>
+    where
+        C: FileChunk,
+    {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return self.get_piece(piece_idx),
+        };
+        let chunk_idx = piece_idx.0 / NUM_PIECES_IN_CHUNK;
+        let has_piece = self.state.has(piece_idx)?;
+        if self.released_chunks[chunk_idx] {
+            Err(FileGetPieceError::ChunkIsReleased { chunk_idx })
+        } else if has_piece {
+            let offset = (piece_idx.0 * FILE_PIECE_SIZE) as u64;
+            Ok(Some(storage.read(offset, self.piece_len(piece_idx)).await?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Writes the piece either to the storage or to the in-memory chunk.
+    ///
+    /// The piece is marked as available only after the write is completed.
+    pub async fn write_piece(
+        &mut self,
+        piece_idx: &FilePieceIdx,
+        data: &[u8],
+    ) -> Result<FileStateSetStatus, FileSetPieceError>
+    where
+        C: FileChunk,
+    {
+        let storage = match &self.storage {
+            Some(storage) => storage,
+            None => return self.set_piece(piece_idx, data),
+        };
+        self.check_piece_len(piece_idx, data)?;
+        let chunk_idx = piece_idx.0 / NUM_PIECES_IN_CHUNK;
+        if self.released_chunks[chunk_idx] {
+            return self.released_piece_status(piece_idx, chunk_idx);
+        }
+        let offset = (piece_idx.0 * FILE_PIECE_SIZE) as u64;
+        storage.write(offset, data).await?;
+        Ok(self.state.set(piece_idx)?)
+    }
+
+    /// Moves the file contents from wasm memory to the storage.
+    ///
+    /// Chunks are dropped only after all of them are written,
+    /// so the file stays in memory and remains usable if any write fails.
+    pub async fn move_to_storage(
+        &mut self,
+        storage: Box<dyn FileStorage>,
+    ) -> Result<(), FileMoveToStorageError>
+    where
+        C: FileChunk,
+    {
+        if self.storage.is_some() {
+            return Err(FileMoveToStorageError::FileIsAlreadyStored);
+        }
+        for chunk_idx in 0..self.chunks.len() {
+            if self.released_chunks[chunk_idx] {
+                continue;
+            }
+            let (offset, len) = self.chunk_range(chunk_idx);
+            storage
+                .write(offset, &self.chunks[chunk_idx].get(0, len))
+                .await?;
+        }
+        for chunk in &mut self.chunks {
+            *chunk = C::with_len(0);
+        }
+        self.storage = Some(storage);
+        Ok(())
+    }
+
+    // Released chunks have no contents, so pieces missing in them, e.g. unset to be received again,
+    // can not be set and are reported instead of being treated as already set.
+    fn released_piece_status(
+        &self,
+        piece_idx: &FilePieceIdx,
+        chunk_idx: usize,
+    ) -> Result<FileStateSetStatus, FileSetPieceError> {
+        if self.state.has(piece_idx)? {
+            Ok(FileStateSetStatus::AlreadySet)
+        } else {
+            Err(FileSetPieceError::ChunkIsReleased { chunk_idx })
+        }
+    }
+
+    // The piece length is only defined for pieces within the file.
+    fn check_piece_len(
+        &self,
+        piece_idx: &FilePieceIdx,
+        data: &[u8],
+    ) -> Result<(), FileSetPieceError> {
+        let _: bool = self.state.has(piece_idx)?;
         let len = data.len();
         let expected = self.piece_len(piece_idx);
-
         if len == expected {
-            let chunk_idx = piece_idx.0 / NUM_PIECES_IN_CHUNK;
-            let chunk_piece_idx = piece_idx.0 % NUM_PIECES_IN_CHUNK;
-            let chunk = &mut self.chunks[chunk_idx];
-            let offset = chunk_piece_idx * FILE_PIECE_SIZE;
-
-            chunk.set(offset, data);
-            Ok(self.state.set(piece_idx)?)
+            Ok(())
         } else {
-            Err(FileSetPieceError::InvalidPieceLen { expected })
+            Err(FileSetPieceError::InvalidPieceLen {
+                piece_idx: *piece_idx,
+                len,
+                expected,
+            })
         }
     }
 }
@@ -240,6 +739,28 @@ pub enum FileFromError {
     SizeIsTooLarge { len: FileLen },
 }
 
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum FileFromPartialError {
+    #[error("file size {} is too large", len.0)]
+    SizeIsTooLarge { len: FileLen },
+    #[error("file size {} does not match expected size {}", len.0, expected.0)]
+    SizeMismatch { len: FileLen, expected: FileLen },
+    #[error("number of chunks {len} does not match expected {expected}")]
+    InvalidNumChunks { len: usize, expected: usize },
+    #[error("file state length {len} does not match number of pieces {expected}")]
+    InvalidStateLen { len: usize, expected: usize },
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum FileReplaceStateError {
+    #[error("file state length {len} does not match number of pieces {expected}")]
+    InvalidStateLen { len: usize, expected: usize },
+    #[error("chunk {chunk_idx} hash mismatch")]
+    ChunkHashMismatch { chunk_idx: usize },
+    #[error("stored file state can not be verified")]
+    FileIsStored,
+}
+
 #[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
 pub enum FileToBlobError {
     #[error(
@@ -248,6 +769,10 @@ pub enum FileToBlobError {
          missing pieces: {missing}"
     )]
     NotComplete { available: usize, missing: usize },
+    #[error("{num_released} file chunks are released")]
+    ChunksReleased { num_released: usize },
+    #[error(transparent)]
+    Storage(#[from] FileStorageError),
 }
 
 #[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
@@ -260,12 +785,389 @@ pub enum FileHasPieceError {
 pub enum FileGetPieceError {
     #[error(transparent)]
     HasPieceError(#[from] FileStatePieceError),
+    #[error("chunk {chunk_idx} is released")]
+    ChunkIsReleased { chunk_idx: usize },
+    #[error("chunk {chunk_idx} is stored and can only be read asynchronously")]
+    ChunkIsStored { chunk_idx: usize },
+    #[error(transparent)]
+    Storage(#[from] FileStorageError),
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum FileReleaseChunkError {
+    #[error("chunk index out of range, number of chunks: {len}")]
+    ChunkIndexOutOfRange { len: usize },
+    #[error("chunk is already released")]
+    ChunkIsAlreadyReleased,
+    #[error("chunk is not available")]
+    ChunkIsNotAvailable,
 }
 
 #[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
 pub enum FileSetPieceError {
     #[error(transparent)]
     AddPieceError(#[from] FileStatePieceError),
-    #[error("invalid piece length, expected: {expected}")]
-    InvalidPieceLen { expected: usize },
+    #[error("piece {} length {len} does not match expected length {expected}", piece_idx.0)]
+    InvalidPieceLen {
+        piece_idx: FilePieceIdx,
+        len: usize,
+        expected: usize,
+    },
+    #[error("chunk {chunk_idx} is stored and can only be written asynchronously")]
+    ChunkIsStored { chunk_idx: usize },
+    #[error("chunk {chunk_idx} is released and its missing pieces can not be set")]
+    ChunkIsReleased { chunk_idx: usize },
+    #[error(transparent)]
+    Storage(#[from] FileStorageError),
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum FileMoveToStorageError {
+    #[error("file is already stored")]
+    FileIsAlreadyStored,
+    #[error(transparent)]
+    Storage(#[from] FileStorageError),
+}
+
+#[test]
+fn load_partial_file() {
+    use crate::{FileStateUnsetStatus, SharedFile};
+    use sha2::{Digest, Sha256};
+    use tracker_protocol::PeerId;
+
+    const NUM_PIECES: usize = NUM_PIECES_IN_CHUNK * 2 + 10;
+
+    let bytes: Vec<u8> = (0..NUM_PIECES * FILE_PIECE_SIZE)
+        .map(|j| (j % 251) as u8)
+        .collect();
+    let chunk_hashes = bytes
+        .chunks(FILE_CHUNK_SIZE)
+        .map(|chunk| FileSha256(Sha256::digest(chunk).into()))
+        .collect();
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen(bytes.len() as u64),
+    )
+    .with_chunk_hashes(chunk_hashes);
+
+    // The first chunk is corrupted, the last one is only partially available.
+    let mut chunks: Vec<Box<[u8]>> = bytes
+        .chunks(FILE_CHUNK_SIZE)
+        .map(|chunk| chunk.to_vec().into_boxed_slice())
+        .collect();
+    chunks[0][FILE_PIECE_SIZE * 5] ^= 1;
+    let mut have_mask = FileState::from_complete(NUM_PIECES);
+    for j in [NUM_PIECES - 1, NUM_PIECES - 3] {
+        assert_eq!(
+            have_mask.unset(&FilePieceIdx(j)),
+            Ok(FileStateUnsetStatus::JustUnset)
+        );
+    }
+
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> =
+        File::from_partial_chunks(metadata.clone(), chunks, have_mask).unwrap();
+    assert_eq!(
+        file.state().num_available(),
+        NUM_PIECES - NUM_PIECES_IN_CHUNK - 2
+    );
+    assert_eq!(file.has_piece(&FilePieceIdx(0)), Ok(false));
+    assert_eq!(
+        file.get_piece(&FilePieceIdx(NUM_PIECES_IN_CHUNK)).unwrap(),
+        Some(bytes[FILE_CHUNK_SIZE..FILE_CHUNK_SIZE + FILE_PIECE_SIZE].into())
+    );
+
+    // Available pieces are shared with peers.
+    let num_available = file.state().num_available();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file.set_peer_file_missing(PeerId(1)).unwrap();
+    assert_eq!(
+        shared_file.piece_queues().next_queue().unwrap().1.len(),
+        num_available
+    );
+
+    let result: Result<File<Box<[u8]>, FILE_CHUNK_SIZE>, _> =
+        File::from_partial_chunks(metadata, Vec::new(), FileState::from_complete(NUM_PIECES));
+    assert_eq!(
+        result.err(),
+        Some(FileFromPartialError::InvalidNumChunks {
+            len: 0,
+            expected: 3
+        })
+    );
+}
+
+#[test]
+fn use_mime_type_as_blob_type() {
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "image.png".to_owned(),
+        FileLen(FILE_PIECE_SIZE as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata.clone()).unwrap();
+    assert_eq!(file.blob_type(), "");
+
+    let metadata = metadata.with_mime_type(Some("image/png".to_owned()));
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    assert_eq!(file.blob_type(), "image/png");
+}
+
+#[test]
+fn create_file_from_bytes() {
+    use sha2::{Digest, Sha256};
+
+    let bytes: Vec<u8> = (0..FILE_CHUNK_SIZE + FILE_PIECE_SIZE * 3 + 17)
+        .map(|j| (j % 253) as u8)
+        .collect();
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::from_bytes("generated".to_owned(), &bytes);
+    assert_eq!(file.name(), "generated");
+    assert_eq!(file.len(), FileLen(bytes.len() as u64));
+    assert_eq!(file.sha256(), FileSha256(Sha256::digest(&bytes).into()));
+    assert_eq!(file.num_chunks(), 2);
+    assert_eq!(file.num_pieces(), NUM_PIECES_IN_CHUNK + 4);
+    assert!(file.state().is_complete());
+    for chunk_idx in 0..file.num_chunks() {
+        assert_eq!(
+            file.chunk_sha256(chunk_idx),
+            file.metadata().chunk_hashes()[chunk_idx]
+        );
+    }
+
+    let last_piece = FilePieceIdx(file.num_pieces() - 1);
+    let offset = last_piece.0 * FILE_PIECE_SIZE;
+    assert_eq!(
+        file.get_piece(&last_piece).unwrap(),
+        Some(bytes[offset..].into())
+    );
+
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::from_bytes("empty".to_owned(), &[]);
+    assert_eq!(file.sha256(), FileSha256(Sha256::digest(&[]).into()));
+    assert_eq!(file.num_chunks(), 0);
+    assert_eq!(file.num_pieces(), 0);
+}
+
+#[test]
+fn create_empty_and_single_byte_files() {
+    use crate::{FileLen, FileMetadata, FileStatePieceError, FileStateSetStatus};
+
+    let metadata = FileMetadata::new(FileSha256([0; 32]), "empty".to_owned(), FileLen(0));
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    assert_eq!(file.num_chunks(), 0);
+    assert_eq!(file.num_pieces(), 0);
+    assert!(file.state().is_complete());
+    assert_eq!(
+        file.get_piece(&FilePieceIdx(0)),
+        Err(FileGetPieceError::HasPieceError(
+            FileStatePieceError::PieceIndexOutOfRange
+        ))
+    );
+
+    let metadata = FileMetadata::new(FileSha256([1; 32]), "byte".to_owned(), FileLen(1));
+    let mut file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    assert_eq!(file.num_chunks(), 1);
+    assert_eq!(file.num_pieces(), 1);
+    assert_eq!(file.piece_len(&FilePieceIdx(0)), 1);
+    assert!(file.state().is_missing());
+    assert_eq!(
+        file.set_piece(&FilePieceIdx(0), &[42]),
+        Ok(FileStateSetStatus::JustSet)
+    );
+    assert!(file.state().is_complete());
+    assert_eq!(file.get_piece(&FilePieceIdx(0)), Ok(Some([42].into())));
+}
+
+#[test]
+fn set_last_piece_with_exact_len() {
+    use crate::FileStatePieceError;
+
+    for (len, last_piece_len) in [
+        (FILE_PIECE_SIZE * 3, FILE_PIECE_SIZE),
+        (FILE_PIECE_SIZE * 3 + 1, 1),
+        (FILE_PIECE_SIZE * 3 - 1, FILE_PIECE_SIZE - 1),
+    ] {
+        let metadata = FileMetadata::new(
+            FileSha256(Default::default()),
+            "filename".to_owned(),
+            FileLen(len as u64),
+        );
+        let mut file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+        let last_piece = FilePieceIdx(file.num_pieces() - 1);
+        assert_eq!(file.piece_len(&last_piece), last_piece_len);
+
+        for wrong_len in [last_piece_len - 1, last_piece_len + 1] {
+            assert_eq!(
+                file.set_piece(&last_piece, &vec![1; wrong_len]),
+                Err(FileSetPieceError::InvalidPieceLen {
+                    piece_idx: last_piece,
+                    len: wrong_len,
+                    expected: last_piece_len,
+                })
+            );
+        }
+        assert_eq!(
+            file.set_piece(&last_piece, &vec![1; last_piece_len]),
+            Ok(FileStateSetStatus::JustSet)
+        );
+        assert_eq!(
+            file.get_piece(&last_piece).unwrap().unwrap().len(),
+            last_piece_len
+        );
+
+        // Pieces past the end are refused instead of computing their length.
+        assert_eq!(
+            file.set_piece(&FilePieceIdx(last_piece.0 + 1), &[1; FILE_PIECE_SIZE]),
+            Err(FileSetPieceError::AddPieceError(
+                FileStatePieceError::PieceIndexOutOfRange
+            ))
+        );
+    }
+}
+
+#[test]
+fn refuse_missing_pieces_of_released_chunks() {
+    let mut file: File<Box<[u8]>, FILE_CHUNK_SIZE> =
+        File::from_bytes("released".to_owned(), &[3; 3 * FILE_PIECE_SIZE]);
+    file.release_chunk(0).unwrap();
+    assert_eq!(
+        file.set_piece(&FilePieceIdx(0), &[3; FILE_PIECE_SIZE]),
+        Ok(FileStateSetStatus::AlreadySet)
+    );
+
+    // A piece unset in a released chunk is lost rather than already set.
+    let _: FileStateUnsetStatus = file.unset_piece(&FilePieceIdx(1)).unwrap();
+    assert_eq!(
+        file.set_piece(&FilePieceIdx(1), &[3; FILE_PIECE_SIZE]),
+        Err(FileSetPieceError::ChunkIsReleased { chunk_idx: 0 })
+    );
+    assert!(!file.has_piece(&FilePieceIdx(1)).unwrap());
+}
+
+#[test]
+fn read_and_write_stored_file() {
+    use crate::file_storage::NativeFileStorage;
+    use async_std::task::block_on;
+
+    let bytes: Vec<u8> = (0..FILE_CHUNK_SIZE + FILE_PIECE_SIZE * 2 + 5)
+        .map(|j| (j % 247) as u8)
+        .collect();
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "stored".to_owned(),
+        FileLen(bytes.len() as u64),
+    );
+    let storage = NativeFileStorage::temp("read-and-write-stored-file");
+    let mut file: File<Box<[u8]>, FILE_CHUNK_SIZE> =
+        File::new_with_backend(metadata, Box::new(storage)).unwrap();
+    assert!(file.is_stored());
+
+    block_on(async {
+        let last_piece = FilePieceIdx(file.num_pieces() - 1);
+        for piece_idx in [FilePieceIdx(1), last_piece] {
+            let offset = piece_idx.0 * FILE_PIECE_SIZE;
+            let piece = &bytes[offset..offset + file.piece_len(&piece_idx)];
+            assert_eq!(
+                file.write_piece(&piece_idx, piece).await,
+                Ok(FileStateSetStatus::JustSet)
+            );
+            assert_eq!(file.read_piece(&piece_idx).await, Ok(Some(piece.into())));
+        }
+        assert_eq!(file.read_piece(&FilePieceIdx(0)).await, Ok(None));
+        assert_eq!(file.state().num_available(), 2);
+
+        // Stored pieces are not accessible synchronously.
+        assert_eq!(
+            file.get_piece(&FilePieceIdx(1)),
+            Err(FileGetPieceError::ChunkIsStored { chunk_idx: 0 })
+        );
+        assert_eq!(
+            file.set_piece(&FilePieceIdx(0), &bytes[..FILE_PIECE_SIZE]),
+            Err(FileSetPieceError::ChunkIsStored { chunk_idx: 0 })
+        );
+        assert_eq!(
+            file.write_piece(&last_piece, &[0; FILE_PIECE_SIZE]).await,
+            Err(FileSetPieceError::InvalidPieceLen {
+                piece_idx: last_piece,
+                len: FILE_PIECE_SIZE,
+                expected: 5,
+            })
+        );
+    });
+}
+
+#[test]
+fn move_file_to_storage() {
+    use crate::file_storage::NativeFileStorage;
+    use crate::FileStorageError;
+    use async_std::task::block_on;
+    use futures::future::LocalBoxFuture;
+
+    #[derive(Debug)]
+    struct FailingStorage;
+
+    impl FileStorage for FailingStorage {
+        fn read(
+            &self,
+            offset: u64,
+            len: usize,
+        ) -> LocalBoxFuture<'_, Result<Box<[u8]>, FileStorageError>> {
+            Box::pin(async move { Err(FileStorageError::Read { offset, len }) })
+        }
+
+        fn write<'a>(
+            &'a self,
+            offset: u64,
+            data: &'a [u8],
+        ) -> LocalBoxFuture<'a, Result<(), FileStorageError>> {
+            Box::pin(async move {
+                Err(FileStorageError::Write {
+                    offset,
+                    len: data.len(),
+                })
+            })
+        }
+    }
+
+    let bytes: Vec<u8> = (0..FILE_CHUNK_SIZE * 2 + FILE_PIECE_SIZE / 2)
+        .map(|j| (j % 241) as u8)
+        .collect();
+    let mut file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::from_bytes("moved".to_owned(), &bytes);
+    let last_piece = FilePieceIdx(file.num_pieces() - 1);
+    let last_piece_bytes = file.get_piece(&last_piece).unwrap();
+
+    block_on(async {
+        // A failed move leaves the file in memory.
+        assert_eq!(
+            file.move_to_storage(Box::new(FailingStorage)).await,
+            Err(FileMoveToStorageError::Storage(FileStorageError::Write {
+                offset: 0,
+                len: FILE_CHUNK_SIZE
+            }))
+        );
+        assert!(!file.is_stored());
+        assert_eq!(file.get_piece(&last_piece).unwrap(), last_piece_bytes);
+
+        file.move_to_storage(Box::new(NativeFileStorage::temp("move-file-to-storage")))
+            .await
+            .unwrap();
+        assert!(file.is_stored());
+        assert_eq!(
+            file.read_piece(&last_piece).await.unwrap(),
+            last_piece_bytes
+        );
+        let piece_idx = FilePieceIdx(NUM_PIECES_IN_CHUNK + 3);
+        let offset = piece_idx.0 * FILE_PIECE_SIZE;
+        assert_eq!(
+            file.read_piece(&piece_idx).await,
+            Ok(Some(bytes[offset..offset + FILE_PIECE_SIZE].into()))
+        );
+        assert_eq!(
+            file.move_to_storage(Box::new(FailingStorage)).await,
+            Err(FileMoveToStorageError::FileIsAlreadyStored)
+        );
+        assert_eq!(
+            file.replace_state(FileState::from_complete(file.num_pieces())),
+            Err(FileReplaceStateError::FileIsStored)
+        );
+    });
 }
@@ -0,0 +1,98 @@
+use core::ops::Add;
+use core::time::Duration;
+
+pub const FILE_DISCOVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum FileDiscoveryStatus {
+    Searching,
+    NoSeedersFound,
+    SeedersFound,
+}
+
+/// Discovery of peers that have any pieces of a file.
+///
+/// The status changes to `NoSeedersFound` if no peer has announced any pieces before the timeout,
+/// but it still changes to `SeedersFound` if the pieces are announced later.
+#[derive(Clone, Debug)]
+pub struct FileDiscovery<T> {
+    deadline: T,
+    status: FileDiscoveryStatus,
+}
+
+impl<T> FileDiscovery<T> {
+    pub fn new(now: T, timeout: Duration) -> Self
+    where
+        T: Add<Duration, Output = T>,
+    {
+        Self {
+            deadline: now + timeout,
+            status: FileDiscoveryStatus::Searching,
+        }
+    }
+
+    pub fn status(&self) -> FileDiscoveryStatus {
+        self.status
+    }
+
+    pub fn update(&mut self, now: &T, has_seeders: bool) -> FileDiscoveryStatus
+    where
+        T: Ord,
+    {
+        self.status = match self.status {
+            FileDiscoveryStatus::SeedersFound => FileDiscoveryStatus::SeedersFound,
+            _ if has_seeders => FileDiscoveryStatus::SeedersFound,
+            FileDiscoveryStatus::Searching if *now < self.deadline => {
+                FileDiscoveryStatus::Searching
+            }
+            FileDiscoveryStatus::Searching | FileDiscoveryStatus::NoSeedersFound => {
+                FileDiscoveryStatus::NoSeedersFound
+            }
+        };
+        self.status
+    }
+}
+
+#[test]
+fn time_out_file_discovery_without_seeders() {
+    let secs = Duration::from_secs;
+    let mut discovery = FileDiscovery::new(secs(10), secs(5));
+    assert_eq!(discovery.status(), FileDiscoveryStatus::Searching);
+
+    assert_eq!(
+        discovery.update(&secs(14), false),
+        FileDiscoveryStatus::Searching
+    );
+    assert_eq!(
+        discovery.update(&secs(15), false),
+        FileDiscoveryStatus::NoSeedersFound
+    );
+    assert_eq!(
+        discovery.update(&secs(20), false),
+        FileDiscoveryStatus::NoSeedersFound
+    );
+
+    // Seeders discovered by background offer retries are still picked up.
+    assert_eq!(
+        discovery.update(&secs(40), true),
+        FileDiscoveryStatus::SeedersFound
+    );
+    assert_eq!(
+        discovery.update(&secs(50), false),
+        FileDiscoveryStatus::SeedersFound
+    );
+}
+
+#[test]
+fn find_seeders_before_file_discovery_timeout() {
+    let secs = Duration::from_secs;
+    let mut discovery = FileDiscovery::new(secs(0), secs(5));
+    assert_eq!(
+        discovery.update(&secs(1), true),
+        FileDiscoveryStatus::SeedersFound
+    );
+    assert_eq!(
+        discovery.update(&secs(10), false),
+        FileDiscoveryStatus::SeedersFound
+    );
+}
@@ -0,0 +1,124 @@
+use serde::{Deserialize, Serialize};
+use tracker_protocol::FileSha256;
+
+/// Algorithm used to identify files and to verify their chunks.
+///
+/// The algorithm is carried in the file metadata,
+/// so peers never compare hashes computed by different algorithms.
+/// SHA-256 is the only implemented algorithm.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum FileHashAlgorithm {
+    Sha256,
+}
+
+impl Default for FileHashAlgorithm {
+    fn default() -> Self {
+        Self::Sha256
+    }
+}
+
+impl FileHashAlgorithm {
+    /// Returns the algorithm name used in magnet `urn:<name>:<hex>` identifiers.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "sha256" => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    /// Returns the hash length in bytes.
+    pub fn hash_len(self) -> usize {
+        match self {
+            Self::Sha256 => 32,
+        }
+    }
+
+    pub fn digest(self, bytes: &[u8]) -> FileHash {
+        use sha2::{Digest, Sha256};
+
+        match self {
+            Self::Sha256 => FileHash::Sha256(FileSha256(Sha256::digest(bytes).into())),
+        }
+    }
+}
+
+/// File or chunk hash tagged with its algorithm.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum FileHash {
+    Sha256(FileSha256),
+}
+
+impl From<FileSha256> for FileHash {
+    fn from(sha256: FileSha256) -> Self {
+        Self::Sha256(sha256)
+    }
+}
+
+impl FileHash {
+    pub fn algorithm(&self) -> FileHashAlgorithm {
+        match self {
+            Self::Sha256(_) => FileHashAlgorithm::Sha256,
+        }
+    }
+
+    /// Returns the SHA-256 hash, `None` if the hash is computed by another algorithm.
+    pub fn sha256(&self) -> Option<FileSha256> {
+        match self {
+            Self::Sha256(sha256) => Some(*sha256),
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Sha256(sha256) => &sha256.0,
+        }
+    }
+
+    /// Decodes a hex encoded hash of the given algorithm.
+    pub fn decode_hex(algorithm: FileHashAlgorithm, hex: &str) -> Option<Self> {
+        match algorithm {
+            FileHashAlgorithm::Sha256 => {
+                let mut sha256 = [0; 32];
+                hex::decode_to_slice(hex, &mut sha256).ok()?;
+                Some(Self::Sha256(FileSha256(sha256)))
+            }
+        }
+    }
+}
+
+#[test]
+fn file_hash_roundtrip() {
+    let algorithm = FileHashAlgorithm::default();
+    assert_eq!(algorithm, FileHashAlgorithm::Sha256);
+    assert_eq!(
+        FileHashAlgorithm::from_name(algorithm.name()),
+        Some(algorithm)
+    );
+    assert_eq!(FileHashAlgorithm::from_name("md5"), None);
+
+    let encoded = bincode::serialize(&algorithm).unwrap();
+    assert_eq!(
+        bincode::deserialize::<FileHashAlgorithm>(&encoded).unwrap(),
+        algorithm
+    );
+
+    let hash = algorithm.digest(b"file");
+    assert_eq!(hash.algorithm(), algorithm);
+    assert_eq!(hash.as_bytes().len(), algorithm.hash_len());
+    let encoded = bincode::serialize(&hash).unwrap();
+    assert_eq!(bincode::deserialize::<FileHash>(&encoded).unwrap(), hash);
+
+    let sha256 = hash.sha256().unwrap();
+    assert_eq!(FileHash::from(sha256), hash);
+    assert_eq!(
+        FileHash::decode_hex(algorithm, &sha256.to_string()),
+        Some(hash)
+    );
+    assert_eq!(FileHash::decode_hex(algorithm, "ABCD"), None);
+}
@@ -2,19 +2,75 @@ use serde::{Deserialize, Serialize};
 use thiserror::Error;
 use tracker_protocol::FileSha256;
 
+use crate::{FileHash, FileHashAlgorithm};
+
+pub const MAGNET_PREFIX: &str = "webtorrent-lite:?";
+
+/// Layout version prepended to metadata encoded by `FileMetadata::encode_base64`.
+const FILE_METADATA_VERSION: u8 = 1;
+
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
 pub struct FileLen(pub u64);
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct FileMetadata {
+    /// Algorithm of the file and chunk hashes.
+    hash_algorithm: FileHashAlgorithm,
     sha256: FileSha256,
     name: String,
     len: FileLen,
+    /// Sha256 hashes of file chunks, empty if chunks can not be verified.
+    chunk_hashes: Vec<FileSha256>,
+    /// MIME type used to open the downloaded file, e.g. `image/png`.
+    mime_type: Option<String>,
+    /// Short description shared alongside the file.
+    description: Option<String>,
+    /// Compress pieces sent to peers, useful for text-heavy files.
+    compressed: bool,
 }
 
 impl FileMetadata {
     pub fn new(sha256: FileSha256, name: String, len: FileLen) -> Self {
-        Self { sha256, name, len }
+        Self {
+            hash_algorithm: FileHashAlgorithm::default(),
+            sha256,
+            name,
+            len,
+            chunk_hashes: Vec::new(),
+            mime_type: None,
+            description: None,
+            compressed: false,
+        }
+    }
+
+    pub fn with_chunk_hashes(self, chunk_hashes: Vec<FileSha256>) -> Self {
+        Self {
+            chunk_hashes,
+            ..self
+        }
+    }
+
+    pub fn with_mime_type(self, mime_type: Option<String>) -> Self {
+        Self { mime_type, ..self }
+    }
+
+    pub fn with_description(self, description: Option<String>) -> Self {
+        Self {
+            description,
+            ..self
+        }
+    }
+
+    pub fn hash_algorithm(&self) -> FileHashAlgorithm {
+        self.hash_algorithm
+    }
+
+    pub fn hash(&self) -> FileHash {
+        FileHash::from(self.sha256)
+    }
+
+    pub fn with_compressed(self, compressed: bool) -> Self {
+        Self { compressed, ..self }
     }
 
     pub fn sha256(&self) -> FileSha256 {
@@ -29,17 +85,318 @@ impl FileMetadata {
         self.len
     }
 
+    pub fn chunk_hashes(&self) -> &[FileSha256] {
+        &self.chunk_hashes
+    }
+
+    pub fn mime_type(&self) -> Option<&str> {
+        self.mime_type.as_deref()
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
     pub fn encode_base64(&self) -> Result<String, FileMetaDataEncodeBase64Error> {
-        let encoded: Vec<u8> = bincode::serialize(&self)?;
+        let mut encoded = vec![FILE_METADATA_VERSION];
+        bincode::serialize_into(&mut encoded, &self)?;
         Ok(base64::encode(encoded))
     }
 
+    /// Decodes metadata encoded by `encode_base64`,
+    /// including metadata encoded before the layout version was prepended.
     pub fn decode_base64(base64: &str) -> Result<Self, FileMetaDataDecodeBase64Error> {
+        use bincode::Options;
+
         let encoded = base64::decode(base64)?;
-        Ok(bincode::deserialize(&encoded[..])?)
+        let metadata = match encoded.split_first() {
+            Some((&FILE_METADATA_VERSION, versioned)) => bincode::DefaultOptions::new()
+                .with_fixint_encoding()
+                .deserialize(versioned)
+                .or_else(|err| decode_unversioned(&encoded).map_err(|_| err)),
+            Some(_) | None => decode_unversioned(&encoded),
+        };
+        Ok(metadata?)
+    }
+
+    /// Encodes metadata as
+    /// `webtorrent-lite:?xt=urn:sha256:<hex>&dn=<name>&xl=<len>&ch=<hex>&mt=<mime>&ds=<text>&cp=1`
+    /// where `sha256` is the hash algorithm name, `ch` contains concatenated chunk hashes,
+    /// `mt` and `ds` contain the MIME type and the description, `cp` enables piece compression,
+    /// and parameters without values are omitted.
+    pub fn encode_magnet(&self) -> String {
+        let mut magnet = format!(
+            "{}xt=urn:{}:{}&dn={}&xl={}",
+            MAGNET_PREFIX,
+            self.hash_algorithm.name(),
+            self.sha256,
+            percent_encode(&self.name),
+            self.len.0
+        );
+        if !self.chunk_hashes.is_empty() {
+            magnet.push_str("&ch=");
+            for chunk_hash in &self.chunk_hashes {
+                magnet.push_str(&chunk_hash.to_string());
+            }
+        }
+        if let Some(mime_type) = &self.mime_type {
+            magnet.push_str("&mt=");
+            magnet.push_str(&percent_encode(mime_type));
+        }
+        if let Some(description) = &self.description {
+            magnet.push_str("&ds=");
+            magnet.push_str(&percent_encode(description));
+        }
+        if self.compressed {
+            magnet.push_str("&cp=1");
+        }
+        magnet
+    }
+
+    /// Parses metadata encoded by `encode_magnet` or by legacy `encode_base64`.
+    pub fn parse_magnet(magnet: &str) -> Result<Self, FileMetaDataParseMagnetError> {
+        let params = match magnet.strip_prefix(MAGNET_PREFIX) {
+            Some(params) => params,
+            None => return Ok(Self::decode_base64(magnet)?),
+        };
+
+        let mut hash = None;
+        let mut name = None;
+        let mut len = None;
+        let mut chunk_hashes = "";
+        let mut mime_type = None;
+        let mut description = None;
+        let mut compressed = false;
+        for param in params.split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key {
+                "xt" => {
+                    let (algorithm, hex) = value
+                        .strip_prefix("urn:")
+                        .and_then(|urn| urn.split_once(':'))
+                        .ok_or(FileMetaDataParseMagnetError::InvalidSha256)?;
+                    let algorithm = FileHashAlgorithm::from_name(algorithm)
+                        .ok_or(FileMetaDataParseMagnetError::UnsupportedHashAlgorithm)?;
+                    hash = Some(
+                        FileHash::decode_hex(algorithm, hex)
+                            .ok_or(FileMetaDataParseMagnetError::InvalidSha256)?,
+                    );
+                }
+                "dn" => {
+                    name = Some(
+                        percent_decode(value).ok_or(FileMetaDataParseMagnetError::InvalidName)?,
+                    );
+                }
+                "xl" => {
+                    len = Some(FileLen(
+                        value
+                            .parse()
+                            .map_err(|_| FileMetaDataParseMagnetError::InvalidLen)?,
+                    ));
+                }
+                "ch" => chunk_hashes = value,
+                "mt" => {
+                    mime_type = Some(
+                        percent_decode(value)
+                            .ok_or(FileMetaDataParseMagnetError::InvalidMimeType)?,
+                    );
+                }
+                "ds" => {
+                    description = Some(
+                        percent_decode(value)
+                            .ok_or(FileMetaDataParseMagnetError::InvalidDescription)?,
+                    );
+                }
+                "cp" => compressed = value == "1",
+                _ => {}
+            }
+        }
+
+        let hash = hash.ok_or(FileMetaDataParseMagnetError::MissingParameter("xt"))?;
+        let hash_algorithm = hash.algorithm();
+        let sha256 = hash
+            .sha256()
+            .ok_or(FileMetaDataParseMagnetError::UnsupportedHashAlgorithm)?;
+        // Chunk hashes are parsed after the file hash since they share its algorithm.
+        let hex_len = hash_algorithm.hash_len() * 2;
+        if chunk_hashes.len() % hex_len != 0 {
+            return Err(FileMetaDataParseMagnetError::InvalidChunkHashes);
+        }
+        let chunk_hashes = (0..chunk_hashes.len())
+            .step_by(hex_len)
+            .map(|offset| {
+                chunk_hashes
+                    .get(offset..offset + hex_len)
+                    .and_then(|hex| FileHash::decode_hex(hash_algorithm, hex))
+                    .and_then(|hash| hash.sha256())
+            })
+            .collect::<Option<_>>()
+            .ok_or(FileMetaDataParseMagnetError::InvalidChunkHashes)?;
+
+        Ok(Self {
+            hash_algorithm,
+            sha256,
+            name: name.ok_or(FileMetaDataParseMagnetError::MissingParameter("dn"))?,
+            len: len.ok_or(FileMetaDataParseMagnetError::MissingParameter("xl"))?,
+            chunk_hashes,
+            mime_type,
+            description,
+            compressed,
+        })
     }
 }
 
+// Unversioned layouts are tried from the latest one,
+// trailing bytes are rejected so that older layouts never match newer metadata.
+fn decode_unversioned(encoded: &[u8]) -> Result<FileMetadata, bincode::Error> {
+    use bincode::Options;
+
+    let options = bincode::DefaultOptions::new().with_fixint_encoding();
+    options
+        .deserialize::<FileMetadata>(encoded)
+        .or_else(|err| {
+            options
+                .deserialize::<HashAlgorithmFileMetadata>(encoded)
+                .map(FileMetadata::from)
+                .map_err(|_| err)
+        })
+        .or_else(|err| {
+            options
+                .deserialize::<AttachmentsFileMetadata>(encoded)
+                .map(FileMetadata::from)
+                .map_err(|_| err)
+        })
+        .or_else(|err| {
+            options
+                .deserialize::<ChunkHashesFileMetadata>(encoded)
+                .map(FileMetadata::from)
+                .map_err(|_| err)
+        })
+        .or_else(|err| {
+            options
+                .deserialize::<LegacyFileMetadata>(encoded)
+                .map(FileMetadata::from)
+                .map_err(|_| err)
+        })
+}
+
+/// Metadata layout encoded by `encode_base64` before magnets were introduced.
+#[derive(Deserialize)]
+struct LegacyFileMetadata {
+    sha256: FileSha256,
+    name: String,
+    len: FileLen,
+}
+
+/// Metadata layout encoded by `encode_base64` before attachments were added.
+#[derive(Deserialize)]
+struct ChunkHashesFileMetadata {
+    sha256: FileSha256,
+    name: String,
+    len: FileLen,
+    chunk_hashes: Vec<FileSha256>,
+}
+
+/// Metadata layout encoded by `encode_base64` before the hash algorithm was added.
+#[derive(Deserialize)]
+struct AttachmentsFileMetadata {
+    sha256: FileSha256,
+    name: String,
+    len: FileLen,
+    chunk_hashes: Vec<FileSha256>,
+    mime_type: Option<String>,
+    description: Option<String>,
+}
+
+/// Metadata layout encoded by `encode_base64` before piece compression was added.
+#[derive(Deserialize)]
+struct HashAlgorithmFileMetadata {
+    hash_algorithm: FileHashAlgorithm,
+    sha256: FileSha256,
+    name: String,
+    len: FileLen,
+    chunk_hashes: Vec<FileSha256>,
+    mime_type: Option<String>,
+    description: Option<String>,
+}
+
+impl From<LegacyFileMetadata> for FileMetadata {
+    fn from(metadata: LegacyFileMetadata) -> Self {
+        Self::new(metadata.sha256, metadata.name, metadata.len)
+    }
+}
+
+impl From<ChunkHashesFileMetadata> for FileMetadata {
+    fn from(metadata: ChunkHashesFileMetadata) -> Self {
+        Self::new(metadata.sha256, metadata.name, metadata.len)
+            .with_chunk_hashes(metadata.chunk_hashes)
+    }
+}
+
+impl From<AttachmentsFileMetadata> for FileMetadata {
+    fn from(metadata: AttachmentsFileMetadata) -> Self {
+        Self::new(metadata.sha256, metadata.name, metadata.len)
+            .with_chunk_hashes(metadata.chunk_hashes)
+            .with_mime_type(metadata.mime_type)
+            .with_description(metadata.description)
+    }
+}
+
+impl From<HashAlgorithmFileMetadata> for FileMetadata {
+    fn from(metadata: HashAlgorithmFileMetadata) -> Self {
+        Self {
+            hash_algorithm: metadata.hash_algorithm,
+            ..Self::new(metadata.sha256, metadata.name, metadata.len)
+                .with_chunk_hashes(metadata.chunk_hashes)
+                .with_mime_type(metadata.mime_type)
+                .with_description(metadata.description)
+        }
+    }
+}
+
+// Only RFC 3986 unreserved characters are left as is.
+fn percent_encode(value: &str) -> String {
+    use core::fmt::Write;
+
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-_.~".contains(&byte) {
+            encoded.push(char::from(byte));
+        } else {
+            write!(encoded, "%{:02X}", byte).unwrap();
+        }
+    }
+    encoded
+}
+
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' => {
+                let hex = value.get(idx + 1..idx + 3)?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                idx += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                idx += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                idx += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
 #[derive(Error, Debug)]
 pub enum FileMetaDataEncodeBase64Error {
     #[error(transparent)]
@@ -53,3 +410,178 @@ pub enum FileMetaDataDecodeBase64Error {
     #[error(transparent)]
     DeserializeError(#[from] bincode::Error),
 }
+
+#[derive(Error, Debug)]
+pub enum FileMetaDataParseMagnetError {
+    #[error("magnet parameter `{0}` is missing")]
+    MissingParameter(&'static str),
+    #[error("magnet file sha256 is invalid")]
+    InvalidSha256,
+    #[error("magnet file hash algorithm is not supported")]
+    UnsupportedHashAlgorithm,
+    #[error("magnet file name is invalid")]
+    InvalidName,
+    #[error("magnet file length is invalid")]
+    InvalidLen,
+    #[error("magnet chunk hashes are invalid")]
+    InvalidChunkHashes,
+    #[error("magnet file mime type is invalid")]
+    InvalidMimeType,
+    #[error("magnet file description is invalid")]
+    InvalidDescription,
+    #[error(transparent)]
+    Base64Error(#[from] FileMetaDataDecodeBase64Error),
+}
+
+#[test]
+fn magnet_roundtrip() {
+    for (name, len) in [
+        ("file.txt", 0),
+        ("file name with spaces & symbols=?%+.tar.gz", 12345),
+        ("файл 文件 🦀.bin", u64::MAX),
+    ] {
+        let metadata = FileMetadata::new(FileSha256([0xA5; 32]), name.to_owned(), FileLen(len));
+        let magnet = metadata.encode_magnet();
+        assert!(magnet.starts_with(MAGNET_PREFIX));
+        assert!(!magnet[MAGNET_PREFIX.len()..].contains(' '));
+        assert_eq!(FileMetadata::parse_magnet(&magnet).unwrap(), metadata);
+    }
+
+    let metadata = FileMetadata::new(FileSha256([1; 32]), "chunks".to_owned(), FileLen(3 << 20))
+        .with_chunk_hashes(vec![
+            FileSha256([2; 32]),
+            FileSha256([3; 32]),
+            FileSha256([4; 32]),
+        ]);
+    let magnet = metadata.encode_magnet();
+    assert_eq!(FileMetadata::parse_magnet(&magnet).unwrap(), metadata);
+
+    let metadata = FileMetadata::new(FileSha256([5; 32]), "image.png".to_owned(), FileLen(42))
+        .with_mime_type(Some("image/png".to_owned()))
+        .with_description(Some("Holiday photo & notes".to_owned()));
+    let magnet = metadata.encode_magnet();
+    assert_eq!(FileMetadata::parse_magnet(&magnet).unwrap(), metadata);
+
+    let metadata = FileMetadata::new(FileSha256([6; 32]), "notes.txt".to_owned(), FileLen(42))
+        .with_compressed(true);
+    let magnet = metadata.encode_magnet();
+    assert!(magnet.ends_with("&cp=1"));
+    let parsed = FileMetadata::parse_magnet(&magnet).unwrap();
+    assert!(parsed.is_compressed());
+    assert_eq!(parsed, metadata);
+}
+
+#[test]
+fn base64_roundtrip_with_attachments() {
+    let metadata = FileMetadata::new(FileSha256([9; 32]), "notes.txt".to_owned(), FileLen(7))
+        .with_mime_type(Some("text/plain".to_owned()))
+        .with_description(Some("Meeting notes".to_owned()));
+    let encoded = metadata.encode_base64().unwrap();
+    let decoded = FileMetadata::decode_base64(&encoded).unwrap();
+    assert_eq!(decoded, metadata);
+    assert_eq!(decoded.mime_type(), Some("text/plain"));
+    assert_eq!(decoded.description(), Some("Meeting notes"));
+
+    let metadata = FileMetadata::new(FileSha256([9; 32]), "plain".to_owned(), FileLen(7));
+    let encoded = metadata.encode_base64().unwrap();
+    let decoded = FileMetadata::decode_base64(&encoded).unwrap();
+    assert_eq!(decoded.mime_type(), None);
+    assert_eq!(decoded.description(), None);
+}
+
+#[test]
+fn parse_legacy_and_invalid_magnets() {
+    let metadata = FileMetadata::new(FileSha256([7; 32]), "legacy".to_owned(), FileLen(1 << 40));
+    // Encoded by `encode_base64` before magnets were introduced.
+    let legacy = "BwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcHBwcGAAAAAAAAAGxlZ2FjeQAAAAAAAQAA";
+    assert_eq!(FileMetadata::parse_magnet(legacy).unwrap(), metadata);
+
+    let sha256 = FileSha256([7; 32]);
+    for (magnet, expected) in [
+        (
+            format!("{}dn=name&xl=1", MAGNET_PREFIX),
+            "magnet parameter `xt` is missing",
+        ),
+        (
+            format!("{}xt=urn:sha256:ABCD&dn=name&xl=1", MAGNET_PREFIX),
+            "magnet file sha256 is invalid",
+        ),
+        (
+            format!("{}xt=urn:md5:ABCD&dn=name&xl=1", MAGNET_PREFIX),
+            "magnet file hash algorithm is not supported",
+        ),
+        (
+            format!("{}xt=urn:sha256:{}&dn=%FF&xl=1", MAGNET_PREFIX, sha256),
+            "magnet file name is invalid",
+        ),
+        (
+            format!("{}xt=urn:sha256:{}&dn=name&xl=-1", MAGNET_PREFIX, sha256),
+            "magnet file length is invalid",
+        ),
+        (
+            format!(
+                "{}xt=urn:sha256:{}&dn=name&xl=1&ch=AB",
+                MAGNET_PREFIX, sha256
+            ),
+            "magnet chunk hashes are invalid",
+        ),
+    ] {
+        let err = FileMetadata::parse_magnet(&magnet).unwrap_err();
+        assert_eq!(err.to_string(), expected);
+    }
+}
+
+#[test]
+fn serialize_hash_algorithm() {
+    let metadata = FileMetadata::new(FileSha256([3; 32]), "hashed".to_owned(), FileLen(9))
+        .with_chunk_hashes(vec![FileSha256([4; 32])]);
+    assert_eq!(metadata.hash_algorithm(), FileHashAlgorithm::Sha256);
+    assert_eq!(metadata.hash(), FileHash::Sha256(FileSha256([3; 32])));
+
+    let decoded = FileMetadata::decode_base64(&metadata.encode_base64().unwrap()).unwrap();
+    assert_eq!(decoded.hash_algorithm(), FileHashAlgorithm::Sha256);
+    assert_eq!(decoded, metadata);
+
+    let magnet = metadata.encode_magnet();
+    assert!(magnet.contains("xt=urn:sha256:"));
+    let parsed = FileMetadata::parse_magnet(&magnet).unwrap();
+    assert_eq!(parsed.hash_algorithm(), FileHashAlgorithm::Sha256);
+    assert_eq!(parsed, metadata);
+}
+
+#[test]
+fn decode_base64_of_previous_layouts() {
+    let metadata = FileMetadata::new(FileSha256([1; 32]), "chunks".to_owned(), FileLen(5))
+        .with_chunk_hashes(vec![FileSha256([2; 32])]);
+    let encoded = metadata.encode_base64().unwrap();
+    assert_eq!(base64::decode(&encoded).unwrap()[0], FILE_METADATA_VERSION);
+
+    // Encoded with chunk hashes, its first hash byte equals the layout version.
+    let decoded = FileMetadata::decode_base64(
+        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEGAAAAAAAAAGNodW5rcwUAAAAAAAAAAQAAAAAAAAACAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAg==",
+    );
+    assert_eq!(decoded.unwrap(), metadata);
+
+    let metadata = metadata.with_mime_type(Some("text/plain".to_owned()));
+    // Encoded with attachments.
+    let decoded = FileMetadata::decode_base64(
+        "AQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEGAAAAAAAAAGNodW5rcwUAAAAAAAAAAQAAAAAAAAACAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgEKAAAAAAAAAHRleHQvcGxhaW4A",
+    );
+    assert_eq!(decoded.unwrap(), metadata);
+    // Encoded with the hash algorithm.
+    let decoded = FileMetadata::decode_base64(
+        "AAAAAAEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBBgAAAAAAAABjaHVua3MFAAAAAAAAAAEAAAAAAAAAAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgIBCgAAAAAAAAB0ZXh0L3BsYWluAA==",
+    );
+    assert_eq!(decoded.unwrap(), metadata);
+
+    let metadata = metadata.with_compressed(true);
+    // Encoded with piece compression, before the layout version was prepended.
+    let decoded = FileMetadata::decode_base64(
+        "AAAAAAEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBAQEBBgAAAAAAAABjaHVua3MFAAAAAAAAAAEAAAAAAAAAAgICAgICAgICAgICAgICAgICAgICAgICAgICAgICAgIBCgAAAAAAAAB0ZXh0L3BsYWluAAE=",
+    );
+    assert_eq!(decoded.unwrap(), metadata);
+    assert_eq!(
+        FileMetadata::decode_base64(&metadata.encode_base64().unwrap()).unwrap(),
+        metadata
+    );
+}
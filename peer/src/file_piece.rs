@@ -22,4 +22,61 @@ pub struct FilePieceData {
     pub peer_shift: PiecePeerShift,
     pub num_confirmed_owners: PieceNumConfirmedOwners,
     pub num_possible_owners: PieceNumPossibleOwners,
+    /// Number of times the piece was selected for a peer and not confirmed by it yet,
+    /// pieces resent many times may be dropped by a peer that never acknowledges them.
+    pub send_attempts: u32,
+}
+
+/// Returns the index of the peer to try at `shift` in the piece-specific peer order
+/// defined by the piece `hash`, both its starting peer and its stride depend on the hash.
+///
+/// Shifts in any `num_peers` consecutive values visit every peer exactly once.
+pub fn select_peer_offset(hash: u64, num_peers: usize, shift: usize) -> usize {
+    assert!(num_peers > 0);
+    if num_peers == 1 {
+        return 0;
+    }
+    // The stride must be coprime with the number of peers, otherwise some peers are never visited,
+    // `num_peers - 1` is always coprime so the search is bounded.
+    let mut stride = (hash >> 32) as usize % (num_peers - 1) + 1;
+    while gcd(stride, num_peers) != 1 {
+        stride += 1;
+    }
+    let start = (hash & u64::from(u32::MAX)) as usize % num_peers;
+    stride * ((start + shift % num_peers) % num_peers) % num_peers
+}
+
+fn gcd(mut lhs: usize, mut rhs: usize) -> usize {
+    while rhs != 0 {
+        (lhs, rhs) = (rhs, lhs % rhs);
+    }
+    lhs
+}
+
+#[test]
+fn visit_every_peer_once() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let hashes: Vec<u64> = (0..64)
+        .map(|stride| stride << 32)
+        .chain((0..256).map(|_| rng.gen()))
+        .collect();
+    for num_peers in 1..=32 {
+        for &hash in &hashes {
+            for start in [0, 1, num_peers, 3 * num_peers + 2] {
+                let mut visited = vec![false; num_peers];
+                for shift in start..start + num_peers {
+                    let offset = select_peer_offset(hash, num_peers, shift);
+                    assert!(
+                        !visited[offset],
+                        "peer {} visited twice for {} peers and hash {:#x}",
+                        offset, num_peers, hash
+                    );
+                    visited[offset] = true;
+                }
+            }
+        }
+    }
 }
@@ -10,6 +10,9 @@ pub struct FilePiecesQueues {
     sharable_pieces: Vec<Option<FilePiecesQueuePiece>>,
     pieces_by_num_possible_owners: Vec<Vec<FilePieceIdx>>,
     min_possible_owners: Option<PieceNumPossibleOwners>,
+    len: usize,
+    /// Keep queue pieces sorted by index instead of the `swap_remove` order.
+    stable_order: bool,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -29,9 +32,50 @@ impl FilePiecesQueues {
             sharable_pieces: vec![None; num_pieces],
             pieces_by_num_possible_owners: Vec::new(),
             min_possible_owners: None,
+            len: 0,
+            stable_order: false,
         }
     }
 
+    /// Keeps pieces of each queue sorted by index,
+    /// so that the queue order does not depend on the insertion and removal history.
+    ///
+    /// Insertions and removals take linear time in the queue length instead of constant time.
+    pub fn set_stable_order(&mut self, stable_order: bool) {
+        self.stable_order = stable_order;
+        if stable_order {
+            for pieces in &mut self.pieces_by_num_possible_owners {
+                pieces.sort_unstable();
+                update_offsets(&mut self.sharable_pieces, pieces, 0);
+            }
+        }
+    }
+
+    pub fn is_stable_order(&self) -> bool {
+        self.stable_order
+    }
+
+    /// Returns the number of queued pieces in all queues.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterates over all queued pieces in the piece index order.
+    pub fn iter_pieces(&self) -> impl Iterator<Item = (FilePieceIdx, &FilePieceData)> {
+        self.sharable_pieces
+            .iter()
+            .enumerate()
+            .filter_map(|(piece_idx, piece)| {
+                piece
+                    .as_ref()
+                    .map(|piece| (FilePieceIdx(piece_idx), &piece.data))
+            })
+    }
+
     pub fn next_queue(&self) -> Option<(PieceNumPossibleOwners, &[FilePieceIdx])> {
         match self.min_possible_owners {
             Some(idx) => Some((idx, &self.pieces_by_num_possible_owners[idx.0])),
@@ -42,10 +86,7 @@ impl FilePiecesQueues {
     /// Gets piece data from piece queues.
     ///
     /// Returns piece data for available pieces not yet received by all receivers.
-    pub fn get(
-        &mut self,
-        piece_idx: FilePieceIdx,
-    ) -> Result<FilePieceData, FilePiecesQueueGetError> {
+    pub fn get(&self, piece_idx: FilePieceIdx) -> Result<FilePieceData, FilePiecesQueueGetError> {
         match &self.sharable_pieces.get(piece_idx.0) {
             Some(Some(piece)) => Ok(piece.data),
             Some(None) => Err(FilePiecesQueueGetError::PieceIsNotAdded),
@@ -71,12 +112,21 @@ impl FilePiecesQueues {
                 let pieces = self
                     .pieces_by_num_possible_owners
                     .get_mut_or_resize_default(data.num_possible_owners.0);
-                let offset = NonMaxUsize::new(pieces.push_and_get_offset(piece_idx)).unwrap();
+                let offset = if self.stable_order {
+                    let offset = pieces.partition_point(|stored| *stored < piece_idx);
+                    pieces.insert(offset, piece_idx);
+                    update_offsets(&mut self.sharable_pieces, pieces, offset + 1);
+                    offset
+                } else {
+                    pieces.push_and_get_offset(piece_idx)
+                };
+                let offset = NonMaxUsize::new(offset).unwrap();
                 self.min_possible_owners = Some(match self.min_possible_owners {
                     None => data.num_possible_owners,
                     Some(value) => value.min(data.num_possible_owners),
                 });
                 self.sharable_pieces[piece_idx.0] = Some(FilePiecesQueuePiece { offset, data });
+                self.len += 1;
                 Ok(())
             }
             None => Err(FilePiecesQueueInsertError::PieceIndexOutOfRange {
@@ -97,10 +147,16 @@ impl FilePiecesQueues {
                 let offset = piece.offset.get();
                 let pieces =
                     &mut self.pieces_by_num_possible_owners[piece.data.num_possible_owners.0];
-                let stored_piece_idx = pieces.swap_remove(offset);
-                debug_assert_eq!(piece_idx, &stored_piece_idx);
+                if self.stable_order {
+                    let stored_piece_idx = pieces.remove(offset);
+                    debug_assert_eq!(piece_idx, &stored_piece_idx);
+                    update_offsets(&mut self.sharable_pieces, pieces, offset);
+                } else {
+                    let stored_piece_idx = pieces.swap_remove(offset);
+                    debug_assert_eq!(piece_idx, &stored_piece_idx);
+                }
 
-                if offset != pieces.len() {
+                if !self.stable_order && offset != pieces.len() {
                     let moved_piece_idx = pieces[offset];
                     self.sharable_pieces[moved_piece_idx.0]
                         .as_mut()
@@ -108,6 +164,7 @@ impl FilePiecesQueues {
                         .offset = offset.try_into().unwrap();
                 }
 
+                self.len -= 1;
                 self.update_min_possible_owners_after_remove();
                 Ok(piece.data)
             }
@@ -131,6 +188,17 @@ impl FilePiecesQueues {
     }
 }
 
+// Updates stored offsets of the queue pieces starting from `start`.
+fn update_offsets(
+    sharable_pieces: &mut [Option<FilePiecesQueuePiece>],
+    pieces: &[FilePieceIdx],
+    start: usize,
+) {
+    for (offset, piece_idx) in pieces.iter().enumerate().skip(start) {
+        sharable_pieces[piece_idx.0].as_mut().unwrap().offset = offset.try_into().unwrap();
+    }
+}
+
 #[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
 pub enum FilePiecesQueueGetError {
     #[error("piece index out of range for piece count {len}")]
@@ -154,3 +222,158 @@ pub enum FilePiecesQueueRemoveError {
     #[error("piece is not added to FilePiecesQueue")]
     PieceIsNotAdded,
 }
+
+#[cfg(test)]
+fn piece_data(num_possible_owners: usize) -> FilePieceData {
+    use crate::{PieceNumConfirmedOwners, PiecePeerShift};
+
+    FilePieceData {
+        peer_shift: PiecePeerShift(0),
+        num_confirmed_owners: PieceNumConfirmedOwners(0),
+        num_possible_owners: PieceNumPossibleOwners(num_possible_owners),
+        send_attempts: 0,
+    }
+}
+
+#[test]
+fn count_queued_pieces() {
+    let mut queues = FilePiecesQueues::new(8);
+    assert_eq!(queues.len(), 0);
+    assert!(queues.is_empty());
+
+    for (piece_idx, num_possible_owners) in [(0, 2), (3, 0), (5, 2), (7, 1)] {
+        queues
+            .insert(FilePieceIdx(piece_idx), piece_data(num_possible_owners))
+            .unwrap();
+    }
+    assert_eq!(queues.len(), 4);
+    assert_eq!(
+        queues.insert(FilePieceIdx(3), piece_data(1)),
+        Err(FilePiecesQueueInsertError::PieceIsAlreadyAdded)
+    );
+    assert_eq!(
+        queues.insert(FilePieceIdx(8), piece_data(1)),
+        Err(FilePiecesQueueInsertError::PieceIndexOutOfRange { len: 8 })
+    );
+    assert_eq!(queues.len(), 4);
+
+    let _: FilePieceData = queues.remove(&FilePieceIdx(3)).unwrap();
+    assert_eq!(
+        queues.remove(&FilePieceIdx(3)).unwrap_err(),
+        FilePiecesQueueRemoveError::PieceIsNotAdded
+    );
+    assert_eq!(queues.len(), 3);
+    assert_eq!(
+        queues.next_queue(),
+        Some((PieceNumPossibleOwners(1), &[FilePieceIdx(7)][..]))
+    );
+
+    for piece_idx in [0, 5, 7] {
+        let _: FilePieceData = queues.remove(&FilePieceIdx(piece_idx)).unwrap();
+    }
+    assert_eq!(queues.len(), 0);
+    assert!(queues.is_empty());
+    assert_eq!(queues.next_queue(), None);
+}
+
+#[test]
+fn iterate_queued_pieces() {
+    let mut queues = FilePiecesQueues::new(16);
+    assert_eq!(queues.iter_pieces().count(), 0);
+
+    for piece_idx in [9, 2, 14, 4, 11] {
+        queues
+            .insert(FilePieceIdx(piece_idx), piece_data(piece_idx % 3))
+            .unwrap();
+    }
+    let _: FilePieceData = queues.remove(&FilePieceIdx(14)).unwrap();
+
+    let pieces: Vec<_> = queues
+        .iter_pieces()
+        .map(|(piece_idx, data)| (piece_idx.0, data.num_possible_owners.0))
+        .collect();
+    assert_eq!(pieces, [(2, 2), (4, 1), (9, 0), (11, 2)]);
+    assert_eq!(pieces.len(), queues.len());
+}
+
+#[test]
+fn keep_stable_queue_order() {
+    use rand::rngs::StdRng;
+    use rand::seq::SliceRandom;
+    use rand::SeedableRng;
+
+    const NUM_PIECES: usize = 64;
+
+    let run = |seed| {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut piece_indices: Vec<_> = (0..NUM_PIECES).collect();
+        piece_indices.shuffle(&mut rng);
+
+        let mut queues = FilePiecesQueues::new(NUM_PIECES);
+        queues.set_stable_order(true);
+        for &piece_idx in &piece_indices {
+            queues
+                .insert(FilePieceIdx(piece_idx), piece_data(piece_idx % 2))
+                .unwrap();
+        }
+        // Pieces are removed and reinserted in a different order on each run.
+        piece_indices.shuffle(&mut rng);
+        for &piece_idx in piece_indices.iter().filter(|&&j| j % 4 >= 2) {
+            let _: FilePieceData = queues.remove(&FilePieceIdx(piece_idx)).unwrap();
+        }
+        piece_indices.shuffle(&mut rng);
+        for &piece_idx in piece_indices.iter().filter(|&&j| j % 8 >= 6) {
+            queues
+                .insert(FilePieceIdx(piece_idx), piece_data(piece_idx % 2))
+                .unwrap();
+        }
+        let mut orders = Vec::new();
+        while let Some((_, pieces)) = queues.next_queue() {
+            let pieces = pieces.to_vec();
+            assert!(pieces.windows(2).all(|pair| pair[0] < pair[1]));
+            for piece_idx in &pieces {
+                let _: FilePieceData = queues.remove(piece_idx).unwrap();
+            }
+            orders.push(pieces);
+        }
+        orders
+    };
+
+    let orders = run(0);
+    assert_eq!(orders.len(), 2);
+    for seed in 1..8 {
+        assert_eq!(run(seed), orders);
+    }
+
+    // Enabling the option sorts already queued pieces.
+    let mut queues = FilePiecesQueues::new(8);
+    for piece_idx in [5, 1, 7, 3] {
+        queues
+            .insert(FilePieceIdx(piece_idx), piece_data(0))
+            .unwrap();
+    }
+    let _: FilePieceData = queues.remove(&FilePieceIdx(1)).unwrap();
+    assert_eq!(
+        queues.next_queue(),
+        Some((
+            PieceNumPossibleOwners(0),
+            &[FilePieceIdx(5), FilePieceIdx(3), FilePieceIdx(7)][..]
+        ))
+    );
+    queues.set_stable_order(true);
+    assert!(queues.is_stable_order());
+    assert_eq!(
+        queues.next_queue(),
+        Some((
+            PieceNumPossibleOwners(0),
+            &[FilePieceIdx(3), FilePieceIdx(5), FilePieceIdx(7)][..]
+        ))
+    );
+    let _: FilePieceData = queues.remove(&FilePieceIdx(3)).unwrap();
+    assert!(queues.get(FilePieceIdx(7)).is_ok());
+    let _: FilePieceData = queues.remove(&FilePieceIdx(7)).unwrap();
+    assert_eq!(
+        queues.next_queue(),
+        Some((PieceNumPossibleOwners(0), &[FilePieceIdx(5)][..]))
+    );
+}
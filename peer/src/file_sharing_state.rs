@@ -3,7 +3,7 @@ use std::collections::{HashMap, HashSet};
 use thiserror::Error;
 use tracker_protocol::PeerId;
 
-use crate::{FilePieceIdx, FilePiecesQueues, FileState};
+use crate::{select_peer_offset, FilePieceIdx, FilePiecesQueues, FileState};
 
 type PeerIdx = usize;
 
@@ -184,10 +184,7 @@ impl FileSharingState {
         let mut piece = self.pieces.remove(piece_idx).unwrap();
 
         let hash = fxhash::hash64(&piece_idx);
-        let peer_idx_mult1 = ((hash >> 32) as u32 % num_peers) + 1;
-        let peer_idx_mult2 = (hash & ((1 << 32) - 1)) as u32;
-
-        let offset = |shift| ((peer_idx_mult1 * (peer_idx_mult2 + shift)) % num_peers) as usize;
+        let offset = |shift| select_peer_offset(hash, num_peers as usize, shift as usize);
 
         for shift in piece.peer_shift..piece.peer_shift + num_peers {
             let peer = &mut self.peers[offset(shift)];
@@ -1,5 +1,5 @@
 use core::fmt::Debug;
-use core::ops::BitAnd;
+use core::ops::{BitAnd, BitOr, BitXor};
 
 use bitvec::boxed::BitBox;
 use bitvec::ptr::{BitRef, Const, Mut};
@@ -8,6 +8,9 @@ use thiserror::Error;
 
 use crate::FilePieceIdx;
 
+/// Version tag of the file state byte layout, stored as the first byte.
+pub const FILE_STATE_BYTES_VERSION: u8 = 1;
+
 #[derive(Clone, Debug)]
 pub struct FileState {
     raw: BitBox,
@@ -52,6 +55,23 @@ impl FileState {
         }
     }
 
+    /// Creates the state of `len` pieces with only the given pieces available,
+    /// duplicate indices are allowed.
+    pub fn from_indices(
+        len: usize,
+        indices: impl IntoIterator<Item = FilePieceIdx>,
+    ) -> Result<Self, FileStatePieceError> {
+        use bitvec::bitbox;
+
+        let mut raw = bitbox![0; len];
+        for piece_idx in indices {
+            raw.get_mut(piece_idx.0)
+                .ok_or(FileStatePieceError::PieceIndexOutOfRange)?
+                .set(true);
+        }
+        Ok(Self::from(raw))
+    }
+
     pub fn is_missing(&self) -> bool {
         self.num_available() == 0
     }
@@ -72,6 +92,92 @@ impl FileState {
         self.num_available
     }
 
+    /// Returns `true` if all available pieces are also available in `other`.
+    /// States of different lengths are never subsets of each other.
+    pub fn is_subset_of(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self.num_available <= other.num_available
+            && self.raw.iter_ones().all(|idx| other.raw[idx])
+    }
+
+    /// Sets all pieces available in `other`, e.g. to reconcile several partial copies of a file,
+    /// and returns the number of newly available pieces.
+    pub fn union_into(&mut self, other: &Self) -> Result<usize, FileStateUnionError> {
+        if other.len() != self.len() {
+            return Err(FileStateUnionError::LenMismatch {
+                len: other.len(),
+                expected: self.len(),
+            });
+        }
+        let mut num_gained = 0;
+        for idx in other.raw.iter_ones() {
+            if !self.raw[idx] {
+                self.raw.set(idx, true);
+                num_gained += 1;
+            }
+        }
+        self.num_available += num_gained;
+        self.debug_assert_consistent();
+        Ok(num_gained)
+    }
+
+    /// Packs the state into bytes independent from the `bitvec` storage layout.
+    ///
+    /// The first byte is the layout version, it is followed by pieces packed
+    /// eight per byte in the piece index order, least significant bit first.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        Self::bits_to_bytes(&self.raw)
+    }
+
+    /// Unpacks the state of `len` pieces packed with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Result<Self, FileStateFromBytesError> {
+        Self::bits_from_bytes(bytes, len).map(Self::from)
+    }
+
+    pub fn bits_to_bytes(bits: &BitSlice) -> Vec<u8> {
+        let mut bytes = vec![0; 1 + (bits.len() + 7) / 8];
+        bytes[0] = FILE_STATE_BYTES_VERSION;
+        for idx in bits.iter_ones() {
+            bytes[1 + idx / 8] |= 1 << (idx % 8);
+        }
+        bytes
+    }
+
+    pub fn bits_from_bytes(bytes: &[u8], len: usize) -> Result<BitBox, FileStateFromBytesError> {
+        use bitvec::bitbox;
+
+        let (&version, packed) = bytes
+            .split_first()
+            .ok_or(FileStateFromBytesError::MissingVersion)?;
+        if version != FILE_STATE_BYTES_VERSION {
+            return Err(FileStateFromBytesError::UnsupportedVersion { version });
+        }
+        let expected = (len + 7) / 8;
+        if packed.len() != expected {
+            return Err(FileStateFromBytesError::InvalidLen {
+                len: packed.len(),
+                expected,
+            });
+        }
+
+        let mut bits = bitbox![0; len];
+        for idx in 0..len {
+            if packed[idx / 8] & (1 << (idx % 8)) != 0 {
+                bits.set(idx, true);
+            }
+        }
+        Ok(bits)
+    }
+
+    /// Asserts that the number of available pieces matches the pieces mask in debug builds.
+    pub fn debug_assert_consistent(&self) {
+        debug_assert_eq!(
+            self.num_available,
+            self.raw.count_ones(),
+            "number of available pieces does not match the pieces mask"
+        );
+    }
+
     pub fn raw(&self) -> &BitSlice {
         &self.raw
     }
@@ -113,6 +219,7 @@ impl FileState {
         } else {
             bit.set(true);
             self.num_available += 1;
+            self.debug_assert_consistent();
             Ok(FileStateSetStatus::JustSet)
         }
     }
@@ -126,6 +233,7 @@ impl FileState {
         if *bit {
             bit.set(false);
             self.num_available -= 1;
+            self.debug_assert_consistent();
             Ok(FileStateUnsetStatus::JustUnset)
         } else {
             Ok(FileStateUnsetStatus::AlreadyUnset)
@@ -133,6 +241,14 @@ impl FileState {
     }
 }
 
+impl PartialEq for FileState {
+    fn eq(&self, other: &Self) -> bool {
+        self.num_available == other.num_available && self.raw == other.raw
+    }
+}
+
+impl Eq for FileState {}
+
 impl From<BitBox> for FileState {
     fn from(pieces_mask: BitBox) -> Self {
         let num_available = pieces_mask.count_ones();
@@ -143,18 +259,47 @@ impl From<BitBox> for FileState {
     }
 }
 
-impl BitAnd<&Self> for FileState {
-    type Output = Self;
-
-    fn bitand(self, rhs: &Self) -> Self {
+impl FileState {
+    // States of different lengths are truncated to the shorter one.
+    fn zip_raw_with(self, rhs: &Self, op: impl Fn(&mut usize, usize)) -> Self {
         use bitvec::vec::BitVec;
 
+        let len = self.raw.len().min(rhs.raw.len());
         let mut state = self.raw.into_boxed_slice();
         for (lhs, rhs) in state.iter_mut().zip(rhs.raw.as_raw_slice()) {
-            *lhs &= rhs;
+            op(lhs, *rhs);
         }
-        let mask = BitVec::from_vec(state.into_vec()).into_boxed_bitslice();
-        Self::from(mask)
+        let mut mask = BitVec::from_vec(state.into_vec());
+        mask.truncate(len);
+        let state = Self::from(mask.into_boxed_bitslice());
+        state.debug_assert_consistent();
+        state
+    }
+}
+
+impl BitAnd<&Self> for FileState {
+    type Output = Self;
+
+    fn bitand(self, rhs: &Self) -> Self {
+        self.zip_raw_with(rhs, |lhs, rhs| *lhs &= rhs)
+    }
+}
+
+/// Pieces available in any of the states.
+impl BitOr<&Self> for FileState {
+    type Output = Self;
+
+    fn bitor(self, rhs: &Self) -> Self {
+        self.zip_raw_with(rhs, |lhs, rhs| *lhs |= rhs)
+    }
+}
+
+/// Pieces available in exactly one of the states.
+impl BitXor<&Self> for FileState {
+    type Output = Self;
+
+    fn bitxor(self, rhs: &Self) -> Self {
+        self.zip_raw_with(rhs, |lhs, rhs| *lhs ^= rhs)
     }
 }
 
@@ -163,3 +308,253 @@ pub enum FileStatePieceError {
     #[error("piece index out of range")]
     PieceIndexOutOfRange,
 }
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum FileStateUnionError {
+    #[error("file state length {len} does not match expected length {expected}")]
+    LenMismatch { len: usize, expected: usize },
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum FileStateFromBytesError {
+    #[error("file state version is missing")]
+    MissingVersion,
+    #[error("unsupported file state version {version}")]
+    UnsupportedVersion { version: u8 },
+    #[error("invalid file state length {len} bytes, expected {expected} bytes")]
+    InvalidLen { len: usize, expected: usize },
+}
+
+#[test]
+fn compare_file_states() {
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+
+    let first = FileState::from(bitbox![0, 1, 0, 1, 1]);
+    let second = FileState::from(bitbox![1, 1, 0, 1, 1]);
+    let third = FileState::from(bitbox![1, 0, 1, 1, 1]);
+
+    assert_eq!(first, first.clone());
+    assert_ne!(first, second);
+    assert!(first.is_subset_of(&first));
+    assert!(first.is_subset_of(&second));
+    assert!(!second.is_subset_of(&first));
+    assert!(!first.is_subset_of(&third));
+    assert!(FileState::from_missing(5).is_subset_of(&first));
+    assert!(first.is_subset_of(&FileState::from_complete(5)));
+
+    let mut state = FileState::from_missing(5);
+    for idx in [1, 3, 4] {
+        let _: FileStateSetStatus = state.set(&FilePieceIdx(idx)).unwrap();
+    }
+    assert_eq!(state, first);
+
+    // Bitwise and keeps the length, so the result is comparable with its operands.
+    let intersection = second.clone() & &third;
+    assert_eq!(intersection, FileState::from(bitbox![1, 0, 0, 1, 1]));
+    assert!(intersection.is_subset_of(&second));
+    assert!(intersection.is_subset_of(&third));
+}
+
+#[test]
+fn compare_file_states_of_different_lengths() {
+    let short = FileState::from_complete(5);
+    let long = FileState::from_complete(6);
+
+    assert_ne!(short, long);
+    assert!(!short.is_subset_of(&long));
+    assert!(!long.is_subset_of(&short));
+    assert!(!FileState::empty().is_subset_of(&short));
+    assert_ne!(FileState::from_missing(5), FileState::from_missing(6));
+}
+
+#[test]
+fn combine_file_states() {
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+
+    let first = FileState::from(bitbox![0, 1, 0, 1, 1, 0]);
+    let second = FileState::from(bitbox![1, 1, 0, 0, 1, 0]);
+
+    let union = first.clone() | &second;
+    assert_eq!(union, FileState::from(bitbox![1, 1, 0, 1, 1, 0]));
+    assert_eq!(union.num_available(), 4);
+    assert!(first.is_subset_of(&union));
+    assert!(second.is_subset_of(&union));
+
+    let difference = first.clone() ^ &second;
+    assert_eq!(difference, FileState::from(bitbox![1, 0, 0, 1, 0, 0]));
+    assert_eq!(difference.num_available(), 2);
+
+    // Pieces available locally but missing on the peer.
+    let missing = (first.clone() ^ &second) & &first;
+    assert_eq!(missing, FileState::from(bitbox![0, 0, 0, 1, 0, 0]));
+}
+
+#[test]
+fn combine_file_states_of_different_lengths() {
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+
+    let short = FileState::from(bitbox![1, 0, 1]);
+    let long = FileState::from(bitbox![0, 1, 1, 1, 1]);
+
+    assert_eq!(short.clone() & &long, FileState::from(bitbox![0, 0, 1]));
+    assert_eq!(long.clone() & &short, FileState::from(bitbox![0, 0, 1]));
+    assert_eq!(short.clone() | &long, FileState::from(bitbox![1, 1, 1]));
+    assert_eq!(long.clone() | &short, FileState::from(bitbox![1, 1, 1]));
+    assert_eq!(short.clone() ^ &long, FileState::from(bitbox![1, 1, 0]));
+    assert_eq!(long ^ &short, FileState::from(bitbox![1, 1, 0]));
+
+    // Words beyond the shorter state do not leak into the result.
+    let long = FileState::from_complete(200);
+    let short = FileState::from_missing(70);
+    let union = long | &short;
+    assert_eq!(union.len(), 70);
+    assert_eq!(union.num_available(), 70);
+}
+
+#[test]
+fn file_state_bytes_roundtrip() {
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+
+    let state = FileState::from(bitbox![1, 0, 1, 1, 0, 0, 0, 1, 0, 1, 1, 0, 1]);
+    let bytes = state.to_bytes();
+    assert_eq!(bytes, [FILE_STATE_BYTES_VERSION, 0b1000_1101, 0b0001_0110]);
+    assert_eq!(FileState::from_bytes(&bytes, 13), Ok(state));
+
+    for len in [0, 1, 7, 8, 9, 13, 64, 65] {
+        let mut state = FileState::from_missing(len);
+        for idx in (0..len).step_by(3) {
+            let _: FileStateSetStatus = state.set(&FilePieceIdx(idx)).unwrap();
+        }
+        let bytes = state.to_bytes();
+        assert_eq!(bytes.len(), 1 + (len + 7) / 8);
+        assert_eq!(FileState::from_bytes(&bytes, len), Ok(state));
+        assert_eq!(
+            FileState::from_bytes(&FileState::from_complete(len).to_bytes(), len),
+            Ok(FileState::from_complete(len))
+        );
+    }
+}
+
+#[test]
+fn reject_invalid_file_state_bytes() {
+    let bytes = FileState::from_complete(13).to_bytes();
+    assert_eq!(
+        FileState::from_bytes(&[], 13),
+        Err(FileStateFromBytesError::MissingVersion)
+    );
+    assert_eq!(
+        FileState::from_bytes(&[2, 0xFF, 0xFF], 13),
+        Err(FileStateFromBytesError::UnsupportedVersion { version: 2 })
+    );
+    assert_eq!(
+        FileState::from_bytes(&bytes, 17),
+        Err(FileStateFromBytesError::InvalidLen {
+            len: 2,
+            expected: 3
+        })
+    );
+    assert_eq!(
+        FileState::from_bytes(&bytes[..2], 13),
+        Err(FileStateFromBytesError::InvalidLen {
+            len: 1,
+            expected: 2
+        })
+    );
+}
+
+#[test]
+fn keep_num_available_consistent() {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    let len = 150;
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut state = FileState::from_missing(len);
+    for _ in 0..2000 {
+        let idx = FilePieceIdx(rng.gen_range(0..len));
+        state = match rng.gen_range(0..6) {
+            0 | 1 => {
+                let _: FileStateSetStatus = state.set(&idx).unwrap();
+                state
+            }
+            2 => {
+                let _: FileStateUnsetStatus = state.unset(&idx).unwrap();
+                state
+            }
+            op => {
+                let mut other = FileState::from_missing(len);
+                for idx in 0..len {
+                    if rng.gen_bool(0.7) {
+                        let _: FileStateSetStatus = other.set(&FilePieceIdx(idx)).unwrap();
+                    }
+                }
+                match op {
+                    3 => state & &other,
+                    4 => state | &other,
+                    _ => state ^ &other,
+                }
+            }
+        };
+        assert_eq!(state.num_available(), state.raw().count_ones());
+        state.debug_assert_consistent();
+    }
+}
+
+#[test]
+fn union_partial_file_states() {
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+
+    let mut state = FileState::from(bitbox![1, 1, 0, 0, 1, 0, 0]);
+    let other = FileState::from(bitbox![0, 0, 1, 0, 0, 1, 0]);
+    assert_eq!(state.union_into(&other), Ok(2));
+    assert_eq!(state, FileState::from(bitbox![1, 1, 1, 0, 1, 1, 0]));
+    assert_eq!(state.num_available(), 5);
+
+    // Pieces available in both states are not counted again.
+    assert_eq!(state.union_into(&other), Ok(0));
+    assert_eq!(state.union_into(&FileState::from_complete(7)), Ok(2));
+    assert!(state.is_complete());
+
+    assert_eq!(
+        state.union_into(&FileState::from_missing(8)),
+        Err(FileStateUnionError::LenMismatch {
+            len: 8,
+            expected: 7
+        })
+    );
+}
+
+#[test]
+fn build_file_state_from_indices() {
+    let state = FileState::from_indices(10, [3, 7, 3, 0].map(FilePieceIdx)).unwrap();
+    assert_eq!(state.len(), 10);
+    assert_eq!(state.num_available(), 3);
+    assert_eq!(state.raw().iter_ones().collect::<Vec<_>>(), [0, 3, 7]);
+    state.debug_assert_consistent();
+
+    let state = FileState::from_indices(10, []).unwrap();
+    assert_eq!(state.len(), 10);
+    assert!(state.is_missing());
+
+    let state = FileState::from_indices(0, []).unwrap();
+    assert!(state.is_missing());
+    assert!(state.is_complete());
+}
+
+#[test]
+fn reject_out_of_range_file_state_indices() {
+    assert_eq!(
+        FileState::from_indices(10, [3, 10].map(FilePieceIdx)).err(),
+        Some(FileStatePieceError::PieceIndexOutOfRange)
+    );
+    assert_eq!(
+        FileState::from_indices(0, [FilePieceIdx(0)]).err(),
+        Some(FileStatePieceError::PieceIndexOutOfRange)
+    );
+    assert!(FileState::from_indices(10, [FilePieceIdx(9)]).is_ok());
+}
@@ -0,0 +1,126 @@
+use core::fmt::Debug;
+
+use futures::future::LocalBoxFuture;
+use thiserror::Error;
+
+/// Backing storage of file contents outside of wasm linear memory.
+///
+/// Offsets are file byte offsets, chunks and pieces are stored contiguously.
+/// The storage does not track which bytes were written, the file state does.
+pub trait FileStorage: Debug {
+    /// Reads exactly `len` bytes from `offset`.
+    fn read(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> LocalBoxFuture<'_, Result<Box<[u8]>, FileStorageError>>;
+    fn write<'a>(
+        &'a self,
+        offset: u64,
+        data: &'a [u8],
+    ) -> LocalBoxFuture<'a, Result<(), FileStorageError>>;
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum FileStorageError {
+    #[error("file storage is unavailable")]
+    Unavailable,
+    #[error("failed to read {len} bytes at offset {offset} from file storage")]
+    Read { offset: u64, len: usize },
+    #[error("failed to write {len} bytes at offset {offset} to file storage")]
+    Write { offset: u64, len: usize },
+}
+
+/// Native file storage used to test stored files without a browser.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct NativeFileStorage {
+    path: std::path::PathBuf,
+    file: std::fs::File,
+}
+
+#[cfg(test)]
+impl NativeFileStorage {
+    /// Creates an empty temporary file removed on drop.
+    pub fn temp(name: &str) -> Self {
+        use std::fs::OpenOptions;
+
+        let path =
+            std::env::temp_dir().join(format!("peer-file-storage-{}-{}", std::process::id(), name));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        Self { path, file }
+    }
+}
+
+#[cfg(test)]
+impl Drop for NativeFileStorage {
+    fn drop(&mut self) {
+        let _: std::io::Result<()> = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+impl FileStorage for NativeFileStorage {
+    fn read(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> LocalBoxFuture<'_, Result<Box<[u8]>, FileStorageError>> {
+        use std::os::unix::fs::FileExt;
+
+        Box::pin(async move {
+            let mut bytes = vec![0; len].into_boxed_slice();
+            self.file
+                .read_exact_at(&mut bytes, offset)
+                .map_err(|_| FileStorageError::Read { offset, len })?;
+            Ok(bytes)
+        })
+    }
+
+    fn write<'a>(
+        &'a self,
+        offset: u64,
+        data: &'a [u8],
+    ) -> LocalBoxFuture<'a, Result<(), FileStorageError>> {
+        use std::os::unix::fs::FileExt;
+
+        Box::pin(async move {
+            self.file
+                .write_all_at(data, offset)
+                .map_err(|_| FileStorageError::Write {
+                    offset,
+                    len: data.len(),
+                })
+        })
+    }
+}
+
+#[test]
+fn read_and_write_native_file_storage() {
+    use async_std::task::block_on;
+
+    let storage = NativeFileStorage::temp("read-and-write");
+    block_on(async {
+        assert_eq!(
+            storage.read(0, 1).await,
+            Err(FileStorageError::Read { offset: 0, len: 1 })
+        );
+        storage.write(4, &[1, 2, 3]).await.unwrap();
+        storage.write(0, &[9]).await.unwrap();
+        assert_eq!(
+            storage.read(0, 7).await.as_deref(),
+            Ok(&[9, 0, 0, 0, 1, 2, 3][..])
+        );
+        assert_eq!(storage.read(5, 2).await.as_deref(), Ok(&[2, 3][..]));
+        assert_eq!(
+            storage.read(5, 3).await,
+            Err(FileStorageError::Read { offset: 5, len: 3 })
+        );
+    });
+}
@@ -0,0 +1,146 @@
+use thiserror::Error;
+
+/// STUN or TURN server used to establish peer connections.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct IceServerConfig {
+    pub url: String,
+    pub username: Option<String>,
+    pub credential: Option<String>,
+}
+
+impl IceServerConfig {
+    /// Parses an ICE server from `[user:pass@]url` string,
+    /// where url starts with `stun:`, `turn:` or `turns:`.
+    pub fn parse(value: &str) -> Result<Self, IceServerConfigParseError> {
+        // ICE server urls can not contain `@`, so the last one separates credentials.
+        let (credentials, url) = match value.rsplit_once('@') {
+            Some((credentials, url)) => (Some(credentials), url),
+            None => (None, value),
+        };
+
+        if !has_ice_scheme(url) {
+            return Err(IceServerConfigParseError::InvalidScheme {
+                url: url.to_owned(),
+            });
+        }
+
+        let (username, credential) = match credentials {
+            Some(credentials) => match credentials.split_once(':') {
+                Some((username, credential)) if !username.is_empty() => {
+                    (Some(username.to_owned()), Some(credential.to_owned()))
+                }
+                _ => {
+                    return Err(IceServerConfigParseError::InvalidCredentials {
+                        url: url.to_owned(),
+                    })
+                }
+            },
+            None => (None, None),
+        };
+
+        Ok(Self {
+            url: url.to_owned(),
+            username,
+            credential,
+        })
+    }
+
+    /// Parses a comma-separated list of ICE servers, empty entries are skipped.
+    pub fn parse_list(value: &str) -> Result<Vec<Self>, IceServerConfigParseError> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(Self::parse)
+            .collect()
+    }
+}
+
+/// ICE candidates gathered to establish peer connections.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum IceCandidatePolicy {
+    /// Host candidates and candidates obtained from the configured ICE servers.
+    All,
+    /// Host candidates only, no STUN or TURN servers are contacted, e.g. on a trusted LAN.
+    HostOnly,
+}
+
+impl IceCandidatePolicy {
+    /// Returns the ICE servers to be passed to the peer connection configuration.
+    pub fn ice_servers(self, ice_servers: &[IceServerConfig]) -> &[IceServerConfig] {
+        match self {
+            Self::All => ice_servers,
+            Self::HostOnly => &[],
+        }
+    }
+}
+
+fn has_ice_scheme(url: &str) -> bool {
+    ["stun:", "turn:", "turns:"]
+        .iter()
+        .any(|scheme| url.starts_with(scheme))
+}
+
+#[derive(Clone, Error, Debug, Eq, PartialEq)]
+pub enum IceServerConfigParseError {
+    #[error("ICE server url {url} should start with stun:, turn: or turns:")]
+    InvalidScheme { url: String },
+    #[error("ICE server {url} credentials should be specified as user:pass@")]
+    InvalidCredentials { url: String },
+}
+
+#[test]
+fn parse_ice_server_list() {
+    assert_eq!(
+        IceServerConfig::parse_list(
+            " stun:stun.example.com:3478 ,, user:p@ss:w@turns:turn.example.com "
+        ),
+        Ok(vec![
+            IceServerConfig {
+                url: "stun:stun.example.com:3478".to_owned(),
+                username: None,
+                credential: None,
+            },
+            IceServerConfig {
+                url: "turns:turn.example.com".to_owned(),
+                username: Some("user".to_owned()),
+                credential: Some("p@ss:w".to_owned()),
+            },
+        ])
+    );
+    assert_eq!(IceServerConfig::parse_list(""), Ok(vec![]));
+}
+
+#[test]
+fn parse_invalid_ice_server() {
+    assert_eq!(
+        IceServerConfig::parse("http://stun.example.com"),
+        Err(IceServerConfigParseError::InvalidScheme {
+            url: "http://stun.example.com".to_owned()
+        })
+    );
+    assert_eq!(
+        IceServerConfig::parse("user@turn:turn.example.com"),
+        Err(IceServerConfigParseError::InvalidCredentials {
+            url: "turn:turn.example.com".to_owned()
+        })
+    );
+    assert_eq!(
+        IceServerConfig::parse(":pass@turn:turn.example.com"),
+        Err(IceServerConfigParseError::InvalidCredentials {
+            url: "turn:turn.example.com".to_owned()
+        })
+    );
+}
+
+#[test]
+fn skip_ice_servers_for_host_only_policy() {
+    let ice_servers = IceServerConfig::parse_list("stun:stun.l.google.com:19302").unwrap();
+    assert_eq!(
+        IceCandidatePolicy::All.ice_servers(&ice_servers),
+        &ice_servers[..]
+    );
+    assert!(IceCandidatePolicy::HostOnly
+        .ice_servers(&ice_servers)
+        .is_empty());
+}
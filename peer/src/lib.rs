@@ -12,21 +12,51 @@
     unused_results
 )]
 
+mod adaptive_send_interval;
+mod buffer_low_detector;
+mod clock;
+mod connection_queue;
+mod control_queue;
+mod data_channel_config;
 mod file;
 mod file_chunk;
+mod file_discovery;
+mod file_hash;
 mod file_metadata;
 mod file_piece;
 mod file_pieces_queues;
 mod file_state;
+mod file_storage;
+mod ice_server;
 mod local_peer;
+mod log_scope;
 mod message;
 mod message_fmt;
+mod negotiation_role;
 mod object_url;
+mod offer_options;
+mod opfs_file_storage;
 mod params;
+mod peer_change;
+mod peer_error;
+mod piece_cache;
+mod piece_cipher;
+mod piece_compression;
+mod piece_transfer;
+mod protocol_version;
 mod remote_peer;
+mod retry_backoff;
 mod scheduler;
+#[cfg(feature = "selection-trace")]
+mod selection_trace;
+mod send_phase;
+mod session_snapshot;
 mod shared_file;
 mod tracker;
+mod transport;
+
+#[cfg(test)]
+mod mock_transport;
 
 mod callback;
 mod ignore_empty;
@@ -34,38 +64,90 @@ mod ok_or_log;
 mod upwrap_or;
 mod vec_ext;
 
+pub use adaptive_send_interval::{
+    AdaptiveSendInterval, MAX_ADAPTIVE_SEND_INTERVAL, MIN_ADAPTIVE_SEND_INTERVAL,
+};
+pub use buffer_low_detector::{BufferLowDetector, BUFFER_LOW_EVENT_TIMEOUT};
+pub use clock::Clock;
+pub use connection_queue::ConnectionQueue;
+pub use control_queue::{ControlQueue, CONTROL_QUEUE_BUFFER_LOW_THRESHOLD, CONTROL_QUEUE_CAPACITY};
+pub use data_channel_config::{
+    DataChannelConfig, DataChannelConfigError, DataChannelMismatchError,
+    DEFAULT_DATA_CHANNEL_LABEL, MAX_DATA_CHANNEL_ID, MAX_DATA_CHANNEL_STRING_LEN,
+};
 pub use file::{
-    File, FileGetPieceError, FileHasPieceError, FileSetPieceError, JsFile, FILE_CHUNK_SIZE,
+    File, FileFromPartialError, FileGetPieceError, FileHasPieceError, FileMoveToStorageError,
+    FileReleaseChunkError, FileReplaceStateError, FileSetPieceError, JsFile, FILE_CHUNK_SIZE,
 };
 pub use file_chunk::FileChunk;
-pub use file_metadata::{FileLen, FileMetadata};
+pub use file_discovery::{FileDiscovery, FileDiscoveryStatus, FILE_DISCOVERY_TIMEOUT};
+pub use file_hash::{FileHash, FileHashAlgorithm};
+pub use file_metadata::{FileLen, FileMetaDataParseMagnetError, FileMetadata, MAGNET_PREFIX};
 pub use file_piece::{
-    FilePieceData, FilePieceIdx, PieceNumConfirmedOwners, PieceNumPossibleOwners, PiecePeerShift,
-    FILE_PIECE_SIZE,
+    select_peer_offset, FilePieceData, FilePieceIdx, PieceNumConfirmedOwners,
+    PieceNumPossibleOwners, PiecePeerShift, FILE_PIECE_SIZE,
 };
 pub use file_pieces_queues::{
     FilePiecesQueueGetError, FilePiecesQueueInsertError, FilePiecesQueueRemoveError,
     FilePiecesQueues,
 };
-pub use file_state::{FileState, FileStatePieceError, FileStateSetStatus, FileStateUnsetStatus};
-pub use local_peer::LocalPeer;
-pub use message::PeerPeerMessage;
+pub use file_state::{
+    FileState, FileStateFromBytesError, FileStatePieceError, FileStateSetStatus,
+    FileStateUnionError, FileStateUnsetStatus, FILE_STATE_BYTES_VERSION,
+};
+pub use file_storage::{FileStorage, FileStorageError};
+pub use ice_server::{IceCandidatePolicy, IceServerConfig, IceServerConfigParseError};
+pub use local_peer::{LocalPeer, LocalPeerImportSessionError, LocalPeerStats};
+pub use log_scope::{LogScope, LogScopeGuard};
+pub use message::{
+    encrypted_file_piece_messages, file_piece_messages, PeerPeerMessage, FILE_STATE_CHUNK_LEN,
+    MAX_PEER_MESSAGE_SIZE,
+};
 pub use message_fmt::PeerPeerMessageFmt;
+pub use negotiation_role::{NegotiationRole, OfferAction};
 pub use object_url::ObjectUrl;
+pub use offer_options::{needs_ice_restart, OfferOptions};
+pub use opfs_file_storage::OpfsFileStorage;
 pub use params::{
     DEFAULT_MAX_DATACHANNEL_BUFFER_BYTES, DEFAULT_PEER_SEND_INTERVAL_MS,
     DEFAULT_UPLOAD_SPEED_BITS_PER_SECOND,
 };
+pub use peer_change::{ConnectedPeers, PeerChangeEvent, PeerChangeHandler};
+pub use peer_error::{PeerError, PeerOperation};
+pub use piece_cache::{PieceCache, DEFAULT_PIECE_CACHE_BYTES};
+pub use piece_cipher::{
+    check_remote_piece_key, derive_piece_key, PieceCipher, PieceDecryptError, PieceEncryptError,
+    PieceKeyError, PieceKeyId, PIECE_KEY_ROUNDS,
+};
+pub use piece_compression::{compress_piece, decompress_piece, PieceDecompressError};
+pub use piece_transfer::{PieceTransfer, PieceTransferMode, MAX_PENDING_PIECE_REQUESTS};
+pub use protocol_version::{
+    negotiate_protocol_version, ProtocolVersionError, MIN_PEER_PROTOCOL_VERSION,
+    PEER_PROTOCOL_VERSION,
+};
 pub use remote_peer::{PeerConnectionSendError, RemotePeer, RemotePeerKind};
+pub use retry_backoff::{RetryBackoff, OFFER_RETRY_MAX_INTERVAL, OFFER_RETRY_MIN_INTERVAL};
 pub use scheduler::macrotask;
+#[cfg(feature = "selection-trace")]
+pub use selection_trace::SelectionEvent;
+pub use send_phase::{SendPhase, SendPhases, SendPhasesError, NUM_SEND_PHASES};
+pub use session_snapshot::{SessionFile, SessionFileError, SessionSnapshot};
 pub use shared_file::{
-    JsSharedFile, LocalStateStatusError, SharedFile, SharedFileAddLocalPieceError,
-    SharedFileAddPeerError, SharedFileLocalStateStatus, SharedFileMarkStatus,
-    SharedFileRemovePeerError,
+    JsSharedFile, LocalStateStatusError, SharedFile, SharedFileAcceptStateSeqError,
+    SharedFileAddLocalPieceError, SharedFileAddPeerError, SharedFileBlockPeerError,
+    SharedFileInvalidatePiecesError, SharedFileLocalStateStatus, SharedFileMarkStatus,
+    SharedFileNewError, SharedFilePeerMissingPiecesError, SharedFilePrioritizePiecesError,
+    SharedFileRemovePeerError, SharedFileSelectPiecePeerError, SharedFileServePieceRequestError,
+    SharedFileSetPeerStateChunkError, SharedFileStateChunkStatus, SharedFileStateSeqStatus,
+    DEFAULT_FILE_PRIORITY, ETA_STALL_TIMEOUT, INITIAL_CONGESTION_WINDOW, MAX_CONGESTION_WINDOW,
+    MIN_CONGESTION_WINDOW, PIECE_ARRIVALS_LEN,
 };
 pub use tracker::Tracker;
+pub use transport::{PeerTransport, TrackerTransport};
 
-pub use callback::{init_weak_callback, Callback, ClosureCell0, ClosureCell1};
+pub use callback::{
+    clear_closure_cells, init_weak_callback, Callback, ClearClosureCell, ClosureCell0, ClosureCell1,
+};
 use ignore_empty::IgnoreEmpty;
 use ok_or_log::OkOrLog;
 use vec_ext::{PushAndReturnOffset, SetWithResizeDefault};
@@ -74,13 +74,12 @@ impl LocalFile {
         }))
     }
 
-    pub async fn from_file(
+    pub async fn from_file<F>(
         file: WebSysFile,
-        //mut progress_cb: F,
+        mut on_progress: F,
     ) -> Result<Arc<Self>, FileFromError>
-where
-        //F: FnMut(FileLen, FileLen) -> R,
-        //R: Future<Output = ()>,
+    where
+        F: FnMut(FileLen, FileLen),
     {
         log::debug!("adding file {} ...", file.name());
         use js_sys::{ArrayBuffer, Number};
@@ -109,9 +108,6 @@ where
         let mut chunks = Vec::new();
         let mut hasher = Sha256::new();
         for start in (0..len).step_by(FILE_CHUNK_SIZE) {
-            //if start > 0 {
-            //    progress_cb(start, len).await;
-            //}
             let end = start + FILE_CHUNK_SIZE_U64;
             let chunk = blob
                 .slice_with_f64_and_f64(start as f64, end as f64)
@@ -127,6 +123,7 @@ where
             hasher.update(&u8_array.to_vec());
             chunks.push(u8_array);
             log::debug!("adding file {} ... {}/{}bytes", file.name(), start, len);
+            on_progress(end.min(len), len);
         }
 
         let sha256 = FileSha256(hasher.finalize().into());
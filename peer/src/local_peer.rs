@@ -1,31 +1,107 @@
-use core::cell::RefCell;
-use std::collections::HashMap;
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::ops::Add;
+use core::time::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Weak};
 
 use async_std::sync::RwLock;
+use futures::channel::oneshot;
 use thiserror::Error;
 use tracker_protocol::{FileSha256, PeerId, PeerTrackerMessage, TrackerPeerMessage};
 
-use crate::{JsFile, JsSharedFile, PeerPeerMessage, RemotePeer, Tracker};
+use crate::{
+    log_scoped, ConnectedPeers, ConnectionQueue, DataChannelConfig, FileChunk, FileDiscovery,
+    FileDiscoveryStatus, FileMetadata, FilePieceIdx, FileState, IceCandidatePolicy,
+    IceServerConfig, JsFile, JsSharedFile, LogScope, PeerChangeEvent, PeerChangeHandler,
+    PeerPeerMessage, PeerTransport, PieceCipher, PieceTransferMode, RemotePeer, RetryBackoff,
+    SessionFileError, SessionSnapshot, SharedFile, SharedFileNewError, Tracker, TrackerTransport,
+};
 
+/// Local peer sharing files with remote peers.
+///
+/// Locks are acquired in the following order: `peers`, then bookkeeping maps
+/// such as `sent_states` or `idle_peers`, then individual shared files.
+/// The `files` map is only held briefly and is released before any other lock is awaited,
+/// files are accessed through `snapshot_files` or `get_file` instead.
 #[derive(Debug)]
 pub struct LocalPeer<T> {
-    tracker: Tracker,
+    tracker: Box<dyn TrackerTransport>,
+    ice_servers: Vec<IceServerConfig>,
+    ice_candidate_policy: IceCandidatePolicy,
+    data_channel_config: DataChannelConfig,
+    room: String,
     peer_id: RefCell<Option<PeerId>>,
     peers: RwLock<HashMap<PeerId, Arc<RemotePeer<T>>>>,
     files: RwLock<HashMap<FileSha256, Weak<RwLock<JsSharedFile<T>>>>>,
+    idle_peers: RwLock<HashSet<PeerId>>,
+    sent_states: RwLock<HashMap<FileSha256, FileState>>,
+    offer_retries: RwLock<HashMap<FileSha256, RetryBackoff<T>>>,
+    file_discoveries: RwLock<HashMap<FileSha256, FileDiscovery<T>>>,
+    connection_queue: RwLock<ConnectionQueue<TrackerPeerMessage>>,
+    /// Pending swarm queries, the tracker replies to them in order.
+    swarm_queries: RefCell<HashMap<FileSha256, VecDeque<oneshot::Sender<usize>>>>,
+    connected_peers: RefCell<ConnectedPeers>,
+    peer_change_handler: RefCell<Option<PeerChangeHandler>>,
+    piece_cipher: RefCell<Option<PieceCipher>>,
+    piece_transfer_mode: Cell<PieceTransferMode>,
+    max_files: Cell<Option<usize>>,
 }
 
 impl<T> LocalPeer<T> {
-    pub async fn new(tracker_addr: String) -> Arc<Self>
+    pub async fn new(
+        tracker_addr: String,
+        ice_servers: Vec<IceServerConfig>,
+        ice_candidate_policy: IceCandidatePolicy,
+        data_channel_config: DataChannelConfig,
+        room: String,
+        max_connections: usize,
+    ) -> Arc<Self>
+    where
+        T: 'static + Ord,
+    {
+        Self::with_transport(
+            Box::new(Tracker::new(tracker_addr).await),
+            ice_servers,
+            ice_candidate_policy,
+            data_channel_config,
+            room,
+            max_connections,
+        )
+    }
+
+    /// Creates a local peer signaling through a custom tracker transport.
+    pub fn with_transport(
+        tracker: Box<dyn TrackerTransport>,
+        ice_servers: Vec<IceServerConfig>,
+        ice_candidate_policy: IceCandidatePolicy,
+        data_channel_config: DataChannelConfig,
+        room: String,
+        max_connections: usize,
+    ) -> Arc<Self>
     where
         T: 'static + Ord,
     {
         let peer = Arc::new(LocalPeer {
-            tracker: Tracker::new(tracker_addr).await,
+            tracker,
+            ice_servers,
+            ice_candidate_policy,
+            data_channel_config,
+            room,
             peer_id: RefCell::new(None),
             peers: RwLock::new(HashMap::new()),
             files: RwLock::new(HashMap::new()),
+            idle_peers: RwLock::new(HashSet::new()),
+            sent_states: RwLock::new(HashMap::new()),
+            offer_retries: RwLock::new(HashMap::new()),
+            file_discoveries: RwLock::new(HashMap::new()),
+            connection_queue: RwLock::new(ConnectionQueue::new(max_connections)),
+            swarm_queries: RefCell::new(HashMap::new()),
+            connected_peers: RefCell::new(ConnectedPeers::new()),
+            peer_change_handler: RefCell::new(None),
+            piece_cipher: RefCell::new(None),
+            piece_transfer_mode: Cell::new(PieceTransferMode::default()),
+            max_files: Cell::new(None),
         });
 
         peer.init();
@@ -40,11 +116,114 @@ impl<T> LocalPeer<T> {
         use wasm_bindgen_futures::spawn_local;
 
         let self_weak = Arc::downgrade(self);
-        self.tracker.set_handler(move |msg| {
+        self.tracker.set_handler(Box::new(move |msg| {
             if let Some(self_arc) = self_weak.upgrade() {
                 spawn_local(async move { self_arc.on_tracker_message(msg).await });
             }
-        });
+        }));
+    }
+
+    /// Sets the callback invoked when a remote peer becomes ready or a ready peer is removed.
+    pub fn set_on_peer_change(&self, callback: impl 'static + FnMut(PeerChangeEvent)) {
+        let _: Option<_> = self
+            .peer_change_handler
+            .replace(Some(PeerChangeHandler::new(callback)));
+    }
+
+    /// Enables encryption of pieces sent to peers connected afterwards,
+    /// only peers with the same piece key are accepted while it is enabled.
+    pub fn set_piece_cipher(&self, piece_cipher: Option<PieceCipher>) {
+        let _: Option<_> = self.piece_cipher.replace(piece_cipher);
+    }
+
+    pub fn piece_cipher(&self) -> Option<PieceCipher> {
+        self.piece_cipher.borrow().clone()
+    }
+
+    /// Sets how pieces are received from peers connected after the call.
+    pub fn set_piece_transfer_mode(&self, piece_transfer_mode: PieceTransferMode) {
+        self.piece_transfer_mode.set(piece_transfer_mode);
+    }
+
+    pub fn piece_transfer_mode(&self) -> PieceTransferMode {
+        self.piece_transfer_mode.get()
+    }
+
+    /// Limits the number of shared files, already shared files are kept if the limit is lowered.
+    pub fn set_max_files(&self, max_files: Option<usize>) {
+        self.max_files.set(max_files);
+    }
+
+    pub fn max_files(&self) -> Option<usize> {
+        self.max_files.get()
+    }
+
+    /// Returns the number of remote peers with an open data channel.
+    pub fn num_connected_peers(&self) -> usize {
+        self.connected_peers.borrow().len()
+    }
+
+    pub(crate) fn on_peer_ready(self: &Arc<Self>, peer_id: PeerId)
+    where
+        T: 'static,
+    {
+        use wasm_bindgen_futures::spawn_local;
+
+        let event = self.connected_peers.borrow_mut().on_ready(peer_id);
+        if event.is_some() {
+            let self_arc = Arc::clone(self);
+            spawn_local(async move { self_arc.reset_peer_local_states(peer_id).await });
+        }
+        self.notify_peer_change(event);
+    }
+
+    // Pieces added while the peer was not ready are only announced as recent pieces,
+    // so the full local state is sent again once the peer becomes ready.
+    async fn reset_peer_local_states(&self, peer_id: PeerId) {
+        use crate::unwrap_or_return;
+
+        let remote_peer = self.peers.read().await.get(&peer_id).cloned();
+        let remote_peer = unwrap_or_return!(remote_peer);
+        for file in self.snapshot_files().await {
+            on_file_peer_ready(&mut *file.write().await, &*remote_peer);
+        }
+    }
+
+    pub(crate) fn on_peer_closed(&self, peer_id: PeerId) {
+        let event = self.connected_peers.borrow_mut().on_removed(peer_id);
+        self.notify_peer_change(event);
+    }
+
+    fn notify_peer_change(&self, event: Option<PeerChangeEvent>) {
+        // The connected peers are released so that the callback can query them.
+        if let (Some(event), Some(handler)) = (event, &mut *self.peer_change_handler.borrow_mut()) {
+            handler.call(event);
+        }
+    }
+
+    /// Returns the local peer id assigned by the tracker.
+    pub fn peer_id(&self) -> Option<PeerId> {
+        *self.peer_id.borrow()
+    }
+
+    pub fn ice_servers(&self) -> &[IceServerConfig] {
+        &self.ice_servers
+    }
+
+    pub fn ice_candidate_policy(&self) -> IceCandidatePolicy {
+        self.ice_candidate_policy
+    }
+
+    pub fn data_channel_config(&self) -> &DataChannelConfig {
+        &self.data_channel_config
+    }
+
+    pub fn room(&self) -> &str {
+        &self.room
+    }
+
+    pub async fn max_connections(&self) -> usize {
+        self.connection_queue.read().await.max_connections()
     }
 
     pub fn files(&self) -> &RwLock<HashMap<FileSha256, Weak<RwLock<JsSharedFile<T>>>>> {
@@ -56,77 +235,153 @@ impl<T> LocalPeer<T> {
         self.tracker.send(message);
     }
 
+    /// Queries the tracker for the number of other peers sharing the file in the room
+    /// without joining the file swarm.
+    ///
+    /// Resolves to zero if the local peer is dropped before the reply is received.
+    pub fn query_swarm(&self, sha256: FileSha256) -> impl Future<Output = usize> {
+        let (sender, receiver) = oneshot::channel();
+        self.swarm_queries
+            .borrow_mut()
+            .entry(sha256)
+            .or_default()
+            .push_back(sender);
+        self.send(PeerTrackerMessage::QuerySwarm {
+            room: self.room.clone(),
+            file_sha256: sha256,
+        });
+        async move { receiver.await.unwrap_or(0) }
+    }
+
     async fn on_tracker_message(self: &Arc<Self>, message: TrackerPeerMessage)
     where
         T: 'static + Ord,
     {
-        use crate::{unwrap_or_return, IgnoreEmpty, OkOrLog, RemotePeerKind};
-        use std::collections::hash_map::Entry;
+        use crate::unwrap_or_return;
 
         log::trace!("recv tracker_message {:?}", message);
+
+        let (peer_id, opens_connection) = match &message {
+            TrackerPeerMessage::PeerIdAssigned { .. } | TrackerPeerMessage::SwarmInfo { .. } => {
+                (None, false)
+            }
+            TrackerPeerMessage::RequestOffer { peer_id, .. }
+            | TrackerPeerMessage::PeerOffer { peer_id, .. } => (Some(*peer_id), true),
+            TrackerPeerMessage::PeerAnswer { peer_id, .. }
+            | TrackerPeerMessage::PeerIceCandidate { peer_id, .. }
+            | TrackerPeerMessage::PeerAllIceCandidatesSent { peer_id } => (Some(*peer_id), false),
+        };
+        // The tracker is not trusted to filter out the local peer.
+        if peer_id.is_some() && peer_id == self.peer_id() {
+            log::warn!("tracker message addressed to the local peer itself is dropped");
+            return;
+        }
+        let message = match peer_id {
+            Some(peer_id) => {
+                let peers = self.peers.read().await;
+                if peers.contains_key(&peer_id) {
+                    message
+                } else {
+                    let num_connections = peers.len();
+                    drop(peers);
+                    let message = self.connection_queue.write().await.admit(
+                        peer_id,
+                        message,
+                        opens_connection,
+                        num_connections,
+                    );
+                    unwrap_or_return!(message)
+                }
+            }
+            None => message,
+        };
+        self.handle_tracker_message(message).await;
+    }
+
+    /// Opens queued connections while the number of connections is below the limit.
+    pub async fn admit_pending_connections(self: &Arc<Self>)
+    where
+        T: 'static + Ord,
+    {
+        loop {
+            let num_connections = self.peers.read().await.len();
+            let queued = self.connection_queue.write().await.pop(num_connections);
+            let (peer_id, messages) = match queued {
+                Some(queued) => queued,
+                None => return,
+            };
+            log_scoped!(debug in LogScope::peer(peer_id), "opening queued connection");
+            for message in messages {
+                self.handle_tracker_message(message).await;
+            }
+        }
+    }
+
+    async fn handle_tracker_message(self: &Arc<Self>, message: TrackerPeerMessage)
+    where
+        T: 'static + Ord,
+    {
+        use crate::ok_or_log::OrLog;
+        use crate::{unwrap_or_return, IgnoreEmpty, OkOrLog, RemotePeerKind};
+
         match message {
-            TrackerPeerMessage::PeerIdAssigned { peer_id } => {
+            TrackerPeerMessage::PeerIdAssigned {
+                peer_id,
+                protocol_version,
+            } => {
+                use crate::negotiate_protocol_version;
+                use tracker_protocol::PROTOCOL_VERSION;
+
+                // The peer id is not accepted from an incompatible tracker,
+                // so no connections are made through it.
+                let _: u16 = unwrap_or_return!(negotiate_protocol_version(
+                    PROTOCOL_VERSION,
+                    PROTOCOL_VERSION,
+                    protocol_version
+                )
+                .ok_or_log());
+                // The tracker assigns a new peer id on every connection,
+                // so the previous id is only set if the connection is re-established.
                 let prev_id: Option<_> = self.peer_id.replace(Some(peer_id));
-                assert_eq!(prev_id, None);
+                if prev_id.is_some() {
+                    log::info!("tracker connection re-established");
+                    self.reannounce_all().await;
+                }
             }
             TrackerPeerMessage::RequestOffer {
                 peer_id,
                 file_sha256,
             } => {
-                let shared_file = self
-                    .files
-                    .read()
-                    .await
-                    .get(&file_sha256)
-                    .and_then(|file| file.upgrade());
-                let shared_file = unwrap_or_return!(shared_file);
-
-                let mut peers = self.peers.write().await;
-
-                let remote_peer = peers.entry(peer_id);
-                match remote_peer {
-                    Entry::Occupied(_) => {}
-                    Entry::Vacant(entry) => {
-                        let remote_peer =
-                            RemotePeer::new(self, peer_id, RemotePeerKind::Offering).await;
-                        let _: &mut _ = entry.insert(remote_peer);
-                    }
-                };
-                shared_file
-                    .write()
-                    .await
-                    .add_peer(peer_id)
-                    .ok_or_log()
-                    .ignore_empty();
+                let shared_file = unwrap_or_return!(self.get_file(file_sha256).await);
+                on_request_offer(&self.peers, &shared_file, peer_id, || {
+                    RemotePeer::new(self, peer_id, RemotePeerKind::Offering)
+                })
+                .await
+                .ok_or_log()
+                .ignore_empty();
             }
             TrackerPeerMessage::PeerOffer { peer_id, offer } => {
-                let mut peers = self.peers.write().await;
-                let remote_peer = peers.entry(peer_id);
-                let remote_peer = match remote_peer {
-                    Entry::Occupied(entry) => Arc::clone(entry.get()),
-                    Entry::Vacant(entry) => {
-                        let remote_peer =
-                            RemotePeer::new(self, peer_id, RemotePeerKind::Answering).await;
-                        let _: &mut _ = entry.insert(Arc::clone(&remote_peer));
-                        remote_peer
-                    }
-                };
-                remote_peer.on_peer_offer(offer).await;
+                let remote_peer = get_or_create_peer(&self.peers, peer_id, || {
+                    RemotePeer::new(self, peer_id, RemotePeerKind::Answering)
+                })
+                .await;
+                let remote_peer = unwrap_or_return!(remote_peer.ok_or_log());
+                remote_peer.on_peer_offer(offer).await.or_log();
             }
             TrackerPeerMessage::PeerAnswer { peer_id, answer } => {
                 let peers = self.peers.read().await;
                 if let Some(remote_peer) = peers.get(&peer_id) {
-                    remote_peer.on_peer_answer(answer).await;
+                    remote_peer.on_peer_answer(answer).await.or_log();
                 } else {
-                    log::error!("unexpected answer from peer {}", peer_id);
+                    log_scoped!(error in LogScope::peer(peer_id), "unexpected answer");
                 };
             }
             TrackerPeerMessage::PeerIceCandidate { peer_id, candidate } => {
                 let peers = self.peers.read().await;
                 if let Some(remote_peer) = peers.get(&peer_id) {
-                    remote_peer.on_peer_icecandidate(candidate).await;
+                    remote_peer.on_peer_icecandidate(candidate).await.or_log();
                 } else {
-                    log::error!("unexpected icecandidate from peer {}", peer_id);
+                    log_scoped!(error in LogScope::peer(peer_id), "unexpected icecandidate");
                 };
             }
             TrackerPeerMessage::PeerAllIceCandidatesSent { peer_id } => {
@@ -134,9 +389,23 @@ impl<T> LocalPeer<T> {
                 if let Some(remote_peer) = peers.get(&peer_id) {
                     remote_peer.on_peer_all_icecandidates_sent().await;
                 } else {
-                    log::error!("unexpected all_icecandidates_sent from peer {}", peer_id);
+                    log_scoped!(error in LogScope::peer(peer_id), "unexpected all_icecandidates_sent");
                 };
             }
+            TrackerPeerMessage::SwarmInfo {
+                file_sha256,
+                peer_count,
+            } => {
+                let mut swarm_queries = self.swarm_queries.borrow_mut();
+                let queries = unwrap_or_return!(swarm_queries.get_mut(&file_sha256));
+                if let Some(query) = queries.pop_front() {
+                    // The query future may already be dropped.
+                    let _: Result<(), usize> = query.send(peer_count);
+                }
+                if queries.is_empty() {
+                    let _: Option<_> = swarm_queries.remove(&file_sha256);
+                }
+            }
         }
     }
 
@@ -147,90 +416,13 @@ impl<T> LocalPeer<T> {
     ) where
         T: Ord,
     {
-        use crate::{
-            unwrap_or_return, FileState, IgnoreEmpty, OkOrLog, SharedFileAddPeerError,
-            SharedFileLocalStateStatus, SharedFileMarkStatus,
-        };
-
-        let peer_id = remote_peer.peer_id();
+        use crate::unwrap_or_return;
 
-        let sha256 = *match &message {
-            PeerPeerMessage::FileMissing { sha256 } => sha256,
-            PeerPeerMessage::FileComplete { sha256 } => sha256,
-            PeerPeerMessage::FileState { sha256, state: _ } => sha256,
-            PeerPeerMessage::FileStateReceived { sha256 } => sha256,
-            PeerPeerMessage::FilePiece {
-                sha256,
-                piece_idx: _,
-                bytes: _,
-            } => sha256,
-            PeerPeerMessage::FilePiecesReceived { sha256, pieces: _ } => sha256,
-            PeerPeerMessage::FileRemoved { sha256 } => sha256,
-        };
+        let sha256 = unwrap_or_return!(message.sha256());
 
         let shared_file = unwrap_or_return!(self.get_file(sha256).await);
         let mut shared_file = shared_file.write().await;
-        match shared_file.add_peer(peer_id) {
-            Ok(()) | Err(SharedFileAddPeerError::PeerIsAlreadyAdded) => {}
-        };
-
-        match message {
-            PeerPeerMessage::FileMissing { sha256 } => {
-                shared_file
-                    .set_peer_file_missing(peer_id)
-                    .ok_or_log()
-                    .ignore_empty();
-                remote_peer.send(PeerPeerMessage::FileStateReceived { sha256 });
-            }
-            PeerPeerMessage::FileComplete { sha256 } => {
-                shared_file
-                    .set_peer_file_complete(peer_id)
-                    .ok_or_log()
-                    .ignore_empty();
-                remote_peer.send(PeerPeerMessage::FileStateReceived { sha256 });
-            }
-            PeerPeerMessage::FileState { sha256, state } => {
-                shared_file
-                    .set_peer_state(peer_id, FileState::from(state))
-                    .ok_or_log()
-                    .ignore_empty();
-                remote_peer.send(PeerPeerMessage::FileStateReceived { sha256 });
-            }
-            PeerPeerMessage::FileStateReceived { sha256: _ } => {
-                shared_file
-                    .local_state_status_mut(&peer_id)
-                    .ok_or_log()
-                    .map(|status| *status = SharedFileLocalStateStatus::Received)
-                    .ignore_empty();
-            }
-            PeerPeerMessage::FilePiece {
-                sha256: _,
-                piece_idx,
-                bytes,
-            } => {
-                if !shared_file
-                    .file()
-                    .has_piece(&piece_idx)
-                    .ok_or_log()
-                    .unwrap_or(false)
-                {
-                    shared_file
-                        .add_local_piece(piece_idx, &bytes)
-                        .ok_or_log()
-                        .ignore_empty();
-                }
-            }
-            PeerPeerMessage::FilePiecesReceived { sha256: _, pieces } => {
-                for piece in pieces {
-                    let _: Option<SharedFileMarkStatus> = shared_file
-                        .mark_peer_piece_as_received_by_remote(&peer_id, piece)
-                        .ok_or_log();
-                }
-            }
-            PeerPeerMessage::FileRemoved { sha256: _ } => {
-                shared_file.remove_peer(&peer_id).ok_or_log().ignore_empty();
-            }
-        }
+        on_file_message(&mut shared_file, &**remote_peer, message);
     }
 
     pub async fn add_file(
@@ -241,13 +433,27 @@ impl<T> LocalPeer<T> {
 
         let mut files = self.files.write().await;
 
+        // Dropped files are not counted, they are pruned from the map later.
+        if let Some(max_files) = self.max_files() {
+            let num_files = files
+                .values()
+                .filter(|file| file.strong_count() > 0)
+                .count();
+            if num_files >= max_files && !files.contains_key(&file.sha256()) {
+                return Err(LocalPeerAddFileError::TooManyFiles { max_files });
+            }
+        }
+
         let entry = files.entry(file.sha256());
         match entry {
             Entry::Vacant(entry) => {
-                let shared_file = Arc::new(RwLock::new(JsSharedFile::new(file)));
+                let shared_file = Arc::new(RwLock::new(JsSharedFile::new(file)?));
                 let file_sha256 = *entry.key();
                 let _: &mut _ = entry.insert(Arc::downgrade(&shared_file));
-                let message = PeerTrackerMessage::RequestOffers { file_sha256 };
+                let message = PeerTrackerMessage::RequestOffers {
+                    room: self.room.clone(),
+                    file_sha256,
+                };
                 self.tracker.send(message);
                 Ok(shared_file)
             }
@@ -255,10 +461,90 @@ impl<T> LocalPeer<T> {
         }
     }
 
+    /// Shares a file created from an in-memory buffer rather than from a DOM file.
+    pub async fn add_bytes(
+        &self,
+        name: String,
+        bytes: &[u8],
+    ) -> Result<Arc<RwLock<JsSharedFile<T>>>, LocalPeerAddFileError> {
+        self.add_file(JsFile::from_bytes(name, bytes)).await
+    }
+
+    /// Captures metadata and states of shared files without their contents,
+    /// e.g. to reproduce a reported state or to hand the session off.
+    pub async fn export_session(&self) -> SessionSnapshot {
+        let files = self.snapshot_files().await;
+        let mut file_guards = Vec::with_capacity(files.len());
+        for file in &files {
+            file_guards.push(file.read().await);
+        }
+        let file_refs: Vec<_> = file_guards.iter().map(|file| file.file()).collect();
+        SessionSnapshot::from_files(file_refs.iter().map(|file| &**file))
+    }
+
+    /// Shares files of the snapshot with their recorded states and announces them to the tracker.
+    ///
+    /// Contents are not captured, so fully recorded chunks are released and never sent to peers,
+    /// other pieces are downloaded as usual, including recorded pieces of partial chunks.
+    /// Files that are already shared are kept as they are.
+    pub async fn import_session(
+        &self,
+        snapshot: &SessionSnapshot,
+    ) -> Result<Vec<Arc<RwLock<JsSharedFile<T>>>>, LocalPeerImportSessionError> {
+        let mut shared_files = Vec::with_capacity(snapshot.files.len());
+        for session_file in &snapshot.files {
+            let file = session_file.to_file()?;
+            match self.add_file(file).await {
+                Ok(shared_file) => shared_files.push(shared_file),
+                Err(LocalPeerAddFileError::AlreadyAdded) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(shared_files)
+    }
+
+    /// Returns transfer statistics aggregated over all shared files and connected peers.
+    pub async fn stats(&self) -> LocalPeerStats {
+        let files = self.snapshot_files().await;
+        let mut stats = LocalPeerStats {
+            num_peers: self.peers.read().await.len(),
+            ..LocalPeerStats::default()
+        };
+        for file in files {
+            stats.add_file(&*file.read().await);
+        }
+        stats
+    }
+
+    /// Returns the largest number of bytes queued in a single data channel.
+    pub async fn max_buffered_bytes(&self) -> u64 {
+        self.peers
+            .read()
+            .await
+            .values()
+            .map(|remote_peer| remote_peer.buffered_bytes())
+            .max()
+            .unwrap_or(0)
+    }
+
     pub async fn get_file(&self, sha256: FileSha256) -> Option<Arc<RwLock<JsSharedFile<T>>>> {
         self.files.read().await.get(&sha256).and_then(Weak::upgrade)
     }
 
+    /// Returns all shared files that are not removed yet.
+    ///
+    /// The files map lock is released before returning,
+    /// so individual files can be locked without holding it.
+    pub async fn snapshot_files(&self) -> Vec<Arc<RwLock<JsSharedFile<T>>>> {
+        snapshot_files(&self.files).await
+    }
+
+    /// Returns metadata of all shared files that are not removed yet,
+    /// removed files are cleared from the files map as well.
+    pub async fn list_files(&self) -> Vec<FileMetadata> {
+        list_files(&self.files).await
+    }
+
     pub async fn clear_removed_files(&self) {
         self.files
             .write()
@@ -270,178 +556,1235 @@ impl<T> LocalPeer<T> {
     where
         T: Clone + PartialOrd,
     {
-        use crate::{LocalStateStatusError, SharedFileLocalStateStatus};
-
-        let files = self.files.read().await;
+        let files = self.snapshot_files().await;
         let peers = self.peers.read().await;
+        let mut sent_states = self.sent_states.write().await;
+        let mut shared_sha256s = HashSet::with_capacity(files.len());
 
-        for (sha256, file) in files.iter() {
-            if let Some(shared_file) = file.upgrade() {
-                let mut shared_file = shared_file.write().await;
-                let peer_ids: Vec<_> = shared_file.peer_ids().copied().collect();
-                for peer_id in peer_ids {
-                    let local_state_status = match shared_file.local_state_status_mut(&peer_id) {
-                        Ok(status) => status,
-                        Err(LocalStateStatusError::PeerIsNotAdded) => unreachable!(),
-                    };
-                    let should_resend = match local_state_status {
-                        SharedFileLocalStateStatus::NotSent => true,
-                        SharedFileLocalStateStatus::Sent(time) => *time <= resend_before,
-                        SharedFileLocalStateStatus::Received => false,
-                    };
-                    if should_resend {
-                        let remote_peer = peers.get(&peer_id).unwrap();
-
-                        if remote_peer.is_ready() {
-                            *local_state_status =
-                                SharedFileLocalStateStatus::Sent(current_time.clone());
-
-                            let state = shared_file.file().state();
-
-                            if state.is_missing() {
-                                remote_peer.send(PeerPeerMessage::FileMissing { sha256: *sha256 });
-                            } else if state.is_complete() {
-                                remote_peer.send(PeerPeerMessage::FileComplete { sha256: *sha256 });
-                            } else {
-                                remote_peer.send(PeerPeerMessage::FileState {
-                                    sha256: *sha256,
-                                    state: state.raw().to_bitvec().into_boxed_bitslice(),
-                                });
-                            }
-                        }
-                    }
+        for shared_file in files {
+            let sha256 = {
+                // Nothing to resend if the state is unchanged and received by all peers.
+                let shared_file = shared_file.read().await;
+                let sha256 = shared_file.file().sha256();
+                let _: bool = shared_sha256s.insert(sha256);
+                if sent_states.get(&sha256) == Some(shared_file.file().state())
+                    && shared_file.is_local_state_received()
+                {
+                    continue;
                 }
+                sha256
+            };
+
+            let mut shared_file = shared_file.write().await;
+            let peer_ids: Vec<_> = shared_file.peer_ids().copied().collect();
+            for peer_id in peer_ids {
+                let remote_peer = peers.get(&peer_id).unwrap();
+                send_file_state(
+                    &mut shared_file,
+                    &**remote_peer,
+                    &resend_before,
+                    &current_time,
+                );
+            }
+            let _: Option<_> = sent_states.insert(sha256, shared_file.file().state().clone());
+        }
+        sent_states.retain(|sha256, _| shared_sha256s.contains(sha256));
+    }
+
+    /// Requests offers again with backoff for files without ready peers,
+    /// so that peers that joined later are discovered.
+    pub async fn retry_offer_requests(&self, current_time: T)
+    where
+        T: Clone + Ord + Add<Duration, Output = T>,
+    {
+        use crate::{OFFER_RETRY_MAX_INTERVAL, OFFER_RETRY_MIN_INTERVAL};
+
+        let files = self.snapshot_files().await;
+        let peers = self.peers.read().await;
+        let mut offer_retries = self.offer_retries.write().await;
+        let mut shared_sha256s = HashSet::with_capacity(files.len());
+
+        for shared_file in files {
+            let shared_file = shared_file.read().await;
+            let sha256 = &shared_file.file().sha256();
+            let _: bool = shared_sha256s.insert(*sha256);
+            if has_ready_peers(&shared_file, &peers) {
+                let _: Option<_> = offer_retries.remove(sha256);
+                continue;
+            }
+
+            let backoff = offer_retries.entry(*sha256).or_insert_with(|| {
+                RetryBackoff::new(
+                    current_time.clone(),
+                    OFFER_RETRY_MIN_INTERVAL,
+                    OFFER_RETRY_MAX_INTERVAL,
+                )
+            });
+            if backoff.poll(current_time.clone()) {
+                log_scoped!(
+                    debug in LogScope::file(*sha256),
+                    "no ready peers, requesting offers again"
+                );
+                self.tracker.send(PeerTrackerMessage::RequestOffers {
+                    room: self.room.clone(),
+                    file_sha256: *sha256,
+                });
+            }
+        }
+        offer_retries.retain(|sha256, _| shared_sha256s.contains(sha256));
+    }
+
+    /// Requests offers for all shared files,
+    /// so that the local peer is visible to swarms after the tracker connection is re-established.
+    ///
+    /// Files with ready peers are skipped, they are already connected to their swarms.
+    pub async fn reannounce_all(&self) {
+        let files = self.snapshot_files().await;
+        let peers = self.peers.read().await;
+        for shared_file in files {
+            let shared_file = shared_file.read().await;
+            if has_ready_peers(&shared_file, &peers) {
+                continue;
+            }
+            self.tracker.send(PeerTrackerMessage::RequestOffers {
+                room: self.room.clone(),
+                file_sha256: shared_file.file().sha256(),
+            });
+        }
+    }
+
+    /// Updates discovery statuses of incomplete files,
+    /// offers are still requested in background after the discovery timeout.
+    pub async fn update_file_discoveries(&self, current_time: T)
+    where
+        T: Clone + Ord + Add<Duration, Output = T>,
+    {
+        use crate::FILE_DISCOVERY_TIMEOUT;
+
+        let files = self.snapshot_files().await;
+        let mut file_discoveries = self.file_discoveries.write().await;
+        let mut shared_sha256s = HashSet::with_capacity(files.len());
+
+        for shared_file in files {
+            let shared_file = shared_file.read().await;
+            let sha256 = &shared_file.file().sha256();
+            let _: bool = shared_sha256s.insert(*sha256);
+            if shared_file.file().state().is_complete() {
+                let _: Option<_> = file_discoveries.remove(sha256);
+                continue;
+            }
+
+            let discovery = file_discoveries.entry(*sha256).or_insert_with(|| {
+                FileDiscovery::new(current_time.clone(), FILE_DISCOVERY_TIMEOUT)
+            });
+            let prev_status = discovery.status();
+            let status = discovery.update(&current_time, shared_file.has_seeders());
+            if status != prev_status {
+                log_scoped!(
+                    debug in LogScope::file(*sha256),
+                    "discovery status {:?}",
+                    status
+                );
+            }
+        }
+        file_discoveries.retain(|sha256, _| shared_sha256s.contains(sha256));
+    }
+
+    pub async fn file_discovery_status(&self, sha256: &FileSha256) -> Option<FileDiscoveryStatus> {
+        self.file_discoveries
+            .read()
+            .await
+            .get(sha256)
+            .map(FileDiscovery::status)
+    }
+
+    /// Retries queued control messages of peers with drained data channel buffers.
+    pub async fn poll_control_queues(&self, current_time: T)
+    where
+        T: Clone + Ord + Add<Duration, Output = T>,
+    {
+        use crate::ok_or_log::OrLog;
+
+        let peers = self.peers.read().await;
+        for remote_peer in peers.values() {
+            if remote_peer.is_ready() {
+                remote_peer
+                    .poll_control_queue(current_time.clone())
+                    .or_log();
             }
         }
     }
 
+    /// Stamps pieces received since the previous call with the current time
+    /// for completion estimates.
+    pub async fn stamp_piece_arrivals(&self, current_time: T)
+    where
+        T: Clone,
+    {
+        for file in self.snapshot_files().await {
+            file.write()
+                .await
+                .stamp_piece_arrivals(current_time.clone());
+        }
+    }
+
     pub async fn send_recently_received_to_remote_peers(&self) {
-        let files = self.files.read().await;
+        use crate::ok_or_log::OrLog;
+
+        let files = self.snapshot_files().await;
         let peers = self.peers.read().await;
 
-        for (sha256, file) in files.iter() {
-            if let Some(shared_file) = file.upgrade() {
-                let mut shared_file = shared_file.write().await;
-                let pieces = shared_file.take_recently_added_pieces();
-                if !pieces.is_empty() {
-                    for peer_id in shared_file.peer_ids() {
-                        let remote_peer = peers.get(&peer_id).unwrap();
-                        if remote_peer.is_ready() {
-                            remote_peer.send(PeerPeerMessage::FilePiecesReceived {
-                                sha256: *sha256,
+        for shared_file in files {
+            let mut shared_file = shared_file.write().await;
+            let pieces = shared_file.take_recently_added_pieces();
+            if !pieces.is_empty() {
+                let sha256 = shared_file.file().sha256();
+                for peer_id in shared_file.peer_ids() {
+                    let remote_peer = peers.get(&peer_id).unwrap();
+                    if remote_peer.is_ready() {
+                        remote_peer
+                            .send(PeerPeerMessage::FilePiecesReceived {
+                                sha256,
                                 pieces: pieces.clone(),
-                            });
-                        }
+                            })
+                            .or_log();
                     }
                 }
             }
         }
     }
 
+    pub async fn update_peer_rates(&self, elapsed: Duration) {
+        for file in self.snapshot_files().await {
+            file.write().await.update_peer_rates(elapsed);
+        }
+    }
+
     pub async fn resend_pieces_before(&self, time: T)
     where
         T: Clone + Ord,
     {
         use crate::ok_or_log::OrLog;
 
-        let files = self.files.read().await;
-        let files = files.values().filter_map(Weak::upgrade);
-
-        for file in files {
-            file.write()
-                .await
+        for file in self.snapshot_files().await {
+            let mut shared_file = file.write().await;
+            shared_file
                 .mark_pieces_for_resend_before(time.clone())
                 .or_log();
+            shared_file.expire_piece_requests_before(&time);
         }
     }
 
-    pub async fn send_pieces_to_remote_peers(
-        &self,
-        mut num_pieces_to_be_sent: usize,
-        max_buffer_bytes: Option<u64>,
-        current_time: T,
-        mut rng: impl rand::Rng,
-    ) where
-        T: Clone + Ord,
+    /// Requests missing pieces from peers that pieces are pulled from.
+    pub async fn request_pieces_from_remote_peers(&self, current_time: T)
+    where
+        T: Clone,
     {
-        use crate::{PeerConnectionSendError, PieceNumPossibleOwners};
-        use core::cmp::Ordering;
+        use crate::MAX_PENDING_PIECE_REQUESTS;
 
-        let files: Vec<_> = self
-            .files
-            .read()
-            .await
-            .values()
-            .filter_map(Weak::upgrade)
-            .collect();
         let peers = self.peers.read().await;
+        for file in self.snapshot_files().await {
+            let mut shared_file = file.write().await;
+            request_file_pieces(
+                &mut shared_file,
+                peers.values().map(|peer| &**peer),
+                MAX_PENDING_PIECE_REQUESTS,
+                current_time.clone(),
+            );
+        }
+    }
 
-        while num_pieces_to_be_sent > 0 {
-            let mut min_possible_owners = None;
-            let mut file_pieces = Vec::new();
-
-            for (file_idx, shared_file) in files.iter().enumerate() {
-                let shared_file = shared_file.read().await;
-                let piece_queues = shared_file.piece_queues();
-                let queue = piece_queues.next_queue();
-
-                if let Some((file_min_possible_owners, pieces)) = queue {
-                    match file_min_possible_owners
-                        .cmp(&min_possible_owners.unwrap_or(PieceNumPossibleOwners(usize::MAX)))
-                    {
-                        Ordering::Less => {
-                            min_possible_owners = Some(file_min_possible_owners);
-                            if file_min_possible_owners < shared_file.num_peers_with_state() {
-                                file_pieces
-                                    .extend(pieces.iter().map(|&piece_idx| (file_idx, piece_idx)));
-                            }
-                        }
-                        Ordering::Equal => {
-                            file_pieces.clear();
-                            if file_min_possible_owners < shared_file.num_peers_with_state() {
-                                file_pieces
-                                    .extend(pieces.iter().map(|&piece_idx| (file_idx, piece_idx)));
-                            }
-                        }
-                        Ordering::Greater => {}
-                    }
-                }
-            }
+    /// Updates liveness of remote peers from pongs received since the previous call
+    /// and pings ready peers again.
+    ///
+    /// Peers that have not answered since `unresponsive_before`, e.g. with frozen background tabs,
+    /// are skipped in piece selection until they answer again.
+    pub async fn check_peers_liveness(&self, current_time: T, unresponsive_before: T)
+    where
+        T: Clone + Ord,
+    {
+        use crate::ok_or_log::OrLog;
 
-            if file_pieces.is_empty() {
-                return;
+        let peers = self.peers.read().await;
+        let ponged_peers: HashSet<_> = peers
+            .values()
+            .filter(|peer| peer.take_pong())
+            .map(|peer| peer.peer_id())
+            .collect();
+        for file in self.snapshot_files().await {
+            let mut shared_file = file.write().await;
+            update_file_peers_liveness(
+                &mut shared_file,
+                &ponged_peers,
+                current_time.clone(),
+                &unresponsive_before,
+            );
+        }
+        for peer in peers.values() {
+            if peer.is_ready() {
+                peer.send(PeerPeerMessage::Ping).or_log();
             }
+        }
+    }
 
-            while num_pieces_to_be_sent > 0 && file_pieces.len() > 0 {
-                let idx = rng.gen_range(0..file_pieces.len());
-                let (file_idx, piece_idx) = file_pieces.swap_remove(idx);
+    /// Closes and removes peers that have no shared files
+    /// since the previous call of this method.
+    ///
+    /// If connections are queued and the connection limit is still reached,
+    /// one peer that needs no local pieces and owns the least rare pieces is closed as well.
+    pub async fn prune_idle_peers(&self)
+    where
+        T: Ord,
+    {
+        use crate::{IgnoreEmpty, OkOrLog};
 
-                let mut shared_file = files[file_idx].write().await;
-                let peer_id = shared_file
-                    .select_piece_peer(piece_idx, current_time.clone())
-                    .unwrap();
-                let file = shared_file.file();
+        let mut peers = self.peers.write().await;
+        let files = self.snapshot_files().await;
+        let mut file_guards = Vec::with_capacity(files.len());
+        for file in &files {
+            file_guards.push(file.read().await);
+        }
+        let shared_files: Vec<_> = file_guards.iter().map(|file| &**file).collect();
 
-                let remote_peer = peers.get(&peer_id).unwrap();
-                let message = PeerPeerMessage::FilePiece {
-                    sha256: file.sha256(),
-                    piece_idx,
-                    bytes: file.get_piece(&piece_idx).unwrap().unwrap(),
-                };
-                match max_buffer_bytes {
-                    Some(max_buffer_bytes) => {
-                        let result =
-                            remote_peer.send_with_max_buffer_size(message, max_buffer_bytes);
-                        match result {
-                            Ok(()) => {}
-                            Err(PeerConnectionSendError::BufferIsFilled) => return,
-                        }
-                    }
-                    None => remote_peer.send(message),
-                };
+        let mut idle_peers = self.idle_peers.write().await;
+        let pruned_peers = select_idle_peers(peers.keys().copied(), &shared_files, &mut idle_peers);
+        for peer_id in pruned_peers {
+            if let Some(remote_peer) = peers.remove(&peer_id) {
+                log_scoped!(debug in LogScope::peer(peer_id), "idle, closing connection");
+                remote_peer.close();
+                self.on_peer_closed(peer_id);
+            }
+        }
 
-                num_pieces_to_be_sent -= 1;
+        let connection_queue = self.connection_queue.read().await;
+        if connection_queue.is_empty() || peers.len() < connection_queue.max_connections() {
+            return;
+        }
+        let candidates = peers.keys().filter_map(|peer_id| {
+            let needs_local_pieces = shared_files.iter().any(|shared_file| {
+                shared_file.has_peer(*peer_id) && shared_file.peer_needs_local_pieces(peer_id)
+            });
+            if needs_local_pieces {
+                return None;
             }
+            let score = shared_files
+                .iter()
+                .map(|shared_file| shared_file.peer_rarity_score(peer_id))
+                .sum();
+            Some((*peer_id, score))
+        });
+        let peer_id = match select_peer_to_close(candidates) {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+        drop(shared_files);
+        drop(file_guards);
+
+        if let Some(remote_peer) = peers.remove(&peer_id) {
+            log_scoped!(
+                debug in LogScope::peer(peer_id),
+                "closing connection for {} queued connections",
+                connection_queue.len()
+            );
+            remote_peer.close();
+            self.on_peer_closed(peer_id);
+        }
+        for file in &files {
+            file.write()
+                .await
+                .remove_peer(&peer_id)
+                .ok_or_log()
+                .ignore_empty();
+        }
+    }
+
+    /// Sends up to `num_pieces_to_be_sent` pieces to remote peers,
+    /// assigning at most `max_pieces_per_peer` of them to a single peer.
+    ///
+    /// If `max_batch_size` is set, control is returned to the browser event loop
+    /// via a macrotask after every `max_batch_size` sent pieces,
+    /// so incoming messages and rendering are not blocked for the whole interval.
+    /// Smaller batches reduce UI latency but add scheduling overhead
+    /// and may lower the throughput, larger batches do the opposite.
+    pub async fn send_pieces_to_remote_peers(
+        &self,
+        num_pieces_to_be_sent: usize,
+        max_buffer_bytes: Option<u64>,
+        max_batch_size: Option<usize>,
+        max_pieces_per_peer: Option<usize>,
+        current_time: T,
+        rng: impl rand::Rng,
+    ) where
+        T: Clone + Ord,
+    {
+        use crate::macrotask;
+
+        let files = self.snapshot_files().await;
+        let limits = PieceSendLimits {
+            max_buffer_bytes,
+            max_batch_size,
+            max_pieces_per_peer,
+        };
+        send_pieces_to_peers(
+            &files,
+            &self.peers,
+            num_pieces_to_be_sent,
+            limits,
+            current_time,
+            rng,
+            macrotask,
+        )
+        .await;
+    }
+}
+
+/// Limits of a single piece sending cycle, see `LocalPeer::send_pieces_to_remote_peers`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct PieceSendLimits {
+    pub max_buffer_bytes: Option<u64>,
+    pub max_batch_size: Option<usize>,
+    pub max_pieces_per_peer: Option<usize>,
+}
+
+/// Sends up to `num_pieces_to_be_sent` pieces of files that are not fully distributed yet,
+/// splitting them between files by priority and serving requested pieces first.
+///
+/// `yield_batch` is awaited after every `limits.max_batch_size` sent pieces.
+/// Peers with filled send buffers are excluded for the rest of the cycle.
+pub async fn send_pieces_to_peers<C, T, P, F, const CHUNK_SIZE: usize>(
+    files: &[Arc<RwLock<SharedFile<C, T, CHUNK_SIZE>>>],
+    peers: &RwLock<HashMap<PeerId, Arc<P>>>,
+    mut num_pieces_to_be_sent: usize,
+    limits: PieceSendLimits,
+    current_time: T,
+    mut rng: impl rand::Rng,
+    mut yield_batch: impl FnMut() -> F,
+) where
+    C: FileChunk,
+    T: Clone + Ord,
+    P: PeerTransport,
+    F: Future<Output = ()>,
+{
+    use crate::{unwrap_or_continue, PieceNumPossibleOwners};
+    use core::cmp::Ordering;
+
+    let mut files_to_distribute = Vec::new();
+    for file in files {
+        if !file.read().await.is_fully_distributed() {
+            files_to_distribute.push(Arc::clone(file));
+        }
+    }
+    let files = files_to_distribute;
+
+    let mut priorities = Vec::with_capacity(files.len());
+    for file in &files {
+        let shared_file = file.read().await;
+        let has_pieces_to_send =
+            shared_file
+                .next_pieces()
+                .is_some_and(|(num_possible_owners, _)| {
+                    num_possible_owners < shared_file.num_peers_with_state()
+                });
+        priorities.push(
+            if has_pieces_to_send || shared_file.has_queued_piece_requests() {
+                shared_file.priority()
+            } else {
+                0
+            },
+        );
+    }
+    let mut budgets = split_pieces_budget(num_pieces_to_be_sent, &priorities);
+    // No file has pieces missing on its peers, e.g. the only peer has received them all.
+    if budgets.iter().all(|&budget| budget == 0) {
+        return;
+    }
+
+    let max_batch_size = limits.max_batch_size.map(|size| size.max(1));
+    let mut num_pieces_in_batch = 0;
+    let mut assignments = PeerAssignments::new(limits.max_pieces_per_peer);
+
+    // Pieces requested by pulling peers are served first, within the same budgets.
+    {
+        let peers = peers.read().await;
+        let mut batches: HashMap<_, Vec<_>> = HashMap::new();
+        for (file_idx, file) in files.iter().enumerate() {
+            let mut shared_file = file.write().await;
+            // Each sending cycle starts piece peer orders from the next peer.
+            shared_file.advance_peer_cursor();
+            let served = shared_file.serve_piece_requests(budgets[file_idx], current_time.clone());
+            budgets[file_idx] -= served.len();
+            num_pieces_to_be_sent -= served.len();
+            num_pieces_in_batch += served.len();
+            let sha256 = shared_file.file().sha256();
+            let compress = shared_file.file().metadata().is_compressed();
+            for (peer_id, piece_idx, bytes) in served {
+                batches
+                    .entry((peer_id, file_idx, sha256, compress))
+                    .or_default()
+                    .push((piece_idx, bytes));
+            }
+        }
+        send_piece_batches(
+            &files,
+            &peers,
+            batches,
+            limits.max_buffer_bytes,
+            &current_time,
+            &mut assignments,
+        )
+        .await;
+    }
+
+    while num_pieces_to_be_sent > 0 {
+        if max_batch_size.is_some_and(|size| num_pieces_in_batch >= size) {
+            num_pieces_in_batch = 0;
+            yield_batch().await;
+        }
+
+        let peers = peers.read().await;
+        let mut min_possible_owners = None;
+        let mut file_pieces = Vec::new();
+
+        for (file_idx, shared_file) in files.iter().enumerate() {
+            if budgets[file_idx] == 0 {
+                continue;
+            }
+            let shared_file = shared_file.read().await;
+            let queue = shared_file.next_pieces();
+
+            if let Some((file_min_possible_owners, pieces)) = queue {
+                match file_min_possible_owners
+                    .cmp(&min_possible_owners.unwrap_or(PieceNumPossibleOwners(usize::MAX)))
+                {
+                    Ordering::Less => {
+                        min_possible_owners = Some(file_min_possible_owners);
+                        if file_min_possible_owners < shared_file.num_peers_with_state() {
+                            file_pieces
+                                .extend(pieces.iter().map(|&piece_idx| (file_idx, piece_idx)));
+                        }
+                    }
+                    Ordering::Equal => {
+                        file_pieces.clear();
+                        if file_min_possible_owners < shared_file.num_peers_with_state() {
+                            file_pieces
+                                .extend(pieces.iter().map(|&piece_idx| (file_idx, piece_idx)));
+                        }
+                    }
+                    Ordering::Greater => {}
+                }
+            }
+        }
+
+        if file_pieces.is_empty() {
+            return;
+        }
+
+        let mut batches: HashMap<_, Vec<_>> = HashMap::new();
+        let mut num_selected = 0;
+        while num_pieces_to_be_sent > 0
+            && file_pieces.len() > 0
+            && max_batch_size.is_none_or(|size| num_pieces_in_batch < size)
+        {
+            let idx = rng.gen_range(0..file_pieces.len());
+            let (file_idx, piece_idx) = file_pieces.swap_remove(idx);
+            if budgets[file_idx] == 0 {
+                continue;
+            }
+
+            let mut shared_file = files[file_idx].write().await;
+            let selected = select_file_piece(
+                &mut shared_file,
+                piece_idx,
+                current_time.clone(),
+                &mut assignments,
+            );
+            let (peer_id, bytes) = unwrap_or_continue!(selected);
+            num_selected += 1;
+            let compress = shared_file.file().metadata().is_compressed();
+            batches
+                .entry((peer_id, file_idx, shared_file.file().sha256(), compress))
+                .or_default()
+                .push((piece_idx, bytes));
+
+            budgets[file_idx] -= 1;
+            num_pieces_to_be_sent -= 1;
+            num_pieces_in_batch += 1;
+        }
+
+        send_piece_batches(
+            &files,
+            &peers,
+            batches,
+            limits.max_buffer_bytes,
+            &current_time,
+            &mut assignments,
+        )
+        .await;
+
+        // The least owned pieces are missing only on peers that reached the cap.
+        if num_selected == 0 {
+            return;
+        }
+    }
+}
+
+/// Sends batches of file pieces keyed by peers, file indices in `files`, file hashes
+/// and compression flags.
+///
+/// Peers with filled buffers are excluded from further assignments,
+/// batches of other peers are still sent.
+async fn send_piece_batches<C, T, P, const CHUNK_SIZE: usize>(
+    files: &[Arc<RwLock<SharedFile<C, T, CHUNK_SIZE>>>],
+    peers: &HashMap<PeerId, Arc<P>>,
+    batches: HashMap<(PeerId, usize, FileSha256, bool), Vec<(FilePieceIdx, Box<[u8]>)>>,
+    max_buffer_bytes: Option<u64>,
+    current_time: &T,
+    assignments: &mut PeerAssignments,
+) where
+    T: Clone,
+    P: PeerTransport,
+{
+    use crate::PeerConnectionSendError;
+
+    for ((peer_id, file_idx, sha256, compress), pieces) in batches {
+        let remote_peer = peers.get(&peer_id).unwrap();
+        match remote_peer.send_file_pieces(sha256, pieces, compress, max_buffer_bytes) {
+            Ok(()) => {
+                let mut shared_file = files[file_idx].write().await;
+                shared_file.mark_peer_send_unblocked(&peer_id);
+            }
+            Err(PeerConnectionSendError::BufferIsFilled) => {
+                let mut shared_file = files[file_idx].write().await;
+                shared_file.mark_peer_send_blocked(&peer_id, current_time.clone());
+                assignments.exclude(peer_id);
+            }
+            // Pieces are resent once the peer announces its piece key.
+            Err(err @ PeerConnectionSendError::PieceKeyIsNotConfirmed) => {
+                log_scoped!(debug in LogScope::peer(peer_id).with_file(sha256), "{}", err);
+            }
+            Err(PeerConnectionSendError::PeerError(err)) => {
+                log_scoped!(error in LogScope::peer(peer_id).with_file(sha256), "{}", err);
+            }
+        }
+    }
+}
+
+/// Returns upgraded files of the files map, holding its lock only while they are collected.
+pub async fn snapshot_files<F>(
+    files: &RwLock<HashMap<FileSha256, Weak<RwLock<F>>>>,
+) -> Vec<Arc<RwLock<F>>> {
+    files
+        .read()
+        .await
+        .values()
+        .filter_map(Weak::upgrade)
+        .collect()
+}
+
+pub async fn list_files<C, T, const CHUNK_SIZE: usize>(
+    files: &RwLock<HashMap<FileSha256, Weak<RwLock<SharedFile<C, T, CHUNK_SIZE>>>>>,
+) -> Vec<FileMetadata>
+where
+    C: FileChunk,
+{
+    let live_files: Vec<_> = {
+        let mut files = files.write().await;
+        files.retain(|_, file| file.strong_count() > 0);
+        files.values().filter_map(Weak::upgrade).collect()
+    };
+    let mut metadata = Vec::with_capacity(live_files.len());
+    for file in live_files {
+        metadata.push(file.read().await.file().metadata().clone());
+    }
+    metadata.sort_by_key(|metadata| metadata.sha256().0);
+    metadata
+}
+
+/// Returns `true` if any peer of the file has an open data channel.
+fn has_ready_peers<T>(
+    shared_file: &JsSharedFile<T>,
+    peers: &HashMap<PeerId, Arc<RemotePeer<T>>>,
+) -> bool {
+    shared_file
+        .peer_ids()
+        .any(|peer_id| peers.get(peer_id).is_some_and(|peer| peer.is_ready()))
+}
+
+/// Resets the local state status of a peer that became ready and sends the full local state
+/// to it immediately instead of on the next send tick,
+/// so that the state is received before any recent pieces.
+///
+/// The status stays not sent, so the state is sent again on the tick unless it is received before.
+pub fn on_file_peer_ready<C, T, P, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    remote_peer: &P,
+) where
+    P: PeerTransport,
+{
+    use crate::SharedFileLocalStateStatus;
+
+    let peer_id = remote_peer.peer_id();
+    if let Ok(status) = shared_file.local_state_status_mut(&peer_id) {
+        *status = SharedFileLocalStateStatus::NotSent;
+        if remote_peer.is_ready() && !shared_file.is_peer_blocked(&peer_id) {
+            send_local_state(shared_file, remote_peer);
+        }
+    }
+}
+
+pub fn on_file_message<C, T, P, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    remote_peer: &P,
+    message: PeerPeerMessage,
+) where
+    C: FileChunk,
+    T: Ord,
+    P: PeerTransport,
+{
+    use crate::ok_or_log::OrLog;
+    use crate::{
+        unwrap_or_return, IgnoreEmpty, OkOrLog, SharedFileAddPeerError, SharedFileLocalStateStatus,
+        SharedFileMarkStatus, SharedFileStateChunkStatus, FILE_STATE_CHUNK_LEN,
+    };
+
+    let peer_id = remote_peer.peer_id();
+    let _scope = LogScope::peer(peer_id).with_file(message.sha256()).enter();
+    match shared_file.add_peer(peer_id) {
+        // The peer becomes known only with its first file message, so the local state is sent back.
+        Ok(()) => on_file_peer_ready(shared_file, remote_peer),
+        Err(SharedFileAddPeerError::PeerIsAlreadyAdded) => {}
+    };
+    // The transfer mode may be announced after the first file messages over an unordered channel.
+    let pulls_pieces = remote_peer.piece_transfer().upload == PieceTransferMode::Pull;
+    shared_file
+        .set_peer_pulls_pieces(&peer_id, pulls_pieces)
+        .ok_or_log()
+        .ignore_empty();
+
+    match message {
+        PeerPeerMessage::FileMissing { sha256, seq } => {
+            if !accept_state_seq(shared_file, peer_id, seq) {
+                return;
+            }
+            shared_file
+                .set_peer_file_missing(peer_id)
+                .ok_or_log()
+                .ignore_empty();
+            remote_peer
+                .send(PeerPeerMessage::FileStateReceived { sha256 })
+                .or_log();
+        }
+        PeerPeerMessage::FileComplete { sha256, seq } => {
+            if !accept_state_seq(shared_file, peer_id, seq) {
+                return;
+            }
+            shared_file
+                .set_peer_file_complete(peer_id)
+                .ok_or_log()
+                .ignore_empty();
+            remote_peer
+                .send(PeerPeerMessage::FileStateReceived { sha256 })
+                .or_log();
+        }
+        PeerPeerMessage::FileState { sha256, seq, state } => {
+            if !accept_state_seq(shared_file, peer_id, seq) {
+                return;
+            }
+            let state = unwrap_or_return!(
+                FileState::from_bytes(&state, shared_file.num_pieces()).ok_or_log()
+            );
+            shared_file
+                .set_peer_state(peer_id, state)
+                .ok_or_log()
+                .ignore_empty();
+            remote_peer
+                .send(PeerPeerMessage::FileStateReceived { sha256 })
+                .or_log();
+        }
+        PeerPeerMessage::FileStateChunk {
+            sha256,
+            seq,
+            num_pieces,
+            offset,
+            state,
+        } => {
+            if !accept_state_seq(shared_file, peer_id, seq) {
+                return;
+            }
+            let chunk_len = num_pieces.saturating_sub(offset).min(FILE_STATE_CHUNK_LEN);
+            let state =
+                unwrap_or_return!(FileState::bits_from_bytes(&state, chunk_len).ok_or_log());
+            let status = shared_file
+                .set_peer_state_chunk(peer_id, num_pieces, offset, &state)
+                .ok_or_log();
+            if status == Some(SharedFileStateChunkStatus::Complete) {
+                remote_peer
+                    .send(PeerPeerMessage::FileStateReceived { sha256 })
+                    .or_log();
+            }
+        }
+        PeerPeerMessage::FileStateReceived { sha256: _ } => {
+            shared_file
+                .local_state_status_mut(&peer_id)
+                .ok_or_log()
+                .map(|status| *status = SharedFileLocalStateStatus::Received)
+                .ignore_empty();
+        }
+        PeerPeerMessage::FilePiece {
+            sha256: _,
+            piece_idx,
+            bytes,
+        } => {
+            add_remote_piece(shared_file, piece_idx, &bytes);
+        }
+        PeerPeerMessage::FilePieceBatch { sha256: _, pieces } => {
+            for (piece_idx, bytes) in pieces {
+                add_remote_piece(shared_file, piece_idx, &bytes);
+            }
+        }
+        PeerPeerMessage::CompressedFilePieceBatch { sha256: _, pieces } => {
+            use crate::decompress_piece;
+
+            for (piece_idx, len, bytes) in pieces {
+                if let Some(bytes) = decompress_piece(&bytes, len).ok_or_log() {
+                    add_remote_piece(shared_file, piece_idx, &bytes);
+                }
+            }
+        }
+        PeerPeerMessage::FilePiecesReceived { sha256: _, pieces } => {
+            for piece in pieces {
+                let _: Option<SharedFileMarkStatus> = shared_file
+                    .mark_peer_piece_as_received_by_remote(&peer_id, piece)
+                    .ok_or_log();
+            }
+        }
+        PeerPeerMessage::FileRemoved { sha256: _ } => {
+            shared_file.remove_peer(&peer_id).ok_or_log().ignore_empty();
+        }
+        // Requested pieces are sent within the upload budget by the next sending cycle.
+        PeerPeerMessage::RequestPiece {
+            sha256: _,
+            piece_idx,
+        } => {
+            shared_file
+                .queue_piece_request(&peer_id, piece_idx)
+                .ok_or_log()
+                .ignore_empty();
+        }
+        // Connection messages are handled and encrypted pieces are decrypted by `RemotePeer`.
+        PeerPeerMessage::DataChannel { .. }
+        | PeerPeerMessage::Hello { .. }
+        | PeerPeerMessage::PieceEncryption { .. }
+        | PeerPeerMessage::PieceTransfer { .. }
+        | PeerPeerMessage::Ping
+        | PeerPeerMessage::Pong
+        | PeerPeerMessage::EncryptedFilePieceBatch { .. } => {}
+    }
+}
+
+/// Marks file peers from `ponged_peers` as seen at `current_time`
+/// and peers last seen before `unresponsive_before` as unresponsive.
+pub fn update_file_peers_liveness<C, T, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    ponged_peers: &HashSet<PeerId>,
+    current_time: T,
+    unresponsive_before: &T,
+) where
+    T: Clone + Ord,
+{
+    use crate::ok_or_log::OrLog;
+
+    for peer_id in ponged_peers {
+        if shared_file.has_peer(*peer_id) {
+            shared_file
+                .mark_peer_seen(peer_id, current_time.clone())
+                .or_log();
+        }
+    }
+    let sha256 = shared_file.file().sha256();
+    for peer_id in shared_file.mark_peers_unresponsive_before(unresponsive_before) {
+        log_scoped!(
+            warn in LogScope::peer(peer_id).with_file(Some(sha256)),
+            "peer does not answer pings, pieces are not sent to it until it answers"
+        );
+    }
+}
+
+/// Requests the rarest missing pieces from ready peers that pieces are pulled from.
+pub fn request_file_pieces<'a, C, T, P, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    peers: impl IntoIterator<Item = &'a P>,
+    max_pending_requests: usize,
+    current_time: T,
+) where
+    C: FileChunk,
+    T: Clone,
+    P: 'a + PeerTransport,
+{
+    use crate::ok_or_log::OrLog;
+
+    let peers: HashMap<_, _> = peers
+        .into_iter()
+        .filter(|peer| {
+            peer.is_ready()
+                && peer.piece_transfer().download == PieceTransferMode::Pull
+                && shared_file.has_peer(peer.peer_id())
+        })
+        .map(|peer| (peer.peer_id(), peer))
+        .collect();
+    if peers.is_empty() {
+        return;
+    }
+
+    let pull_peers = peers.keys().copied().collect();
+    let sha256 = shared_file.file().sha256();
+    for (peer_id, piece_idx) in
+        shared_file.select_piece_requests(&pull_peers, max_pending_requests, current_time)
+    {
+        peers[&peer_id]
+            .send(PeerPeerMessage::RequestPiece { sha256, piece_idx })
+            .or_log();
+    }
+}
+
+/// Returns `false` and logs if the peer state message is older than the last accepted one.
+fn accept_state_seq<C, T, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    peer_id: PeerId,
+    seq: u64,
+) -> bool {
+    use crate::{OkOrLog, SharedFileStateSeqStatus};
+
+    match shared_file.accept_peer_state_seq(&peer_id, seq).ok_or_log() {
+        Some(SharedFileStateSeqStatus::Accepted) => true,
+        Some(SharedFileStateSeqStatus::Outdated) => {
+            log::debug!("outdated file state {} is ignored", seq);
+            false
+        }
+        None => false,
+    }
+}
+
+pub fn send_file_state<C, T, P, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    remote_peer: &P,
+    resend_before: &T,
+    current_time: &T,
+) where
+    T: Clone + PartialOrd,
+    P: PeerTransport,
+{
+    use crate::{LocalStateStatusError, SharedFileLocalStateStatus};
+
+    let sha256 = shared_file.file().sha256();
+    let _scope = LogScope::peer(remote_peer.peer_id())
+        .with_file(sha256)
+        .enter();
+    if shared_file.is_peer_blocked(&remote_peer.peer_id()) {
+        return;
+    }
+    let local_state_status = match shared_file.local_state_status_mut(&remote_peer.peer_id()) {
+        Ok(status) => status,
+        Err(LocalStateStatusError::PeerIsNotAdded) => unreachable!(),
+    };
+    let should_resend = match local_state_status {
+        SharedFileLocalStateStatus::NotSent => true,
+        SharedFileLocalStateStatus::Sent(time) => *time <= *resend_before,
+        SharedFileLocalStateStatus::Received => false,
+    };
+    if should_resend && remote_peer.is_ready() {
+        *local_state_status = SharedFileLocalStateStatus::Sent(current_time.clone());
+        send_local_state(shared_file, remote_peer);
+    }
+}
+
+fn send_local_state<C, T, P, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    remote_peer: &P,
+) where
+    P: PeerTransport,
+{
+    use crate::ok_or_log::OrLog;
+    use crate::FILE_STATE_CHUNK_LEN;
+
+    let sha256 = shared_file.file().sha256();
+    let seq = shared_file.next_state_seq();
+    let file = shared_file.file();
+    let state = file.state();
+    // Empty files are both missing and complete, so they are announced as complete.
+    if state.is_complete() {
+        remote_peer
+            .send(PeerPeerMessage::FileComplete { sha256, seq })
+            .or_log();
+    } else if state.is_missing() {
+        remote_peer
+            .send(PeerPeerMessage::FileMissing { sha256, seq })
+            .or_log();
+    } else if state.len() <= FILE_STATE_CHUNK_LEN {
+        remote_peer
+            .send(PeerPeerMessage::FileState {
+                sha256,
+                seq,
+                state: state.to_bytes(),
+            })
+            .or_log();
+    } else {
+        for (j, chunk) in state.raw().chunks(FILE_STATE_CHUNK_LEN).enumerate() {
+            remote_peer
+                .send(PeerPeerMessage::FileStateChunk {
+                    sha256,
+                    seq,
+                    num_pieces: state.len(),
+                    offset: j * FILE_STATE_CHUNK_LEN,
+                    state: FileState::bits_to_bytes(chunk),
+                })
+                .or_log();
+        }
+    }
+}
+
+/// Returns peers that are not added to any of the files
+/// and were already idle at the previous call,
+/// the remaining idle peers are stored in `idle_peers`.
+///
+/// Peers get a grace period because a newly connected peer
+/// is added to a file only after its first file message.
+pub fn select_idle_peers<C, T, const CHUNK_SIZE: usize>(
+    peer_ids: impl IntoIterator<Item = PeerId>,
+    shared_files: &[&SharedFile<C, T, CHUNK_SIZE>],
+    idle_peers: &mut HashSet<PeerId>,
+) -> Vec<PeerId> {
+    let mut pruned_peers = Vec::new();
+    let mut next_idle_peers = HashSet::new();
+    for peer_id in peer_ids {
+        if shared_files
+            .iter()
+            .any(|shared_file| shared_file.has_peer(peer_id))
+        {
+            continue;
+        }
+        if idle_peers.contains(&peer_id) {
+            pruned_peers.push(peer_id);
+        } else {
+            let _: bool = next_idle_peers.insert(peer_id);
+        }
+    }
+    *idle_peers = next_idle_peers;
+    pruned_peers
+}
+
+/// Splits the pieces budget between files proportionally to their priorities.
+///
+/// The remainder goes to files with the largest fractional shares,
+/// so the whole budget is allocated unless all priorities are zero.
+pub fn split_pieces_budget(budget: usize, priorities: &[u8]) -> Vec<usize> {
+    let total: usize = priorities
+        .iter()
+        .map(|&priority| usize::from(priority))
+        .sum();
+    if total == 0 {
+        return vec![0; priorities.len()];
+    }
+
+    let mut budgets: Vec<_> = priorities
+        .iter()
+        .map(|&priority| budget * usize::from(priority) / total)
+        .collect();
+    let mut remainders: Vec<_> = priorities
+        .iter()
+        .enumerate()
+        .filter(|(_, &priority)| priority > 0)
+        .map(|(idx, &priority)| (budget * usize::from(priority) % total, idx))
+        .collect();
+    remainders.sort_by(|lhs, rhs| rhs.0.cmp(&lhs.0).then(lhs.1.cmp(&rhs.1)));
+
+    let num_allocated: usize = budgets.iter().sum();
+    for &(_, idx) in remainders.iter().take(budget - num_allocated) {
+        budgets[idx] += 1;
+    }
+    budgets
+}
+
+/// Returns the peer with the lowest rarity score.
+pub fn select_peer_to_close(peers: impl IntoIterator<Item = (PeerId, f64)>) -> Option<PeerId> {
+    peers
+        .into_iter()
+        .min_by(|(_, lhs), (_, rhs)| lhs.total_cmp(rhs))
+        .map(|(peer_id, _)| peer_id)
+}
+
+/// Returns the peer with the given id, creating it if it is not added yet.
+///
+/// The write lock is held until the peer is created,
+/// so concurrent calls for the same peer create a single connection.
+pub async fn get_or_create_peer<P, E, F>(
+    peers: &RwLock<HashMap<PeerId, Arc<P>>>,
+    peer_id: PeerId,
+    create: impl FnOnce() -> F,
+) -> Result<Arc<P>, E>
+where
+    F: Future<Output = Result<Arc<P>, E>>,
+{
+    use std::collections::hash_map::Entry;
+
+    match peers.write().await.entry(peer_id) {
+        Entry::Occupied(entry) => Ok(Arc::clone(entry.get())),
+        Entry::Vacant(entry) => {
+            let peer = create().await?;
+            let _: &mut _ = entry.insert(Arc::clone(&peer));
+            Ok(peer)
+        }
+    }
+}
+
+/// Adds the peer which requested an offer to the shared file,
+/// the remote peer is created only if it is not connected yet, e.g. for its first requested file.
+pub async fn on_request_offer<C, T, P, E, F, const CHUNK_SIZE: usize>(
+    peers: &RwLock<HashMap<PeerId, Arc<P>>>,
+    shared_file: &RwLock<SharedFile<C, T, CHUNK_SIZE>>,
+    peer_id: PeerId,
+    create: impl FnOnce() -> F,
+) -> Result<(), E>
+where
+    P: PeerTransport,
+    F: Future<Output = Result<Arc<P>, E>>,
+{
+    use crate::OkOrLog;
+
+    let remote_peer = get_or_create_peer(peers, peer_id, create).await?;
+    let mut shared_file = shared_file.write().await;
+    // The local state is sent immediately if the data channel is already open.
+    if shared_file.add_peer(peer_id).ok_or_log().is_some() {
+        on_file_peer_ready(&mut shared_file, &*remote_peer);
+    }
+    Ok(())
+}
+
+fn add_remote_piece<C, T, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    piece_idx: FilePieceIdx,
+    bytes: &[u8],
+) where
+    C: FileChunk,
+{
+    use crate::{IgnoreEmpty, OkOrLog};
+
+    if !shared_file
+        .file()
+        .has_piece(&piece_idx)
+        .ok_or_log()
+        .unwrap_or(false)
+    {
+        shared_file
+            .add_local_piece(piece_idx, bytes)
+            .ok_or_log()
+            .ignore_empty();
+    }
+}
+
+/// Returns `None` if the piece is missing only on peers that have reached the assignment cap
+/// or if the piece can not be read, e.g. if its chunk is released.
+pub fn select_file_piece<C, T, const CHUNK_SIZE: usize>(
+    shared_file: &mut SharedFile<C, T, CHUNK_SIZE>,
+    piece_idx: FilePieceIdx,
+    current_time: T,
+    assignments: &mut PeerAssignments,
+) -> Option<(PeerId, Box<[u8]>)>
+where
+    C: FileChunk,
+    T: Clone + Ord,
+{
+    use crate::{OkOrLog, SharedFileSelectPiecePeerError};
+
+    // The piece is read before a peer is selected, so unreadable pieces stay queued
+    // and are not marked as sent.
+    let bytes = match shared_file.read_piece(&piece_idx).ok_or_log()? {
+        Some(bytes) => bytes,
+        None => {
+            log::warn!("queued piece {} is not available", piece_idx.0);
+            return None;
+        }
+    };
+    let peer_id = match shared_file.select_piece_peer_excluding(
+        piece_idx,
+        current_time,
+        assignments.capped_peers(),
+    ) {
+        Ok(peer_id) => peer_id,
+        Err(SharedFileSelectPiecePeerError::AllPeersAreExcluded) => return None,
+        Err(err) => {
+            log::warn!("queued piece {} is not selected: {}", piece_idx.0, err);
+            return None;
+        }
+    };
+    assignments.assign(peer_id);
+    Some((peer_id, bytes))
+}
+
+/// Number of pieces assigned to each peer during a single sending cycle.
+#[derive(Clone, Debug, Default)]
+pub struct PeerAssignments {
+    max_pieces_per_peer: Option<usize>,
+    num_pieces: HashMap<PeerId, usize>,
+    capped_peers: HashSet<PeerId>,
+}
+
+impl PeerAssignments {
+    pub fn new(max_pieces_per_peer: Option<usize>) -> Self {
+        Self {
+            max_pieces_per_peer,
+            num_pieces: HashMap::new(),
+            capped_peers: HashSet::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn num_pieces(&self, peer_id: &PeerId) -> usize {
+        self.num_pieces.get(peer_id).copied().unwrap_or(0)
+    }
+
+    /// Returns peers that have been assigned `max_pieces_per_peer` pieces or excluded.
+    pub fn capped_peers(&self) -> &HashSet<PeerId> {
+        &self.capped_peers
+    }
+
+    /// Excludes the peer from further assignments, e.g. if its send buffer is filled.
+    pub fn exclude(&mut self, peer_id: PeerId) {
+        let _: bool = self.capped_peers.insert(peer_id);
+    }
+
+    pub fn assign(&mut self, peer_id: PeerId) {
+        let num_pieces = self.num_pieces.entry(peer_id).or_default();
+        *num_pieces += 1;
+        if self
+            .max_pieces_per_peer
+            .is_some_and(|max_pieces| *num_pieces >= max_pieces)
+        {
+            let _: bool = self.capped_peers.insert(peer_id);
+        }
+    }
+}
+
+/// Transfer statistics of all local peer files.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct LocalPeerStats {
+    pub num_files: usize,
+    pub num_peers: usize,
+    pub total_bytes_up: u64,
+    pub total_bytes_down: u64,
+    pub files_complete: usize,
+}
+
+impl LocalPeerStats {
+    pub fn add_file<C, T, const CHUNK_SIZE: usize>(
+        &mut self,
+        shared_file: &SharedFile<C, T, CHUNK_SIZE>,
+    ) {
+        self.num_files += 1;
+        self.total_bytes_up += shared_file.uploaded_bytes();
+        self.total_bytes_down += shared_file.downloaded_bytes();
+        if shared_file.file().state().is_complete() {
+            self.files_complete += 1;
         }
     }
 }
@@ -450,4 +1793,682 @@ impl<T> LocalPeer<T> {
 pub enum LocalPeerAddFileError {
     #[error("file is already added")]
     AlreadyAdded,
+    #[error("no more than {max_files} files can be shared")]
+    TooManyFiles { max_files: usize },
+    #[error(transparent)]
+    SharedFileNewError(#[from] SharedFileNewError),
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum LocalPeerImportSessionError {
+    #[error(transparent)]
+    SessionFileError(#[from] SessionFileError),
+    #[error(transparent)]
+    AddFileError(#[from] LocalPeerAddFileError),
+}
+
+#[cfg(test)]
+fn test_local_peer() -> (Arc<LocalPeer<u32>>, crate::mock_transport::MockTracker) {
+    use crate::mock_transport::MockTracker;
+
+    let tracker = MockTracker::new(PeerId(0));
+    let local_peer = LocalPeer::with_transport(
+        Box::new(tracker.clone()),
+        Vec::new(),
+        IceCandidatePolicy::All,
+        DataChannelConfig::default(),
+        "room".to_owned(),
+        4,
+    );
+    (local_peer, tracker)
+}
+
+#[cfg(test)]
+fn empty_file(seed: u8) -> JsFile {
+    use crate::{File, FileLen};
+
+    // Empty files have no chunks, so they are created without a browser.
+    let metadata = FileMetadata::new(FileSha256([seed; 32]), "empty".to_owned(), FileLen(0));
+    File::new(metadata).unwrap()
+}
+
+#[test]
+fn select_idle_peers_after_grace_period() {
+    use crate::{File, FileLen, FILE_CHUNK_SIZE};
+
+    let new_shared_file = |seed| -> SharedFile<Box<[u8]>, u32, FILE_CHUNK_SIZE> {
+        let metadata = FileMetadata::new(
+            FileSha256([seed; 32]),
+            format!("filename{}", seed),
+            FileLen(1000),
+        );
+        SharedFile::new(File::new(metadata).unwrap()).unwrap()
+    };
+    let mut first_file = new_shared_file(1);
+    let mut second_file = new_shared_file(2);
+    first_file.add_peer(PeerId(1)).unwrap();
+    second_file.add_peer(PeerId(2)).unwrap();
+
+    let peer_ids = [PeerId(1), PeerId(2), PeerId(3)];
+    let mut idle_peers = HashSet::new();
+
+    let pruned = select_idle_peers(peer_ids, &[&first_file, &second_file], &mut idle_peers);
+    assert_eq!(pruned, []);
+    assert_eq!(idle_peers, HashSet::from([PeerId(3)]));
+
+    second_file.remove_peer(&PeerId(2)).unwrap();
+    let pruned = select_idle_peers(peer_ids, &[&first_file, &second_file], &mut idle_peers);
+    assert_eq!(pruned, [PeerId(3)]);
+    assert_eq!(idle_peers, HashSet::from([PeerId(2)]));
+
+    // The peer is retained if it is added to a file again during the grace period.
+    second_file.add_peer(PeerId(2)).unwrap();
+    let pruned = select_idle_peers(
+        [PeerId(1), PeerId(2)],
+        &[&first_file, &second_file],
+        &mut idle_peers,
+    );
+    assert_eq!(pruned, []);
+    assert!(idle_peers.is_empty());
+
+    let pruned = select_idle_peers([PeerId(1), PeerId(2)], &[&first_file], &mut idle_peers);
+    assert_eq!(pruned, []);
+    let pruned = select_idle_peers([PeerId(1), PeerId(2)], &[&first_file], &mut idle_peers);
+    assert_eq!(pruned, [PeerId(2)]);
+}
+
+#[test]
+fn split_pieces_budget_by_priority() {
+    assert_eq!(split_pieces_budget(12, &[1, 3]), [3, 9]);
+    assert_eq!(split_pieces_budget(10, &[1, 3]), [3, 7]);
+    assert_eq!(split_pieces_budget(10, &[2, 0, 2]), [5, 0, 5]);
+    assert_eq!(split_pieces_budget(1, &[1, 1]), [1, 0]);
+    assert_eq!(split_pieces_budget(10, &[0, 0]), [0, 0]);
+    assert_eq!(split_pieces_budget(0, &[1, 3]), [0, 0]);
+    assert!(split_pieces_budget(10, &[]).is_empty());
+}
+
+#[test]
+fn spread_pieces_between_peers_with_assignment_cap() {
+    use crate::{File, FileLen, FileMetadata, SharedFileMarkStatus, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 100;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 4;
+    const BUDGET: usize = 10;
+
+    let new_shared_file = || {
+        let metadata = FileMetadata::new(
+            FileSha256(Default::default()),
+            "filename".to_owned(),
+            FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+        );
+        let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+        let mut shared_file: SharedFile<_, usize, CHUNK_LEN> = SharedFile::new(file).unwrap();
+        for j in 0..NUM_PIECES {
+            shared_file
+                .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+                .unwrap();
+        }
+        for peer_id in [PeerId(1), PeerId(2)] {
+            shared_file.add_peer(peer_id).unwrap();
+            shared_file.set_peer_file_missing(peer_id).unwrap();
+        }
+
+        // Only the first peer acknowledges pieces, so it is much faster than the second one.
+        for _ in 0..BUDGET {
+            let piece_idx = shared_file.piece_queues().next_queue().unwrap().1[0];
+            let peer_id = shared_file.select_piece_peer(piece_idx, 0).unwrap();
+            if peer_id == PeerId(1) {
+                let _: SharedFileMarkStatus = shared_file
+                    .mark_peer_piece_as_received_by_remote(&peer_id, piece_idx)
+                    .unwrap();
+            }
+        }
+        shared_file.update_peer_rates(Duration::from_secs(1));
+        shared_file
+    };
+
+    let assign_budget = |max_pieces_per_peer| {
+        let mut shared_file = new_shared_file();
+        let mut assignments = PeerAssignments::new(max_pieces_per_peer);
+        for _ in 0..BUDGET {
+            let piece_idx = shared_file.piece_queues().next_queue().unwrap().1[0];
+            let _: Option<_> = select_file_piece(&mut shared_file, piece_idx, 1, &mut assignments);
+        }
+        assignments
+    };
+
+    let assignments = assign_budget(None);
+    assert!(assignments.num_pieces(&PeerId(1)) > BUDGET / 2);
+    assert!(assignments.capped_peers().is_empty());
+
+    let assignments = assign_budget(Some(BUDGET / 2));
+    assert_eq!(assignments.num_pieces(&PeerId(1)), BUDGET / 2);
+    assert_eq!(assignments.num_pieces(&PeerId(2)), BUDGET / 2);
+    assert_eq!(assignments.capped_peers().len(), 2);
+
+    // Peers with filled send buffers are skipped for the rest of the cycle.
+    let mut shared_file = new_shared_file();
+    let mut assignments = PeerAssignments::new(None);
+    assignments.exclude(PeerId(1));
+    for _ in 0..BUDGET {
+        let piece_idx = shared_file.piece_queues().next_queue().unwrap().1[0];
+        let selected = select_file_piece(&mut shared_file, piece_idx, 1, &mut assignments);
+        assert_eq!(selected.map(|(peer_id, _)| peer_id), Some(PeerId(2)));
+    }
+}
+
+#[test]
+fn skip_unreadable_selected_pieces() {
+    use crate::{File, FileLen, FileMetadata, FILE_CHUNK_SIZE, FILE_PIECE_SIZE};
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((2 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, usize, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    for j in 0..2 {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file.set_peer_file_missing(PeerId(1)).unwrap();
+
+    // The chunk is released while its pieces are still queued.
+    shared_file
+        .shared_file()
+        .borrow_mut()
+        .release_chunk(0)
+        .unwrap();
+    let mut assignments = PeerAssignments::new(None);
+    let selected = select_file_piece(&mut shared_file, FilePieceIdx(0), 1, &mut assignments);
+    assert!(selected.is_none());
+    assert!(assignments.capped_peers().is_empty());
+    assert_eq!(
+        shared_file.peer_missing_pieces(&PeerId(1)),
+        Ok(vec![FilePieceIdx(0), FilePieceIdx(1)])
+    );
+}
+
+#[test]
+fn handle_tracker_messages_through_custom_transport() {
+    use async_std::task::block_on;
+    use core::task::Poll;
+    use futures::poll;
+    use tracker_protocol::PROTOCOL_VERSION;
+
+    let (local_peer, tracker) = test_local_peer();
+    assert!(tracker.has_handler());
+
+    block_on(async {
+        // The peer id assigned by a tracker with an unsupported protocol version is refused.
+        local_peer
+            .on_tracker_message(TrackerPeerMessage::PeerIdAssigned {
+                peer_id: PeerId(2),
+                protocol_version: PROTOCOL_VERSION - 1,
+            })
+            .await;
+        assert_eq!(local_peer.peer_id(), None);
+
+        local_peer
+            .on_tracker_message(TrackerPeerMessage::PeerIdAssigned {
+                peer_id: PeerId(3),
+                protocol_version: PROTOCOL_VERSION,
+            })
+            .await;
+        assert_eq!(local_peer.peer_id(), Some(PeerId(3)));
+
+        let sha256 = FileSha256([1; 32]);
+        let mut query = Box::pin(local_peer.query_swarm(sha256));
+        assert_eq!(poll!(query.as_mut()), Poll::Pending);
+        assert_eq!(
+            tracker.sent_messages(),
+            [PeerTrackerMessage::QuerySwarm {
+                room: "room".to_owned(),
+                file_sha256: sha256,
+            }]
+        );
+
+        local_peer
+            .on_tracker_message(TrackerPeerMessage::SwarmInfo {
+                file_sha256: sha256,
+                peer_count: 12,
+            })
+            .await;
+        assert_eq!(query.await, 12);
+    });
+}
+
+#[test]
+fn drop_self_targeted_tracker_messages() {
+    use async_std::task::block_on;
+    use tracker_protocol::PROTOCOL_VERSION;
+
+    let (local_peer, _) = test_local_peer();
+    let sha256 = FileSha256([1; 32]);
+    let shared_file = Arc::new(RwLock::new(SharedFile::new(empty_file(1)).unwrap()));
+
+    block_on(async {
+        let _: Option<_> = local_peer
+            .files
+            .write()
+            .await
+            .insert(sha256, Arc::downgrade(&shared_file));
+        local_peer
+            .on_tracker_message(TrackerPeerMessage::PeerIdAssigned {
+                peer_id: PeerId(3),
+                protocol_version: PROTOCOL_VERSION,
+            })
+            .await;
+
+        // Creating a remote peer would require a browser, so the test fails if it is attempted.
+        local_peer
+            .on_tracker_message(TrackerPeerMessage::RequestOffer {
+                peer_id: PeerId(3),
+                file_sha256: sha256,
+            })
+            .await;
+        assert_eq!(shared_file.read().await.peer_ids().count(), 0);
+        assert!(local_peer.peers.read().await.is_empty());
+        assert!(local_peer.connection_queue.read().await.is_empty());
+    });
+}
+
+#[test]
+fn answer_offer_requests_of_connected_peer() {
+    use crate::mock_transport::MockTracker;
+    use crate::{
+        File, FileLen, PeerConnectionSendError, PeerError, PieceTransfer, FILE_CHUNK_SIZE,
+        FILE_PIECE_SIZE,
+    };
+    use async_std::task::block_on;
+    use tracker_protocol::{SdpType, SessionDescription};
+
+    #[derive(Debug)]
+    struct RecordingPeer {
+        peer_id: PeerId,
+        sent: RefCell<Vec<PeerPeerMessage>>,
+    }
+
+    impl PeerTransport for RecordingPeer {
+        fn peer_id(&self) -> PeerId {
+            self.peer_id
+        }
+
+        fn is_ready(&self) -> bool {
+            true
+        }
+
+        fn send(&self, message: PeerPeerMessage) -> Result<(), PeerError> {
+            self.sent.borrow_mut().push(message);
+            Ok(())
+        }
+
+        fn send_file_pieces(
+            &self,
+            _: FileSha256,
+            _: Vec<(FilePieceIdx, Box<[u8]>)>,
+            _: bool,
+            _: Option<u64>,
+        ) -> Result<(), PeerConnectionSendError> {
+            Ok(())
+        }
+
+        fn piece_transfer(&self) -> PieceTransfer {
+            PieceTransfer::default()
+        }
+
+        fn take_pong(&self) -> bool {
+            false
+        }
+    }
+
+    let new_shared_file = |seed| {
+        let metadata = FileMetadata::new(
+            FileSha256([seed; 32]),
+            format!("filename{}", seed),
+            FileLen(FILE_PIECE_SIZE as u64),
+        );
+        let mut shared_file: SharedFile<Box<[u8]>, u32, FILE_CHUNK_SIZE> =
+            SharedFile::new(File::new(metadata).unwrap()).unwrap();
+        shared_file.set_verify_chunks(false);
+        shared_file
+            .add_local_piece(FilePieceIdx(0), &[seed; FILE_PIECE_SIZE])
+            .unwrap();
+        RwLock::new(shared_file)
+    };
+    let files = [new_shared_file(1), new_shared_file(2)];
+
+    let tracker = MockTracker::new(PeerId(3));
+    let peers: RwLock<HashMap<PeerId, Arc<RecordingPeer>>> = RwLock::new(HashMap::new());
+    let peer_id = PeerId(5);
+    // The offer is sent once the remote peer connection is created.
+    let create = || async {
+        tracker.send(PeerTrackerMessage::SendOffer {
+            peer_id,
+            offer: SessionDescription {
+                sdp_type: SdpType::Offer,
+                sdp: "v=0\r\n".to_owned(),
+            },
+        });
+        Ok::<_, PeerError>(Arc::new(RecordingPeer {
+            peer_id,
+            sent: RefCell::new(Vec::new()),
+        }))
+    };
+
+    block_on(async {
+        for file in &files {
+            on_request_offer(&peers, file, peer_id, create)
+                .await
+                .unwrap();
+        }
+
+        // Offers for other files of the connected peer reuse its connection.
+        let offers: Vec<_> = tracker
+            .sent_messages()
+            .into_iter()
+            .filter_map(|message| match message {
+                PeerTrackerMessage::SendOffer { peer_id, offer } => Some((peer_id, offer.sdp_type)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(offers, [(peer_id, SdpType::Offer)]);
+        assert_eq!(peers.read().await.len(), 1);
+
+        // The connected peer is added to every file and receives their local states.
+        let remote_peer = Arc::clone(&peers.read().await[&peer_id]);
+        for file in &files {
+            let file = file.read().await;
+            assert_eq!(file.peer_ids().collect::<Vec<_>>(), [&peer_id]);
+            let sha256 = file.file().sha256();
+            assert!(remote_peer
+                .sent
+                .borrow()
+                .iter()
+                .any(|message| message.sha256() == Some(sha256)));
+        }
+    });
+}
+
+#[test]
+fn reannounce_files_after_tracker_reconnect() {
+    use async_std::task::block_on;
+    use tracker_protocol::PROTOCOL_VERSION;
+
+    let (local_peer, tracker) = test_local_peer();
+    let mut shared_files: Vec<_> = (1..=3)
+        .map(|seed| Arc::new(RwLock::new(SharedFile::new(empty_file(seed)).unwrap())))
+        .collect();
+    let assign_peer_id = |peer_id| TrackerPeerMessage::PeerIdAssigned {
+        peer_id,
+        protocol_version: PROTOCOL_VERSION,
+    };
+
+    block_on(async {
+        let mut files = local_peer.files.write().await;
+        for shared_file in &shared_files {
+            let sha256 = shared_file.read().await.file().sha256();
+            let _: Option<_> = files.insert(sha256, Arc::downgrade(shared_file));
+        }
+        drop(files);
+
+        // The first connection is not a reconnect, files are announced when they are added.
+        local_peer
+            .on_tracker_message(assign_peer_id(PeerId(3)))
+            .await;
+        assert!(tracker.sent_messages().is_empty());
+
+        // Removed files are not announced.
+        drop(shared_files.pop());
+
+        local_peer
+            .on_tracker_message(assign_peer_id(PeerId(7)))
+            .await;
+        assert_eq!(local_peer.peer_id(), Some(PeerId(7)));
+        let mut announced: Vec<_> = tracker
+            .sent_messages()
+            .into_iter()
+            .map(|message| match message {
+                PeerTrackerMessage::RequestOffers { room, file_sha256 } => {
+                    assert_eq!(room, "room");
+                    file_sha256
+                }
+                message => panic!("unexpected message {:?}", message),
+            })
+            .collect();
+        announced.sort_unstable_by_key(|sha256| sha256.0);
+        assert_eq!(announced, [FileSha256([1; 32]), FileSha256([2; 32])]);
+    });
+}
+
+#[test]
+fn refuse_files_above_max_files() {
+    use async_std::task::block_on;
+
+    let (local_peer, _) = test_local_peer();
+    local_peer.set_max_files(Some(2));
+
+    block_on(async {
+        let first = local_peer.add_file(empty_file(1)).await.unwrap();
+        let second = local_peer.add_file(empty_file(2)).await.unwrap();
+        assert_eq!(
+            local_peer.add_file(empty_file(3)).await.err(),
+            Some(LocalPeerAddFileError::TooManyFiles { max_files: 2 })
+        );
+        assert_eq!(
+            local_peer.add_file(empty_file(2)).await.err(),
+            Some(LocalPeerAddFileError::AlreadyAdded)
+        );
+
+        // Dropped files free their slots.
+        drop(first);
+        let third = local_peer.add_file(empty_file(3)).await.unwrap();
+        assert_eq!(
+            local_peer.add_file(empty_file(4)).await.err(),
+            Some(LocalPeerAddFileError::TooManyFiles { max_files: 2 })
+        );
+
+        local_peer.set_max_files(None);
+        let fourth = local_peer.add_file(empty_file(4)).await.unwrap();
+        assert_eq!(local_peer.snapshot_files().await.len(), 3);
+        drop((second, third, fourth));
+    });
+}
+
+#[test]
+fn create_single_peer_on_concurrent_requests() {
+    use async_std::task::{block_on, yield_now};
+    use core::cell::Cell;
+    use futures::join;
+
+    let peers: RwLock<HashMap<PeerId, Arc<u32>>> = RwLock::new(HashMap::new());
+    let num_created = Cell::new(0);
+    let create = || async {
+        num_created.set(num_created.get() + 1);
+        // Concurrent requests are handled while the connection is being created.
+        yield_now().await;
+        Ok::<_, ()>(Arc::new(num_created.get()))
+    };
+
+    block_on(async {
+        let (first, second) = join!(
+            get_or_create_peer(&peers, PeerId(1), create),
+            get_or_create_peer(&peers, PeerId(1), create),
+        );
+        assert!(Arc::ptr_eq(&first.unwrap(), &second.unwrap()));
+        assert_eq!(num_created.get(), 1);
+        assert_eq!(peers.read().await.len(), 1);
+
+        let other = get_or_create_peer(&peers, PeerId(2), create).await.unwrap();
+        assert_eq!(*other, 2);
+    });
+}
+
+#[test]
+fn aggregate_local_peer_stats() {
+    use crate::{File, FileLen, FileMetadata, SharedFileMarkStatus, FILE_PIECE_SIZE};
+
+    const NUM_PIECES: usize = 4;
+
+    let new_file = |seed| -> File<Box<[u8]>, FILE_PIECE_SIZE> {
+        let metadata = FileMetadata::new(
+            FileSha256([seed; 32]),
+            "filename".to_owned(),
+            FileLen((NUM_PIECES * FILE_PIECE_SIZE - 10) as u64),
+        );
+        File::new(metadata).unwrap()
+    };
+
+    // The seeder sends three pieces to the peer, two of them are acknowledged.
+    let mut seeder: SharedFile<_, u32, FILE_PIECE_SIZE> = SharedFile::new(new_file(1)).unwrap();
+    for j in 0..NUM_PIECES {
+        let data = vec![0; seeder.file().piece_len(&FilePieceIdx(j))];
+        seeder.add_local_piece(FilePieceIdx(j), &data).unwrap();
+    }
+    seeder.add_peer(PeerId(1)).unwrap();
+    seeder.set_peer_file_missing(PeerId(1)).unwrap();
+    for j in [0, 3, 1] {
+        assert_eq!(seeder.select_piece_peer(FilePieceIdx(j), 0), Ok(PeerId(1)));
+    }
+    for j in [0, 3] {
+        assert_eq!(
+            seeder.mark_peer_piece_as_received_by_remote(&PeerId(1), FilePieceIdx(j)),
+            Ok(SharedFileMarkStatus::JustMarked)
+        );
+    }
+
+    // The leecher receives two pieces, one of them twice.
+    let mut leecher: SharedFile<_, u32, FILE_PIECE_SIZE> = SharedFile::new(new_file(2)).unwrap();
+    for j in [2, 3, 3] {
+        let data = vec![0; leecher.file().piece_len(&FilePieceIdx(j))];
+        let _: Result<_, _> = leecher.add_local_piece(FilePieceIdx(j), &data);
+    }
+
+    let mut stats = LocalPeerStats::default();
+    stats.add_file(&seeder);
+    stats.add_file(&leecher);
+    assert_eq!(
+        stats,
+        LocalPeerStats {
+            num_files: 2,
+            num_peers: 0,
+            total_bytes_up: (2 * FILE_PIECE_SIZE - 10) as u64,
+            // Seeder pieces are added the same way as received ones.
+            total_bytes_down: (6 * FILE_PIECE_SIZE - 20) as u64,
+            files_complete: 1,
+        }
+    );
+}
+
+#[test]
+fn snapshot_files_under_concurrent_access() {
+    use async_std::future::timeout;
+    use async_std::task::{block_on, yield_now};
+    use core::cell::Cell;
+    use futures::future::{join, join_all};
+
+    const NUM_TASKS: usize = 8;
+    const NUM_ROUNDS: u8 = 32;
+
+    let files: RwLock<HashMap<FileSha256, Weak<RwLock<u32>>>> = RwLock::new(HashMap::new());
+    let kept: RefCell<Vec<Arc<RwLock<u32>>>> = RefCell::new(Vec::new());
+    let is_done = Cell::new(false);
+    let (files, kept, is_done) = (&files, &kept, &is_done);
+
+    // Files are added while the lock of a previously added file is held,
+    // which stalls if other tasks wait for that file while holding the files map.
+    let add_files = async move {
+        for round in 0..NUM_ROUNDS {
+            let prev = snapshot_files(files).await.into_iter().next();
+            let _guard = match &prev {
+                Some(prev) => Some(prev.write().await),
+                None => None,
+            };
+            yield_now().await;
+            let file = Arc::new(RwLock::new(0));
+            let _: Option<_> = files
+                .write()
+                .await
+                .insert(FileSha256([round; 32]), Arc::downgrade(&file));
+            kept.borrow_mut().push(file);
+            if round % 4 == 3 {
+                let _: Arc<_> = kept.borrow_mut().remove(0);
+            }
+        }
+        is_done.set(true);
+    };
+    // The last pass starts after all files are added, so that every kept file is updated.
+    let update_files = |_| async move {
+        loop {
+            let is_last_pass = is_done.get();
+            for file in snapshot_files(files).await {
+                *file.write().await += 1;
+                yield_now().await;
+            }
+            if is_last_pass {
+                break;
+            }
+            yield_now().await;
+        }
+    };
+
+    block_on(async {
+        let updates = join_all((0..NUM_TASKS).map(update_files));
+        let _: ((), Vec<()>) = timeout(Duration::from_secs(10), join(add_files, updates))
+            .await
+            .expect("file locks are stalled");
+
+        let num_kept = usize::from(NUM_ROUNDS) * 3 / 4;
+        assert_eq!(files.read().await.len(), usize::from(NUM_ROUNDS));
+        assert_eq!(snapshot_files(files).await.len(), num_kept);
+        let kept = kept.borrow().clone();
+        for file in kept {
+            assert!(*file.read().await > 0);
+        }
+    });
+}
+
+#[test]
+fn list_live_shared_files() {
+    use crate::{File, FileLen, FILE_CHUNK_SIZE};
+    use async_std::task::block_on;
+
+    let metadata = |seed| {
+        FileMetadata::new(
+            FileSha256([seed; 32]),
+            format!("filename{}", seed),
+            FileLen(1000 * u64::from(seed)),
+        )
+    };
+    let new_shared_file = |seed| -> Arc<RwLock<SharedFile<Box<[u8]>, u32, FILE_CHUNK_SIZE>>> {
+        Arc::new(RwLock::new(
+            SharedFile::new(File::new(metadata(seed)).unwrap()).unwrap(),
+        ))
+    };
+    let kept: Vec<_> = [3, 1, 4].into_iter().map(new_shared_file).collect();
+    let removed = new_shared_file(2);
+
+    let files = RwLock::new(
+        kept.iter()
+            .chain([&removed])
+            .map(|file| (block_on(file.read()).file().sha256(), Arc::downgrade(file)))
+            .collect::<HashMap<_, _>>(),
+    );
+    assert_eq!(
+        block_on(list_files(&files)),
+        [metadata(1), metadata(2), metadata(3), metadata(4)]
+    );
+
+    drop(removed);
+    assert_eq!(
+        block_on(list_files(&files)),
+        [metadata(1), metadata(3), metadata(4)]
+    );
+    assert_eq!(block_on(files.read()).len(), 3);
 }
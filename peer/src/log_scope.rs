@@ -0,0 +1,127 @@
+use core::cell::RefCell;
+use core::fmt::{self, Display};
+use core::marker::PhantomData;
+
+use tracker_protocol::{FileSha256, PeerId};
+
+thread_local! {
+    static LOG_SCOPES: RefCell<Vec<LogScope>> = RefCell::new(Vec::new());
+}
+
+/// Peer and file context prepended to messages logged with `log_scoped!`.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct LogScope {
+    pub peer_id: Option<PeerId>,
+    pub sha256: Option<FileSha256>,
+}
+
+/// Keeps the log scope entered until dropped.
+///
+/// Guards must not be held across await points,
+/// otherwise logs of other tasks would be tagged with this scope.
+#[derive(Debug)]
+pub struct LogScopeGuard {
+    _not_send: PhantomData<*const ()>,
+}
+
+impl LogScope {
+    pub fn peer(peer_id: PeerId) -> Self {
+        Self {
+            peer_id: Some(peer_id),
+            sha256: None,
+        }
+    }
+
+    pub fn file(sha256: FileSha256) -> Self {
+        Self {
+            peer_id: None,
+            sha256: Some(sha256),
+        }
+    }
+
+    pub fn with_file(self, sha256: impl Into<Option<FileSha256>>) -> Self {
+        Self {
+            sha256: sha256.into(),
+            ..self
+        }
+    }
+
+    /// Returns the innermost entered scope, missing fields are taken from outer scopes.
+    pub fn current() -> Self {
+        LOG_SCOPES.with(|scopes| scopes.borrow().last().copied().unwrap_or_default())
+    }
+
+    pub fn enter(self) -> LogScopeGuard {
+        let outer = Self::current();
+        let scope = Self {
+            peer_id: self.peer_id.or(outer.peer_id),
+            sha256: self.sha256.or(outer.sha256),
+        };
+        LOG_SCOPES.with(|scopes| scopes.borrow_mut().push(scope));
+        LogScopeGuard {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for LogScopeGuard {
+    fn drop(&mut self) {
+        let _: Option<_> = LOG_SCOPES.with(|scopes| scopes.borrow_mut().pop());
+    }
+}
+
+/// Formats the scope as a log message prefix, empty scopes are formatted as an empty string.
+impl Display for LogScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.peer_id, self.sha256) {
+            (None, None) => Ok(()),
+            (Some(peer_id), None) => write!(f, "peer {}: ", peer_id),
+            (None, Some(sha256)) => write!(f, "file {}: ", short_sha_hex(&sha256)),
+            (Some(peer_id), Some(sha256)) => {
+                write!(f, "peer {} file {}: ", peer_id, short_sha_hex(&sha256))
+            }
+        }
+    }
+}
+
+fn short_sha_hex(sha256: &FileSha256) -> String {
+    hex::encode_upper(&sha256.0[0..4])
+}
+
+/// Logs the message with the given level macro prefixed by the current `LogScope`.
+///
+/// The `level in scope` form enters the scope only for the message itself,
+/// so it can be used in async functions.
+#[macro_export]
+macro_rules! log_scoped {
+    ( $level:ident in $scope:expr, $($arg:tt)+ ) => {{
+        let _scope: $crate::LogScopeGuard = $crate::LogScope::enter($scope);
+        $crate::log_scoped!($level, $($arg)+)
+    }};
+    ( $level:ident, $($arg:tt)+ ) => {
+        log::$level!("{}{}", $crate::LogScope::current(), format_args!($($arg)+))
+    };
+}
+
+#[test]
+fn nest_log_scopes() {
+    let sha256 = FileSha256([0xAB; 32]);
+
+    assert_eq!(LogScope::current().to_string(), "");
+    {
+        let _peer_scope = LogScope::peer(PeerId(5)).enter();
+        assert_eq!(LogScope::current().to_string(), "peer 5: ");
+        {
+            let _file_scope = LogScope::file(sha256).enter();
+            assert_eq!(LogScope::current().to_string(), "peer 5 file ABABABAB: ");
+            let _peer_scope = LogScope::peer(PeerId(7)).enter();
+            assert_eq!(LogScope::current().to_string(), "peer 7 file ABABABAB: ");
+        }
+        assert_eq!(LogScope::current(), LogScope::peer(PeerId(5)));
+    }
+    assert_eq!(LogScope::current(), LogScope::default());
+    assert_eq!(
+        LogScope::file(sha256).to_string(),
+        LogScope::default().with_file(sha256).to_string()
+    );
+}
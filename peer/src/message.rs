@@ -1,20 +1,44 @@
-use bitvec::boxed::BitBox;
 use serde::{Deserialize, Serialize};
 use tracker_protocol::FileSha256;
 
-use crate::FilePieceIdx;
+use crate::{FilePieceIdx, PieceKeyId, PieceTransferMode};
 
+// RFC 8831 recommends 64 KiB as the message size limit
+// which is supported by all data channel implementations.
+pub const MAX_PEER_MESSAGE_SIZE: usize = 65536;
+
+// File state is sent in chunks of 32 KiB so that it fits into a single message.
+pub const FILE_STATE_CHUNK_LEN: usize = 8 * 32768;
+
+/// Messages sent between peers over the data channel.
+///
+/// File state messages carry a `seq` number that increases with every state sent for the file,
+/// so that a state delayed or reordered by the unordered data channel
+/// does not override a newer one. All chunks of a single state share the same `seq`.
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum PeerPeerMessage {
     FileMissing {
         sha256: FileSha256,
+        seq: u64,
     },
     FileComplete {
         sha256: FileSha256,
+        seq: u64,
     },
+    /// File state packed with `FileState::to_bytes`.
     FileState {
         sha256: FileSha256,
-        state: BitBox,
+        seq: u64,
+        state: Vec<u8>,
+    },
+    /// Up to `FILE_STATE_CHUNK_LEN` pieces of the file state starting from `offset`
+    /// packed with `FileState::bits_to_bytes`.
+    FileStateChunk {
+        sha256: FileSha256,
+        seq: u64,
+        num_pieces: usize,
+        offset: usize,
+        state: Vec<u8>,
     },
     FileStateReceived {
         sha256: FileSha256,
@@ -24,6 +48,10 @@ pub enum PeerPeerMessage {
         piece_idx: FilePieceIdx,
         bytes: Box<[u8]>,
     },
+    FilePieceBatch {
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+    },
     FilePiecesReceived {
         sha256: FileSha256,
         pieces: Vec<FilePieceIdx>,
@@ -31,4 +59,378 @@ pub enum PeerPeerMessage {
     FileRemoved {
         sha256: FileSha256,
     },
+    /// Data channel label and protocol, sent once the channel is open
+    /// so that peers can verify that they use the same channel.
+    DataChannel {
+        label: String,
+        protocol: String,
+    },
+    /// Supported protocol version, sent first once the channel is open.
+    ///
+    /// New variants are appended after this one so that it keeps its encoding.
+    Hello {
+        protocol_version: u16,
+    },
+    /// File pieces compressed with `compress_piece` with their original lengths,
+    /// sent only for files with compression enabled in metadata.
+    CompressedFilePieceBatch {
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, usize, Box<[u8]>)>,
+    },
+    /// Piece key id, sent after `Hello` by peers encrypting pieces with a `PieceCipher`,
+    /// since the `Hello` encoding is kept for older versions.
+    PieceEncryption {
+        key_id: PieceKeyId,
+    },
+    /// File pieces encrypted with `PieceCipher::encrypt`,
+    /// sent only to peers that announced the same piece key.
+    EncryptedFilePieceBatch {
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+    },
+    /// Piece transfer mode requested for pieces sent to the sender,
+    /// sent after `Hello` by peers supporting piece requests,
+    /// since the `Hello` encoding is kept for older versions.
+    PieceTransfer {
+        mode: PieceTransferMode,
+    },
+    /// Requests a piece, sent only to peers that announced `PieceTransfer`.
+    RequestPiece {
+        sha256: FileSha256,
+        piece_idx: FilePieceIdx,
+    },
+    /// Liveness check sent periodically, answered with `Pong` by peers processing messages.
+    Ping,
+    /// Answer to `Ping`.
+    Pong,
+}
+
+impl PeerPeerMessage {
+    /// Returns the file the message relates to, `None` for connection messages.
+    pub fn sha256(&self) -> Option<FileSha256> {
+        match self {
+            Self::FileMissing { sha256, seq: _ }
+            | Self::FileComplete { sha256, seq: _ }
+            | Self::FileState {
+                sha256,
+                seq: _,
+                state: _,
+            }
+            | Self::FileStateChunk {
+                sha256,
+                seq: _,
+                num_pieces: _,
+                offset: _,
+                state: _,
+            }
+            | Self::FileStateReceived { sha256 }
+            | Self::FilePiece {
+                sha256,
+                piece_idx: _,
+                bytes: _,
+            }
+            | Self::FilePieceBatch { sha256, pieces: _ }
+            | Self::CompressedFilePieceBatch { sha256, pieces: _ }
+            | Self::EncryptedFilePieceBatch { sha256, pieces: _ }
+            | Self::FilePiecesReceived { sha256, pieces: _ }
+            | Self::FileRemoved { sha256 }
+            | Self::RequestPiece {
+                sha256,
+                piece_idx: _,
+            } => Some(*sha256),
+            Self::DataChannel { .. }
+            | Self::Hello { .. }
+            | Self::PieceEncryption { .. }
+            | Self::PieceTransfer { .. }
+            | Self::Ping
+            | Self::Pong => None,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, bincode::Error> {
+        bincode::serialize(self)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, bincode::Error> {
+        bincode::deserialize(bytes)
+    }
+
+    /// Returns the encoded message length without encoding it.
+    pub fn encoded_len(&self) -> Result<usize, bincode::Error> {
+        bincode::serialized_size(self).map(|len| len as usize)
+    }
+
+    /// Encodes the message as JSON, e.g. to log it readably, peers exchange bincode messages.
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+/// Coalesces file pieces into as few messages as possible
+/// with serialized size not exceeding `max_message_size` if possible.
+///
+/// If `compress` is set, pieces that shrink are sent compressed in separate batches.
+pub fn file_piece_messages(
+    sha256: FileSha256,
+    pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+    max_message_size: usize,
+    compress: bool,
+) -> Vec<PeerPeerMessage> {
+    use crate::compress_piece;
+
+    if !compress {
+        return batch_pieces(pieces, max_message_size, |pieces| {
+            file_piece_message(sha256, pieces)
+        });
+    }
+
+    let mut raw_pieces = Vec::new();
+    let mut compressed_pieces = Vec::new();
+    for (piece_idx, bytes) in pieces {
+        match compress_piece(&bytes) {
+            Some(compressed) => compressed_pieces.push((piece_idx, bytes.len(), compressed)),
+            None => raw_pieces.push((piece_idx, bytes)),
+        }
+    }
+    let mut messages = batch_pieces(raw_pieces, max_message_size, |pieces| {
+        file_piece_message(sha256, pieces)
+    });
+    messages.extend(batch_pieces(
+        compressed_pieces,
+        max_message_size,
+        |pieces| PeerPeerMessage::CompressedFilePieceBatch { sha256, pieces },
+    ));
+    messages
+}
+
+/// Coalesces pieces encrypted with `PieceCipher::encrypt` like `file_piece_messages`.
+pub fn encrypted_file_piece_messages(
+    sha256: FileSha256,
+    pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+    max_message_size: usize,
+) -> Vec<PeerPeerMessage> {
+    batch_pieces(pieces, max_message_size, |pieces| {
+        PeerPeerMessage::EncryptedFilePieceBatch { sha256, pieces }
+    })
+}
+
+fn batch_pieces<P: Serialize>(
+    pieces: Vec<P>,
+    max_message_size: usize,
+    batch_message: impl Fn(Vec<P>) -> PeerPeerMessage,
+) -> Vec<PeerPeerMessage> {
+    use bincode::serialized_size;
+    use core::mem::take;
+
+    let empty_batch_len = batch_message(Vec::new()).encoded_len().unwrap();
+
+    let mut messages = Vec::new();
+    let mut batch = Vec::new();
+    let mut batch_len = empty_batch_len;
+    for piece in pieces {
+        let piece_len = serialized_size(&piece).unwrap() as usize;
+        if !batch.is_empty() && batch_len + piece_len > max_message_size {
+            messages.push(batch_message(take(&mut batch)));
+            batch_len = empty_batch_len;
+        }
+        batch_len += piece_len;
+        batch.push(piece);
+    }
+    if !batch.is_empty() {
+        messages.push(batch_message(batch));
+    }
+    messages
+}
+
+// Single pieces are sent without batch overhead.
+fn file_piece_message(
+    sha256: FileSha256,
+    mut pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+) -> PeerPeerMessage {
+    if pieces.len() == 1 {
+        let (piece_idx, bytes) = pieces.pop().unwrap();
+        PeerPeerMessage::FilePiece {
+            sha256,
+            piece_idx,
+            bytes,
+        }
+    } else {
+        PeerPeerMessage::FilePieceBatch { sha256, pieces }
+    }
+}
+
+#[test]
+fn batch_file_pieces() {
+    use crate::FILE_PIECE_SIZE;
+    use bincode::serialized_size;
+
+    let sha256 = FileSha256([7; 32]);
+    let pieces: Vec<_> = (0..200)
+        .map(|j| {
+            let bytes = vec![j as u8; FILE_PIECE_SIZE].into_boxed_slice();
+            (FilePieceIdx(j), bytes)
+        })
+        .collect();
+
+    let single_messages: Vec<_> = pieces
+        .iter()
+        .cloned()
+        .map(|(piece_idx, bytes)| PeerPeerMessage::FilePiece {
+            sha256,
+            piece_idx,
+            bytes,
+        })
+        .collect();
+    let messages = file_piece_messages(sha256, pieces.clone(), MAX_PEER_MESSAGE_SIZE, false);
+
+    let messages_len = |messages: &[PeerPeerMessage]| -> u64 {
+        messages
+            .iter()
+            .map(|message| serialized_size(message).unwrap())
+            .sum()
+    };
+    assert_eq!(messages.len(), 4);
+    assert!(messages_len(&messages) < messages_len(&single_messages));
+    for message in &messages {
+        assert!(serialized_size(message).unwrap() as usize <= MAX_PEER_MESSAGE_SIZE);
+    }
+
+    let batched_pieces: Vec<_> = messages
+        .into_iter()
+        .flat_map(|message| match message {
+            PeerPeerMessage::FilePieceBatch { sha256: _, pieces } => pieces,
+            _ => panic!("unexpected message {:?}", message),
+        })
+        .collect();
+    assert_eq!(batched_pieces, pieces);
+
+    let messages = file_piece_messages(sha256, pieces[..1].to_vec(), MAX_PEER_MESSAGE_SIZE, false);
+    assert_eq!(messages, single_messages[..1]);
+}
+
+#[test]
+fn encode_and_decode_every_message() {
+    let sha256 = FileSha256([3; 32]);
+    let messages = [
+        PeerPeerMessage::FileMissing { sha256, seq: 1 },
+        PeerPeerMessage::FileComplete { sha256, seq: 2 },
+        PeerPeerMessage::FileState {
+            sha256,
+            seq: 3,
+            state: vec![1, 0b1010_0101],
+        },
+        PeerPeerMessage::FileStateChunk {
+            sha256,
+            seq: 4,
+            num_pieces: 20,
+            offset: 16,
+            state: vec![0b1111],
+        },
+        PeerPeerMessage::FileStateReceived { sha256 },
+        PeerPeerMessage::FilePiece {
+            sha256,
+            piece_idx: FilePieceIdx(5),
+            bytes: vec![5; 100].into_boxed_slice(),
+        },
+        PeerPeerMessage::FilePieceBatch {
+            sha256,
+            pieces: vec![
+                (FilePieceIdx(6), vec![6; 10].into_boxed_slice()),
+                (FilePieceIdx(7), vec![7; 20].into_boxed_slice()),
+            ],
+        },
+        PeerPeerMessage::FilePiecesReceived {
+            sha256,
+            pieces: vec![FilePieceIdx(6), FilePieceIdx(7)],
+        },
+        PeerPeerMessage::FileRemoved { sha256 },
+        PeerPeerMessage::DataChannel {
+            label: "data".to_owned(),
+            protocol: "v1".to_owned(),
+        },
+        PeerPeerMessage::Hello {
+            protocol_version: 1,
+        },
+        PeerPeerMessage::CompressedFilePieceBatch {
+            sha256,
+            pieces: vec![(FilePieceIdx(8), 1024, vec![8; 30].into_boxed_slice())],
+        },
+        PeerPeerMessage::PieceEncryption {
+            key_id: PieceKeyId([9; 8]),
+        },
+        PeerPeerMessage::EncryptedFilePieceBatch {
+            sha256,
+            pieces: vec![(FilePieceIdx(10), vec![10; 40].into_boxed_slice())],
+        },
+        PeerPeerMessage::PieceTransfer {
+            mode: PieceTransferMode::Pull,
+        },
+        PeerPeerMessage::RequestPiece {
+            sha256,
+            piece_idx: FilePieceIdx(11),
+        },
+        PeerPeerMessage::Ping,
+        PeerPeerMessage::Pong,
+    ];
+
+    for message in messages {
+        let bytes = message.encode().unwrap();
+        assert_eq!(message.encoded_len().unwrap(), bytes.len());
+        assert_eq!(PeerPeerMessage::decode(&bytes).unwrap(), message);
+        let json = message.to_json().unwrap();
+        assert_eq!(PeerPeerMessage::from_json(&json).unwrap(), message);
+    }
+    assert!(PeerPeerMessage::decode(&[0xFF; 4]).is_err());
+    assert!(PeerPeerMessage::from_json("{}").is_err());
+}
+
+#[test]
+fn compress_file_pieces() {
+    use crate::{decompress_piece, FILE_PIECE_SIZE};
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+
+    let sha256 = FileSha256([7; 32]);
+    let mut rng = StdRng::seed_from_u64(0);
+    let pieces: Vec<_> = (0..100)
+        .map(|j| {
+            let mut bytes = vec![j as u8; FILE_PIECE_SIZE].into_boxed_slice();
+            if j % 2 == 1 {
+                rng.fill_bytes(&mut bytes);
+            }
+            (FilePieceIdx(j), bytes)
+        })
+        .collect();
+
+    let messages = file_piece_messages(sha256, pieces.clone(), MAX_PEER_MESSAGE_SIZE, true);
+    let uncompressed = file_piece_messages(sha256, pieces.clone(), MAX_PEER_MESSAGE_SIZE, false);
+    let messages_len = |messages: &[PeerPeerMessage]| -> usize {
+        messages
+            .iter()
+            .map(|message| message.encoded_len().unwrap())
+            .sum()
+    };
+    assert!(messages_len(&messages) < messages_len(&uncompressed));
+
+    let mut received = Vec::new();
+    for message in messages {
+        assert!(message.encoded_len().unwrap() <= MAX_PEER_MESSAGE_SIZE);
+        match message {
+            PeerPeerMessage::FilePieceBatch { sha256: _, pieces } => received.extend(pieces),
+            PeerPeerMessage::CompressedFilePieceBatch { sha256: _, pieces } => {
+                // Only compressible pieces are compressed.
+                for (piece_idx, len, bytes) in pieces {
+                    assert_eq!(piece_idx.0 % 2, 0);
+                    received.push((piece_idx, decompress_piece(&bytes, len).unwrap()));
+                }
+            }
+            _ => panic!("unexpected message {:?}", message),
+        }
+    }
+    received.sort_by_key(|(piece_idx, _)| *piece_idx);
+    assert_eq!(received, pieces);
 }
@@ -1,64 +1,105 @@
 use core::borrow::Borrow;
 use core::fmt::{self, Display};
 
-use tracker_protocol::FileSha256;
-
 use crate::PeerPeerMessage;
 
+/// Short message description, the file is expected to be logged as a part of the `LogScope`.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 pub struct PeerPeerMessageFmt<T>(pub T);
 
-fn short_sha_hex(sha256: &FileSha256) -> String {
-    hex::encode_upper(&sha256.0[0..4])
-}
-
 impl<T> Display for PeerPeerMessageFmt<T>
 where
     T: Borrow<PeerPeerMessage>,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self.0.borrow() {
-            PeerPeerMessage::FileMissing { sha256 } => {
-                write!(f, "{}: file missing", short_sha_hex(sha256))
+            PeerPeerMessage::FileMissing { .. } => {
+                write!(f, "file missing")
             }
-            PeerPeerMessage::FileComplete { sha256 } => {
-                write!(f, "{}: file complete", short_sha_hex(sha256))
+            PeerPeerMessage::FileComplete { .. } => {
+                write!(f, "file complete")
             }
-            PeerPeerMessage::FileState { sha256, state } => {
-                let state: String = state
-                    .iter()
-                    .map(|bit| if *bit { '+' } else { '-' })
-                    .collect();
-                write!(f, "{}: file state: {}", short_sha_hex(sha256), state)
+            PeerPeerMessage::FileState { state, .. } => {
+                write!(f, "file state of {} bytes", state.len())
+            }
+            PeerPeerMessage::FileStateChunk {
+                num_pieces,
+                offset,
+                state,
+                ..
+            } => {
+                write!(
+                    f,
+                    "file state chunk from {} of {} of {} bytes",
+                    offset,
+                    num_pieces,
+                    state.len()
+                )
             }
-            PeerPeerMessage::FileStateReceived { sha256 } => {
-                write!(f, "{}: file state received", short_sha_hex(sha256))
+            PeerPeerMessage::FileStateReceived { .. } => {
+                write!(f, "file state received")
             }
             PeerPeerMessage::FilePiece {
-                sha256,
-                piece_idx,
-                bytes,
+                piece_idx, bytes, ..
             } => {
                 write!(
                     f,
-                    "{}: file piece {} with bytes of length {}",
-                    short_sha_hex(sha256),
+                    "file piece {} with bytes of length {}",
                     piece_idx.0,
                     bytes.len()
                 )
             }
-            PeerPeerMessage::FilePiecesReceived { sha256, pieces } => {
-                let pieces: Vec<_> = pieces.iter().map(|piece| piece.0).collect();
+            PeerPeerMessage::FilePieceBatch { pieces, .. } => {
+                write!(
+                    f,
+                    "file piece batch of {} pieces with bytes of length {}",
+                    pieces.len(),
+                    pieces.iter().map(|(_, bytes)| bytes.len()).sum::<usize>()
+                )
+            }
+            PeerPeerMessage::CompressedFilePieceBatch { pieces, .. } => {
                 write!(
                     f,
-                    "{}: file pieces received: {:?}",
-                    short_sha_hex(sha256),
+                    "compressed file piece batch of {} pieces with bytes of length {}",
+                    pieces.len(),
                     pieces
+                        .iter()
+                        .map(|(_, _, bytes)| bytes.len())
+                        .sum::<usize>()
+                )
+            }
+            PeerPeerMessage::EncryptedFilePieceBatch { pieces, .. } => {
+                write!(
+                    f,
+                    "encrypted file piece batch of {} pieces with bytes of length {}",
+                    pieces.len(),
+                    pieces.iter().map(|(_, bytes)| bytes.len()).sum::<usize>()
                 )
             }
-            PeerPeerMessage::FileRemoved { sha256 } => {
-                write!(f, "{}: file removed", short_sha_hex(sha256))
+            PeerPeerMessage::FilePiecesReceived { pieces, .. } => {
+                let pieces: Vec<_> = pieces.iter().map(|piece| piece.0).collect();
+                write!(f, "file pieces received: {:?}", pieces)
+            }
+            PeerPeerMessage::FileRemoved { .. } => {
+                write!(f, "file removed")
+            }
+            PeerPeerMessage::DataChannel { label, protocol } => {
+                write!(f, "data channel {:?} with protocol {:?}", label, protocol)
+            }
+            PeerPeerMessage::Hello { protocol_version } => {
+                write!(f, "hello with protocol version {}", protocol_version)
+            }
+            PeerPeerMessage::PieceEncryption { key_id } => {
+                write!(f, "piece encryption with key id {}", hex::encode(key_id.0))
+            }
+            PeerPeerMessage::PieceTransfer { mode } => {
+                write!(f, "piece transfer in {:?} mode", mode)
+            }
+            PeerPeerMessage::RequestPiece { piece_idx, .. } => {
+                write!(f, "request piece {}", piece_idx.0)
             }
+            PeerPeerMessage::Ping => write!(f, "ping"),
+            PeerPeerMessage::Pong => write!(f, "pong"),
         }
     }
 }
@@ -0,0 +1,1436 @@
+use core::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::sync::Arc;
+
+use async_std::sync::{RwLock, RwLockReadGuard};
+use rand::rngs::StdRng;
+use tracker_protocol::{FileSha256, PeerId, PeerTrackerMessage, TrackerPeerMessage};
+
+use crate::local_peer::PieceSendLimits;
+use crate::{
+    Clock, File, FileMetadata, FilePieceIdx, PeerConnectionSendError, PeerError, PeerPeerMessage,
+    PeerTransport, PieceTransfer, PieceTransferMode, SendPhases, SharedFile, TrackerTransport,
+    FILE_CHUNK_SIZE,
+};
+
+type MockSharedFile = SharedFile<Box<[u8]>, u32, FILE_CHUNK_SIZE>;
+type MockPeerQueue = Rc<RefCell<VecDeque<(PeerId, PeerId, PeerPeerMessage)>>>;
+type MockTrackerQueue = Rc<RefCell<VecDeque<(PeerId, PeerTrackerMessage)>>>;
+
+/// Number of time ticks after which unconfirmed states and pieces are resent.
+const MOCK_RESEND_TICKS: u32 = 4;
+
+/// Number of time ticks without pongs after which peers are considered unresponsive.
+const MOCK_UNRESPONSIVE_TICKS: u32 = 4;
+
+/// Manually advanced clock measured in ticks.
+#[derive(Clone, Debug, Default)]
+pub struct MockClock {
+    now: Cell<u32>,
+}
+
+/// In-memory data channel which queues messages until they are delivered by `MockSwarm`.
+///
+/// Messages sent while the channel is not ready are lost.
+#[derive(Clone, Debug)]
+pub struct MockRemotePeer {
+    local_peer_id: PeerId,
+    peer_id: PeerId,
+    queue: MockPeerQueue,
+    is_ready: Rc<Cell<bool>>,
+    piece_transfer: Cell<PieceTransfer>,
+    pong_received: Cell<bool>,
+}
+
+/// In-memory tracker connection which queues messages until they are handled by `MockSwarm`.
+#[derive(Clone, Debug)]
+pub struct MockTracker {
+    local_peer_id: PeerId,
+    queue: MockTrackerQueue,
+    has_handler: Rc<Cell<bool>>,
+}
+
+/// Native counterpart of `LocalPeer` with synchronous message handling.
+#[derive(Debug)]
+pub struct MockPeer {
+    tracker: MockTracker,
+    peers: HashMap<PeerId, Arc<MockRemotePeer>>,
+    files: HashMap<FileSha256, Arc<RwLock<MockSharedFile>>>,
+    send_phases: SendPhases,
+    piece_send_limits: PieceSendLimits,
+    piece_transfer_mode: PieceTransferMode,
+    answers_pings: bool,
+    num_batch_yields: Cell<usize>,
+}
+
+/// A set of mock peers connected through in-memory transports.
+///
+/// Peer messages are delivered reliably and in order by default,
+/// the loss rate and the reorder window emulate an unreliable unordered data channel
+/// with drops and reorders determined by the seed.
+#[derive(Debug)]
+pub struct MockSwarm {
+    peers: Vec<MockPeer>,
+    peer_queue: MockPeerQueue,
+    tracker_queue: MockTrackerQueue,
+    clock: MockClock,
+    rng: StdRng,
+    loss_rate: f64,
+    reorder_window: usize,
+    num_piece_messages: usize,
+    num_sent_pieces: usize,
+}
+
+impl MockClock {
+    pub fn advance(&self, ticks: u32) {
+        self.now.set(self.now.get() + ticks);
+    }
+}
+
+impl Clock for MockClock {
+    type Time = u32;
+
+    fn now(&self) -> u32 {
+        self.now.get()
+    }
+}
+
+impl MockRemotePeer {
+    /// Returns the number of bytes sent through the channel and not delivered yet.
+    fn buffered_amount(&self) -> u64 {
+        self.queue
+            .borrow()
+            .iter()
+            .filter(|(from, to, _)| *from == self.local_peer_id && *to == self.peer_id)
+            .map(|(_, _, message)| message.encoded_len().unwrap() as u64)
+            .sum()
+    }
+}
+
+impl PeerTransport for MockRemotePeer {
+    fn peer_id(&self) -> PeerId {
+        self.peer_id
+    }
+
+    fn is_ready(&self) -> bool {
+        self.is_ready.get()
+    }
+
+    fn send(&self, message: PeerPeerMessage) -> Result<(), PeerError> {
+        use crate::MAX_PEER_MESSAGE_SIZE;
+
+        if !self.is_ready.get() {
+            return Ok(());
+        }
+        let len = message.encoded_len().unwrap();
+        if len > MAX_PEER_MESSAGE_SIZE {
+            return Err(PeerError::MessageIsTooLarge {
+                peer_id: self.peer_id,
+                len,
+                max_len: MAX_PEER_MESSAGE_SIZE,
+            });
+        }
+        self.queue
+            .borrow_mut()
+            .push_back((self.local_peer_id, self.peer_id, message));
+        Ok(())
+    }
+
+    fn send_file_pieces(
+        &self,
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+        compress: bool,
+        max_buffer_bytes: Option<u64>,
+    ) -> Result<(), PeerConnectionSendError> {
+        use crate::{file_piece_messages, MAX_PEER_MESSAGE_SIZE};
+
+        for message in file_piece_messages(sha256, pieces, MAX_PEER_MESSAGE_SIZE, compress) {
+            if max_buffer_bytes
+                .is_some_and(|max_buffer_bytes| self.buffered_amount() >= max_buffer_bytes)
+            {
+                return Err(PeerConnectionSendError::BufferIsFilled);
+            }
+            self.send(message)?;
+        }
+        Ok(())
+    }
+
+    fn piece_transfer(&self) -> PieceTransfer {
+        self.piece_transfer.get()
+    }
+
+    fn take_pong(&self) -> bool {
+        self.pong_received.replace(false)
+    }
+}
+
+impl MockTracker {
+    /// Creates a tracker connection outside of a swarm which only records sent messages.
+    pub fn new(local_peer_id: PeerId) -> Self {
+        Self {
+            local_peer_id,
+            queue: MockTrackerQueue::default(),
+            has_handler: Rc::new(Cell::new(false)),
+        }
+    }
+
+    /// Returns the messages sent by the local peer that are not handled yet.
+    pub fn sent_messages(&self) -> Vec<PeerTrackerMessage> {
+        self.queue
+            .borrow()
+            .iter()
+            .filter(|(peer_id, _)| *peer_id == self.local_peer_id)
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+
+    pub fn has_handler(&self) -> bool {
+        self.has_handler.get()
+    }
+}
+
+impl TrackerTransport for MockTracker {
+    fn send(&self, message: PeerTrackerMessage) {
+        self.queue
+            .borrow_mut()
+            .push_back((self.local_peer_id, message));
+    }
+
+    // Tracker messages are delivered by `MockSwarm` directly.
+    fn set_handler(&self, _: Box<dyn FnMut(TrackerPeerMessage)>) {
+        self.has_handler.set(true);
+    }
+}
+
+impl MockPeer {
+    pub fn file(&self, sha256: &FileSha256) -> Option<RwLockReadGuard<'_, MockSharedFile>> {
+        self.files
+            .get(sha256)
+            .map(|shared_file| shared_file.try_read().unwrap())
+    }
+
+    /// Sets the phases run on each tick, in order.
+    pub fn set_send_phases(&mut self, send_phases: SendPhases) {
+        self.send_phases = send_phases;
+    }
+
+    /// Sets the buffer, batch and per-peer limits of piece sending on each tick.
+    pub fn set_piece_send_limits(&mut self, piece_send_limits: PieceSendLimits) {
+        self.piece_send_limits = piece_send_limits;
+    }
+
+    /// Sets how pieces are received, announced to peers once their channels become ready.
+    pub fn set_piece_transfer_mode(&mut self, piece_transfer_mode: PieceTransferMode) {
+        self.piece_transfer_mode = piece_transfer_mode;
+    }
+
+    /// Emulates a frozen peer that keeps its channels open but does not answer pings.
+    pub fn set_answers_pings(&mut self, answers_pings: bool) {
+        self.answers_pings = answers_pings;
+    }
+
+    /// Returns the number of times control was returned between piece batches.
+    pub fn num_batch_yields(&self) -> usize {
+        self.num_batch_yields.get()
+    }
+
+    pub fn add_file(&mut self, file: File<Box<[u8]>, FILE_CHUNK_SIZE>) {
+        use tracker_protocol::DEFAULT_ROOM;
+
+        let sha256 = file.sha256();
+        let shared_file = Arc::new(RwLock::new(SharedFile::new(file).unwrap()));
+        let _: Option<_> = self.files.insert(sha256, shared_file);
+        self.tracker.send(PeerTrackerMessage::RequestOffers {
+            room: DEFAULT_ROOM.to_owned(),
+            file_sha256: sha256,
+        });
+    }
+
+    fn on_request_offer(&self, peer_id: PeerId, sha256: FileSha256) {
+        use crate::local_peer::on_file_peer_ready;
+        use crate::OkOrLog;
+
+        if let Some(shared_file) = self.files.get(&sha256) {
+            let mut shared_file = shared_file.try_write().unwrap();
+            if shared_file.add_peer(peer_id).ok_or_log().is_some() {
+                on_file_peer_ready(&mut shared_file, &*self.peers[&peer_id]);
+            }
+        }
+    }
+
+    fn on_peer_ready(&self, peer_id: PeerId) {
+        use crate::local_peer::on_file_peer_ready;
+        use crate::ok_or_log::OrLog;
+
+        let remote_peer = self.peers.get(&peer_id).unwrap();
+        remote_peer
+            .send(PeerPeerMessage::PieceTransfer {
+                mode: self.piece_transfer_mode,
+            })
+            .or_log();
+        for shared_file in self.files.values() {
+            on_file_peer_ready(&mut shared_file.try_write().unwrap(), &**remote_peer);
+        }
+    }
+
+    fn on_peer_message(&self, peer_id: PeerId, message: PeerPeerMessage) {
+        use crate::local_peer::on_file_message;
+        use crate::ok_or_log::OrLog;
+        use crate::unwrap_or_return;
+
+        if let PeerPeerMessage::PieceTransfer { mode } = message {
+            let remote_peer = self.peers.get(&peer_id).unwrap();
+            remote_peer.piece_transfer.set(PieceTransfer::negotiate(
+                self.piece_transfer_mode,
+                Some(mode),
+            ));
+            return;
+        }
+
+        if message == PeerPeerMessage::Ping {
+            if self.answers_pings {
+                let remote_peer = self.peers.get(&peer_id).unwrap();
+                remote_peer.send(PeerPeerMessage::Pong).or_log();
+            }
+            return;
+        }
+
+        if message == PeerPeerMessage::Pong {
+            self.peers.get(&peer_id).unwrap().pong_received.set(true);
+            return;
+        }
+
+        let sha256 = unwrap_or_return!(message.sha256());
+        let shared_file = unwrap_or_return!(self.files.get(&sha256));
+        let remote_peer = self.peers.get(&peer_id).unwrap();
+        on_file_message(
+            &mut shared_file.try_write().unwrap(),
+            &**remote_peer,
+            message,
+        );
+    }
+
+    fn tick(&self, time: u32, num_pieces_to_be_sent: usize, rng: &mut StdRng) {
+        use crate::local_peer::{
+            request_file_pieces, send_file_state, send_pieces_to_peers, update_file_peers_liveness,
+        };
+        use crate::ok_or_log::OrLog;
+        use crate::{SendPhase, MAX_PENDING_PIECE_REQUESTS};
+        use async_std::task::block_on;
+        use core::future::ready;
+        use std::collections::HashSet;
+
+        let ponged_peers: HashSet<_> = self
+            .peers
+            .values()
+            .filter(|peer| peer.take_pong())
+            .map(|peer| peer.peer_id)
+            .collect();
+        let unresponsive_before = time.saturating_sub(MOCK_UNRESPONSIVE_TICKS);
+        for shared_file in self.files.values() {
+            update_file_peers_liveness(
+                &mut shared_file.try_write().unwrap(),
+                &ponged_peers,
+                time,
+                &unresponsive_before,
+            );
+        }
+        for remote_peer in self.peers.values() {
+            remote_peer.send(PeerPeerMessage::Ping).or_log();
+        }
+
+        // Files are sorted, so that seeded swarms select the same pieces on every run.
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_unstable_by_key(|(sha256, _)| sha256.0);
+        let files: Vec<_> = files
+            .into_iter()
+            .map(|(_, shared_file)| Arc::clone(shared_file))
+            .collect();
+
+        let resend_before = time.saturating_sub(MOCK_RESEND_TICKS);
+        for phase in self.send_phases.iter() {
+            match phase {
+                SendPhase::State => {
+                    for shared_file in &files {
+                        let mut shared_file = shared_file.try_write().unwrap();
+                        let peer_ids: Vec<_> = shared_file.peer_ids().copied().collect();
+                        for peer_id in &peer_ids {
+                            let remote_peer = self.peers.get(peer_id).unwrap();
+                            send_file_state(
+                                &mut shared_file,
+                                &**remote_peer,
+                                &resend_before,
+                                &time,
+                            );
+                        }
+                    }
+                }
+                SendPhase::RecentlyReceived => {
+                    for shared_file in &files {
+                        let mut shared_file = shared_file.try_write().unwrap();
+                        let pieces = shared_file.take_recently_added_pieces();
+                        if pieces.is_empty() {
+                            continue;
+                        }
+                        for peer_id in shared_file.peer_ids() {
+                            let remote_peer = self.peers.get(peer_id).unwrap();
+                            if !remote_peer.is_ready() {
+                                continue;
+                            }
+                            remote_peer
+                                .send(PeerPeerMessage::FilePiecesReceived {
+                                    sha256: shared_file.file().sha256(),
+                                    pieces: pieces.clone(),
+                                })
+                                .or_log();
+                        }
+                    }
+                }
+                SendPhase::ResendPieces => {
+                    for shared_file in &files {
+                        let mut shared_file = shared_file.try_write().unwrap();
+                        shared_file
+                            .mark_pieces_for_resend_before(resend_before)
+                            .or_log();
+                        shared_file.expire_piece_requests_before(&resend_before);
+                    }
+                }
+                SendPhase::Pieces => {
+                    for shared_file in &files {
+                        request_file_pieces(
+                            &mut shared_file.try_write().unwrap(),
+                            self.peers.values().map(|peer| &**peer),
+                            MAX_PENDING_PIECE_REQUESTS,
+                            time,
+                        );
+                    }
+                    let peers = RwLock::new(self.peers.clone());
+                    let yield_batch = || {
+                        self.num_batch_yields.set(self.num_batch_yields.get() + 1);
+                        ready(())
+                    };
+                    block_on(send_pieces_to_peers(
+                        &files,
+                        &peers,
+                        num_pieces_to_be_sent,
+                        self.piece_send_limits,
+                        time,
+                        &mut *rng,
+                        yield_batch,
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl MockSwarm {
+    pub fn new() -> Self {
+        use rand::SeedableRng;
+
+        Self {
+            peers: Vec::new(),
+            peer_queue: Rc::new(RefCell::new(VecDeque::new())),
+            tracker_queue: Rc::new(RefCell::new(VecDeque::new())),
+            clock: MockClock::default(),
+            rng: StdRng::seed_from_u64(0),
+            loss_rate: 0.0,
+            reorder_window: 0,
+            num_piece_messages: 0,
+            num_sent_pieces: 0,
+        }
+    }
+
+    pub fn with_seed(self, seed: u64) -> Self {
+        use rand::SeedableRng;
+
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            ..self
+        }
+    }
+
+    /// Sets the probability of a peer message being dropped, from 0.0 to 1.0.
+    pub fn with_loss_rate(self, loss_rate: f64) -> Self {
+        Self { loss_rate, ..self }
+    }
+
+    /// Sets the maximum number of positions a peer message can be delayed by
+    /// relative to the messages sent after it.
+    pub fn with_reorder_window(self, reorder_window: usize) -> Self {
+        Self {
+            reorder_window,
+            ..self
+        }
+    }
+
+    pub fn peer(&self, peer_id: PeerId) -> &MockPeer {
+        &self.peers[peer_id.0 as usize]
+    }
+
+    pub fn peer_mut(&mut self, peer_id: PeerId) -> &mut MockPeer {
+        &mut self.peers[peer_id.0 as usize]
+    }
+
+    /// Returns the number of piece messages sent, including lost ones.
+    pub fn num_piece_messages(&self) -> usize {
+        self.num_piece_messages
+    }
+
+    /// Returns the number of file pieces sent, including lost ones.
+    pub fn num_sent_pieces(&self) -> usize {
+        self.num_sent_pieces
+    }
+
+    pub fn add_peer(&mut self) -> PeerId {
+        let peer_id = PeerId(self.peers.len().try_into().unwrap());
+        self.peers.push(MockPeer {
+            tracker: MockTracker {
+                local_peer_id: peer_id,
+                queue: Rc::clone(&self.tracker_queue),
+                has_handler: Rc::new(Cell::new(false)),
+            },
+            peers: HashMap::new(),
+            files: HashMap::new(),
+            send_phases: SendPhases::default(),
+            piece_send_limits: PieceSendLimits::default(),
+            piece_transfer_mode: PieceTransferMode::default(),
+            answers_pings: true,
+            num_batch_yields: Cell::new(0),
+        });
+        peer_id
+    }
+
+    /// Advances the swarm time by one tick and delivers all queued messages.
+    ///
+    /// Each peer sends up to `num_pieces_to_be_sent` pieces split between its files.
+    pub fn step(&mut self, num_pieces_to_be_sent: usize) {
+        self.clock.advance(1);
+        let time = self.clock.now();
+
+        self.handle_tracker_messages();
+        for peer in &self.peers {
+            peer.tick(time, num_pieces_to_be_sent, &mut self.rng);
+        }
+        self.deliver_peer_messages();
+    }
+
+    /// Opens or closes data channels between two connected peers,
+    /// peers are notified when the channels become ready again.
+    pub fn set_ready(&self, first: PeerId, second: PeerId, is_ready: bool) {
+        // Both directions share the same readiness.
+        let was_ready = self.peer(first).peers[&second].is_ready.replace(is_ready);
+        if is_ready && !was_ready {
+            self.peer(first).on_peer_ready(second);
+            self.peer(second).on_peer_ready(first);
+        }
+    }
+
+    /// Returns `true` if the peers were not connected before.
+    fn connect(&mut self, first: PeerId, second: PeerId) -> bool {
+        if self.peer(first).peers.contains_key(&second) {
+            return false;
+        }
+        let is_ready = Rc::new(Cell::new(true));
+        for (local_peer_id, peer_id) in [(first, second), (second, first)] {
+            let queue = Rc::clone(&self.peer_queue);
+            let is_ready = Rc::clone(&is_ready);
+            let _: Option<_> = self.peer_mut(local_peer_id).peers.insert(
+                peer_id,
+                Arc::new(MockRemotePeer {
+                    local_peer_id,
+                    peer_id,
+                    queue,
+                    is_ready,
+                    piece_transfer: Cell::new(PieceTransfer::default()),
+                    pong_received: Cell::new(false),
+                }),
+            );
+        }
+        true
+    }
+
+    // Only the tracker offer routing is emulated,
+    // SDP and ICE candidate exchange is not needed for in-memory transports.
+    fn handle_tracker_messages(&mut self) {
+        while let Some((peer_id, message)) = pop_front(&self.tracker_queue) {
+            match message {
+                PeerTrackerMessage::RequestOffers {
+                    room: _,
+                    file_sha256,
+                } => {
+                    let offering_peer_ids: Vec<_> = (0..self.peers.len().try_into().unwrap())
+                        .map(PeerId)
+                        .filter(|&offering_peer_id| offering_peer_id != peer_id)
+                        .filter(|&offering_peer_id| {
+                            self.peer(offering_peer_id).file(&file_sha256).is_some()
+                        })
+                        .collect();
+                    for offering_peer_id in offering_peer_ids {
+                        let is_connected = self.connect(offering_peer_id, peer_id);
+                        self.peer(offering_peer_id)
+                            .on_request_offer(peer_id, file_sha256);
+                        // Data channels are opened immediately after the offer is answered.
+                        if is_connected {
+                            self.peer(offering_peer_id).on_peer_ready(peer_id);
+                            self.peer(peer_id).on_peer_ready(offering_peer_id);
+                        }
+                    }
+                }
+                PeerTrackerMessage::RemoveFile { .. }
+                | PeerTrackerMessage::SendOffer { .. }
+                | PeerTrackerMessage::SendAnswer { .. }
+                | PeerTrackerMessage::SendIceCandidate { .. }
+                | PeerTrackerMessage::AllIceCandidatesSent { .. }
+                | PeerTrackerMessage::SetLabel { .. }
+                | PeerTrackerMessage::QuerySwarm { .. } => {}
+            }
+        }
+    }
+
+    // Messages sent by handlers are delivered as a next batch within the same step.
+    fn deliver_peer_messages(&mut self) {
+        use crate::ControlQueue;
+        use rand::Rng;
+
+        loop {
+            let mut batch: Vec<_> = self
+                .peer_queue
+                .borrow_mut()
+                .drain(..)
+                .enumerate()
+                .map(|(j, message)| (j + self.rng.gen_range(0..=self.reorder_window), message))
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+            batch.sort_by_key(|&(position, _)| position);
+            for (_, (from, to, message)) in batch {
+                if !ControlQueue::is_control_message(&message) {
+                    self.num_piece_messages += 1;
+                }
+                self.num_sent_pieces += match &message {
+                    PeerPeerMessage::FilePiece { .. } => 1,
+                    PeerPeerMessage::FilePieceBatch { pieces, .. } => pieces.len(),
+                    PeerPeerMessage::CompressedFilePieceBatch { pieces, .. } => pieces.len(),
+                    _ => 0,
+                };
+                if !self.rng.gen_bool(self.loss_rate) {
+                    self.peer(to).on_peer_message(from, message);
+                }
+            }
+        }
+    }
+}
+
+// Releases the queue borrow before the message is handled,
+// so handlers are able to push new messages into the same queue.
+fn pop_front<T>(queue: &Rc<RefCell<VecDeque<T>>>) -> Option<T> {
+    queue.borrow_mut().pop_front()
+}
+
+fn mock_file_bytes(len: usize, seed: u8) -> Vec<u8> {
+    (0..len).map(|j| (j % 251) as u8 ^ seed).collect()
+}
+
+fn mock_file_metadata(bytes: &[u8], seed: u8) -> FileMetadata {
+    use crate::FileLen;
+
+    FileMetadata::new(
+        FileSha256([seed; 32]),
+        format!("filename{}", seed),
+        FileLen(bytes.len() as u64),
+    )
+}
+
+fn mock_complete_file(metadata: FileMetadata, bytes: &[u8]) -> File<Box<[u8]>, FILE_CHUNK_SIZE> {
+    use crate::{FilePieceIdx, FileStateSetStatus, FILE_PIECE_SIZE};
+
+    let mut file = File::new(metadata).unwrap();
+    for (j, piece) in bytes.chunks(FILE_PIECE_SIZE).enumerate() {
+        let _: FileStateSetStatus = file.set_piece(&FilePieceIdx(j), piece).unwrap();
+    }
+    assert!(file.state().is_complete());
+    file
+}
+
+fn assert_file_received(swarm: &MockSwarm, peer_id: PeerId, sha256: &FileSha256, bytes: &[u8]) {
+    use crate::{FilePieceIdx, FILE_PIECE_SIZE};
+
+    let shared_file = swarm.peer(peer_id).file(sha256).unwrap();
+    let file = shared_file.file();
+    assert!(file.state().is_complete());
+    for (j, piece) in bytes.chunks(FILE_PIECE_SIZE).enumerate() {
+        assert_eq!(&*file.get_piece(&FilePieceIdx(j)).unwrap().unwrap(), piece);
+    }
+}
+
+#[test]
+fn send_file_from_single_seeder_to_multiple_leechers() {
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(37 * FILE_PIECE_SIZE - FILE_PIECE_SIZE / 3, 0);
+    let metadata = mock_file_metadata(&bytes, 0);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    swarm
+        .peer_mut(seeder)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+
+    let leechers: Vec<_> = (0..2).map(|_| swarm.add_peer()).collect();
+    for &leecher in &leechers {
+        swarm
+            .peer_mut(leecher)
+            .add_file(File::new(metadata.clone()).unwrap());
+    }
+
+    for _ in 0..100 {
+        swarm.step(4);
+    }
+
+    for &leecher in &leechers {
+        assert_file_received(&swarm, leecher, &sha256, &bytes);
+    }
+}
+
+#[test]
+fn send_compressed_file() {
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(13 * FILE_PIECE_SIZE + 5, 5);
+    let metadata = mock_file_metadata(&bytes, 5).with_compressed(true);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    let leecher = swarm.add_peer();
+    swarm
+        .peer_mut(seeder)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+    swarm
+        .peer_mut(leecher)
+        .add_file(File::new(metadata).unwrap());
+
+    for _ in 0..50 {
+        swarm.step(4);
+    }
+
+    assert_file_received(&swarm, leecher, &sha256, &bytes);
+}
+
+#[test]
+fn send_file_over_lossy_unordered_link() {
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(29 * FILE_PIECE_SIZE + FILE_PIECE_SIZE / 2, 4);
+    let metadata = mock_file_metadata(&bytes, 4);
+    let sha256 = metadata.sha256();
+
+    for seed in 0..8 {
+        let mut swarm = MockSwarm::new()
+            .with_seed(seed)
+            .with_loss_rate(0.2)
+            .with_reorder_window(8);
+        let seeder = swarm.add_peer();
+        swarm
+            .peer_mut(seeder)
+            .add_file(mock_complete_file(metadata.clone(), &bytes));
+
+        let leechers: Vec<_> = (0..3).map(|_| swarm.add_peer()).collect();
+        for &leecher in &leechers {
+            swarm
+                .peer_mut(leecher)
+                .add_file(File::new(metadata.clone()).unwrap());
+        }
+
+        for _ in 0..300 {
+            swarm.step(4);
+        }
+
+        for &leecher in &leechers {
+            assert_file_received(&swarm, leecher, &sha256, &bytes);
+        }
+    }
+}
+
+#[test]
+fn share_empty_and_single_byte_files() {
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    let leecher = swarm.add_peer();
+    for (len, seed) in [(0, 6), (1, 7)] {
+        let bytes = mock_file_bytes(len, seed);
+        let metadata = mock_file_metadata(&bytes, seed);
+        swarm
+            .peer_mut(seeder)
+            .add_file(mock_complete_file(metadata.clone(), &bytes));
+        swarm
+            .peer_mut(leecher)
+            .add_file(File::new(metadata).unwrap());
+    }
+
+    // Empty files are complete and distributed without any exchange.
+    let empty_sha256 = FileSha256([6; 32]);
+    for peer_id in [seeder, leecher] {
+        let shared_file = swarm.peer(peer_id).file(&empty_sha256).unwrap();
+        assert!(shared_file.file().state().is_complete());
+        assert!(shared_file.is_fully_distributed());
+    }
+
+    for _ in 0..10 {
+        swarm.step(4);
+    }
+
+    assert_file_received(&swarm, leecher, &empty_sha256, &[]);
+    assert_file_received(
+        &swarm,
+        leecher,
+        &FileSha256([7; 32]),
+        &mock_file_bytes(1, 7),
+    );
+    for peer_id in [seeder, leecher] {
+        for sha256 in [empty_sha256, FileSha256([7; 32])] {
+            assert!(swarm
+                .peer(peer_id)
+                .file(&sha256)
+                .unwrap()
+                .is_fully_distributed());
+        }
+    }
+}
+
+#[test]
+fn signal_file_fully_distributed() {
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(11 * FILE_PIECE_SIZE + 7, 3);
+    let metadata = mock_file_metadata(&bytes, 3);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    swarm
+        .peer_mut(seeder)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+    let leechers: Vec<_> = (0..2).map(|_| swarm.add_peer()).collect();
+    for &leecher in &leechers {
+        swarm
+            .peer_mut(leecher)
+            .add_file(File::new(metadata.clone()).unwrap());
+    }
+
+    swarm.step(4);
+    let is_fully_distributed = |swarm: &MockSwarm, peer_id| {
+        swarm
+            .peer(peer_id)
+            .file(&sha256)
+            .unwrap()
+            .is_fully_distributed()
+    };
+    assert!(!is_fully_distributed(&swarm, seeder));
+
+    for _ in 0..100 {
+        swarm.step(4);
+    }
+
+    for &leecher in &leechers {
+        assert_file_received(&swarm, leecher, &sha256, &bytes);
+    }
+    for peer_id in core::iter::once(seeder).chain(leechers) {
+        assert!(is_fully_distributed(&swarm, peer_id));
+    }
+}
+
+#[test]
+fn send_file_to_already_connected_peer() {
+    use crate::FILE_PIECE_SIZE;
+
+    let first_bytes = mock_file_bytes(5 * FILE_PIECE_SIZE, 1);
+    let first_metadata = mock_file_metadata(&first_bytes, 1);
+    let second_bytes = mock_file_bytes(3 * FILE_PIECE_SIZE + 1, 2);
+    let second_metadata = mock_file_metadata(&second_bytes, 2);
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    let leecher = swarm.add_peer();
+    for (metadata, bytes) in [
+        (&first_metadata, &first_bytes),
+        (&second_metadata, &second_bytes),
+    ] {
+        swarm
+            .peer_mut(seeder)
+            .add_file(mock_complete_file(metadata.clone(), bytes));
+    }
+
+    swarm
+        .peer_mut(leecher)
+        .add_file(File::new(first_metadata.clone()).unwrap());
+    for _ in 0..20 {
+        swarm.step(4);
+    }
+    assert_file_received(&swarm, leecher, &first_metadata.sha256(), &first_bytes);
+
+    // Offer for the second file is requested from the peer which is already connected.
+    swarm
+        .peer_mut(leecher)
+        .add_file(File::new(second_metadata.clone()).unwrap());
+    for _ in 0..20 {
+        swarm.step(4);
+    }
+    assert_file_received(&swarm, leecher, &second_metadata.sha256(), &second_bytes);
+}
+
+#[test]
+fn resend_state_at_interval_boundary() {
+    use crate::local_peer::send_file_state;
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(4 * FILE_PIECE_SIZE, 3);
+    let metadata = mock_file_metadata(&bytes, 3);
+    let mut shared_file: MockSharedFile = SharedFile::new(File::new(metadata).unwrap()).unwrap();
+
+    let queue = MockPeerQueue::default();
+    let remote_peer = MockRemotePeer {
+        local_peer_id: PeerId(0),
+        peer_id: PeerId(1),
+        queue: Rc::clone(&queue),
+        is_ready: Rc::new(Cell::new(true)),
+        piece_transfer: Cell::new(PieceTransfer::default()),
+        pong_received: Cell::new(false),
+    };
+    shared_file.add_peer(remote_peer.peer_id).unwrap();
+
+    let clock = MockClock::default();
+    let mut send_state = || {
+        let now = clock.now();
+        let resend_before = now.saturating_sub(MOCK_RESEND_TICKS);
+        send_file_state(&mut shared_file, &remote_peer, &resend_before, &now);
+        queue.borrow_mut().drain(..).count()
+    };
+
+    clock.advance(MOCK_RESEND_TICKS);
+    assert_eq!(send_state(), 1);
+    for _ in 1..MOCK_RESEND_TICKS {
+        clock.advance(1);
+        assert_eq!(send_state(), 0);
+    }
+    clock.advance(1);
+    assert_eq!(send_state(), 1);
+    clock.advance(1);
+    assert_eq!(send_state(), 0);
+}
+
+#[test]
+fn ignore_outdated_file_state() {
+    use crate::local_peer::on_file_message;
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(4 * FILE_PIECE_SIZE, 5);
+    let metadata = mock_file_metadata(&bytes, 5);
+    let sha256 = metadata.sha256();
+    let mut shared_file: MockSharedFile = SharedFile::new(File::new(metadata).unwrap()).unwrap();
+
+    let queue = MockPeerQueue::default();
+    let remote_peer = MockRemotePeer {
+        local_peer_id: PeerId(0),
+        peer_id: PeerId(1),
+        queue: Rc::clone(&queue),
+        is_ready: Rc::new(Cell::new(true)),
+        piece_transfer: Cell::new(PieceTransfer::default()),
+        pong_received: Cell::new(false),
+    };
+    let deliver = |shared_file: &mut MockSharedFile, message| {
+        on_file_message(shared_file, &remote_peer, message);
+        queue.borrow_mut().drain(..).count()
+    };
+
+    // The first message adds the peer, so the local state is sent along with the acknowledgement.
+    assert_eq!(
+        deliver(
+            &mut shared_file,
+            PeerPeerMessage::FileComplete { sha256, seq: 1 }
+        ),
+        2
+    );
+    assert!(shared_file.remote_state().is_complete());
+
+    // The older state is delivered after the newer one and is dropped unacknowledged.
+    assert_eq!(
+        deliver(
+            &mut shared_file,
+            PeerPeerMessage::FileMissing { sha256, seq: 0 }
+        ),
+        0
+    );
+    assert!(shared_file.remote_state().is_complete());
+
+    assert_eq!(
+        deliver(
+            &mut shared_file,
+            PeerPeerMessage::FileMissing { sha256, seq: 2 }
+        ),
+        1
+    );
+    assert!(shared_file.remote_state().is_missing());
+}
+
+#[test]
+fn resend_piece_after_interval() {
+    use crate::{FilePieceIdx, FileState, PieceNumPossibleOwners, FILE_PIECE_SIZE};
+
+    let bytes = mock_file_bytes(FILE_PIECE_SIZE, 4);
+    let metadata = mock_file_metadata(&bytes, 4);
+    let mut shared_file: MockSharedFile =
+        SharedFile::new(mock_complete_file(metadata, &bytes)).unwrap();
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file
+        .set_peer_state(PeerId(1), FileState::from_missing(1))
+        .unwrap();
+
+    let clock = MockClock::default();
+    let next_queue_num_owners =
+        |shared_file: &MockSharedFile| shared_file.piece_queues().next_queue().unwrap().0;
+
+    clock.advance(MOCK_RESEND_TICKS);
+    let sent_at = clock.now();
+    assert_eq!(
+        shared_file.select_piece_peer(FilePieceIdx(0), sent_at),
+        Ok(PeerId(1))
+    );
+    assert_eq!(
+        next_queue_num_owners(&shared_file),
+        PieceNumPossibleOwners(1)
+    );
+
+    // Pieces are resent only if they were sent strictly before `now - interval`.
+    clock.advance(MOCK_RESEND_TICKS);
+    shared_file
+        .mark_pieces_for_resend_before(clock.now() - MOCK_RESEND_TICKS)
+        .unwrap();
+    assert_eq!(
+        next_queue_num_owners(&shared_file),
+        PieceNumPossibleOwners(1)
+    );
+
+    clock.advance(1);
+    shared_file
+        .mark_pieces_for_resend_before(clock.now() - MOCK_RESEND_TICKS)
+        .unwrap();
+    assert_eq!(
+        next_queue_num_owners(&shared_file),
+        PieceNumPossibleOwners(0)
+    );
+}
+
+#[test]
+fn backfill_state_of_peer_ready_again() {
+    use crate::{FilePieceIdx, FileStateSetStatus, FILE_PIECE_SIZE};
+
+    let mut swarm = MockSwarm::new();
+    let seeder_id = swarm.add_peer();
+    let leecher_id = swarm.add_peer();
+
+    let bytes = mock_file_bytes(8 * FILE_PIECE_SIZE, 9);
+    let metadata = mock_file_metadata(&bytes, 9);
+    let sha256 = metadata.sha256();
+    let mut partial_file = File::new(metadata.clone()).unwrap();
+    for (j, piece) in bytes.chunks(FILE_PIECE_SIZE).enumerate().take(2) {
+        let _: FileStateSetStatus = partial_file.set_piece(&FilePieceIdx(j), piece).unwrap();
+    }
+    swarm.peer_mut(seeder_id).add_file(partial_file);
+    swarm
+        .peer_mut(leecher_id)
+        .add_file(File::new(metadata).unwrap());
+    for _ in 0..10 {
+        swarm.step(4);
+    }
+    let seeder_state = |swarm: &MockSwarm| {
+        let matrix = swarm
+            .peer(leecher_id)
+            .file(&sha256)
+            .unwrap()
+            .ownership_matrix();
+        assert_eq!(matrix.len(), 1);
+        assert_eq!(matrix[0].0, seeder_id);
+        matrix[0].1.clone()
+    };
+    assert_eq!(seeder_state(&swarm).raw().count_ones(), 2);
+
+    // Pieces added while the channel is closed are announced as recent pieces and lost.
+    swarm.set_ready(seeder_id, leecher_id, false);
+    for (j, piece) in bytes.chunks(FILE_PIECE_SIZE).enumerate().skip(2) {
+        swarm.peer_mut(seeder_id).files[&sha256]
+            .try_write()
+            .unwrap()
+            .add_local_piece(FilePieceIdx(j), piece)
+            .unwrap();
+    }
+    for _ in 0..10 {
+        swarm.step(4);
+    }
+    assert_eq!(seeder_state(&swarm).raw().count_ones(), 2);
+
+    // The full state is sent once the channel is ready again.
+    swarm.set_ready(seeder_id, leecher_id, true);
+    for _ in 0..10 {
+        swarm.step(4);
+    }
+    assert!(seeder_state(&swarm).is_complete());
+    assert_file_received(&swarm, leecher_id, &sha256, &bytes);
+}
+
+#[test]
+fn exchange_states_on_peer_ready() {
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(8 * FILE_PIECE_SIZE, 11);
+    let metadata = mock_file_metadata(&bytes, 11);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder_id = swarm.add_peer();
+    let leecher_id = swarm.add_peer();
+    // Ticks send nothing, so states can only be sent when data channels are opened.
+    for peer_id in [seeder_id, leecher_id] {
+        swarm
+            .peer_mut(peer_id)
+            .set_send_phases(SendPhases::new(&[]).unwrap());
+    }
+    swarm
+        .peer_mut(seeder_id)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+    swarm
+        .peer_mut(leecher_id)
+        .add_file(File::new(metadata).unwrap());
+
+    swarm.step(4);
+
+    let leecher_file = swarm.peer(leecher_id).file(&sha256).unwrap();
+    assert_eq!(leecher_file.ownership_matrix().len(), 1);
+    assert!(leecher_file.ownership_matrix()[0].1.is_complete());
+    let seeder_file = swarm.peer(seeder_id).file(&sha256).unwrap();
+    assert_eq!(seeder_file.ownership_matrix().len(), 1);
+    assert!(seeder_file.ownership_matrix()[0].1.is_missing());
+    assert_eq!(swarm.num_piece_messages(), 0);
+}
+
+#[test]
+fn skip_disabled_piece_send_phase() {
+    use crate::{SendPhase, FILE_PIECE_SIZE};
+
+    let bytes = mock_file_bytes(8 * FILE_PIECE_SIZE, 10);
+    let metadata = mock_file_metadata(&bytes, 10);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder_id = swarm.add_peer();
+    let leecher_id = swarm.add_peer();
+    let phases = SendPhases::new(&[
+        SendPhase::State,
+        SendPhase::RecentlyReceived,
+        SendPhase::ResendPieces,
+    ])
+    .unwrap();
+    swarm.peer_mut(seeder_id).set_send_phases(phases);
+    swarm
+        .peer_mut(seeder_id)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+    swarm
+        .peer_mut(leecher_id)
+        .add_file(File::new(metadata).unwrap());
+
+    for _ in 0..20 {
+        swarm.step(4);
+    }
+
+    // States are still exchanged, but no pieces are sent.
+    let leecher_file = swarm.peer(leecher_id).file(&sha256).unwrap();
+    assert_eq!(leecher_file.ownership_matrix().len(), 1);
+    assert!(leecher_file.ownership_matrix()[0].1.is_complete());
+    assert!(leecher_file.file().state().is_missing());
+    assert_eq!(swarm.num_piece_messages(), 0);
+    drop(leecher_file);
+
+    swarm
+        .peer_mut(seeder_id)
+        .set_send_phases(SendPhases::pieces_first());
+    for _ in 0..20 {
+        swarm.step(4);
+    }
+    assert!(swarm.num_piece_messages() > 0);
+    assert_file_received(&swarm, leecher_id, &sha256, &bytes);
+}
+
+#[test]
+fn send_file_in_pull_mode() {
+    use crate::FILE_PIECE_SIZE;
+
+    const NUM_PIECES: usize = 37;
+
+    let bytes = mock_file_bytes(NUM_PIECES * FILE_PIECE_SIZE - FILE_PIECE_SIZE / 3, 6);
+    let metadata = mock_file_metadata(&bytes, 6);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    swarm
+        .peer_mut(seeder)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+    let leecher = swarm.add_peer();
+    swarm
+        .peer_mut(leecher)
+        .set_piece_transfer_mode(PieceTransferMode::Pull);
+    swarm
+        .peer_mut(leecher)
+        .add_file(File::new(metadata).unwrap());
+
+    for _ in 0..100 {
+        swarm.step(4);
+    }
+
+    assert_file_received(&swarm, leecher, &sha256, &bytes);
+    assert_eq!(
+        swarm
+            .peer(leecher)
+            .file(&sha256)
+            .unwrap()
+            .num_piece_requests(),
+        0
+    );
+    // Pieces are sent only on requests, so none of them is pushed in addition.
+    assert_eq!(swarm.num_sent_pieces(), NUM_PIECES);
+    assert!(swarm
+        .peer(seeder)
+        .file(&sha256)
+        .unwrap()
+        .peer_pulls_pieces(&leecher));
+}
+
+#[test]
+fn skip_peer_not_answering_pings() {
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(200 * FILE_PIECE_SIZE, 7);
+    let metadata = mock_file_metadata(&bytes, 7);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    swarm
+        .peer_mut(seeder)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+    let leecher = swarm.add_peer();
+    swarm
+        .peer_mut(leecher)
+        .add_file(File::new(metadata.clone()).unwrap());
+    let frozen = swarm.add_peer();
+    swarm
+        .peer_mut(frozen)
+        .add_file(File::new(metadata).unwrap());
+
+    let num_available = |swarm: &MockSwarm, peer_id| {
+        swarm
+            .peer(peer_id)
+            .file(&sha256)
+            .unwrap()
+            .file()
+            .state()
+            .num_available()
+    };
+
+    for _ in 0..3 {
+        swarm.step(2);
+    }
+    assert!(num_available(&swarm, frozen) > 0);
+
+    // Pings are not answered by the frozen peer, but its channels stay open.
+    swarm.peer_mut(frozen).set_answers_pings(false);
+    let mut num_steps = 0;
+    while !swarm
+        .peer(seeder)
+        .file(&sha256)
+        .unwrap()
+        .is_peer_unresponsive(&frozen)
+    {
+        swarm.step(2);
+        num_steps += 1;
+        assert!(num_steps <= MOCK_UNRESPONSIVE_TICKS + 2);
+    }
+    assert!(swarm
+        .peer(leecher)
+        .file(&sha256)
+        .unwrap()
+        .is_peer_unresponsive(&frozen));
+    assert!(!swarm
+        .peer(seeder)
+        .file(&sha256)
+        .unwrap()
+        .is_peer_unresponsive(&leecher));
+
+    let frozen_num_available = num_available(&swarm, frozen);
+    for _ in 0..200 {
+        swarm.step(2);
+    }
+    assert_file_received(&swarm, leecher, &sha256, &bytes);
+    assert_eq!(num_available(&swarm, frozen), frozen_num_available);
+
+    // The peer is selected again once it answers pings.
+    swarm.peer_mut(frozen).set_answers_pings(true);
+    for _ in 0..200 {
+        swarm.step(2);
+    }
+    assert!(!swarm
+        .peer(seeder)
+        .file(&sha256)
+        .unwrap()
+        .is_peer_unresponsive(&frozen));
+    assert_file_received(&swarm, frozen, &sha256, &bytes);
+}
+
+#[test]
+fn split_pieces_budget_between_files() {
+    use crate::FILE_PIECE_SIZE;
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    let leecher = swarm.add_peer();
+    let mut sha256s = Vec::new();
+    for (seed, priority) in [(12, 3), (13, 1)] {
+        let bytes = mock_file_bytes(64 * FILE_PIECE_SIZE, seed);
+        let metadata = mock_file_metadata(&bytes, seed);
+        sha256s.push(metadata.sha256());
+        swarm
+            .peer_mut(seeder)
+            .add_file(mock_complete_file(metadata.clone(), &bytes));
+        swarm.peer(seeder).files[&metadata.sha256()]
+            .try_write()
+            .unwrap()
+            .set_priority(priority);
+        swarm
+            .peer_mut(leecher)
+            .add_file(File::new(metadata).unwrap());
+    }
+
+    let num_available = |swarm: &MockSwarm, sha256| {
+        swarm
+            .peer(leecher)
+            .file(sha256)
+            .unwrap()
+            .file()
+            .state()
+            .num_available()
+    };
+
+    for _ in 0..4 {
+        swarm.step(8);
+    }
+    let num_second_available = num_available(&swarm, &sha256s[1]);
+    assert!(num_second_available > 0);
+    assert_eq!(num_available(&swarm, &sha256s[0]), 3 * num_second_available);
+}
+
+#[test]
+fn yield_between_piece_batches() {
+    use crate::FILE_PIECE_SIZE;
+
+    let bytes = mock_file_bytes(64 * FILE_PIECE_SIZE, 14);
+    let metadata = mock_file_metadata(&bytes, 14);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    swarm
+        .peer_mut(seeder)
+        .set_piece_send_limits(PieceSendLimits {
+            max_batch_size: Some(2),
+            ..PieceSendLimits::default()
+        });
+    swarm
+        .peer_mut(seeder)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+    let leecher = swarm.add_peer();
+    swarm
+        .peer_mut(leecher)
+        .add_file(File::new(metadata).unwrap());
+
+    while swarm.num_sent_pieces() == 0 {
+        swarm.step(7);
+    }
+
+    // Control is returned after the second, the fourth and the sixth piece.
+    let num_batch_yields = swarm.peer(seeder).num_batch_yields();
+    let num_sent_pieces = swarm.num_sent_pieces();
+    swarm.step(7);
+    assert_eq!(swarm.num_sent_pieces() - num_sent_pieces, 7);
+    assert_eq!(swarm.peer(seeder).num_batch_yields() - num_batch_yields, 3);
+
+    for _ in 0..20 {
+        swarm.step(7);
+    }
+    assert_file_received(&swarm, leecher, &sha256, &bytes);
+}
+
+#[test]
+fn skip_peers_with_filled_buffers() {
+    use crate::{SendPhase, FILE_PIECE_SIZE};
+
+    let bytes = mock_file_bytes(16 * FILE_PIECE_SIZE, 15);
+    let metadata = mock_file_metadata(&bytes, 15);
+    let sha256 = metadata.sha256();
+
+    let mut swarm = MockSwarm::new();
+    let seeder = swarm.add_peer();
+    swarm
+        .peer_mut(seeder)
+        .set_piece_send_limits(PieceSendLimits {
+            max_buffer_bytes: Some(FILE_PIECE_SIZE as u64),
+            max_batch_size: Some(1),
+            ..PieceSendLimits::default()
+        });
+    swarm
+        .peer_mut(seeder)
+        .add_file(mock_complete_file(metadata.clone(), &bytes));
+    // Leechers do not forward pieces, so only pieces of the seeder are counted.
+    let leechers: Vec<_> = (0..2).map(|_| swarm.add_peer()).collect();
+    for &leecher in &leechers {
+        let phases = SendPhases::new(&[
+            SendPhase::State,
+            SendPhase::RecentlyReceived,
+            SendPhase::ResendPieces,
+        ])
+        .unwrap();
+        swarm.peer_mut(leecher).set_send_phases(phases);
+        swarm
+            .peer_mut(leecher)
+            .add_file(File::new(metadata.clone()).unwrap());
+    }
+
+    while swarm.num_sent_pieces() == 0 {
+        swarm.step(8);
+    }
+
+    // A single piece fills the buffer of a peer until it is delivered,
+    // but other peers still receive pieces.
+    let num_sent_pieces = swarm.num_sent_pieces();
+    swarm.step(8);
+    assert_eq!(swarm.num_sent_pieces() - num_sent_pieces, 2);
+    for &leecher in &leechers {
+        assert!(swarm
+            .peer(seeder)
+            .file(&sha256)
+            .unwrap()
+            .peer_send_blocked_since(&leecher)
+            .is_some());
+    }
+
+    for _ in 0..50 {
+        swarm.step(8);
+    }
+    for &leecher in &leechers {
+        assert_file_received(&swarm, leecher, &sha256, &bytes);
+    }
+}
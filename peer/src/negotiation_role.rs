@@ -0,0 +1,60 @@
+use tracker_protocol::PeerId;
+
+/// Peer role in the perfect negotiation pattern, used to resolve offer collisions.
+///
+/// Both peers derive opposite roles from their ids, so exactly one of them yields.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum NegotiationRole {
+    /// Rolls back its own offer and accepts the colliding remote offer.
+    Polite,
+    /// Ignores the colliding remote offer and waits for the answer to its own offer.
+    Impolite,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum OfferAction {
+    Accept,
+    RollbackAndAccept,
+    Ignore,
+}
+
+impl NegotiationRole {
+    pub fn new(local_peer_id: PeerId, remote_peer_id: PeerId) -> Self {
+        if local_peer_id.0 < remote_peer_id.0 {
+            Self::Polite
+        } else {
+            Self::Impolite
+        }
+    }
+
+    /// Returns how to handle a remote offer,
+    /// `is_collision` is `true` if a local offer is being made or is not answered yet.
+    pub fn offer_action(self, is_collision: bool) -> OfferAction {
+        match (self, is_collision) {
+            (_, false) => OfferAction::Accept,
+            (Self::Polite, true) => OfferAction::RollbackAndAccept,
+            (Self::Impolite, true) => OfferAction::Ignore,
+        }
+    }
+}
+
+#[test]
+fn assign_opposite_negotiation_roles() {
+    let first = NegotiationRole::new(PeerId(1), PeerId(2));
+    let second = NegotiationRole::new(PeerId(2), PeerId(1));
+    assert_eq!(first, NegotiationRole::Polite);
+    assert_eq!(second, NegotiationRole::Impolite);
+}
+
+#[test]
+fn resolve_offer_collision() {
+    let polite = NegotiationRole::new(PeerId(3), PeerId(7));
+    let impolite = NegotiationRole::new(PeerId(7), PeerId(3));
+
+    assert_eq!(polite.offer_action(false), OfferAction::Accept);
+    assert_eq!(impolite.offer_action(false), OfferAction::Accept);
+
+    // On glare exactly one offer survives.
+    assert_eq!(polite.offer_action(true), OfferAction::RollbackAndAccept);
+    assert_eq!(impolite.offer_action(true), OfferAction::Ignore);
+}
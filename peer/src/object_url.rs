@@ -8,6 +8,10 @@ impl ObjectUrl {
         use web_sys::Url;
         Self(Url::create_object_url_with_blob(&blob).unwrap())
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 impl Drop for ObjectUrl {
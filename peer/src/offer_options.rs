@@ -0,0 +1,58 @@
+use web_sys::{RtcIceConnectionState, RtcOfferOptions};
+
+/// Options of a local offer.
+///
+/// An ICE restart renegotiates the transport only,
+/// so the negotiated data channel and the piece bookkeeping survive it.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct OfferOptions {
+    ice_restart: bool,
+}
+
+impl OfferOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_ice_restart(self, ice_restart: bool) -> Self {
+        Self { ice_restart }
+    }
+
+    pub fn ice_restart(&self) -> bool {
+        self.ice_restart
+    }
+
+    pub fn to_rtc_offer_options(&self) -> RtcOfferOptions {
+        let mut options = RtcOfferOptions::new();
+        let _: &mut RtcOfferOptions = options.ice_restart(self.ice_restart);
+        options
+    }
+}
+
+/// Returns `true` if the connection is lost and the offering side should restart ICE.
+pub fn needs_ice_restart(state: RtcIceConnectionState) -> bool {
+    matches!(
+        state,
+        RtcIceConnectionState::Failed | RtcIceConnectionState::Disconnected
+    )
+}
+
+#[test]
+fn build_ice_restart_offer_options() {
+    let options = OfferOptions::new();
+    assert!(!options.ice_restart());
+
+    let options = options.with_ice_restart(true);
+    assert!(options.ice_restart());
+    assert_eq!(options, OfferOptions { ice_restart: true });
+    assert!(!options.with_ice_restart(false).ice_restart());
+}
+
+#[test]
+fn restart_ice_on_lost_connection() {
+    assert!(needs_ice_restart(RtcIceConnectionState::Failed));
+    assert!(needs_ice_restart(RtcIceConnectionState::Disconnected));
+    assert!(!needs_ice_restart(RtcIceConnectionState::Checking));
+    assert!(!needs_ice_restart(RtcIceConnectionState::Connected));
+    assert!(!needs_ice_restart(RtcIceConnectionState::Closed));
+}
@@ -15,7 +15,7 @@ impl<T, E: Display> OkOrLog for Result<T, E> {
         match self {
             Ok(ok) => Some(ok),
             Err(err) => {
-                log::error!("{}", err);
+                crate::log_scoped!(error, "{}", err);
                 None
             }
         }
@@ -27,7 +27,7 @@ impl<E: Display> OrLog for Result<(), E> {
         match self {
             Ok(()) => {}
             Err(err) => {
-                log::error!("{}", err);
+                crate::log_scoped!(error, "{}", err);
             }
         }
     }
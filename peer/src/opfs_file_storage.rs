@@ -0,0 +1,209 @@
+use core::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use futures::channel::oneshot;
+use futures::future::LocalBoxFuture;
+use wasm_bindgen::JsValue;
+use web_sys::{MessageEvent, Worker};
+
+use crate::{ClosureCell1, FileStorage, FileStorageError};
+
+type OpfsResponseSender = oneshot::Sender<Result<JsValue, JsValue>>;
+
+/// Dedicated worker script accessing the OPFS file through a sync access handle.
+///
+/// Requests are handled one by one in the order they are received,
+/// so responses are posted in the same order.
+const OPFS_WORKER_SOURCE: &str = r#"
+let handle;
+let queue = Promise.resolve();
+
+async function run([operation, ...args]) {
+    switch (operation) {
+        case "open": {
+            const root = await navigator.storage.getDirectory();
+            const file = await root.getFileHandle(args[0], { create: true });
+            handle = await file.createSyncAccessHandle();
+            return null;
+        }
+        case "read": {
+            const [offset, len] = args;
+            const bytes = new Uint8Array(len);
+            if (handle.read(bytes, { at: offset }) !== len) {
+                throw new Error("unexpected end of file");
+            }
+            return bytes;
+        }
+        case "write": {
+            const [offset, bytes] = args;
+            if (handle.write(bytes, { at: offset }) !== bytes.length) {
+                throw new Error("incomplete write");
+            }
+            return null;
+        }
+    }
+}
+
+onmessage = (event) => {
+    queue = queue
+        .then(() => run(event.data))
+        .then(
+            (ok) => postMessage({ ok }, ok ? [ok.buffer] : []),
+            (error) => postMessage({ error: String(error) }),
+        );
+};
+"#;
+
+/// File storage in the Origin Private File System.
+///
+/// OPFS files are not limited by the wasm linear memory,
+/// so downloads larger than it can be stored.
+/// The file is accessed by a dedicated worker through a sync access handle,
+/// which writes in place, unlike writable streams that copy the whole file on every write.
+#[derive(Debug)]
+pub struct OpfsFileStorage {
+    worker: Worker,
+    responses: Rc<RefCell<VecDeque<OpfsResponseSender>>>,
+    message_handler: ClosureCell1<MessageEvent>,
+}
+
+impl OpfsFileStorage {
+    /// Opens the file with the given name in the OPFS root directory, creating it if needed.
+    pub async fn open(name: &str) -> Result<Self, FileStorageError> {
+        use crate::{Callback, ObjectUrl};
+        use js_sys::{Array, Reflect};
+        use wasm_bindgen::closure::Closure;
+        use wasm_bindgen::JsCast;
+        use web_sys::{Blob, BlobPropertyBag};
+
+        let mut options = BlobPropertyBag::new();
+        let _: &mut _ = options.type_("text/javascript");
+        let source = Blob::new_with_str_sequence_and_options(
+            &Array::of1(&JsValue::from_str(OPFS_WORKER_SOURCE)),
+            &options,
+        )
+        .map_err(|err| log_storage_error("create OPFS worker script", err))?;
+        let worker = Worker::new(ObjectUrl::from_blob(source).as_str())
+            .map_err(|err| log_storage_error("start OPFS worker", err))?;
+
+        let responses: Rc<RefCell<VecDeque<OpfsResponseSender>>> = Rc::default();
+        let closure = Closure::with_callback({
+            let responses = Rc::clone(&responses);
+            move |event: MessageEvent| {
+                let data = event.data();
+                let response = match Reflect::get(&data, &JsValue::from_str("error")) {
+                    Ok(error) if !error.is_undefined() => Err(error),
+                    Ok(_) | Err(_) => {
+                        Ok(Reflect::get(&data, &JsValue::from_str("ok")).unwrap_or(JsValue::NULL))
+                    }
+                };
+                if let Some(sender) = responses.borrow_mut().pop_front() {
+                    let _: Result<(), _> = sender.send(response);
+                }
+            }
+        });
+        worker.set_onmessage(Some(closure.as_ref().unchecked_ref()));
+
+        let storage = Self {
+            worker,
+            responses,
+            message_handler: RefCell::new(Some(closure)),
+        };
+        let _: JsValue = storage
+            .request(&Array::of2(&"open".into(), &name.into()), None)
+            .await
+            .map_err(|err| log_storage_error("open OPFS file", err))?;
+        Ok(storage)
+    }
+
+    async fn request(
+        &self,
+        request: &js_sys::Array,
+        transfer: Option<&JsValue>,
+    ) -> Result<JsValue, JsValue> {
+        use js_sys::Array;
+
+        let (sender, receiver) = oneshot::channel();
+        let posted = match transfer {
+            Some(transfer) => self
+                .worker
+                .post_message_with_transfer(request, &Array::of1(transfer)),
+            None => self.worker.post_message(request),
+        };
+        posted?;
+        self.responses.borrow_mut().push_back(sender);
+        receiver
+            .await
+            .unwrap_or_else(|_| Err(JsValue::from_str("OPFS worker is terminated")))
+    }
+}
+
+impl FileStorage for OpfsFileStorage {
+    fn read(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> LocalBoxFuture<'_, Result<Box<[u8]>, FileStorageError>> {
+        use js_sys::{Array, Uint8Array};
+        use wasm_bindgen::JsCast;
+
+        Box::pin(async move {
+            let err = FileStorageError::Read { offset, len };
+            let request = Array::of3(&"read".into(), &(offset as f64).into(), &len.into());
+            let bytes: Uint8Array = self
+                .request(&request, None)
+                .await
+                .map_err(|_| err)?
+                .dyn_into()
+                .map_err(|_| err)?;
+            let bytes = bytes.to_vec();
+            if bytes.len() == len {
+                Ok(bytes.into_boxed_slice())
+            } else {
+                Err(err)
+            }
+        })
+    }
+
+    fn write<'a>(
+        &'a self,
+        offset: u64,
+        data: &'a [u8],
+    ) -> LocalBoxFuture<'a, Result<(), FileStorageError>> {
+        use js_sys::{Array, Uint8Array};
+
+        Box::pin(async move {
+            let err = FileStorageError::Write {
+                offset,
+                len: data.len(),
+            };
+
+            // The data is copied out of the wasm memory,
+            // which may be reallocated before the write is completed.
+            let data = Uint8Array::from(data);
+            let request = Array::of3(&"write".into(), &(offset as f64).into(), &data);
+            let _: JsValue = self
+                .request(&request, Some(&data.buffer()))
+                .await
+                .map_err(|_| err)?;
+            Ok(())
+        })
+    }
+}
+
+impl Drop for OpfsFileStorage {
+    fn drop(&mut self) {
+        use crate::ClearClosureCell;
+
+        // The sync access handle is released once the worker is terminated.
+        self.worker.set_onmessage(None);
+        self.worker.terminate();
+        let _: bool = self.message_handler.clear_closure();
+    }
+}
+
+fn log_storage_error(operation: &str, err: JsValue) -> FileStorageError {
+    log::warn!("failed to {}: {:?}", operation, err);
+    FileStorageError::Unavailable
+}
@@ -0,0 +1,103 @@
+use core::fmt;
+use std::collections::HashSet;
+
+use tracker_protocol::PeerId;
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PeerChangeEvent {
+    /// The peer data channel is open and the peer is ready to exchange messages.
+    Connected(PeerId),
+    /// The connected peer is closed and removed.
+    Disconnected(PeerId),
+}
+
+/// Connected peers, reports each readiness transition once.
+///
+/// Peers removed before becoming ready are not reported at all.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ConnectedPeers {
+    peers: HashSet<PeerId>,
+}
+
+impl ConnectedPeers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    pub fn contains(&self, peer_id: PeerId) -> bool {
+        self.peers.contains(&peer_id)
+    }
+
+    /// Returns the connected event unless the peer is already connected.
+    pub fn on_ready(&mut self, peer_id: PeerId) -> Option<PeerChangeEvent> {
+        self.peers
+            .insert(peer_id)
+            .then_some(PeerChangeEvent::Connected(peer_id))
+    }
+
+    /// Returns the disconnected event if the peer was connected.
+    pub fn on_removed(&mut self, peer_id: PeerId) -> Option<PeerChangeEvent> {
+        self.peers
+            .remove(&peer_id)
+            .then_some(PeerChangeEvent::Disconnected(peer_id))
+    }
+}
+
+/// Callback invoked on peer changes.
+pub struct PeerChangeHandler(Box<dyn FnMut(PeerChangeEvent)>);
+
+impl PeerChangeHandler {
+    pub fn new(callback: impl 'static + FnMut(PeerChangeEvent)) -> Self {
+        Self(Box::new(callback))
+    }
+
+    pub fn call(&mut self, event: PeerChangeEvent) {
+        (self.0)(event);
+    }
+}
+
+impl fmt::Debug for PeerChangeHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("PeerChangeHandler").finish()
+    }
+}
+
+#[test]
+fn report_peer_changes_once() {
+    let mut peers = ConnectedPeers::new();
+    let mut events = Vec::new();
+
+    events.extend(peers.on_ready(PeerId(1)));
+    events.extend(peers.on_ready(PeerId(1)));
+    events.extend(peers.on_ready(PeerId(2)));
+    assert_eq!(peers.len(), 2);
+
+    // Peers that never became ready are removed silently.
+    events.extend(peers.on_removed(PeerId(3)));
+    events.extend(peers.on_removed(PeerId(1)));
+    events.extend(peers.on_removed(PeerId(1)));
+    assert_eq!(peers.len(), 1);
+    assert!(!peers.contains(PeerId(1)));
+    assert!(peers.contains(PeerId(2)));
+
+    // A reconnected peer is reported again.
+    events.extend(peers.on_ready(PeerId(1)));
+
+    assert_eq!(
+        events,
+        [
+            PeerChangeEvent::Connected(PeerId(1)),
+            PeerChangeEvent::Connected(PeerId(2)),
+            PeerChangeEvent::Disconnected(PeerId(1)),
+            PeerChangeEvent::Connected(PeerId(1)),
+        ]
+    );
+}
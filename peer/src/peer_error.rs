@@ -0,0 +1,118 @@
+use core::fmt::{self, Display};
+
+use thiserror::Error;
+use tracker_protocol::PeerId;
+use wasm_bindgen::JsValue;
+
+use crate::{
+    DataChannelConfigError, DataChannelMismatchError, PieceDecryptError, PieceEncryptError,
+    PieceKeyError, ProtocolVersionError,
+};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PeerOperation {
+    CreatePeerConnection,
+    CreateOffer,
+    CreateAnswer,
+    SetLocalDescription,
+    SetRemoteDescription,
+    RollbackLocalDescription,
+    CreateIceCandidate,
+    AddIceCandidate,
+    SendData,
+    ReceiveData,
+}
+
+#[derive(Clone, Debug, Error, Eq, PartialEq)]
+pub enum PeerError {
+    #[error("peer {peer_id}: {operation} failed: {message}")]
+    JsError {
+        peer_id: PeerId,
+        operation: PeerOperation,
+        message: String,
+    },
+    #[error("peer {peer_id}: {operation} returned invalid session description")]
+    InvalidSessionDescription {
+        peer_id: PeerId,
+        operation: PeerOperation,
+    },
+    #[error("peer {peer_id}: message size {len} exceeds maximum message size {max_len}")]
+    MessageIsTooLarge {
+        peer_id: PeerId,
+        len: usize,
+        max_len: usize,
+    },
+    #[error("peer {peer_id}: message serialization failed: {message}")]
+    SerializationError { peer_id: PeerId, message: String },
+    #[error("peer {peer_id}: message deserialization failed: {message}")]
+    DeserializationError { peer_id: PeerId, message: String },
+    #[error("peer {peer_id}: {err}")]
+    InvalidDataChannelConfig {
+        peer_id: PeerId,
+        err: DataChannelConfigError,
+    },
+    #[error("peer {peer_id}: {err}")]
+    DataChannelMismatch {
+        peer_id: PeerId,
+        err: DataChannelMismatchError,
+    },
+    #[error("peer {peer_id}: {err}")]
+    ProtocolVersionMismatch {
+        peer_id: PeerId,
+        err: ProtocolVersionError,
+    },
+    #[error("peer {peer_id}: {err}")]
+    PieceKey { peer_id: PeerId, err: PieceKeyError },
+    #[error("peer {peer_id}: {err}")]
+    PieceEncrypt {
+        peer_id: PeerId,
+        err: PieceEncryptError,
+    },
+    #[error("peer {peer_id}: {err}")]
+    PieceDecrypt {
+        peer_id: PeerId,
+        err: PieceDecryptError,
+    },
+}
+
+impl PeerError {
+    pub fn js(peer_id: PeerId, operation: PeerOperation, value: &JsValue) -> Self {
+        Self::JsError {
+            peer_id,
+            operation,
+            message: js_error_message(value),
+        }
+    }
+}
+
+impl Display for PeerOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::CreatePeerConnection => "create peer connection",
+            Self::CreateOffer => "create offer",
+            Self::CreateAnswer => "create answer",
+            Self::SetLocalDescription => "set local description",
+            Self::SetRemoteDescription => "set remote description",
+            Self::RollbackLocalDescription => "rollback local description",
+            Self::CreateIceCandidate => "create ice candidate",
+            Self::AddIceCandidate => "add ice candidate",
+            Self::SendData => "send data",
+            Self::ReceiveData => "receive data",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// JS exceptions are usually `Error` or `DOMException` objects with a `message` property.
+fn js_error_message(value: &JsValue) -> String {
+    use js_sys::Reflect;
+
+    value
+        .as_string()
+        .or_else(|| {
+            Reflect::get(value, &JsValue::from_str("message"))
+                .ok()
+                .and_then(|message| message.as_string())
+        })
+        .unwrap_or_else(|| format!("{:?}", value))
+}
@@ -0,0 +1,170 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{FilePieceIdx, FILE_PIECE_SIZE};
+
+/// Default memory budget of the cache of recently read pieces.
+pub const DEFAULT_PIECE_CACHE_BYTES: usize = 64 * FILE_PIECE_SIZE;
+
+/// Least recently used cache of piece bytes,
+/// avoids reading hot pieces sent to many peers from the file chunks again.
+#[derive(Clone, Debug)]
+pub struct PieceCache {
+    pieces: HashMap<FilePieceIdx, Box<[u8]>>,
+    /// Cached pieces from the least to the most recently used.
+    order: VecDeque<FilePieceIdx>,
+    max_bytes: usize,
+    num_bytes: usize,
+    num_hits: u64,
+    num_misses: u64,
+}
+
+impl Default for PieceCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_PIECE_CACHE_BYTES)
+    }
+}
+
+impl PieceCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            pieces: HashMap::new(),
+            order: VecDeque::new(),
+            max_bytes,
+            num_bytes: 0,
+            num_hits: 0,
+            num_misses: 0,
+        }
+    }
+
+    /// Sets the memory budget, zero disables caching.
+    pub fn set_max_bytes(&mut self, max_bytes: usize) {
+        self.max_bytes = max_bytes;
+        self.evict();
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    pub fn num_bytes(&self) -> usize {
+        self.num_bytes
+    }
+
+    pub fn num_hits(&self) -> u64 {
+        self.num_hits
+    }
+
+    pub fn num_misses(&self) -> u64 {
+        self.num_misses
+    }
+
+    pub fn contains(&self, piece_idx: &FilePieceIdx) -> bool {
+        self.pieces.contains_key(piece_idx)
+    }
+
+    /// Returns cached piece bytes and marks the piece as the most recently used.
+    pub fn get(&mut self, piece_idx: &FilePieceIdx) -> Option<&[u8]> {
+        if !self.pieces.contains_key(piece_idx) {
+            self.num_misses += 1;
+            return None;
+        }
+        self.num_hits += 1;
+        self.touch(piece_idx);
+        self.pieces.get(piece_idx).map(|bytes| &**bytes)
+    }
+
+    /// Caches piece bytes evicting the least recently used pieces over the budget,
+    /// pieces larger than the whole budget are not cached.
+    pub fn insert(&mut self, piece_idx: FilePieceIdx, bytes: Box<[u8]>) {
+        if bytes.len() > self.max_bytes {
+            return;
+        }
+        self.num_bytes += bytes.len();
+        match self.pieces.insert(piece_idx, bytes) {
+            Some(prev) => {
+                self.num_bytes -= prev.len();
+                self.touch(&piece_idx);
+            }
+            None => self.order.push_back(piece_idx),
+        }
+        self.evict();
+    }
+
+    pub fn remove(&mut self, piece_idx: &FilePieceIdx) {
+        if let Some(bytes) = self.pieces.remove(piece_idx) {
+            self.num_bytes -= bytes.len();
+            self.order.retain(|idx| idx != piece_idx);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.pieces.clear();
+        self.order.clear();
+        self.num_bytes = 0;
+    }
+
+    fn touch(&mut self, piece_idx: &FilePieceIdx) {
+        if let Some(pos) = self.order.iter().position(|idx| idx == piece_idx) {
+            let _: Option<FilePieceIdx> = self.order.remove(pos);
+            self.order.push_back(*piece_idx);
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.num_bytes > self.max_bytes {
+            let piece_idx = self.order.pop_front().unwrap();
+            self.num_bytes -= self.pieces.remove(&piece_idx).unwrap().len();
+        }
+    }
+}
+
+#[test]
+fn hit_cached_pieces() {
+    let mut cache = PieceCache::new(4 * FILE_PIECE_SIZE);
+    assert_eq!(cache.get(&FilePieceIdx(1)), None);
+    assert_eq!(cache.num_misses(), 1);
+
+    cache.insert(FilePieceIdx(1), vec![1; FILE_PIECE_SIZE].into_boxed_slice());
+    for _ in 0..3 {
+        assert_eq!(cache.get(&FilePieceIdx(1)), Some(&[1; FILE_PIECE_SIZE][..]));
+    }
+    assert_eq!(cache.num_hits(), 3);
+    assert_eq!(cache.num_misses(), 1);
+
+    cache.insert(FilePieceIdx(1), vec![2; 10].into_boxed_slice());
+    assert_eq!(cache.num_bytes(), 10);
+    cache.remove(&FilePieceIdx(1));
+    assert_eq!(cache.num_bytes(), 0);
+    assert_eq!(cache.get(&FilePieceIdx(1)), None);
+}
+
+#[test]
+fn evict_least_recently_used_pieces() {
+    let mut cache = PieceCache::new(3 * FILE_PIECE_SIZE);
+    for idx in 0..3 {
+        cache.insert(
+            FilePieceIdx(idx),
+            vec![0; FILE_PIECE_SIZE].into_boxed_slice(),
+        );
+    }
+    assert_eq!(cache.num_bytes(), 3 * FILE_PIECE_SIZE);
+
+    // The first piece becomes the most recently used one, so the second one is evicted.
+    assert!(cache.get(&FilePieceIdx(0)).is_some());
+    cache.insert(FilePieceIdx(3), vec![0; FILE_PIECE_SIZE].into_boxed_slice());
+    assert_eq!(cache.num_bytes(), 3 * FILE_PIECE_SIZE);
+    assert!(cache.contains(&FilePieceIdx(0)));
+    assert!(!cache.contains(&FilePieceIdx(1)));
+    assert!(cache.contains(&FilePieceIdx(2)));
+    assert!(cache.contains(&FilePieceIdx(3)));
+
+    cache.insert(
+        FilePieceIdx(4),
+        vec![0; 4 * FILE_PIECE_SIZE].into_boxed_slice(),
+    );
+    assert!(!cache.contains(&FilePieceIdx(4)));
+
+    cache.set_max_bytes(FILE_PIECE_SIZE);
+    assert_eq!(cache.num_bytes(), FILE_PIECE_SIZE);
+    assert!(cache.contains(&FilePieceIdx(3)));
+}
@@ -0,0 +1,285 @@
+use core::fmt;
+
+use chacha20poly1305::XChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracker_protocol::FileSha256;
+
+use crate::FilePieceIdx;
+
+/// Number of PBKDF2 rounds used to derive the piece key from a passphrase.
+pub const PIECE_KEY_ROUNDS: u32 = 100_000;
+
+const PIECE_KEY_SALT: &[u8] = b"webrtc-file-sharing piece key";
+const PIECE_KEY_ID_PREFIX: &[u8] = b"webrtc-file-sharing piece key id";
+const PIECE_NONCE_LEN: usize = 24;
+const PIECE_TAG_LEN: usize = 16;
+
+/// Piece key fingerprint announced to peers,
+/// so that peers using different passphrases detect the mismatch without revealing the key.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct PieceKeyId(pub [u8; 8]);
+
+/// Encrypts file pieces with a key derived from a passphrase shared out-of-band,
+/// so that pieces relayed through a compromised TURN server stay private
+/// regardless of the data channel DTLS encryption.
+///
+/// Encrypted pieces are bound to their file and index,
+/// so they can not be substituted for other pieces.
+#[derive(Clone)]
+pub struct PieceCipher {
+    cipher: XChaCha20Poly1305,
+    key_id: PieceKeyId,
+}
+
+impl PieceCipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        use chacha20poly1305::{Key, KeyInit};
+        use sha2::{Digest, Sha256};
+
+        let digest = Sha256::new()
+            .chain(PIECE_KEY_ID_PREFIX)
+            .chain(key)
+            .finalize();
+        let mut key_id = [0; 8];
+        key_id.copy_from_slice(&digest[..8]);
+        Self {
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+            key_id: PieceKeyId(key_id),
+        }
+    }
+
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        Self::new(derive_piece_key(passphrase, PIECE_KEY_ROUNDS))
+    }
+
+    pub fn key_id(&self) -> PieceKeyId {
+        self.key_id
+    }
+
+    /// Encrypts piece bytes, the random nonce is prepended to the encrypted bytes.
+    pub fn encrypt(
+        &self,
+        sha256: FileSha256,
+        piece_idx: FilePieceIdx,
+        bytes: &[u8],
+    ) -> Result<Box<[u8]>, PieceEncryptError> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        use chacha20poly1305::XNonce;
+
+        let mut nonce = [0; PIECE_NONCE_LEN];
+        getrandom::getrandom(&mut nonce).map_err(|_| PieceEncryptError::RandomIsUnavailable)?;
+        let aad = piece_aad(sha256, piece_idx);
+        let payload = Payload {
+            msg: bytes,
+            aad: &aad,
+        };
+        let encrypted = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| PieceEncryptError::PieceIsTooLarge)?;
+        Ok(nonce.iter().copied().chain(encrypted).collect())
+    }
+
+    /// Decrypts piece bytes encrypted with `encrypt` using the same key.
+    pub fn decrypt(
+        &self,
+        sha256: FileSha256,
+        piece_idx: FilePieceIdx,
+        bytes: &[u8],
+    ) -> Result<Box<[u8]>, PieceDecryptError> {
+        use chacha20poly1305::aead::{Aead, Payload};
+        use chacha20poly1305::XNonce;
+
+        if bytes.len() < PIECE_NONCE_LEN + PIECE_TAG_LEN {
+            return Err(PieceDecryptError::InvalidLen { len: bytes.len() });
+        }
+        let (nonce, encrypted) = bytes.split_at(PIECE_NONCE_LEN);
+        let aad = piece_aad(sha256, piece_idx);
+        let payload = Payload {
+            msg: encrypted,
+            aad: &aad,
+        };
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), payload)
+            .map(Vec::into_boxed_slice)
+            .map_err(|_| PieceDecryptError::AuthenticationFailed)
+    }
+
+    pub fn encrypt_pieces(
+        &self,
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+    ) -> Result<Vec<(FilePieceIdx, Box<[u8]>)>, PieceEncryptError> {
+        pieces
+            .into_iter()
+            .map(|(piece_idx, bytes)| Ok((piece_idx, self.encrypt(sha256, piece_idx, &bytes)?)))
+            .collect()
+    }
+
+    pub fn decrypt_pieces(
+        &self,
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+    ) -> Result<Vec<(FilePieceIdx, Box<[u8]>)>, PieceDecryptError> {
+        pieces
+            .into_iter()
+            .map(|(piece_idx, bytes)| Ok((piece_idx, self.decrypt(sha256, piece_idx, &bytes)?)))
+            .collect()
+    }
+}
+
+impl fmt::Debug for PieceCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PieceCipher")
+            .field("key_id", &self.key_id)
+            .finish()
+    }
+}
+
+/// Derives the piece key from the passphrase with PBKDF2-HMAC-SHA256.
+pub fn derive_piece_key(passphrase: &str, rounds: u32) -> [u8; 32] {
+    use hmac::Hmac;
+    use sha2::Sha256;
+
+    let mut key = [0; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), PIECE_KEY_SALT, rounds, &mut key);
+    key
+}
+
+/// Checks the piece key announced by the remote peer,
+/// `local_key_id` is `None` if the local peer does not encrypt pieces.
+pub fn check_remote_piece_key(
+    local_key_id: Option<PieceKeyId>,
+    remote_key_id: PieceKeyId,
+) -> Result<(), PieceKeyError> {
+    match local_key_id {
+        Some(local_key_id) if local_key_id == remote_key_id => Ok(()),
+        Some(_) => Err(PieceKeyError::KeyMismatch),
+        None => Err(PieceKeyError::EncryptionIsDisabled),
+    }
+}
+
+fn piece_aad(sha256: FileSha256, piece_idx: FilePieceIdx) -> Vec<u8> {
+    sha256
+        .0
+        .iter()
+        .copied()
+        .chain((piece_idx.0 as u64).to_le_bytes())
+        .collect()
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum PieceKeyError {
+    #[error("remote peer encrypts pieces, but local piece encryption is disabled")]
+    EncryptionIsDisabled,
+    #[error("remote piece key does not match the local one")]
+    KeyMismatch,
+    #[error("remote peer sends unencrypted pieces, but local piece encryption is enabled")]
+    UnencryptedPieces,
+    #[error("remote peer sends file messages before announcing its piece key")]
+    KeyIsNotAnnounced,
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum PieceEncryptError {
+    #[error("random nonce generation is unavailable")]
+    RandomIsUnavailable,
+    #[error("piece is too large to be encrypted")]
+    PieceIsTooLarge,
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum PieceDecryptError {
+    #[error("encrypted piece length {len} is invalid")]
+    InvalidLen { len: usize },
+    #[error("piece authentication failed, the key does not match or the piece is corrupted")]
+    AuthenticationFailed,
+}
+
+#[test]
+fn encrypt_and_decrypt_piece() {
+    use crate::FILE_PIECE_SIZE;
+
+    let sha256 = FileSha256([5; 32]);
+    let cipher = PieceCipher::new(derive_piece_key("secret", 1));
+    let bytes: Vec<u8> = (0..FILE_PIECE_SIZE).map(|j| j as u8).collect();
+
+    let encrypted = cipher.encrypt(sha256, FilePieceIdx(3), &bytes).unwrap();
+    assert_eq!(
+        encrypted.len(),
+        PIECE_NONCE_LEN + FILE_PIECE_SIZE + PIECE_TAG_LEN
+    );
+    assert!(!encrypted.windows(16).any(|window| window == &bytes[..16]));
+    assert_eq!(
+        cipher
+            .decrypt(sha256, FilePieceIdx(3), &encrypted)
+            .as_deref(),
+        Ok(&bytes[..])
+    );
+
+    // Nonces are random, so equal pieces are encrypted differently.
+    assert_ne!(
+        cipher.encrypt(sha256, FilePieceIdx(3), &bytes).unwrap(),
+        encrypted
+    );
+
+    // The same passphrase derives the same key.
+    let same_cipher = PieceCipher::new(derive_piece_key("secret", 1));
+    assert_eq!(same_cipher.key_id(), cipher.key_id());
+    assert_eq!(
+        same_cipher
+            .decrypt(sha256, FilePieceIdx(3), &encrypted)
+            .as_deref(),
+        Ok(&bytes[..])
+    );
+}
+
+#[test]
+fn refuse_piece_encrypted_with_wrong_key() {
+    let sha256 = FileSha256([5; 32]);
+    let cipher = PieceCipher::new(derive_piece_key("secret", 1));
+    let wrong_cipher = PieceCipher::new(derive_piece_key("guess", 1));
+    assert_ne!(wrong_cipher.key_id(), cipher.key_id());
+
+    assert_eq!(
+        check_remote_piece_key(Some(cipher.key_id()), wrong_cipher.key_id()),
+        Err(PieceKeyError::KeyMismatch)
+    );
+    assert_eq!(
+        check_remote_piece_key(None, cipher.key_id()),
+        Err(PieceKeyError::EncryptionIsDisabled)
+    );
+    assert_eq!(
+        check_remote_piece_key(Some(cipher.key_id()), cipher.key_id()),
+        Ok(())
+    );
+
+    let encrypted = cipher.encrypt(sha256, FilePieceIdx(3), &[7; 100]).unwrap();
+    assert_eq!(
+        wrong_cipher.decrypt(sha256, FilePieceIdx(3), &encrypted),
+        Err(PieceDecryptError::AuthenticationFailed)
+    );
+
+    // Pieces can not be substituted for other pieces or corrupted.
+    assert_eq!(
+        cipher.decrypt(sha256, FilePieceIdx(4), &encrypted),
+        Err(PieceDecryptError::AuthenticationFailed)
+    );
+    assert_eq!(
+        cipher.decrypt(FileSha256([6; 32]), FilePieceIdx(3), &encrypted),
+        Err(PieceDecryptError::AuthenticationFailed)
+    );
+    let mut corrupted = encrypted.clone();
+    corrupted[PIECE_NONCE_LEN] ^= 1;
+    assert_eq!(
+        cipher.decrypt(sha256, FilePieceIdx(3), &corrupted),
+        Err(PieceDecryptError::AuthenticationFailed)
+    );
+    assert_eq!(
+        cipher.decrypt(sha256, FilePieceIdx(3), &encrypted[..PIECE_NONCE_LEN]),
+        Err(PieceDecryptError::InvalidLen {
+            len: PIECE_NONCE_LEN
+        })
+    );
+}
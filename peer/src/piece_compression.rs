@@ -0,0 +1,65 @@
+use thiserror::Error;
+
+use crate::FILE_PIECE_SIZE;
+
+/// Compresses piece bytes, returns `None` if compression does not shrink the piece.
+pub fn compress_piece(bytes: &[u8]) -> Option<Box<[u8]>> {
+    let compressed = lz4_flex::block::compress(bytes);
+    if compressed.len() < bytes.len() {
+        Some(compressed.into_boxed_slice())
+    } else {
+        None
+    }
+}
+
+/// Decompresses piece bytes compressed with `compress_piece`,
+/// `len` is the original piece length.
+pub fn decompress_piece(bytes: &[u8], len: usize) -> Result<Box<[u8]>, PieceDecompressError> {
+    if len > FILE_PIECE_SIZE {
+        return Err(PieceDecompressError::InvalidLen { len });
+    }
+    let decompressed =
+        lz4_flex::block::decompress(bytes, len).map_err(|_| PieceDecompressError::InvalidData)?;
+    if decompressed.len() != len {
+        return Err(PieceDecompressError::InvalidLen {
+            len: decompressed.len(),
+        });
+    }
+    Ok(decompressed.into_boxed_slice())
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum PieceDecompressError {
+    #[error("decompressed piece length {len} is invalid")]
+    InvalidLen { len: usize },
+    #[error("compressed piece data is invalid")]
+    InvalidData,
+}
+
+#[test]
+fn compress_piece_roundtrip() {
+    use rand::rngs::StdRng;
+    use rand::{RngCore, SeedableRng};
+
+    let text = b"the quick brown fox jumps over the lazy dog ".repeat(30);
+    let text = &text[..FILE_PIECE_SIZE];
+    let compressed = compress_piece(text).unwrap();
+    assert!(compressed.len() < text.len());
+    assert_eq!(
+        decompress_piece(&compressed, text.len()).as_deref(),
+        Ok(text)
+    );
+
+    // Random data does not shrink, so it is sent as is.
+    let mut random = [0; FILE_PIECE_SIZE];
+    StdRng::seed_from_u64(0).fill_bytes(&mut random);
+    assert_eq!(compress_piece(&random), None);
+
+    assert_eq!(
+        decompress_piece(&compressed, FILE_PIECE_SIZE + 1),
+        Err(PieceDecompressError::InvalidLen {
+            len: FILE_PIECE_SIZE + 1
+        })
+    );
+    assert!(decompress_piece(&compressed[..compressed.len() / 2], text.len()).is_err());
+}
@@ -0,0 +1,67 @@
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of pieces of a single file requested and not received yet in pull mode.
+pub const MAX_PENDING_PIECE_REQUESTS: usize = 64;
+
+/// How file pieces are transferred from a remote peer to the local one.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum PieceTransferMode {
+    /// The uploader selects pieces and pushes them, see `SharedFile::select_piece_peer`.
+    #[default]
+    Push,
+    /// The downloader requests the rarest pieces with `PeerPeerMessage::RequestPiece`,
+    /// see `SharedFile::select_piece_requests`.
+    Pull,
+}
+
+/// Piece transfer modes used with a remote peer, negotiated separately for each direction.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct PieceTransfer {
+    /// Mode of pieces received from the remote peer.
+    pub download: PieceTransferMode,
+    /// Mode of pieces sent to the remote peer.
+    pub upload: PieceTransferMode,
+}
+
+impl PieceTransfer {
+    /// Returns modes used with the remote peer that announced `remote_mode` for its downloads.
+    ///
+    /// Peers that have not announced a mode may not support piece requests,
+    /// so pieces are pushed in both directions until they do.
+    pub fn negotiate(
+        local_mode: PieceTransferMode,
+        remote_mode: Option<PieceTransferMode>,
+    ) -> Self {
+        match remote_mode {
+            Some(remote_mode) => Self {
+                download: local_mode,
+                upload: remote_mode,
+            },
+            None => Self::default(),
+        }
+    }
+}
+
+#[test]
+fn negotiate_piece_transfer() {
+    use PieceTransferMode::{Pull, Push};
+
+    assert_eq!(
+        PieceTransfer::negotiate(Pull, None),
+        PieceTransfer::default()
+    );
+    assert_eq!(
+        PieceTransfer::negotiate(Pull, Some(Push)),
+        PieceTransfer {
+            download: Pull,
+            upload: Push
+        }
+    );
+    assert_eq!(
+        PieceTransfer::negotiate(Push, Some(Pull)),
+        PieceTransfer {
+            download: Push,
+            upload: Pull
+        }
+    );
+}
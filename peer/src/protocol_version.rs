@@ -0,0 +1,60 @@
+use thiserror::Error;
+
+/// Peer protocol version sent in `PeerPeerMessage::Hello`,
+/// incremented on incompatible message changes.
+pub const PEER_PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest peer protocol version that is still supported.
+pub const MIN_PEER_PROTOCOL_VERSION: u16 = 1;
+
+/// Returns the protocol version to be used with the remote side.
+///
+/// Newer remote versions fall back to the local version
+/// since the remote side is expected to support older versions,
+/// remote versions older than `min_version` are refused.
+pub fn negotiate_protocol_version(
+    local_version: u16,
+    min_version: u16,
+    remote_version: u16,
+) -> Result<u16, ProtocolVersionError> {
+    if remote_version < min_version {
+        return Err(ProtocolVersionError::Unsupported {
+            version: remote_version,
+            min_version,
+        });
+    }
+    Ok(remote_version.min(local_version))
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum ProtocolVersionError {
+    #[error(
+        "protocol version {version} is older than the minimum supported version {min_version}"
+    )]
+    Unsupported { version: u16, min_version: u16 },
+}
+
+#[test]
+fn negotiate_compatible_protocol_version() {
+    assert_eq!(negotiate_protocol_version(3, 2, 3), Ok(3));
+    assert_eq!(negotiate_protocol_version(3, 2, 2), Ok(2));
+    assert_eq!(negotiate_protocol_version(3, 2, 5), Ok(3));
+}
+
+#[test]
+fn refuse_unsupported_protocol_version() {
+    assert_eq!(
+        negotiate_protocol_version(3, 2, 1),
+        Err(ProtocolVersionError::Unsupported {
+            version: 1,
+            min_version: 2
+        })
+    );
+    assert_eq!(
+        negotiate_protocol_version(PEER_PROTOCOL_VERSION, MIN_PEER_PROTOCOL_VERSION, 0),
+        Err(ProtocolVersionError::Unsupported {
+            version: 0,
+            min_version: MIN_PEER_PROTOCOL_VERSION
+        })
+    );
+}
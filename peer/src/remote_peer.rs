@@ -1,14 +1,22 @@
+use core::cell::{Cell, RefCell};
+use core::ops::Add;
 use core::sync::atomic::AtomicBool;
+use core::time::Duration;
 use std::sync::{Arc, Weak};
 
 use thiserror::Error;
-use tracker_protocol::{IceCandidate, PeerId, SdpType, SessionDescription};
+use tracker_protocol::{FileSha256, IceCandidate, PeerId, SdpType, SessionDescription};
 use web_sys::{
     Event, MessageEvent, RtcConfiguration, RtcDataChannel, RtcPeerConnection,
     RtcPeerConnectionIceEvent, RtcSdpType, RtcSessionDescriptionInit,
 };
 
-use crate::{ClosureCell1, LocalPeer, PeerPeerMessage};
+use crate::{
+    clear_closure_cells, log_scoped, BufferLowDetector, ClearClosureCell, ClosureCell1,
+    ControlQueue, FilePieceIdx, IceServerConfig, LocalPeer, LogScope, NegotiationRole,
+    OfferOptions, PeerError, PeerOperation, PeerPeerMessage, PieceCipher, PieceKeyId,
+    PieceTransfer, PieceTransferMode,
+};
 
 #[derive(Clone, Copy, Debug)]
 pub enum RemotePeerKind {
@@ -28,6 +36,11 @@ pub struct RemotePeer<T> {
     local_peer: Weak<LocalPeer<T>>,
     peer_id: PeerId,
     state: RemotePeerState,
+    role: NegotiationRole,
+    /// Local offer is being created and set.
+    making_offer: AtomicBool,
+    /// The last remote offer was ignored due to a collision.
+    ignore_offer: AtomicBool,
     peer_connection: RtcPeerConnection,
     data_channel: RtcDataChannel,
     icecandidate_handler: ClosureCell1<RtcPeerConnectionIceEvent>,
@@ -38,6 +51,21 @@ pub struct RemotePeer<T> {
     data_message_handler: ClosureCell1<MessageEvent>,
     data_open_handler: ClosureCell1<Event>,
     data_error_handler: ClosureCell1<Event>,
+    data_bufferedamountlow_handler: ClosureCell1<Event>,
+    control_queue: RefCell<ControlQueue>,
+    buffer_low: RefCell<BufferLowDetector<T>>,
+    /// Negotiated protocol version, `None` until `PeerPeerMessage::Hello` is received.
+    protocol_version: Cell<Option<u16>>,
+    /// Piece cipher of the local peer at the time the remote peer was created.
+    piece_cipher: Option<PieceCipher>,
+    /// The remote peer announced the same piece key, pieces are not sent to it until then.
+    piece_key_confirmed: Cell<bool>,
+    /// Piece transfer mode of the local peer at the time the remote peer was created.
+    piece_transfer_mode: PieceTransferMode,
+    /// Negotiated piece transfer modes, pieces are pushed until the remote peer announces its mode.
+    piece_transfer: Cell<PieceTransfer>,
+    /// `PeerPeerMessage::Pong` was received since the last liveness check.
+    pong_received: Cell<bool>,
 }
 
 impl<T> RemotePeer<T> {
@@ -45,23 +73,42 @@ impl<T> RemotePeer<T> {
         local_peer: &Arc<LocalPeer<T>>,
         peer_id: PeerId,
         kind: RemotePeerKind,
-    ) -> Arc<Self>
+    ) -> Result<Arc<Self>, PeerError>
     where
         T: 'static + Ord,
     {
-        use core::cell::RefCell;
+        use crate::{BUFFER_LOW_EVENT_TIMEOUT, CONTROL_QUEUE_BUFFER_LOW_THRESHOLD};
         use web_sys::{RtcDataChannelInit, RtcDataChannelType};
 
+        let config = local_peer.data_channel_config();
+        config
+            .validate()
+            .map_err(|err| PeerError::InvalidDataChannelConfig { peer_id, err })?;
+
+        let ice_servers = local_peer
+            .ice_candidate_policy()
+            .ice_servers(local_peer.ice_servers());
         let peer_connection =
-            RtcPeerConnection::new_with_configuration(&default_rtc_configuration()).unwrap();
+            RtcPeerConnection::new_with_configuration(&rtc_configuration(ice_servers))
+                .map_err(|err| PeerError::js(peer_id, PeerOperation::CreatePeerConnection, &err))?;
         let mut data_channel_init = RtcDataChannelInit::new();
-        let _: &mut _ = data_channel_init.id(0);
+        let _: &mut _ = data_channel_init.id(config.id());
         let _: &mut _ = data_channel_init.negotiated(true);
         let _: &mut _ = data_channel_init.ordered(false);
-        let _: &mut _ = data_channel_init.max_retransmits(0);
-        let data_channel =
-            peer_connection.create_data_channel_with_data_channel_dict("data", &data_channel_init);
+        match config.max_packet_life_time() {
+            Some(max_packet_life_time) => {
+                let _: &mut _ = data_channel_init.max_packet_life_time(max_packet_life_time);
+            }
+            None => {
+                let _: &mut _ =
+                    data_channel_init.max_retransmits(config.max_retransmits().unwrap_or(0));
+            }
+        }
+        let _: &mut _ = data_channel_init.protocol(config.protocol());
+        let data_channel = peer_connection
+            .create_data_channel_with_data_channel_dict(config.label(), &data_channel_init);
         data_channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+        data_channel.set_buffered_amount_low_threshold(CONTROL_QUEUE_BUFFER_LOW_THRESHOLD);
         let state = match kind {
             RemotePeerKind::Offering => RemotePeerState::Offering,
             RemotePeerKind::Answering => RemotePeerState::Answering {
@@ -69,12 +116,22 @@ impl<T> RemotePeer<T> {
             },
         };
 
+        // The local id is assigned before any offer is requested or received.
+        let role = local_peer
+            .peer_id()
+            .map_or(NegotiationRole::Polite, |local_peer_id| {
+                NegotiationRole::new(local_peer_id, peer_id)
+            });
+
         let remote_peer = Arc::new(Self {
             local_peer: Arc::downgrade(local_peer),
             peer_id,
             peer_connection,
             data_channel,
             state,
+            role,
+            making_offer: AtomicBool::new(false),
+            ignore_offer: AtomicBool::new(false),
             icecandidate_handler: RefCell::new(None),
             negotiationneeded_handler: RefCell::new(None),
             iceconnectionstatechange_handler: RefCell::new(None),
@@ -83,12 +140,24 @@ impl<T> RemotePeer<T> {
             data_message_handler: RefCell::new(None),
             data_open_handler: RefCell::new(None),
             data_error_handler: RefCell::new(None),
+            data_bufferedamountlow_handler: RefCell::new(None),
+            control_queue: RefCell::new(ControlQueue::new()),
+            buffer_low: RefCell::new(BufferLowDetector::new(
+                CONTROL_QUEUE_BUFFER_LOW_THRESHOLD.into(),
+                BUFFER_LOW_EVENT_TIMEOUT,
+            )),
+            protocol_version: Cell::new(None),
+            piece_cipher: local_peer.piece_cipher(),
+            piece_key_confirmed: Cell::new(false),
+            piece_transfer_mode: local_peer.piece_transfer_mode(),
+            piece_transfer: Cell::new(PieceTransfer::default()),
+            pong_received: Cell::new(false),
             //files: RwLock::new(HashMap::new()),
         });
 
         remote_peer.init().await;
 
-        remote_peer
+        Ok(remote_peer)
     }
 
     async fn init(self: &Arc<Self>)
@@ -160,141 +229,223 @@ impl<T> RemotePeer<T> {
             RtcDataChannel::set_onerror,
             &self.data_channel,
         );
+
+        init_weak_callback(
+            &self,
+            Self::on_data_bufferedamountlow,
+            &self.data_bufferedamountlow_handler,
+            RtcDataChannel::set_onbufferedamountlow,
+            &self.data_channel,
+        );
     }
 
-    async fn send_offer(&self) {
+    async fn send_offer(&self, options: OfferOptions) -> Result<(), PeerError> {
+        use std::sync::atomic::Ordering;
+
+        self.making_offer.store(true, Ordering::Relaxed);
+        let result = self.make_offer(options).await;
+        self.making_offer.store(false, Ordering::Relaxed);
+        result
+    }
+
+    async fn make_offer(&self, options: OfferOptions) -> Result<(), PeerError> {
         use crate::unwrap_or_return;
         use tracker_protocol::PeerTrackerMessage;
         use wasm_bindgen::{JsCast, JsValue};
         use wasm_bindgen_futures::JsFuture;
 
-        let local_peer = unwrap_or_return!(self.local_peer.upgrade());
+        let local_peer = unwrap_or_return!(self.local_peer.upgrade(), Ok(()));
+        let peer_id = self.peer_id;
 
-        let offer = JsFuture::from(self.peer_connection.create_offer())
-            .await
-            .unwrap();
+        let options = options.to_rtc_offer_options();
+        let offer = JsFuture::from(
+            self.peer_connection
+                .create_offer_with_rtc_offer_options(&options),
+        )
+        .await
+        .map_err(|err| PeerError::js(peer_id, PeerOperation::CreateOffer, &err))?;
         let offer: &RtcSessionDescriptionInit = offer.as_ref().unchecked_ref();
 
         let _: JsValue = JsFuture::from(self.peer_connection.set_local_description(offer))
             .await
-            .unwrap();
+            .map_err(|err| PeerError::js(peer_id, PeerOperation::SetLocalDescription, &err))?;
 
-        let offer = SessionDescription {
-            sdp_type: offer.get_sdp_type().unwrap(),
-            sdp: offer.get_sdp().unwrap(),
-        };
-        log::debug!("local offer: {:?}", offer);
+        let offer = session_description(offer, peer_id, PeerOperation::CreateOffer)?;
+        log_scoped!(debug in LogScope::peer(self.peer_id), "local offer: {:?}", offer);
 
-        let peer_id = self.peer_id;
         local_peer.send(PeerTrackerMessage::SendOffer { peer_id, offer });
+        Ok(())
     }
 
-    async fn send_answer(&self) {
+    async fn send_answer(&self) -> Result<(), PeerError> {
         use crate::unwrap_or_return;
         use tracker_protocol::PeerTrackerMessage;
         use wasm_bindgen::JsCast;
         use wasm_bindgen::JsValue;
         use wasm_bindgen_futures::JsFuture;
 
-        let local_peer = unwrap_or_return!(self.local_peer.upgrade());
+        let local_peer = unwrap_or_return!(self.local_peer.upgrade(), Ok(()));
+        let peer_id = self.peer_id;
 
         let answer = JsFuture::from(self.peer_connection.create_answer())
             .await
-            .unwrap();
+            .map_err(|err| PeerError::js(peer_id, PeerOperation::CreateAnswer, &err))?;
         let answer: &RtcSessionDescriptionInit = answer.as_ref().unchecked_ref();
 
         let _: JsValue = JsFuture::from(self.peer_connection.set_local_description(answer))
             .await
-            .unwrap();
+            .map_err(|err| PeerError::js(peer_id, PeerOperation::SetLocalDescription, &err))?;
 
-        let answer = SessionDescription {
-            sdp_type: answer.get_sdp_type().unwrap(),
-            sdp: answer.get_sdp().unwrap(),
-        };
-        log::debug!("local answer: {:?}", answer);
+        let answer = session_description(answer, peer_id, PeerOperation::CreateAnswer)?;
+        log_scoped!(debug in LogScope::peer(self.peer_id), "local answer: {:?}", answer);
 
-        let peer_id = self.peer_id;
         local_peer.send(PeerTrackerMessage::SendAnswer { peer_id, answer });
+        Ok(())
     }
 
-    pub async fn on_peer_offer(self: &Arc<Self>, offer: SessionDescription) {
+    pub async fn on_peer_offer(
+        self: &Arc<Self>,
+        offer: SessionDescription,
+    ) -> Result<(), PeerError> {
+        use crate::OfferAction;
         use std::sync::atomic::Ordering;
         use wasm_bindgen::JsValue;
         use wasm_bindgen_futures::JsFuture;
+        use web_sys::RtcSignalingState;
 
-        log::debug!("remote offer: {:?}", offer);
+        log_scoped!(debug in LogScope::peer(self.peer_id), "remote offer: {:?}", offer);
 
-        match &self.state {
-            RemotePeerState::Offering => {
-                // TODO: Maybe return result
-                log::error!("offer received by the offering peer");
+        let is_collision = self.making_offer.load(Ordering::Relaxed)
+            || self.peer_connection.signaling_state() != RtcSignalingState::Stable;
+        let action = self.role.offer_action(is_collision);
+        self.ignore_offer
+            .store(action == OfferAction::Ignore, Ordering::Relaxed);
+
+        match action {
+            OfferAction::Accept => {}
+            OfferAction::RollbackAndAccept => {
+                log_scoped!(debug in LogScope::peer(self.peer_id), "offer collision, rolling back local offer");
+                self.rollback_local_description().await?;
             }
-            RemotePeerState::Answering { has_offer } => has_offer.store(true, Ordering::Relaxed),
+            // The impolite peer keeps its own offer, the polite one rolls back instead.
+            OfferAction::Ignore => {
+                log_scoped!(debug in LogScope::peer(self.peer_id), "offer collision, ignoring remote offer");
+                return Ok(());
+            }
+        }
+
+        if let RemotePeerState::Answering { has_offer } = &self.state {
+            has_offer.store(true, Ordering::Relaxed);
         }
 
-        let sdp_type = protocol_sdp_type_to_web_sys_sdp_type(offer.sdp_type);
-        let mut remote_description = RtcSessionDescriptionInit::new(sdp_type);
-        let _: &mut _ = remote_description.sdp(&offer.sdp);
+        let remote_description = rtc_session_description_init(&offer);
 
         let peer_connection = self.peer_connection.clone();
         let _: JsValue =
             JsFuture::from(peer_connection.set_remote_description(&remote_description))
                 .await
-                .unwrap();
+                .map_err(|err| {
+                    PeerError::js(self.peer_id, PeerOperation::SetRemoteDescription, &err)
+                })?;
 
-        self.send_answer().await;
+        self.send_answer().await
     }
 
-    pub async fn on_peer_answer(self: &Arc<Self>, answer: SessionDescription) {
+    /// Discards the local offer that has not been answered yet,
+    /// returning the connection to the stable signaling state.
+    pub async fn rollback_local_description(&self) -> Result<(), PeerError> {
         use wasm_bindgen::JsValue;
         use wasm_bindgen_futures::JsFuture;
 
-        log::debug!("remote answer: {:?}", answer);
+        let rollback = rtc_session_description_init(&rollback_session_description());
+        let _: JsValue = JsFuture::from(self.peer_connection.set_local_description(&rollback))
+            .await
+            .map_err(|err| {
+                PeerError::js(self.peer_id, PeerOperation::RollbackLocalDescription, &err)
+            })?;
+        Ok(())
+    }
+
+    pub async fn on_peer_answer(
+        self: &Arc<Self>,
+        answer: SessionDescription,
+    ) -> Result<(), PeerError> {
+        use wasm_bindgen::JsValue;
+        use wasm_bindgen_futures::JsFuture;
+
+        log_scoped!(debug in LogScope::peer(self.peer_id), "remote answer: {:?}", answer);
 
         match &self.state {
             RemotePeerState::Offering => {}
             RemotePeerState::Answering { .. } => {
                 // TODO: Maybe return result
-                log::error!("answer received by the answering peer");
-                return;
+                log_scoped!(error in LogScope::peer(self.peer_id), "answer received by the answering peer");
+                return Ok(());
             }
         }
 
-        let sdp_type = protocol_sdp_type_to_web_sys_sdp_type(answer.sdp_type);
-        let mut remote_description = RtcSessionDescriptionInit::new(sdp_type);
-        let _: &mut _ = remote_description.sdp(&answer.sdp);
+        let remote_description = rtc_session_description_init(&answer);
 
         let peer_connection = self.peer_connection.clone();
         let _: JsValue =
             JsFuture::from(peer_connection.set_remote_description(&remote_description))
                 .await
-                .unwrap();
+                .map_err(|err| {
+                    PeerError::js(self.peer_id, PeerOperation::SetRemoteDescription, &err)
+                })?;
+        Ok(())
     }
 
-    pub async fn on_peer_icecandidate(self: &Arc<Self>, candidate: IceCandidate) {
+    pub async fn on_peer_icecandidate(
+        self: &Arc<Self>,
+        candidate: IceCandidate,
+    ) -> Result<(), PeerError> {
+        use js_sys::Reflect;
+        use std::sync::atomic::Ordering;
         use wasm_bindgen::JsValue;
         use wasm_bindgen_futures::JsFuture;
         use web_sys::{RtcIceCandidate, RtcIceCandidateInit};
 
-        log::debug!("remote ice candidate: {:?}", candidate);
+        log_scoped!(debug in LogScope::peer(self.peer_id), "remote ice candidate: {:?}", candidate);
 
         let mut candidate_init = RtcIceCandidateInit::new(&candidate.candidate);
         let _: &mut _ = candidate_init
             .sdp_mid(candidate.sdp_mid.as_deref())
             .sdp_m_line_index(candidate.sdp_mline_index);
-        let candidate = RtcIceCandidate::new(&candidate_init).unwrap();
+        // `usernameFragment` is not available in `web_sys`, so it is set via `Reflect`.
+        if let Some(username_fragment) = &candidate.username_fragment {
+            let _: bool = Reflect::set(
+                &candidate_init,
+                &JsValue::from_str("usernameFragment"),
+                &JsValue::from_str(username_fragment),
+            )
+            .map_err(|err| PeerError::js(self.peer_id, PeerOperation::CreateIceCandidate, &err))?;
+        }
+        let candidate = RtcIceCandidate::new(&candidate_init)
+            .map_err(|err| PeerError::js(self.peer_id, PeerOperation::CreateIceCandidate, &err))?;
 
-        let _: JsValue = JsFuture::from(
+        let result = JsFuture::from(
             self.peer_connection
                 .add_ice_candidate_with_opt_rtc_ice_candidate(Some(&candidate)),
         )
-        .await
-        .unwrap();
+        .await;
+        match result {
+            Ok(_) => Ok(()),
+            // Candidates of the ignored colliding offer are expected to fail.
+            Err(_) if self.ignore_offer.load(Ordering::Relaxed) => Ok(()),
+            Err(err) => Err(PeerError::js(
+                self.peer_id,
+                PeerOperation::AddIceCandidate,
+                &err,
+            )),
+        }
     }
 
     fn on_icecandidate(self: &Arc<Self>, ev: RtcPeerConnectionIceEvent) {
         use crate::unwrap_or_return;
+        use js_sys::Reflect;
         use tracker_protocol::PeerTrackerMessage;
+        use wasm_bindgen::JsValue;
 
         let local_peer = unwrap_or_return!(self.local_peer.upgrade());
         let candidate = unwrap_or_return!(ev.candidate());
@@ -303,7 +454,7 @@ impl<T> RemotePeer<T> {
         let candidate_str = candidate.candidate();
         match candidate_str.as_ref() {
             "" => {
-                log::debug!("local all ice candidates sent");
+                log_scoped!(debug in LogScope::peer(self.peer_id), "local all ice candidates sent");
                 local_peer.send(PeerTrackerMessage::AllIceCandidatesSent { peer_id });
             }
             _ => {
@@ -311,53 +462,88 @@ impl<T> RemotePeer<T> {
                     candidate: candidate_str,
                     sdp_mid: candidate.sdp_mid(),
                     sdp_mline_index: candidate.sdp_m_line_index(),
-                    username_fragment: None,
+                    username_fragment: Reflect::get(
+                        &candidate,
+                        &JsValue::from_str("usernameFragment"),
+                    )
+                    .ok()
+                    .and_then(|username_fragment| username_fragment.as_string()),
                 };
-                log::debug!("local ice candidate: {:?}", candidate);
+                log_scoped!(debug in LogScope::peer(self.peer_id), "local ice candidate: {:?}", candidate);
                 local_peer.send(PeerTrackerMessage::SendIceCandidate { peer_id, candidate });
             }
         };
     }
 
     pub async fn on_peer_all_icecandidates_sent(self: &Arc<Self>) {
-        log::debug!("remote all ice candidates sent");
+        log_scoped!(debug in LogScope::peer(self.peer_id), "remote all ice candidates sent");
     }
 
     fn on_negotiationneeded(self: &Arc<Self>, _: Event)
     where
         T: 'static,
     {
+        use crate::ok_or_log::OrLog;
         use core::sync::atomic::Ordering;
         use wasm_bindgen_futures::spawn_local;
 
         let self_arc = Arc::clone(self);
         // TODO: Do not send offer if send in progress
         match &self.state {
-            RemotePeerState::Offering => spawn_local(async move { self_arc.send_offer().await }),
+            RemotePeerState::Offering => {
+                spawn_local(async move {
+                    self_arc.send_offer(OfferOptions::new()).await.or_log();
+                });
+            }
             RemotePeerState::Answering { has_offer } => {
                 if has_offer.load(Ordering::Relaxed) {
-                    spawn_local(async move { self_arc.send_answer().await })
+                    spawn_local(async move { self_arc.send_answer().await.or_log() })
                 }
             }
         };
     }
 
-    fn on_iceconnectionstatechange(self: &Arc<Self>, _: Event) {
-        log::debug!(
-            "ice connection state: {:?}",
-            self.peer_connection.ice_connection_state()
-        );
+    /// Renegotiates the transport while keeping the data channel and the sent pieces.
+    ///
+    /// Only the offering side restarts ICE, the answering side answers the restart offer.
+    pub async fn restart_ice(&self) -> Result<(), PeerError> {
+        use std::sync::atomic::Ordering;
+
+        if !matches!(self.state, RemotePeerState::Offering)
+            || self.making_offer.load(Ordering::Relaxed)
+        {
+            return Ok(());
+        }
+        log_scoped!(debug in LogScope::peer(self.peer_id), "restarting ice");
+        self.send_offer(OfferOptions::new().with_ice_restart(true))
+            .await
+    }
+
+    fn on_iceconnectionstatechange(self: &Arc<Self>, _: Event)
+    where
+        T: 'static,
+    {
+        use crate::needs_ice_restart;
+        use crate::ok_or_log::OrLog;
+        use wasm_bindgen_futures::spawn_local;
+
+        let state = self.peer_connection.ice_connection_state();
+        log_scoped!(debug in LogScope::peer(self.peer_id), "ice connection state: {:?}", state);
+        if needs_ice_restart(state) {
+            let self_arc = Arc::clone(self);
+            spawn_local(async move { self_arc.restart_ice().await.or_log() });
+        }
     }
 
     fn on_icegatheringstatechange(self: &Arc<Self>, _: Event) {
-        log::debug!(
+        log_scoped!(debug in LogScope::peer(self.peer_id),
             "ice gathering state: {:?}",
             self.peer_connection.ice_gathering_state()
         );
     }
 
     fn on_signalingstatechange(self: &Arc<Self>, _: Event) {
-        log::debug!(
+        log_scoped!(debug in LogScope::peer(self.peer_id),
             "signaling state: {:?}",
             self.peer_connection.signaling_state()
         );
@@ -373,14 +559,166 @@ impl<T> RemotePeer<T> {
         self.peer_id
     }
 
-    pub fn send(&self, message: PeerPeerMessage) {
+    pub fn piece_transfer(&self) -> PieceTransfer {
+        self.piece_transfer.get()
+    }
+
+    pub fn take_pong(&self) -> bool {
+        self.pong_received.replace(false)
+    }
+
+    /// Returns the number of bytes queued in the data channel and not sent yet.
+    pub fn buffered_bytes(&self) -> u64 {
+        self.data_channel.buffered_amount().into()
+    }
+
+    /// Returns the protocol version negotiated with the peer, `None` until it is known.
+    pub fn protocol_version(&self) -> Option<u16> {
+        self.protocol_version.get()
+    }
+
+    /// Closes the connection and releases the event handlers.
+    pub fn close(&self) {
+        self.peer_connection.set_onicecandidate(None);
+        self.peer_connection.set_onnegotiationneeded(None);
+        self.peer_connection.set_oniceconnectionstatechange(None);
+        self.peer_connection.set_onicegatheringstatechange(None);
+        self.peer_connection.set_onsignalingstatechange(None);
+        self.data_channel.set_onmessage(None);
+        self.data_channel.set_onopen(None);
+        self.data_channel.set_onerror(None);
+        self.data_channel.set_onbufferedamountlow(None);
+
+        self.data_channel.close();
+        self.peer_connection.close();
+
+        let _: usize = clear_closure_cells(&self.closure_cells());
+    }
+
+    fn closure_cells(&self) -> [&dyn ClearClosureCell; 9] {
+        [
+            &self.icecandidate_handler,
+            &self.negotiationneeded_handler,
+            &self.iceconnectionstatechange_handler,
+            &self.icegatheringstatechange_handler,
+            &self.signalingstatechange_handler,
+            &self.data_message_handler,
+            &self.data_open_handler,
+            &self.data_error_handler,
+            &self.data_bufferedamountlow_handler,
+        ]
+    }
+
+    /// Sends the message, control messages are queued if they can not be sent right now
+    /// and are retried when the data channel buffer is drained.
+    pub fn send(&self, message: PeerPeerMessage) -> Result<(), PeerError> {
         use crate::PeerPeerMessageFmt;
 
-        log::trace!("send peer_message: {}", PeerPeerMessageFmt(&message));
+        if ControlQueue::is_control_message(&message) {
+            let dropped = self.control_queue.borrow_mut().push(message);
+            if let Some(dropped) = dropped {
+                log_scoped!(
+                    warn in LogScope::peer(self.peer_id).with_file(dropped.sha256()),
+                    "control queue is full, dropped {}",
+                    PeerPeerMessageFmt(&dropped)
+                );
+            }
+            self.send_control_queue()
+        } else {
+            self.send_now(&message)
+        }
+    }
 
-        use bincode::serialize;
-        let request: Vec<u8> = serialize(&message).unwrap();
-        self.data_channel.send_with_u8_array(&request).unwrap();
+    fn send_control_queue(&self) -> Result<(), PeerError> {
+        let mut control_queue = self.control_queue.borrow_mut();
+        while let Some(message) = control_queue.front() {
+            match self.send_now(message) {
+                Ok(()) => {}
+                Err(err @ PeerError::JsError { .. }) => {
+                    log_scoped!(debug in LogScope::peer(self.peer_id), "{}, {} messages queued", err, control_queue.len());
+                    return Ok(());
+                }
+                Err(err) => {
+                    let _: Option<_> = control_queue.pop_front();
+                    return Err(err);
+                }
+            }
+            let _: Option<_> = control_queue.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Retries queued control messages if the data channel buffer is drained,
+    /// used as a fallback for browsers that do not fire `bufferedamountlow`.
+    pub fn poll_control_queue(&self, now: T) -> Result<(), PeerError>
+    where
+        T: Clone + Ord + Add<Duration, Output = T>,
+    {
+        if self.control_queue.borrow().is_empty() {
+            return Ok(());
+        }
+        let buffered_amount = self.data_channel.buffered_amount().into();
+        if self
+            .buffer_low
+            .borrow_mut()
+            .can_send_more(now, buffered_amount)
+        {
+            self.send_control_queue()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn send_now(&self, message: &PeerPeerMessage) -> Result<(), PeerError> {
+        use crate::PeerPeerMessageFmt;
+
+        log_scoped!(
+            trace in LogScope::peer(self.peer_id).with_file(message.sha256()),
+            "send peer_message: {}",
+            PeerPeerMessageFmt(message)
+        );
+
+        let peer_id = self.peer_id;
+        let request = message
+            .encode()
+            .map_err(|err| PeerError::SerializationError {
+                peer_id,
+                message: err.to_string(),
+            })?;
+        let max_len = self.max_message_size();
+        if request.len() > max_len {
+            return Err(PeerError::MessageIsTooLarge {
+                peer_id,
+                len: request.len(),
+                max_len,
+            });
+        }
+        self.data_channel
+            .send_with_u8_array(&request)
+            .map_err(|err| PeerError::js(peer_id, PeerOperation::SendData, &err))
+    }
+
+    // `RTCSctpTransport` is not available in `web_sys`, so it is accessed via `Reflect`.
+    // The transport is not available until the connection is established,
+    // the conservative default is used in this case.
+    fn max_message_size(&self) -> usize {
+        use crate::MAX_PEER_MESSAGE_SIZE;
+        use js_sys::Reflect;
+        use wasm_bindgen::JsValue;
+
+        Reflect::get(&self.peer_connection, &JsValue::from_str("sctp"))
+            .ok()
+            .filter(JsValue::is_object)
+            .and_then(|sctp| Reflect::get(&sctp, &JsValue::from_str("maxMessageSize")).ok())
+            .and_then(|size| size.as_f64())
+            .filter(|&size| size > 0.0)
+            .map_or(MAX_PEER_MESSAGE_SIZE, |size| {
+                if size.is_finite() {
+                    size as usize
+                } else {
+                    usize::MAX
+                }
+            })
     }
 
     pub fn send_with_max_buffer_size(
@@ -388,21 +726,93 @@ impl<T> RemotePeer<T> {
         message: PeerPeerMessage,
         max_buffer_bytes: u64,
     ) -> Result<(), PeerConnectionSendError> {
-        use crate::PeerPeerMessageFmt;
-        use bincode::serialize;
-
         if (self.data_channel.buffered_amount() as u64) < max_buffer_bytes {
-            log::trace!("send peer_message: {}", PeerPeerMessageFmt(&message));
-            let request: Vec<u8> = serialize(&message).unwrap();
-            self.data_channel.send_with_u8_array(&request).unwrap();
-            Ok(())
+            Ok(self.send(message)?)
         } else {
             Err(PeerConnectionSendError::BufferIsFilled)
         }
     }
 
-    fn on_data_open(self: &Arc<Self>, _: Event) {
-        log::debug!("data channel opened");
+    /// Sends file pieces coalesced into batches that fit into the maximum message size,
+    /// compressible pieces are compressed if `compress` is set.
+    ///
+    /// If piece encryption is enabled, pieces are encrypted instead of being compressed
+    /// and are sent only once the remote peer has announced the same piece key.
+    pub fn send_file_pieces(
+        &self,
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+        compress: bool,
+        max_buffer_bytes: Option<u64>,
+    ) -> Result<(), PeerConnectionSendError> {
+        use crate::{encrypted_file_piece_messages, file_piece_messages};
+
+        let messages = match &self.piece_cipher {
+            Some(_) if !self.piece_key_confirmed.get() => {
+                return Err(PeerConnectionSendError::PieceKeyIsNotConfirmed);
+            }
+            Some(piece_cipher) => {
+                let pieces = piece_cipher.encrypt_pieces(sha256, pieces).map_err(|err| {
+                    PeerError::PieceEncrypt {
+                        peer_id: self.peer_id,
+                        err,
+                    }
+                })?;
+                encrypted_file_piece_messages(sha256, pieces, self.max_message_size())
+            }
+            None => file_piece_messages(sha256, pieces, self.max_message_size(), compress),
+        };
+
+        // Control messages are more important than pieces.
+        self.send_control_queue()?;
+        for message in messages {
+            match max_buffer_bytes {
+                Some(max_buffer_bytes) => {
+                    self.send_with_max_buffer_size(message, max_buffer_bytes)?;
+                }
+                None => self.send(message)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn on_data_open(self: &Arc<Self>, _: Event)
+    where
+        T: 'static,
+    {
+        use crate::ok_or_log::OrLog;
+        use crate::{unwrap_or_return, PEER_PROTOCOL_VERSION};
+
+        log_scoped!(debug in LogScope::peer(self.peer_id), "data channel opened");
+        let local_peer = unwrap_or_return!(self.local_peer.upgrade());
+        local_peer.on_peer_ready(self.peer_id);
+        self.send(PeerPeerMessage::Hello {
+            protocol_version: PEER_PROTOCOL_VERSION,
+        })
+        .or_log();
+        if let Some(piece_cipher) = &self.piece_cipher {
+            self.send(PeerPeerMessage::PieceEncryption {
+                key_id: piece_cipher.key_id(),
+            })
+            .or_log();
+        }
+        self.send(PeerPeerMessage::PieceTransfer {
+            mode: self.piece_transfer_mode,
+        })
+        .or_log();
+        let config = local_peer.data_channel_config();
+        self.send(PeerPeerMessage::DataChannel {
+            label: config.label().to_owned(),
+            protocol: config.protocol().to_owned(),
+        })
+        .or_log();
+    }
+
+    fn on_data_bufferedamountlow(self: &Arc<Self>, _: Event) {
+        use crate::ok_or_log::OrLog;
+
+        self.buffer_low.borrow_mut().on_event();
+        self.send_control_queue().or_log();
     }
 
     fn on_data_error(self: &Arc<Self>, ev: Event) {
@@ -410,52 +820,264 @@ impl<T> RemotePeer<T> {
         use wasm_bindgen::JsValue;
 
         let error = Reflect::get(&ev, &JsValue::from_str("error")).unwrap();
-        log::error!("data channel error: {:?}", error);
+        log_scoped!(error in LogScope::peer(self.peer_id), "data channel error: {:?}", error);
     }
 
     fn on_data_message(self: &Arc<Self>, ev: MessageEvent)
     where
         T: 'static + Ord,
     {
-        use crate::{unwrap_or_return, PeerPeerMessageFmt};
-        use bincode::deserialize;
-        use js_sys::{ArrayBuffer, Uint8Array};
-        use wasm_bindgen::JsCast;
+        use crate::ok_or_log::OrLog;
+        use crate::{unwrap_or_return, OkOrLog, PeerPeerMessageFmt, PieceKeyError};
         use wasm_bindgen_futures::spawn_local;
 
         let local_peer = unwrap_or_return!(self.local_peer.upgrade());
+        let message = unwrap_or_return!(self.parse_data_message(&ev).ok_or_log());
 
-        let array_buffer: ArrayBuffer = ev.data().dyn_into().unwrap();
-        let data = Uint8Array::new(&array_buffer).to_vec();
-        let message = deserialize(&data).unwrap();
+        log_scoped!(
+            trace in LogScope::peer(self.peer_id).with_file(message.sha256()),
+            "recv peer_message: {}",
+            PeerPeerMessageFmt(&message)
+        );
+
+        if let PeerPeerMessage::DataChannel { label, protocol } = &message {
+            let result = local_peer
+                .data_channel_config()
+                .check_remote(label, protocol)
+                .map_err(|err| PeerError::DataChannelMismatch {
+                    peer_id: self.peer_id,
+                    err,
+                });
+            if result.ok_or_log().is_none() {
+                self.refuse();
+            }
+            return;
+        }
+
+        if let PeerPeerMessage::Hello { protocol_version } = message {
+            self.on_hello(protocol_version);
+            return;
+        }
+
+        if let PeerPeerMessage::PieceEncryption { key_id } = message {
+            self.on_piece_encryption(key_id);
+            return;
+        }
 
-        log::trace!("recv peer_message: {}", PeerPeerMessageFmt(&message));
+        if let PeerPeerMessage::PieceTransfer { mode } = message {
+            self.piece_transfer.set(PieceTransfer::negotiate(
+                self.piece_transfer_mode,
+                Some(mode),
+            ));
+            return;
+        }
+
+        if message == PeerPeerMessage::Ping {
+            self.send(PeerPeerMessage::Pong).or_log();
+            return;
+        }
+
+        if message == PeerPeerMessage::Pong {
+            self.pong_received.set(true);
+            return;
+        }
+
+        // The piece key is announced right after `Hello`, before any file messages are sent,
+        // so remote peers that have not announced it by the first file message do not encrypt
+        // pieces and would otherwise never be sent any.
+        if self.piece_cipher.is_some()
+            && !self.piece_key_confirmed.get()
+            && message.sha256().is_some()
+        {
+            let err = PeerError::PieceKey {
+                peer_id: self.peer_id,
+                err: PieceKeyError::KeyIsNotAnnounced,
+            };
+            log_scoped!(error in LogScope::peer(self.peer_id), "{}", err);
+            self.refuse();
+            return;
+        }
+
+        let message = match self.decrypt_pieces(message) {
+            Ok(message) => message,
+            Err(err @ PeerError::PieceKey { .. }) => {
+                log_scoped!(error in LogScope::peer(self.peer_id), "{}", err);
+                self.refuse();
+                return;
+            }
+            Err(err) => {
+                log_scoped!(error in LogScope::peer(self.peer_id), "{}", err);
+                return;
+            }
+        };
 
         let remote_peer = Arc::clone(self);
         spawn_local(async move {
             local_peer.on_peer_message(&remote_peer, message).await;
         });
     }
+
+    // Peers with unsupported protocol versions are refused by closing the connection
+    // before any file messages are exchanged.
+    fn on_hello(&self, remote_version: u16) {
+        use crate::{
+            negotiate_protocol_version, OkOrLog, MIN_PEER_PROTOCOL_VERSION, PEER_PROTOCOL_VERSION,
+        };
+
+        let version = negotiate_protocol_version(
+            PEER_PROTOCOL_VERSION,
+            MIN_PEER_PROTOCOL_VERSION,
+            remote_version,
+        )
+        .map_err(|err| PeerError::ProtocolVersionMismatch {
+            peer_id: self.peer_id,
+            err,
+        });
+        match version.ok_or_log() {
+            Some(version) => self.protocol_version.set(Some(version)),
+            None => self.refuse(),
+        }
+    }
+
+    // Peers with a different piece key or without piece encryption are refused,
+    // so that pieces are never exchanged unencrypted or undecryptable.
+    fn on_piece_encryption(&self, remote_key_id: PieceKeyId) {
+        use crate::{check_remote_piece_key, OkOrLog};
+
+        let local_key_id = self.piece_cipher.as_ref().map(PieceCipher::key_id);
+        let result = check_remote_piece_key(local_key_id, remote_key_id).map_err(|err| {
+            PeerError::PieceKey {
+                peer_id: self.peer_id,
+                err,
+            }
+        });
+        match result.ok_or_log() {
+            Some(()) => self.piece_key_confirmed.set(true),
+            None => self.refuse(),
+        }
+    }
+
+    // Pieces that fail authentication are dropped and resent later like lost pieces.
+    fn decrypt_pieces(&self, message: PeerPeerMessage) -> Result<PeerPeerMessage, PeerError> {
+        use crate::PieceKeyError;
+
+        let peer_id = self.peer_id;
+        match (&self.piece_cipher, message) {
+            (Some(piece_cipher), PeerPeerMessage::EncryptedFilePieceBatch { sha256, pieces }) => {
+                let pieces = piece_cipher
+                    .decrypt_pieces(sha256, pieces)
+                    .map_err(|err| PeerError::PieceDecrypt { peer_id, err })?;
+                Ok(PeerPeerMessage::FilePieceBatch { sha256, pieces })
+            }
+            (None, PeerPeerMessage::EncryptedFilePieceBatch { .. }) => Err(PeerError::PieceKey {
+                peer_id,
+                err: PieceKeyError::EncryptionIsDisabled,
+            }),
+            (
+                Some(_),
+                PeerPeerMessage::FilePiece { .. }
+                | PeerPeerMessage::FilePieceBatch { .. }
+                | PeerPeerMessage::CompressedFilePieceBatch { .. },
+            ) => Err(PeerError::PieceKey {
+                peer_id,
+                err: PieceKeyError::UnencryptedPieces,
+            }),
+            (_, message) => Ok(message),
+        }
+    }
+
+    fn refuse(&self) {
+        self.close();
+        if let Some(local_peer) = self.local_peer.upgrade() {
+            local_peer.on_peer_closed(self.peer_id);
+        }
+    }
+
+    fn parse_data_message(&self, ev: &MessageEvent) -> Result<PeerPeerMessage, PeerError> {
+        use js_sys::{ArrayBuffer, Uint8Array};
+        use wasm_bindgen::JsCast;
+
+        let peer_id = self.peer_id;
+        let array_buffer: ArrayBuffer = ev
+            .data()
+            .dyn_into()
+            .map_err(|data| PeerError::js(peer_id, PeerOperation::ReceiveData, &data))?;
+        let data = Uint8Array::new(&array_buffer).to_vec();
+        PeerPeerMessage::decode(&data).map_err(|err| PeerError::DeserializationError {
+            peer_id,
+            message: err.to_string(),
+        })
+    }
 }
 
-fn default_rtc_configuration() -> RtcConfiguration {
+// Dropped peers release the connection and the event handlers.
+impl<T> Drop for RemotePeer<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+fn rtc_configuration(ice_servers: &[IceServerConfig]) -> RtcConfiguration {
     use js_sys::Array;
     use wasm_bindgen::JsValue;
     use web_sys::RtcIceServer;
 
     let mut configuration = RtcConfiguration::new();
 
-    let ice_server_urls = vec![JsValue::from("stun:stun.l.google.com:19302")];
-    let ice_server_urls: Array = ice_server_urls.into_iter().collect();
-    let mut ice_server = RtcIceServer::new();
-    let _: &mut _ = ice_server.urls(&JsValue::from(ice_server_urls));
-
-    let ice_servers: Array = vec![ice_server].into_iter().collect();
+    let ice_servers: Array = ice_servers
+        .iter()
+        .map(|config| {
+            let mut ice_server = RtcIceServer::new();
+            let _: &mut _ = ice_server.urls(&JsValue::from_str(&config.url));
+            if let Some(username) = &config.username {
+                let _: &mut _ = ice_server.username(username);
+            }
+            if let Some(credential) = &config.credential {
+                let _: &mut _ = ice_server.credential(credential);
+            }
+            ice_server
+        })
+        .collect();
     let _: &mut _ = configuration.ice_servers(&JsValue::from(ice_servers));
 
     configuration
 }
 
+fn session_description(
+    init: &RtcSessionDescriptionInit,
+    peer_id: PeerId,
+    operation: PeerOperation,
+) -> Result<SessionDescription, PeerError> {
+    match (init.get_sdp_type(), init.get_sdp()) {
+        (Some(sdp_type), Some(sdp)) => Ok(SessionDescription { sdp_type, sdp }),
+        _ => Err(PeerError::InvalidSessionDescription { peer_id, operation }),
+    }
+}
+
+fn rollback_session_description() -> SessionDescription {
+    SessionDescription {
+        sdp_type: SdpType::Rollback,
+        sdp: String::new(),
+    }
+}
+
+// Rollback descriptions carry no SDP.
+fn session_description_sdp(description: &SessionDescription) -> Option<&str> {
+    match description.sdp_type {
+        SdpType::Offer | SdpType::Answer | SdpType::Pranswer => Some(&description.sdp),
+        SdpType::Rollback => None,
+    }
+}
+
+fn rtc_session_description_init(description: &SessionDescription) -> RtcSessionDescriptionInit {
+    let sdp_type = protocol_sdp_type_to_web_sys_sdp_type(description.sdp_type);
+    let mut init = RtcSessionDescriptionInit::new(sdp_type);
+    if let Some(sdp) = session_description_sdp(description) {
+        let _: &mut _ = init.sdp(sdp);
+    }
+    init
+}
+
 fn sdp_type_to_protocol_sdp_type(sdp_type: &str) -> Option<SdpType> {
     match sdp_type {
         "offer" => Some(SdpType::Offer),
@@ -494,16 +1116,36 @@ impl RtcSessionDescriptionInitExt for RtcSessionDescriptionInit {
         use js_sys::Reflect;
         use wasm_bindgen::JsValue;
 
-        let sdp_type = Reflect::get(&self, &JsValue::from_str("type"))
-            .unwrap()
-            .as_string()
-            .unwrap();
-        sdp_type_to_protocol_sdp_type(&sdp_type)
+        Reflect::get(&self, &JsValue::from_str("type"))
+            .ok()
+            .and_then(|value| value.as_string())
+            .and_then(|sdp_type| sdp_type_to_protocol_sdp_type(&sdp_type))
     }
 }
 
-#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+#[derive(Clone, Error, Debug, Eq, PartialEq)]
 pub enum PeerConnectionSendError {
     #[error("DataChannel buffer is filled")]
     BufferIsFilled,
+    #[error("remote peer has not announced the local piece key yet")]
+    PieceKeyIsNotConfirmed,
+    #[error(transparent)]
+    PeerError(#[from] PeerError),
+}
+
+#[test]
+fn build_rollback_session_description() {
+    let rollback = rollback_session_description();
+    assert_eq!(rollback.sdp_type, SdpType::Rollback);
+    assert_eq!(session_description_sdp(&rollback), None);
+
+    let offer = SessionDescription {
+        sdp_type: SdpType::Offer,
+        sdp: "v=0".to_owned(),
+    };
+    assert_eq!(session_description_sdp(&offer), Some("v=0"));
+    assert_eq!(
+        sdp_type_to_protocol_sdp_type("rollback"),
+        Some(SdpType::Rollback)
+    );
 }
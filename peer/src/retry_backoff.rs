@@ -0,0 +1,69 @@
+use core::ops::Add;
+use core::time::Duration;
+
+pub const OFFER_RETRY_MIN_INTERVAL: Duration = Duration::from_secs(1);
+pub const OFFER_RETRY_MAX_INTERVAL: Duration = Duration::from_secs(64);
+
+/// Retry schedule with the interval doubled after every retry up to the maximum interval.
+#[derive(Clone, Debug)]
+pub struct RetryBackoff<T> {
+    next_retry: T,
+    interval: Duration,
+    max_interval: Duration,
+}
+
+impl<T> RetryBackoff<T> {
+    pub fn new(now: T, min_interval: Duration, max_interval: Duration) -> Self
+    where
+        T: Add<Duration, Output = T>,
+    {
+        Self {
+            next_retry: now + min_interval,
+            interval: min_interval,
+            max_interval,
+        }
+    }
+
+    pub fn next_retry(&self) -> &T {
+        &self.next_retry
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Returns `true` if the retry is due and schedules the next one.
+    pub fn poll(&mut self, now: T) -> bool
+    where
+        T: Ord + Add<Duration, Output = T>,
+    {
+        if now < self.next_retry {
+            return false;
+        }
+        self.interval = (self.interval * 2).min(self.max_interval);
+        self.next_retry = now + self.interval;
+        true
+    }
+}
+
+#[test]
+fn double_retry_interval_up_to_max() {
+    let secs = Duration::from_secs;
+    let mut backoff = RetryBackoff::new(secs(10), secs(1), secs(8));
+    assert_eq!(backoff.next_retry(), &secs(11));
+
+    assert!(!backoff.poll(secs(10)));
+    assert!(backoff.poll(secs(11)));
+    assert_eq!(backoff.next_retry(), &secs(13));
+
+    assert!(!backoff.poll(secs(12)));
+    // Late polls schedule the next retry relative to the poll time.
+    assert!(backoff.poll(secs(14)));
+    assert_eq!(backoff.next_retry(), &secs(18));
+
+    assert!(backoff.poll(secs(18)));
+    assert_eq!(backoff.interval(), secs(8));
+    assert!(backoff.poll(secs(26)));
+    assert_eq!(backoff.interval(), secs(8));
+    assert_eq!(backoff.next_retry(), &secs(34));
+}
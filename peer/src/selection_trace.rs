@@ -0,0 +1,180 @@
+use bitvec::boxed::BitBox;
+use core::time::Duration;
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+use tracker_protocol::PeerId;
+
+use crate::{File, FileChunk, FilePieceIdx, FileState, SharedFile, SharedFileNewError};
+
+/// `SharedFile` call that changes piece selection state.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum SelectionEvent<T> {
+    AddPeer {
+        peer_id: PeerId,
+    },
+    RemovePeer {
+        peer_id: PeerId,
+    },
+    SetPeerState {
+        peer_id: PeerId,
+        state: BitBox,
+    },
+    AddLocalPiece {
+        piece_idx: FilePieceIdx,
+    },
+    AdvancePeerCursor,
+    SelectPiecePeer {
+        piece_idx: FilePieceIdx,
+        time: T,
+        excluded_peers: Vec<PeerId>,
+    },
+    MarkPeerPieceAsReceivedByRemote {
+        peer_id: PeerId,
+        piece_idx: FilePieceIdx,
+    },
+    MarkForResendIfNotSent {
+        peer_id: PeerId,
+        piece_idx: FilePieceIdx,
+    },
+    MarkPiecesForResendBefore {
+        time: T,
+    },
+    UpdatePeerRates {
+        elapsed: Duration,
+    },
+    SetReleaseConfirmedChunks {
+        release_confirmed_chunks: bool,
+    },
+    SetStablePieceOrder {
+        stable_piece_order: bool,
+    },
+    PrioritizePieces {
+        pieces: Vec<FilePieceIdx>,
+    },
+    SetPeerBlocked {
+        peer_id: PeerId,
+        blocked: bool,
+    },
+    SetPeerPullsPieces {
+        peer_id: PeerId,
+        pulls_pieces: bool,
+    },
+    QueuePieceRequest {
+        peer_id: PeerId,
+        piece_idx: FilePieceIdx,
+    },
+    ServePieceRequests {
+        max_pieces: usize,
+        time: T,
+    },
+    MarkPeerSeen {
+        peer_id: PeerId,
+        time: T,
+    },
+    MarkPeersUnresponsiveBefore {
+        time: T,
+    },
+}
+
+impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE>
+where
+    C: FileChunk,
+    T: Clone + Ord,
+{
+    /// Rebuilds a shared file by applying recorded events to the file
+    /// in the state it had when the recording started.
+    ///
+    /// Piece contents are not recorded, so added pieces are zero-filled and chunks are not verified.
+    /// Stored files are refused like in `SharedFile::new`.
+    pub fn replay<'a, I>(file: File<C, CHUNK_SIZE>, events: I) -> Result<Self, SharedFileNewError>
+    where
+        I: IntoIterator<Item = &'a SelectionEvent<T>>,
+        T: 'a,
+    {
+        let mut shared_file = Self::new(file)?;
+        shared_file.set_verify_chunks(false);
+        for event in events {
+            match event {
+                SelectionEvent::AddPeer { peer_id } => {
+                    let _: Result<_, _> = shared_file.add_peer(*peer_id);
+                }
+                SelectionEvent::RemovePeer { peer_id } => {
+                    let _: Result<_, _> = shared_file.remove_peer(peer_id);
+                }
+                SelectionEvent::SetPeerState { peer_id, state } => {
+                    let _: Result<_, _> =
+                        shared_file.set_peer_state(*peer_id, FileState::from(state.clone()));
+                }
+                SelectionEvent::AddLocalPiece { piece_idx } => {
+                    let data = vec![0; shared_file.file().piece_len(piece_idx)];
+                    let _: Result<_, _> = shared_file.add_local_piece(*piece_idx, &data);
+                }
+                SelectionEvent::AdvancePeerCursor => shared_file.advance_peer_cursor(),
+                SelectionEvent::SelectPiecePeer {
+                    piece_idx,
+                    time,
+                    excluded_peers,
+                } => {
+                    let excluded_peers: HashSet<_> = excluded_peers.iter().copied().collect();
+                    let _: Result<_, _> = shared_file.select_piece_peer_excluding(
+                        *piece_idx,
+                        time.clone(),
+                        &excluded_peers,
+                    );
+                }
+                SelectionEvent::MarkPeerPieceAsReceivedByRemote { peer_id, piece_idx } => {
+                    let _: Result<_, _> =
+                        shared_file.mark_peer_piece_as_received_by_remote(peer_id, *piece_idx);
+                }
+                SelectionEvent::MarkForResendIfNotSent { peer_id, piece_idx } => {
+                    let _: Result<_, _> =
+                        shared_file.mark_for_resend_if_not_sent(peer_id, *piece_idx);
+                }
+                SelectionEvent::MarkPiecesForResendBefore { time } => {
+                    let _: Result<_, _> = shared_file.mark_pieces_for_resend_before(time.clone());
+                }
+                SelectionEvent::UpdatePeerRates { elapsed } => {
+                    shared_file.update_peer_rates(*elapsed);
+                }
+                SelectionEvent::SetReleaseConfirmedChunks {
+                    release_confirmed_chunks,
+                } => {
+                    shared_file.set_release_confirmed_chunks(*release_confirmed_chunks);
+                }
+                SelectionEvent::SetStablePieceOrder { stable_piece_order } => {
+                    shared_file.set_stable_piece_order(*stable_piece_order);
+                }
+                SelectionEvent::PrioritizePieces { pieces } => {
+                    let _: Result<_, _> = shared_file.prioritize_pieces(pieces);
+                }
+                SelectionEvent::SetPeerBlocked { peer_id, blocked } => {
+                    let _: Result<_, _> = if *blocked {
+                        shared_file.block_peer(peer_id)
+                    } else {
+                        shared_file.unblock_peer(peer_id)
+                    };
+                }
+                SelectionEvent::SetPeerPullsPieces {
+                    peer_id,
+                    pulls_pieces,
+                } => {
+                    let _: Result<_, _> = shared_file.set_peer_pulls_pieces(peer_id, *pulls_pieces);
+                }
+                SelectionEvent::QueuePieceRequest { peer_id, piece_idx } => {
+                    let _: Result<_, _> = shared_file.queue_piece_request(peer_id, *piece_idx);
+                }
+                SelectionEvent::ServePieceRequests { max_pieces, time } => {
+                    let _: Vec<_> = shared_file.serve_piece_requests(*max_pieces, time.clone());
+                }
+                SelectionEvent::MarkPeerSeen { peer_id, time } => {
+                    let _: Result<_, _> = shared_file.mark_peer_seen(peer_id, time.clone());
+                }
+                SelectionEvent::MarkPeersUnresponsiveBefore { time } => {
+                    let _: Vec<_> = shared_file.mark_peers_unresponsive_before(time);
+                }
+            }
+        }
+        Ok(shared_file)
+    }
+}
@@ -0,0 +1,116 @@
+use thiserror::Error;
+
+/// Number of distinct send phases.
+pub const NUM_SEND_PHASES: usize = 4;
+
+/// A phase of a single sender tick.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SendPhase {
+    /// Sends changed and unconfirmed file states.
+    State,
+    /// Announces recently received pieces.
+    RecentlyReceived,
+    /// Marks unconfirmed pieces for resend.
+    ResendPieces,
+    /// Requests pieces from peers in pull mode, selects and sends pieces.
+    Pieces,
+}
+
+/// Phases run on each sender tick, in order.
+///
+/// Sending states first lets peers converge on file states with lower latency,
+/// sending pieces first spends the send buffer on data before states.
+/// Phases may be omitted, e.g. a seeder without downloads never announces received pieces.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct SendPhases {
+    phases: [Option<SendPhase>; NUM_SEND_PHASES],
+}
+
+impl Default for SendPhases {
+    fn default() -> Self {
+        Self::state_first()
+    }
+}
+
+impl SendPhases {
+    pub fn new(phases: &[SendPhase]) -> Result<Self, SendPhasesError> {
+        let mut result = [None; NUM_SEND_PHASES];
+        for (j, &phase) in phases.iter().enumerate() {
+            if phases[..j].contains(&phase) {
+                return Err(SendPhasesError::DuplicatePhase { phase });
+            }
+            result[j] = Some(phase);
+        }
+        Ok(Self { phases: result })
+    }
+
+    pub fn state_first() -> Self {
+        Self {
+            phases: [
+                Some(SendPhase::State),
+                Some(SendPhase::RecentlyReceived),
+                Some(SendPhase::ResendPieces),
+                Some(SendPhase::Pieces),
+            ],
+        }
+    }
+
+    pub fn pieces_first() -> Self {
+        Self {
+            phases: [
+                Some(SendPhase::ResendPieces),
+                Some(SendPhase::Pieces),
+                Some(SendPhase::RecentlyReceived),
+                Some(SendPhase::State),
+            ],
+        }
+    }
+
+    pub fn iter(&self) -> impl '_ + Iterator<Item = SendPhase> {
+        self.phases.iter().flatten().copied()
+    }
+
+    pub fn contains(&self, phase: SendPhase) -> bool {
+        self.phases.contains(&Some(phase))
+    }
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SendPhasesError {
+    #[error("send phase {phase:?} is specified more than once")]
+    DuplicatePhase { phase: SendPhase },
+}
+
+#[test]
+fn build_send_phases() {
+    let phases = SendPhases::default();
+    assert_eq!(
+        phases.iter().collect::<Vec<_>>(),
+        [
+            SendPhase::State,
+            SendPhase::RecentlyReceived,
+            SendPhase::ResendPieces,
+            SendPhase::Pieces,
+        ]
+    );
+    assert_eq!(
+        SendPhases::new(&phases.iter().collect::<Vec<_>>()),
+        Ok(phases)
+    );
+
+    let phases = SendPhases::new(&[SendPhase::Pieces, SendPhase::State]).unwrap();
+    assert_eq!(
+        phases.iter().collect::<Vec<_>>(),
+        [SendPhase::Pieces, SendPhase::State]
+    );
+    assert!(phases.contains(SendPhase::State));
+    assert!(!phases.contains(SendPhase::RecentlyReceived));
+
+    assert_eq!(SendPhases::new(&[]).unwrap().iter().count(), 0);
+    assert_eq!(
+        SendPhases::new(&[SendPhase::State, SendPhase::Pieces, SendPhase::State]),
+        Err(SendPhasesError::DuplicatePhase {
+            phase: SendPhase::State
+        })
+    );
+}
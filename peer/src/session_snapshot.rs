@@ -0,0 +1,197 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracker_protocol::FileSha256;
+
+use crate::{File, FileChunk, FileFromPartialError, FileMetadata, FileStateFromBytesError};
+
+/// Metadata and states of files shared by a local peer, captured by `LocalPeer::export_session`.
+///
+/// File contents are not captured, so snapshots are small enough
+/// to be attached to bug reports or handed off to another session.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SessionSnapshot {
+    pub files: Vec<SessionFile>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct SessionFile {
+    pub metadata: FileMetadata,
+    /// Available pieces packed with `FileState::to_bytes`.
+    pub state: Vec<u8>,
+}
+
+impl SessionSnapshot {
+    pub fn from_files<'a, C, const CHUNK_SIZE: usize>(
+        files: impl IntoIterator<Item = &'a File<C, CHUNK_SIZE>>,
+    ) -> Self
+    where
+        C: 'a,
+    {
+        let mut files: Vec<_> = files.into_iter().map(SessionFile::new).collect();
+        files.sort_unstable_by_key(|file| file.metadata.sha256().0);
+        Self { files }
+    }
+}
+
+impl SessionFile {
+    pub fn new<C, const CHUNK_SIZE: usize>(file: &File<C, CHUNK_SIZE>) -> Self {
+        Self {
+            metadata: file.metadata().clone(),
+            state: file.state().to_bytes(),
+        }
+    }
+
+    /// Rehydrates the file with its recorded state, see `File::from_released_state`.
+    pub fn to_file<C, const CHUNK_SIZE: usize>(
+        &self,
+    ) -> Result<File<C, CHUNK_SIZE>, SessionFileError>
+    where
+        C: FileChunk,
+    {
+        use crate::{FileState, FILE_PIECE_SIZE};
+
+        const FILE_PIECE_SIZE_U64: u64 = FILE_PIECE_SIZE as u64;
+
+        let sha256 = self.metadata.sha256();
+        let len = self.metadata.len();
+        let num_pieces: usize = ((len.0 + FILE_PIECE_SIZE_U64 - 1) / FILE_PIECE_SIZE_U64)
+            .try_into()
+            .map_err(|_| SessionFileError::InvalidFile {
+                sha256,
+                err: FileFromPartialError::SizeIsTooLarge { len },
+            })?;
+        let state = FileState::from_bytes(&self.state, num_pieces)
+            .map_err(|err| SessionFileError::InvalidState { sha256, err })?;
+        File::from_released_state(self.metadata.clone(), state)
+            .map_err(|err| SessionFileError::InvalidFile { sha256, err })
+    }
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SessionFileError {
+    #[error("file {sha256} state is invalid: {err}")]
+    InvalidState {
+        sha256: FileSha256,
+        err: FileStateFromBytesError,
+    },
+    #[error("file {sha256} can not be created: {err}")]
+    InvalidFile {
+        sha256: FileSha256,
+        err: FileFromPartialError,
+    },
+}
+
+#[test]
+fn export_and_import_session() {
+    use crate::{FileLen, FilePieceIdx, FileStateSetStatus, FILE_CHUNK_SIZE, FILE_PIECE_SIZE};
+
+    type TestFile = File<Box<[u8]>, FILE_CHUNK_SIZE>;
+
+    let complete = TestFile::from_bytes("complete".to_owned(), &[7; 3 * FILE_PIECE_SIZE]);
+    let mut partial = TestFile::new(FileMetadata::new(
+        FileSha256([2; 32]),
+        "partial".to_owned(),
+        FileLen(5 * FILE_PIECE_SIZE as u64),
+    ))
+    .unwrap();
+    let _: FileStateSetStatus = partial
+        .set_piece(&FilePieceIdx(1), &[1; FILE_PIECE_SIZE])
+        .unwrap();
+    let missing = TestFile::new(FileMetadata::new(
+        FileSha256([3; 32]),
+        "missing".to_owned(),
+        FileLen(100),
+    ))
+    .unwrap();
+
+    let snapshot = SessionSnapshot::from_files([&complete, &partial, &missing]);
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let snapshot: SessionSnapshot = serde_json::from_str(&json).unwrap();
+
+    let files: Vec<TestFile> = snapshot
+        .files
+        .iter()
+        .map(|file| file.to_file().unwrap())
+        .collect();
+    // Pieces of partially recorded chunks are imported as missing, other states are kept.
+    let reexported = SessionSnapshot::from_files(&files);
+    for (reexported, recorded) in reexported.files.iter().zip(&snapshot.files) {
+        if recorded.metadata.sha256() == partial.sha256() {
+            assert_eq!(reexported.metadata, recorded.metadata);
+        } else {
+            assert_eq!(reexported, recorded);
+        }
+    }
+
+    let imported_complete = files
+        .iter()
+        .find(|file| file.sha256() == complete.sha256())
+        .unwrap();
+    // Contents are not captured, so pieces of recorded chunks are never read.
+    assert!(imported_complete.state().is_complete());
+    assert!(imported_complete.is_piece_released(&FilePieceIdx(1)));
+    assert!(imported_complete.get_piece(&FilePieceIdx(1)).is_err());
+
+    let imported_partial = files
+        .iter()
+        .find(|file| file.sha256() == partial.sha256())
+        .unwrap();
+    assert_eq!(imported_partial.metadata(), partial.metadata());
+    assert!(imported_partial.state().is_missing());
+    assert!(!imported_partial.is_piece_released(&FilePieceIdx(1)));
+    assert_eq!(imported_partial.get_piece(&FilePieceIdx(1)), Ok(None));
+
+    let mut invalid = snapshot.files[0].clone();
+    let _: Option<u8> = invalid.state.pop();
+    assert!(matches!(
+        invalid.to_file::<Box<[u8]>, FILE_CHUNK_SIZE>(),
+        Err(SessionFileError::InvalidState { .. })
+    ));
+}
+
+#[test]
+fn complete_imported_partial_session() {
+    use crate::{
+        FileLen, FilePieceIdx, FileState, FileStateSetStatus, FILE_CHUNK_SIZE, FILE_PIECE_SIZE,
+    };
+
+    const NUM_PIECES_IN_CHUNK: usize = FILE_CHUNK_SIZE / FILE_PIECE_SIZE;
+    const NUM_PIECES: usize = NUM_PIECES_IN_CHUNK + 3;
+
+    // The first chunk is recorded completely, the second one only partially.
+    let recorded = FileState::from_indices(
+        NUM_PIECES,
+        (0..=NUM_PIECES_IN_CHUNK + 1)
+            .filter(|&j| j != NUM_PIECES_IN_CHUNK)
+            .map(FilePieceIdx),
+    )
+    .unwrap();
+    let session_file = SessionFile {
+        metadata: FileMetadata::new(
+            FileSha256([4; 32]),
+            "partial".to_owned(),
+            FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+        ),
+        state: recorded.to_bytes(),
+    };
+
+    let mut file: File<Box<[u8]>, FILE_CHUNK_SIZE> = session_file.to_file().unwrap();
+    assert!(file.is_chunk_released(0));
+    assert!(!file.is_chunk_released(1));
+    assert!(file.has_piece(&FilePieceIdx(0)).unwrap());
+    assert!(!file
+        .has_piece(&FilePieceIdx(NUM_PIECES_IN_CHUNK + 1))
+        .unwrap());
+
+    for j in NUM_PIECES_IN_CHUNK..NUM_PIECES {
+        assert_eq!(
+            file.set_piece(&FilePieceIdx(j), &[5; FILE_PIECE_SIZE]),
+            Ok(FileStateSetStatus::JustSet)
+        );
+    }
+    assert!(file.state().is_complete());
+    assert_eq!(
+        file.get_piece(&FilePieceIdx(NUM_PIECES_IN_CHUNK + 1)),
+        Ok(Some([5; FILE_PIECE_SIZE].into()))
+    );
+}
@@ -1,22 +1,56 @@
 use js_sys::Uint8Array;
 
-use core::borrow::Borrow;
-use std::collections::{BTreeMap, HashMap};
+use bitvec::slice::BitSlice;
+use bitvec::vec::BitVec;
+use core::cell::{Ref, RefCell};
+use core::ops::{Add, Sub};
+use core::time::Duration;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::rc::Rc;
 
 use thiserror::Error;
 use tracker_protocol::PeerId;
 
 use crate::{
-    File, FileChunk, FilePieceData, FilePieceIdx, FilePiecesQueues, FileSetPieceError, FileState,
-    PieceNumConfirmedOwners, PieceNumPossibleOwners, FILE_CHUNK_SIZE,
+    File, FileChunk, FileGetPieceError, FilePieceData, FilePieceIdx, FilePiecesQueues,
+    FileReplaceStateError, FileSetPieceError, FileState, PieceCache, PieceNumConfirmedOwners,
+    PieceNumPossibleOwners, FILE_CHUNK_SIZE, FILE_PIECE_SIZE,
 };
 
+#[cfg(feature = "selection-trace")]
+use crate::SelectionEvent;
+
 pub type JsSharedFile<T> = SharedFile<Uint8Array, T, FILE_CHUNK_SIZE>;
 
+/// Weight of the latest measurement in the smoothed peer rate.
+const PEER_RATE_SMOOTHING: f64 = 0.5;
+
+/// Minimum peer selection weight relative to the fastest peer,
+/// so that slow peers still receive a share of pieces.
+const MIN_PEER_RATE_RATIO: f64 = 0.1;
+
+/// Initial number of sent but not yet acknowledged bytes allowed per peer.
+pub const INITIAL_CONGESTION_WINDOW: u64 = 256 * FILE_PIECE_SIZE as u64;
+
+/// The congestion window is not shrunk below a few pieces so that a stalled peer can recover.
+pub const MIN_CONGESTION_WINDOW: u64 = 16 * FILE_PIECE_SIZE as u64;
+
+pub const MAX_CONGESTION_WINDOW: u64 = 16384 * FILE_PIECE_SIZE as u64;
+
+/// Upload priority weight of newly shared files.
+pub const DEFAULT_FILE_PRIORITY: u8 = 1;
+
+/// Number of piece arrival stamps the completion estimate is based on.
+pub const PIECE_ARRIVALS_LEN: usize = 32;
+
+/// The completion is not estimated if no pieces have arrived for this long.
+pub const ETA_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug)]
 pub struct SharedFile<C, T, const CHUNK_SIZE: usize> {
-    /// File metadata and contents.
-    file: File<C, CHUNK_SIZE>,
+    /// File metadata and contents, possibly shared with other shared files.
+    file: Rc<RefCell<File<C, CHUNK_SIZE>>>,
 
     /// File piece mask where pieces are owned by all remote devices.
     confirmed_remote_state: FileState,
@@ -27,20 +61,104 @@ pub struct SharedFile<C, T, const CHUNK_SIZE: usize> {
     /// PeerId ordered by PeerIdx.
     shared_peers_order: Vec<PeerId>,
 
+    /// Index in `shared_peers_order` piece peer orders start from,
+    /// it is advanced once per sending cycle so that cycles start from different peers.
+    peer_cursor: usize,
+
     /// File pieces sharing data queues and cache.
     piece_queues: FilePiecesQueues,
 
+    /// Pieces selected before the least owned ones, e.g. media headers and footers,
+    /// until they are selected for all peers with known state.
+    prioritized_pieces: Vec<FilePieceIdx>,
+
     /// Pieces that have been sent and may not have been received.
     sent_pieces: BTreeMap<T, Vec<(PeerId, FilePieceIdx)>>,
 
     /// A list of recently received file pieces.
     recently_added_pieces: Vec<FilePieceIdx>,
+
+    /// Recent arrival times and numbers of pieces added since the previous stamp.
+    piece_arrivals: VecDeque<(T, usize)>,
+
+    /// Number of pieces added since the last arrival stamp.
+    num_unstamped_arrivals: usize,
+
+    /// Verify chunk hashes from file metadata as soon as chunks are complete.
+    verify_chunks: bool,
+
+    /// Share of the upload budget relative to other files, zero pauses uploading.
+    priority: u8,
+
+    /// Release chunks as soon as all their pieces are confirmed by all peers.
+    release_confirmed_chunks: bool,
+
+    /// Sequence number of the next local state message.
+    next_state_seq: u64,
+
+    /// Bytes of pieces sent to peers and acknowledged by them.
+    uploaded_bytes: u64,
+
+    /// Bytes of pieces added to the file after it was created, i.e. received from peers.
+    downloaded_bytes: u64,
+
+    /// Recently read pieces, so that pieces sent to many peers are read once.
+    piece_cache: PieceCache,
+
+    /// Pieces requested from peers in pull mode with the peers and the request times.
+    piece_requests: HashMap<FilePieceIdx, (PeerId, T)>,
+
+    /// Pieces requested by peers in pull mode and not served yet.
+    queued_piece_requests: VecDeque<(PeerId, FilePieceIdx)>,
+
+    /// Recorded piece selection events, if recording is enabled.
+    #[cfg(feature = "selection-trace")]
+    trace: Option<Vec<SelectionEvent<T>>>,
 }
 
 #[derive(Clone, Debug)]
 struct SharedFilePeer<T> {
     state: Option<SharedFilePeerState>,
     local_state_status: SharedFileLocalStateStatus<T>,
+    /// Peer state chunks received so far.
+    pending_state: BitVec,
+    /// Sequence number of the last accepted peer state message.
+    state_seq: Option<u64>,
+    /// Bytes acknowledged by the peer since the last rate update.
+    acked_bytes: u64,
+    /// Smoothed acknowledged bytes per second.
+    rate: Option<f64>,
+    /// Piece selection credit, accumulated in proportion to the peer rate.
+    credit: f64,
+    /// Bytes of pieces selected for the peer and not acknowledged or resent yet.
+    in_flight_bytes: u64,
+    /// Maximum in-flight bytes, grows additively on acknowledgements and halves on resends.
+    congestion_window: u64,
+    /// Time of the first send that failed because the peer buffer was filled,
+    /// reset once a send succeeds.
+    send_blocked_since: Option<T>,
+    /// Pieces and the local state are not sent to the peer while it is blocked.
+    blocked: bool,
+    /// Pieces are sent to the peer only on its requests.
+    pulls_pieces: bool,
+    /// Time the peer last answered a liveness check, `None` until it answers the first one.
+    last_seen: Option<T>,
+    /// The peer has not answered liveness checks for too long, e.g. its tab is frozen,
+    /// pieces are not pushed to or requested from it until it answers again.
+    unresponsive: bool,
+}
+
+impl<T> SharedFilePeer<T> {
+    fn has_window_for(&self, piece_len: u64) -> bool {
+        self.in_flight_bytes + piece_len <= self.congestion_window
+    }
+
+    // The window grows by about a single piece per window of acknowledged bytes.
+    fn on_piece_acked(&mut self, piece_len: u64) {
+        self.in_flight_bytes = self.in_flight_bytes.saturating_sub(piece_len);
+        let increase = (piece_len * FILE_PIECE_SIZE as u64 / self.congestion_window).max(1);
+        self.congestion_window = (self.congestion_window + increase).min(MAX_CONGESTION_WINDOW);
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -58,20 +176,204 @@ pub struct SharedFilePeerState {
 }
 
 impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
-    pub fn new(file: File<C, CHUNK_SIZE>) -> Self {
-        let num_pieces = file.num_pieces();
-        Self {
+    /// Creates a shared file over an in-memory file.
+    ///
+    /// Pieces are read and written synchronously, so stored files are refused.
+    pub fn new(file: File<C, CHUNK_SIZE>) -> Result<Self, SharedFileNewError> {
+        Self::from_shared(Rc::new(RefCell::new(file)))
+    }
+
+    /// Creates a shared file over a file that may be shared with other shared files,
+    /// e.g. to distribute the same file in multiple rooms with different settings.
+    ///
+    /// Each shared file tracks its own peers and piece queues,
+    /// pieces added through another shared file are announced with the next state
+    /// and are not sent to peers of this shared file until they are queued here.
+    /// Chunks are not released while the file is shared,
+    /// since peers of other shared files may not have received them.
+    pub fn from_shared(file: Rc<RefCell<File<C, CHUNK_SIZE>>>) -> Result<Self, SharedFileNewError> {
+        if file.borrow().is_stored() {
+            return Err(SharedFileNewError::FileIsStored);
+        }
+        let num_pieces = file.borrow().num_pieces();
+        Ok(Self {
             file,
             confirmed_remote_state: FileState::from_complete(num_pieces),
             peers: HashMap::new(),
             shared_peers_order: Vec::new(),
+            peer_cursor: 0,
             piece_queues: FilePiecesQueues::new(num_pieces),
+            prioritized_pieces: Vec::new(),
             sent_pieces: BTreeMap::new(),
             recently_added_pieces: Vec::new(),
+            piece_arrivals: VecDeque::new(),
+            num_unstamped_arrivals: 0,
+            verify_chunks: true,
+            priority: DEFAULT_FILE_PRIORITY,
+            release_confirmed_chunks: false,
+            next_state_seq: 0,
+            uploaded_bytes: 0,
+            downloaded_bytes: 0,
+            piece_cache: PieceCache::default(),
+            piece_requests: HashMap::new(),
+            queued_piece_requests: VecDeque::new(),
+            #[cfg(feature = "selection-trace")]
+            trace: None,
+        })
+    }
+
+    /// Creates a shared file with a known-good state, e.g. restored from storage.
+    ///
+    /// Available pieces are shared with peers as soon as their states are known.
+    pub fn with_state(
+        mut file: File<C, CHUNK_SIZE>,
+        state: FileState,
+    ) -> Result<Self, FileReplaceStateError>
+    where
+        C: FileChunk,
+    {
+        let _: FileState = file.replace_state(state)?;
+        // Stored files are refused by `replace_state`.
+        Ok(Self::new(file).unwrap())
+    }
+
+    pub fn set_verify_chunks(&mut self, verify_chunks: bool) {
+        self.verify_chunks = verify_chunks;
+    }
+
+    pub fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Enables releasing chunks received by all peers,
+    /// which reduces memory usage but prevents saving the file and seeding it to new peers.
+    pub fn set_release_confirmed_chunks(&mut self, release_confirmed_chunks: bool) {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::SetReleaseConfirmedChunks {
+            release_confirmed_chunks,
+        });
+        self.release_confirmed_chunks = release_confirmed_chunks;
+    }
+
+    /// Sets the memory budget of the cache of recently read pieces, zero disables caching.
+    pub fn set_piece_cache_max_bytes(&mut self, max_bytes: usize) {
+        self.piece_cache.set_max_bytes(max_bytes);
+    }
+
+    pub fn piece_cache(&self) -> &PieceCache {
+        &self.piece_cache
+    }
+
+    /// Reads the piece like `File::get_piece` and caches its bytes for the next reads.
+    pub fn read_piece(
+        &mut self,
+        piece_idx: &FilePieceIdx,
+    ) -> Result<Option<Box<[u8]>>, FileGetPieceError>
+    where
+        C: FileChunk,
+    {
+        if let Some(bytes) = self.piece_cache.get(piece_idx) {
+            return Ok(Some(bytes.into()));
+        }
+        let bytes = self.file.borrow().get_piece(piece_idx)?;
+        if let Some(bytes) = &bytes {
+            self.piece_cache.insert(*piece_idx, bytes.clone());
+        }
+        Ok(bytes)
+    }
+
+    /// Keeps equally owned pieces ordered by index, see `FilePiecesQueues::set_stable_order`.
+    pub fn set_stable_piece_order(&mut self, stable_piece_order: bool) {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::SetStablePieceOrder { stable_piece_order });
+        self.piece_queues.set_stable_order(stable_piece_order);
+    }
+
+    /// Selects the given pieces before the least owned ones, in the given order.
+    ///
+    /// Priorities are cleared once pieces are selected for all peers with known state.
+    pub fn prioritize_pieces(
+        &mut self,
+        pieces: &[FilePieceIdx],
+    ) -> Result<(), SharedFilePrioritizePiecesError> {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::PrioritizePieces {
+            pieces: pieces.to_vec(),
+        });
+
+        let num_pieces = self.file.borrow().num_pieces();
+        if pieces.iter().any(|piece_idx| piece_idx.0 >= num_pieces) {
+            return Err(SharedFilePrioritizePiecesError::PieceIndexOutOfRange);
+        }
+        for piece_idx in pieces {
+            if !self.prioritized_pieces.contains(piece_idx) {
+                self.prioritized_pieces.push(*piece_idx);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn prioritized_pieces(&self) -> &[FilePieceIdx] {
+        &self.prioritized_pieces
+    }
+
+    /// Returns `true` if releasing is enabled, the file is not shared with other shared files
+    /// and all chunk pieces are available and confirmed by all peers with known state.
+    pub fn is_chunk_releasable(&self, chunk_idx: usize) -> bool {
+        self.release_confirmed_chunks
+            && Rc::strong_count(&self.file) == 1
+            && !self.shared_peers_order.is_empty()
+            && !self.file.borrow().is_chunk_released(chunk_idx)
+            && self.file.borrow().has_chunk(chunk_idx)
+            && self
+                .file
+                .borrow()
+                .chunk_pieces(chunk_idx)
+                .all(|piece_idx| self.confirmed_remote_state.has(&piece_idx).unwrap())
+    }
+
+    /// Starts recording piece selection events, discarding previously recorded ones.
+    ///
+    /// The recorded trace can be replayed with `SharedFile::replay`
+    /// starting from the file in its current state.
+    #[cfg(feature = "selection-trace")]
+    pub fn start_trace(&mut self) {
+        self.trace = Some(Vec::new());
+    }
+
+    /// Stops recording and returns recorded piece selection events.
+    #[cfg(feature = "selection-trace")]
+    pub fn take_trace(&mut self) -> Vec<SelectionEvent<T>> {
+        self.trace.take().unwrap_or_default()
+    }
+
+    #[cfg(feature = "selection-trace")]
+    fn record(&mut self, event: impl FnOnce() -> SelectionEvent<T>) {
+        if let Some(trace) = &mut self.trace {
+            trace.push(event());
+        }
+    }
+
+    fn release_chunk_if_confirmed(&mut self, piece_idx: &FilePieceIdx)
+    where
+        C: FileChunk,
+    {
+        let chunk_idx = self.file.borrow().piece_chunk_idx(piece_idx);
+        if self.is_chunk_releasable(chunk_idx) {
+            self.file.borrow_mut().release_chunk(chunk_idx).unwrap();
         }
     }
 
-    pub fn file(&self) -> &File<C, CHUNK_SIZE> {
+    pub fn file(&self) -> Ref<'_, File<C, CHUNK_SIZE>> {
+        self.file.borrow()
+    }
+
+    /// Returns the underlying file to create other shared files with `from_shared`.
+    pub fn shared_file(&self) -> &Rc<RefCell<File<C, CHUNK_SIZE>>> {
         &self.file
     }
 
@@ -87,14 +389,183 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         PieceNumPossibleOwners(self.shared_peers_order.len())
     }
 
+    /// Returns `true` if any peer has announced at least one piece of the file.
+    pub fn has_seeders(&self) -> bool {
+        self.peers.values().any(|peer| {
+            peer.state
+                .as_ref()
+                .is_some_and(|state| !state.confirmed.is_missing())
+        })
+    }
+
+    /// Returns the number of known copies of each piece including the local one.
+    fn piece_num_copies(&self) -> Vec<usize> {
+        let mut num_copies: Vec<_> = self
+            .file
+            .borrow()
+            .state()
+            .raw()
+            .iter()
+            .map(|bit| usize::from(*bit))
+            .collect();
+        for state in self.peers.values().filter_map(|peer| peer.state.as_ref()) {
+            for piece_idx in state.possible.raw().iter_ones() {
+                num_copies[piece_idx] += 1;
+            }
+        }
+        num_copies
+    }
+
+    /// Returns the average number of known copies of file pieces in the swarm.
+    ///
+    /// The file may never complete if the availability is less than one.
+    pub fn availability(&self) -> f64 {
+        let num_copies = self.piece_num_copies();
+        if num_copies.is_empty() {
+            return 0.0;
+        }
+        num_copies.iter().sum::<usize>() as f64 / num_copies.len() as f64
+    }
+
+    /// Returns the number of known copies of the rarest file piece in the swarm.
+    pub fn min_availability(&self) -> usize {
+        self.piece_num_copies().into_iter().min().unwrap_or(0)
+    }
+
+    /// Returns confirmed states of peers with known states ordered by peer id,
+    /// for example to render a grid of peers and pieces.
+    ///
+    /// States are cloned, so it takes `O(peers * pieces)` and should be called at a limited rate.
+    pub fn ownership_matrix(&self) -> Vec<(PeerId, FileState)> {
+        let mut matrix: Vec<_> = self
+            .peers
+            .iter()
+            .filter_map(|(peer_id, peer)| {
+                peer.state
+                    .as_ref()
+                    .map(|state| (*peer_id, state.confirmed.clone()))
+            })
+            .collect();
+        matrix.sort_unstable_by_key(|(peer_id, _)| peer_id.0);
+        matrix
+    }
+
+    /// Returns the sum of inverse copy counts of pieces that the peer has and are missing locally,
+    /// so that peers owning rare pieces get higher scores.
+    pub fn peer_rarity_score(&self, peer_id: &PeerId) -> f64 {
+        let state = match self.peers.get(peer_id).and_then(|peer| peer.state.as_ref()) {
+            Some(state) => state,
+            None => return 0.0,
+        };
+        let file = self.file.borrow();
+        let local_state = file.state().raw();
+        let num_copies = self.piece_num_copies();
+        state
+            .possible
+            .raw()
+            .iter_ones()
+            .filter(|&piece_idx| !local_state[piece_idx])
+            .map(|piece_idx| 1.0 / num_copies[piece_idx] as f64)
+            .sum()
+    }
+
+    /// Returns `true` if the peer may miss some of the locally available pieces.
+    pub fn peer_needs_local_pieces(&self, peer_id: &PeerId) -> bool {
+        match self.peers.get(peer_id).and_then(|peer| peer.state.as_ref()) {
+            Some(state) => !self.file.borrow().state().is_subset_of(&state.possible),
+            None => true,
+        }
+    }
+
+    /// Returns pieces that are available locally but are not received or sent to the peer yet.
+    pub fn peer_missing_pieces(
+        &self,
+        peer_id: &PeerId,
+    ) -> Result<Vec<FilePieceIdx>, SharedFilePeerMissingPiecesError> {
+        let peer = self
+            .peers
+            .get(peer_id)
+            .ok_or(SharedFilePeerMissingPiecesError::PeerIsNotAdded)?;
+        let state = peer
+            .state
+            .as_ref()
+            .ok_or(SharedFilePeerMissingPiecesError::PeerStateIsNotAdded)?;
+
+        let file = self.file.borrow();
+        let local = file.state();
+        let missing = (local.clone() ^ &state.possible) & local;
+        Ok(missing.raw().iter_ones().map(FilePieceIdx).collect())
+    }
+
     pub fn num_pieces(&self) -> usize {
-        self.file.num_pieces()
+        self.file.borrow().num_pieces()
+    }
+
+    /// Returns the number of bytes of missing pieces, the last piece may be shorter than others.
+    pub fn bytes_remaining(&self) -> u64 {
+        let file = self.file.borrow();
+        file.state()
+            .raw()
+            .iter_zeros()
+            .map(|piece_idx| file.piece_len(&FilePieceIdx(piece_idx)) as u64)
+            .sum()
+    }
+
+    /// Returns the number of bytes of available pieces.
+    pub fn bytes_available(&self) -> u64 {
+        self.file.borrow().len().0 - self.bytes_remaining()
+    }
+
+    /// Returns `true` if the file is complete locally
+    /// and all pieces are confirmed by all peers with known state.
+    pub fn is_fully_distributed(&self) -> bool {
+        self.file.borrow().state().is_complete()
+            && self.confirmed_remote_state.is_complete()
+            && self.piece_queues.is_empty()
     }
 
     pub fn piece_queues(&self) -> &FilePiecesQueues {
         &self.piece_queues
     }
 
+    /// Returns pieces to be selected next, prioritized pieces missing on some peers if any,
+    /// otherwise the least owned pieces like `FilePiecesQueues::next_queue`.
+    ///
+    /// Prioritized pieces are reported as not owned by any peer,
+    /// so that they also precede the least owned pieces of other files.
+    pub fn next_pieces(&self) -> Option<(PieceNumPossibleOwners, Cow<'_, [FilePieceIdx]>)> {
+        let num_peers = self.num_peers_with_state();
+        let prioritized: Vec<_> = self
+            .prioritized_pieces
+            .iter()
+            .copied()
+            .filter(|piece_idx| {
+                self.piece_queues
+                    .get(*piece_idx)
+                    .is_ok_and(|data| data.num_possible_owners < num_peers)
+            })
+            .collect();
+        if prioritized.is_empty() {
+            self.piece_queues
+                .next_queue()
+                .map(|(num_possible_owners, pieces)| (num_possible_owners, Cow::Borrowed(pieces)))
+        } else {
+            Some((PieceNumPossibleOwners(0), Cow::Owned(prioritized)))
+        }
+    }
+
+    /// Returns queued pieces selected more than `max_send_attempts` times without confirmation,
+    /// e.g. to prioritize them or to find peers that never acknowledge pieces.
+    pub fn excessively_sent_pieces(
+        &self,
+        max_send_attempts: u32,
+    ) -> impl Iterator<Item = FilePieceIdx> + '_ {
+        self.piece_queues
+            .iter_pieces()
+            .filter(move |(_, piece)| piece.send_attempts > max_send_attempts)
+            .map(|(piece_idx, _)| piece_idx)
+    }
+
     pub fn has_peer(&self, peer_id: PeerId) -> bool {
         self.peers.contains_key(&peer_id)
     }
@@ -102,6 +573,9 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
     pub fn add_peer(&mut self, peer_id: PeerId) -> Result<(), SharedFileAddPeerError> {
         use std::collections::hash_map::Entry;
 
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::AddPeer { peer_id });
+
         let entry = match self.peers.entry(peer_id) {
             Entry::Occupied(_) => Err(SharedFileAddPeerError::PeerIsAlreadyAdded),
             Entry::Vacant(entry) => Ok(entry),
@@ -110,6 +584,18 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         let _: &mut _ = entry.insert(SharedFilePeer {
             state: None,
             local_state_status: SharedFileLocalStateStatus::NotSent,
+            pending_state: BitVec::new(),
+            state_seq: None,
+            acked_bytes: 0,
+            rate: None,
+            credit: 0.0,
+            in_flight_bytes: 0,
+            congestion_window: INITIAL_CONGESTION_WINDOW,
+            send_blocked_since: None,
+            blocked: false,
+            pulls_pieces: false,
+            last_seen: None,
+            unresponsive: false,
         });
 
         Ok(())
@@ -139,16 +625,28 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
             });
         }
 
-        let local_state = self.file.state().raw().iter();
+        let max_prev_owners = self.shared_peers_order.len();
+        let peer_idx = self.shared_peers_order.push_and_get_offset(peer_id);
+
+        // The state is stored first so that queue owner counts include the added peer.
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        peer.state = Some(SharedFilePeerState {
+            peer_idx,
+            confirmed: state.clone(),
+            possible: state.clone(),
+        });
+
+        let file = self.file.borrow();
+        let local_state = file.state().raw().iter();
         let remote_state = self.confirmed_remote_state.raw().iter();
         let peer_state = state.raw().iter();
-        let max_prev_owners = self.shared_peers_order.len();
 
         for (piece_idx, (local, (remote, peer))) in
             local_state.zip(remote_state.zip(peer_state)).enumerate()
         {
             let piece_idx = FilePieceIdx(piece_idx);
-            match (*local, *remote, *peer) {
+            let local = *local && !file.is_piece_released(&piece_idx);
+            match (local, *remote, *peer) {
                 // the piece is present locally and on all remote peers, except the added one
                 (true, true, false) => {
                     let num_confirmed_owners = PieceNumConfirmedOwners(max_prev_owners);
@@ -157,6 +655,7 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
                         peer_shift: PiecePeerShift(0),
                         num_confirmed_owners,
                         num_possible_owners,
+                        send_attempts: 0,
                     };
                     insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
                 }
@@ -172,15 +671,6 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         }
 
         self.confirmed_remote_state = self.confirmed_remote_state.clone() & &state;
-        let peer_idx = self.shared_peers_order.push_and_get_offset(peer_id);
-
-        let peer = self.peers.get_mut(&peer_id).unwrap();
-
-        peer.state = Some(SharedFilePeerState {
-            peer_idx,
-            confirmed: state.clone(),
-            possible: state,
-        });
 
         Ok(())
     }
@@ -189,6 +679,9 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
     where
         T: Ord,
     {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::RemovePeer { peer_id: *peer_id });
+
         match self.remove_peer_state(peer_id) {
             Ok(()) | Err(SharedFileRemovePeerStateError::PeerStateIsAlreadyRemoved) => Ok(()),
             Err(SharedFileRemovePeerStateError::PeerIsNotAdded) => {
@@ -197,6 +690,10 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         }?;
 
         let _ = self.peers.remove(&peer_id).unwrap();
+        self.piece_requests
+            .retain(|_, (requested_peer_id, _)| requested_peer_id != peer_id);
+        self.queued_piece_requests
+            .retain(|(requested_peer_id, _)| requested_peer_id != peer_id);
 
         Ok(())
     }
@@ -216,17 +713,25 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
             Some(peer_state) => peer_state,
             None => return Err(SharedFileRemovePeerStateError::PeerStateIsAlreadyRemoved),
         };
+        peer.in_flight_bytes = 0;
 
-        let local_state = self.file.state().raw().iter();
+        let file = self.file.borrow();
+        let local_state = file.state().raw().iter();
         let remote_state = self.confirmed_remote_state.raw().iter();
         let _: PeerId = self.shared_peers_order.swap_remove(peer_state.peer_idx);
+        if let Some(moved_peer_id) = self.shared_peers_order.get(peer_state.peer_idx) {
+            let moved_peer = self.peers.get_mut(moved_peer_id).unwrap();
+            moved_peer.state.as_mut().unwrap().peer_idx = peer_state.peer_idx;
+        }
+        let possible_state = peer_state.possible;
         let peer_state = peer_state.confirmed.raw().iter();
 
         for (piece_idx, (local, (remote, peer))) in
             local_state.zip(remote_state.zip(peer_state)).enumerate()
         {
             let piece_idx = FilePieceIdx(piece_idx);
-            match (*local, *remote, *peer) {
+            let local = *local && !file.is_piece_released(&piece_idx);
+            match (local, *remote, *peer) {
                 // the piece is present locally and on all remote peers, except the added one
                 (true, true, false) => {
                     let _ = self.piece_queues.remove(&piece_idx).unwrap();
@@ -238,10 +743,17 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
                     piece.num_possible_owners.0 -= 1;
                     insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
                 }
+                // the piece has been sent to the removed peer, but not confirmed yet
+                (true, false, false) if possible_state.has(&piece_idx).unwrap() => {
+                    let mut piece = self.piece_queues.remove(&piece_idx).unwrap();
+                    piece.num_possible_owners.0 -= 1;
+                    insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
+                }
                 (true, false, false) | (true, true, true) | (false, _, _) => {}
             }
         }
 
+        let prev_remote_state = self.confirmed_remote_state.clone();
         self.confirmed_remote_state = self
             .peers
             .values()
@@ -249,6 +761,16 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
             .map(|peer| &peer.confirmed)
             .fold(FileState::from_complete(self.num_pieces()), BitAnd::bitand);
 
+        // pieces that are now present on all remaining peers no longer need to be shared
+        let confirmed_state = self.confirmed_remote_state.clone() ^ &prev_remote_state;
+        for piece_idx in confirmed_state.raw().iter_ones().map(FilePieceIdx) {
+            if self.file.borrow().has_piece(&piece_idx).unwrap()
+                && !self.file.borrow().is_piece_released(&piece_idx)
+            {
+                let _ = self.piece_queues.remove(&piece_idx).unwrap();
+            }
+        }
+
         self.sent_pieces = take(&mut self.sent_pieces)
             .into_iter()
             .map(|(duration, pieces)| {
@@ -265,6 +787,8 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         Ok(())
     }
 
+    /// Replaces the peer state, the new state may also lack pieces the previous one had,
+    /// e.g. if the peer dropped them, such pieces are queued for sending again.
     pub fn set_peer_state(
         &mut self,
         peer_id: PeerId,
@@ -273,6 +797,12 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
     where
         T: Ord,
     {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::SetPeerState {
+            peer_id,
+            state: state.raw().into(),
+        });
+
         match self.remove_peer_state(&peer_id) {
             Ok(()) | Err(SharedFileRemovePeerStateError::PeerStateIsAlreadyRemoved) => Ok(()),
             Err(SharedFileRemovePeerStateError::PeerIsNotAdded) => {
@@ -293,6 +823,64 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         }
     }
 
+    /// Appends a chunk of the peer state received as `PeerPeerMessage::FileStateChunk`.
+    ///
+    /// Chunks are expected in order, the peer state is set once the last chunk is received.
+    pub fn set_peer_state_chunk(
+        &mut self,
+        peer_id: PeerId,
+        num_pieces: usize,
+        offset: usize,
+        chunk: &BitSlice,
+    ) -> Result<SharedFileStateChunkStatus, SharedFileSetPeerStateChunkError>
+    where
+        T: Ord,
+    {
+        use core::mem::take;
+
+        let peer = self
+            .peers
+            .get_mut(&peer_id)
+            .ok_or(SharedFileSetPeerStateChunkError::PeerIsNotAdded)?;
+
+        if offset == 0 {
+            peer.pending_state.clear();
+        }
+        let expected = peer.pending_state.len();
+        if offset != expected {
+            peer.pending_state.clear();
+            return Err(SharedFileSetPeerStateChunkError::UnexpectedChunkOffset {
+                offset,
+                expected,
+            });
+        }
+        if offset + chunk.len() > num_pieces {
+            peer.pending_state.clear();
+            return Err(SharedFileSetPeerStateChunkError::ChunkOutOfRange {
+                end: offset + chunk.len(),
+                num_pieces,
+            });
+        }
+
+        peer.pending_state.extend_from_bitslice(chunk);
+        if peer.pending_state.len() < num_pieces {
+            return Ok(SharedFileStateChunkStatus::Partial);
+        }
+
+        let state = take(&mut peer.pending_state).into_boxed_bitslice();
+        match self.set_peer_state(peer_id, FileState::from(state)) {
+            Ok(()) => Ok(SharedFileStateChunkStatus::Complete),
+            Err(SharedFileSetPeerStateError::PeerIsNotAdded) => unreachable!(),
+            Err(SharedFileSetPeerStateError::PeerInvalidStateLen {
+                peer_len,
+                local_len,
+            }) => Err(SharedFileSetPeerStateChunkError::PeerInvalidStateLen {
+                peer_len,
+                local_len,
+            }),
+        }
+    }
+
     pub fn set_peer_file_missing(
         &mut self,
         peer_id: PeerId,
@@ -323,15 +911,29 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
     {
         use crate::{FileStateSetStatus, PiecePeerShift};
 
-        match self.file.set_piece(&piece_idx, data)? {
+        match self.file.borrow_mut().set_piece(&piece_idx, data)? {
             FileStateSetStatus::AlreadySet => Err(SharedFileAddLocalPieceError::PieceIsAlreadySet),
             FileStateSetStatus::JustSet => Ok(()),
         }?;
+        let _: Option<_> = self.piece_requests.remove(&piece_idx);
+
+        if self.verify_chunks {
+            self.verify_piece_chunk(&piece_idx)?;
+        }
+
+        // Only verified pieces are recorded since piece contents are not replayed.
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::AddLocalPiece { piece_idx });
+
+        self.downloaded_bytes += data.len() as u64;
 
         self.recently_added_pieces.push(piece_idx);
+        self.num_unstamped_arrivals += 1;
 
         let num_confirmed_owners = num_piece_confirmed_owners(&self.peers, &piece_idx);
-        if num_confirmed_owners.0 == self.peers.len() {
+        if num_confirmed_owners.0 == self.shared_peers_order.len() {
+            let _: FileStateSetStatus = self.confirmed_remote_state.set(&piece_idx).unwrap();
+            self.release_chunk_if_confirmed(&piece_idx);
             return Ok(());
         }
 
@@ -342,92 +944,441 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
             peer_shift: PiecePeerShift(0),
             num_confirmed_owners: num_confirmed_owners,
             num_possible_owners: num_possible_owners,
+            send_attempts: 0,
         };
         insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
 
         Ok(())
     }
 
+    // If the chunk is corrupted, its pieces are dropped
+    // and the local state is resent to peers so that the pieces are sent again.
+    fn verify_piece_chunk(
+        &mut self,
+        piece_idx: &FilePieceIdx,
+    ) -> Result<(), SharedFileAddLocalPieceError>
+    where
+        C: FileChunk,
+    {
+        let chunk_idx = self.file.borrow().piece_chunk_idx(piece_idx);
+        let expected = match self.file.borrow().metadata().chunk_hashes().get(chunk_idx) {
+            Some(expected) => *expected,
+            None => return Ok(()),
+        };
+        if !self.file.borrow().has_chunk(chunk_idx)
+            || self.file.borrow().chunk_sha256(chunk_idx) == expected
+        {
+            return Ok(());
+        }
+
+        let pieces = self.file.borrow_mut().unset_chunk(chunk_idx);
+        self.drop_unset_pieces(&pieces);
+
+        Err(SharedFileAddLocalPieceError::ChunkHashMismatch { chunk_idx })
+    }
+
+    /// Marks pieces as missing locally, e.g. if they are suspected to be corrupted,
+    /// and resends the local state to peers so that the pieces are received again.
+    ///
+    /// Fails without changes if any of the pieces is missing, released,
+    /// or is not owned by any peer.
+    pub fn invalidate_pieces(
+        &mut self,
+        pieces: &[FilePieceIdx],
+    ) -> Result<(), SharedFileInvalidatePiecesError> {
+        use crate::FileStateUnsetStatus;
+
+        let num_pieces = self.num_pieces();
+        for piece_idx in pieces {
+            let piece_idx = check_piece_idx(piece_idx, num_pieces)
+                .ok_or(SharedFileInvalidatePiecesError::PieceIndexOutOfRange)?;
+            if !self.file.borrow().has_piece(piece_idx).unwrap() {
+                return Err(SharedFileInvalidatePiecesError::PieceIsMissing {
+                    piece_idx: *piece_idx,
+                });
+            }
+            if self.file.borrow().is_piece_released(piece_idx) {
+                return Err(SharedFileInvalidatePiecesError::PieceIsReleased {
+                    piece_idx: *piece_idx,
+                });
+            }
+            if num_piece_confirmed_owners(&self.peers, piece_idx).0 == 0 {
+                return Err(SharedFileInvalidatePiecesError::PieceIsNotOwnedByPeers {
+                    piece_idx: *piece_idx,
+                });
+            }
+        }
+
+        let pieces: Vec<_> = pieces
+            .iter()
+            .filter(|piece_idx| {
+                self.file.borrow_mut().unset_piece(piece_idx).unwrap()
+                    == FileStateUnsetStatus::JustUnset
+            })
+            .copied()
+            .collect();
+        self.drop_unset_pieces(&pieces);
+
+        Ok(())
+    }
+
+    // Pieces that are no longer available locally are not shared until they are received again.
+    fn drop_unset_pieces(&mut self, pieces: &[FilePieceIdx]) {
+        use crate::FileStateUnsetStatus;
+
+        for piece_idx in pieces {
+            let _: Result<_, _> = self.piece_queues.remove(piece_idx);
+            self.piece_cache.remove(piece_idx);
+        }
+        self.recently_added_pieces
+            .retain(|piece_idx| !pieces.contains(piece_idx));
+        for sent in self.sent_pieces.values_mut() {
+            sent.retain(|(_, piece_idx)| !pieces.contains(piece_idx));
+        }
+        for peer in self.peers.values_mut() {
+            if let Some(state) = &mut peer.state {
+                for piece_idx in pieces {
+                    if !state.confirmed.has(piece_idx).unwrap()
+                        && state.possible.unset(piece_idx).unwrap()
+                            == FileStateUnsetStatus::JustUnset
+                    {
+                        peer.in_flight_bytes = peer
+                            .in_flight_bytes
+                            .saturating_sub(self.file.borrow().piece_len(piece_idx) as u64);
+                    }
+                }
+            }
+            peer.local_state_status = SharedFileLocalStateStatus::NotSent;
+        }
+    }
+
     pub fn take_recently_added_pieces(&mut self) -> Vec<FilePieceIdx> {
         use core::mem::take;
 
         take(&mut self.recently_added_pieces)
     }
 
+    /// Records that pieces added since the previous call arrived at `now`.
+    ///
+    /// Pieces are added without a time, so arrivals are stamped periodically instead.
+    pub fn stamp_piece_arrivals(&mut self, now: T) {
+        use core::mem::take;
+
+        let num_pieces = take(&mut self.num_unstamped_arrivals);
+        if num_pieces == 0 {
+            return;
+        }
+        if self.piece_arrivals.len() == PIECE_ARRIVALS_LEN {
+            let _: Option<_> = self.piece_arrivals.pop_front();
+        }
+        self.piece_arrivals.push_back((now, num_pieces));
+    }
+
+    /// Returns the estimated completion time based on the recent piece arrival rate.
+    ///
+    /// Returns `None` if the rate is unknown yet or no pieces have arrived recently.
+    pub fn estimated_completion(&self, now: T) -> Option<T>
+    where
+        T: Clone + Ord + Add<Duration, Output = T> + Sub<Output = Duration>,
+    {
+        let num_missing = self.file.borrow().state().num_missing();
+        if num_missing == 0 {
+            return Some(now);
+        }
+        let (last_time, _) = self.piece_arrivals.back()?;
+        if last_time.clone() + ETA_STALL_TIMEOUT < now {
+            return None;
+        }
+        // Pieces stamped at the first time arrived during an unknown interval before it.
+        let (first_time, _) = self.piece_arrivals.front()?;
+        let num_pieces: usize = self.piece_arrivals.iter().skip(1).map(|(_, n)| n).sum();
+        let elapsed = (now.clone() - first_time.clone()).as_secs_f64();
+        if num_pieces == 0 || elapsed <= 0.0 {
+            return None;
+        }
+        let rate = num_pieces as f64 / elapsed;
+        Some(now + Duration::from_secs_f64(num_missing as f64 / rate))
+    }
+
     pub fn mark_pieces_for_resend_before(&mut self, time: T) -> Result<(), SharedFileMarkError>
     where
-        T: Ord,
+        T: Clone + Ord,
     {
         use core::mem::take;
 
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::MarkPiecesForResendBefore { time: time.clone() });
+
         let mut not_sent = take(&mut self.sent_pieces);
         self.sent_pieces = not_sent.split_off(&time);
 
+        let mut stalled_peers = HashSet::new();
         for pieces in not_sent.into_values() {
             for (peer_id, piece_idx) in pieces {
-                let _: SharedFileMarkForResendStatus =
-                    self.mark_for_resend_if_not_sent(&peer_id, piece_idx)?;
+                let status = self.mark_for_resend(&peer_id, piece_idx)?;
+                if status == SharedFileMarkForResendStatus::JustMarked {
+                    let _: bool = stalled_peers.insert(peer_id);
+                }
             }
         }
 
+        // The window is halved once per call regardless of the number of resent pieces.
+        for peer_id in stalled_peers {
+            let peer = self.peers.get_mut(&peer_id).unwrap();
+            peer.congestion_window = (peer.congestion_window / 2).max(MIN_CONGESTION_WINDOW);
+        }
+
         Ok(())
     }
 
+    /// Starts piece peer orders of the next sending cycle from the next peer.
+    ///
+    /// The piece hash still defines the order of peers for each piece,
+    /// but rotating its start spreads pieces evenly between peers over many cycles.
+    pub fn advance_peer_cursor(&mut self) {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::AdvancePeerCursor);
+
+        let num_peers = self.shared_peers_order.len();
+        if num_peers > 0 {
+            self.peer_cursor = (self.peer_cursor + 1) % num_peers;
+        }
+    }
+
     pub fn select_piece_peer(
         &mut self,
         piece_idx: FilePieceIdx,
         time: T,
     ) -> Result<PeerId, SharedFileSelectPiecePeerError>
     where
-        T: Ord,
+        T: Clone + Ord,
     {
-        use crate::FileStateSetStatus;
+        self.select_piece_peer_excluding(piece_idx, time, &HashSet::new())
+    }
 
-        let piece_idx = check_piece_idx(piece_idx, self.num_pieces())
-            .ok_or(SharedFileSelectPiecePeerError::PieceIndexOutOfRange)?;
+    /// Selects a peer for the piece skipping `excluded_peers`,
+    /// e.g. peers that have already been assigned enough pieces.
+    pub fn select_piece_peer_excluding(
+        &mut self,
+        piece_idx: FilePieceIdx,
+        time: T,
+        excluded_peers: &HashSet<PeerId>,
+    ) -> Result<PeerId, SharedFileSelectPiecePeerError>
+    where
+        T: Clone + Ord,
+    {
+        use crate::FileStateSetStatus;
+
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::SelectPiecePeer {
+            piece_idx,
+            time: time.clone(),
+            excluded_peers: excluded_peers.iter().copied().collect(),
+        });
+
+        let piece_idx = check_piece_idx(piece_idx, self.num_pieces())
+            .ok_or(SharedFileSelectPiecePeerError::PieceIndexOutOfRange)?;
 
         let num_peers = self.shared_peers_order.len();
         let mut piece = self.piece_queues.remove(&piece_idx).unwrap();
+        let piece_len = self.file.borrow().piece_len(&piece_idx) as u64;
+
+        // Blocked, pulling and unresponsive peers and peers with full congestion windows
+        // are skipped the same way as excluded peers.
+        let is_excluded = |peer_id: &PeerId, peer: &SharedFilePeer<T>| {
+            excluded_peers.contains(peer_id)
+                || peer.blocked
+                || peer.pulls_pieces
+                || peer.unresponsive
+                || !peer.has_window_for(piece_len)
+        };
 
-        let hash = fxhash::hash64(&piece_idx);
-        let peer_idx_mult = ((hash >> 32) as usize % (num_peers - 1).max(1)) + 1;
-        let peer_idx_offset = (hash & ((1 << 32) - 1)) as usize;
-
-        let offset = |shift| ((peer_idx_mult * (peer_idx_offset + shift)) % num_peers) as usize;
-
-        for shift in piece.peer_shift.0..piece.peer_shift.0 + num_peers {
-            let peer_id = self.shared_peers_order[offset(shift)];
-            println!(
-                "{} {} {} {} {}",
-                piece_idx.0,
-                shift,
-                piece.peer_shift.0,
-                piece.peer_shift.0 + num_peers,
-                peer_id.0
-            );
-            let peer = self.peers.get_mut(&peer_id).unwrap();
-            let peer_state = peer.state.as_mut().unwrap();
-            if peer_state.possible.set(&piece_idx).unwrap() == FileStateSetStatus::JustSet {
-                piece.num_possible_owners.0 += 1;
-                piece.peer_shift.0 = (shift + 1) % num_peers;
+        let weights = self.peer_weights();
+        let mut selected: Option<(usize, PeerId, f64)> = None;
+        let mut has_excluded = false;
+        if num_peers == 1 {
+            // The sole peer is selected without the piece-specific peer order.
+            let peer_id = self.shared_peers_order[0];
+            let peer = self.peers.get(&peer_id).unwrap();
+            let peer_state = peer.state.as_ref().unwrap();
+            if !peer_state.possible.has(&piece_idx).unwrap() {
+                if is_excluded(&peer_id, peer) {
+                    has_excluded = true;
+                } else {
+                    selected = Some((piece.peer_shift.0, peer_id, peer.credit));
+                }
+            }
+        } else {
+            // The piece hash defines the order of peers, but it starts from the cycle cursor,
+            // since hash offsets alone cluster assignments over many pieces.
+            use crate::select_peer_offset;
+
+            let hash = fxhash::hash64(&piece_idx);
+            let offset =
+                |shift| (select_peer_offset(hash, num_peers, shift) + self.peer_cursor) % num_peers;
+
+            // Without measured rates peers are selected in the piece-specific order,
+            // otherwise the first peer with non-negative credit or the one with the largest credit.
+            for shift in piece.peer_shift.0..piece.peer_shift.0 + num_peers {
+                let peer_id = self.shared_peers_order[offset(shift)];
+                let peer = self.peers.get(&peer_id).unwrap();
+                let peer_state = peer.state.as_ref().unwrap();
+                if peer_state.possible.has(&piece_idx).unwrap() {
+                    continue;
+                }
+                if is_excluded(&peer_id, peer) {
+                    has_excluded = true;
+                    continue;
+                }
+                if selected.is_none_or(|(_, _, credit)| peer.credit > credit) {
+                    selected = Some((shift, peer_id, peer.credit));
+                }
+                if weights.is_none() || peer.credit >= 0.0 {
+                    break;
+                }
+            }
+        }
+        let (shift, peer_id, _) = match selected {
+            Some(selected) => selected,
+            None => {
                 insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
-                self.sent_pieces
-                    .entry(time)
-                    .or_default()
-                    .push((peer_id, piece_idx));
-                return Ok(peer_id);
+                return Err(if has_excluded {
+                    SharedFileSelectPiecePeerError::AllPeersAreExcluded
+                } else {
+                    SharedFileSelectPiecePeerError::PieceIsAlreadyOwned
+                });
+            }
+        };
+
+        let peer = self.peers.get_mut(&peer_id).unwrap();
+        peer.in_flight_bytes += piece_len;
+        let peer_state = peer.state.as_mut().unwrap();
+        let _: FileStateSetStatus = peer_state.possible.set(&piece_idx).unwrap();
+        piece.num_possible_owners.0 += 1;
+        piece.send_attempts = piece.send_attempts.saturating_add(1);
+        piece.peer_shift.0 = (shift + 1) % num_peers;
+        if piece.num_possible_owners.0 == num_peers {
+            self.prioritized_pieces
+                .retain(|&stored| stored != piece_idx);
+        }
+        insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
+        self.sent_pieces
+            .entry(time)
+            .or_default()
+            .push((peer_id, piece_idx));
+
+        if let Some(weights) = weights {
+            let total: f64 = weights.iter().sum();
+            for (shared_peer_id, weight) in self.shared_peers_order.iter().zip(weights) {
+                self.peers.get_mut(shared_peer_id).unwrap().credit += weight / total;
             }
+            self.peers.get_mut(&peer_id).unwrap().credit -= 1.0;
+        }
+
+        Ok(peer_id)
+    }
+
+    /// Updates smoothed peer rates with bytes acknowledged during `elapsed` time.
+    pub fn update_peer_rates(&mut self, elapsed: Duration) {
+        use core::mem::take;
+
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::UpdatePeerRates { elapsed });
+
+        let secs = elapsed.as_secs_f64();
+        if secs <= 0.0 {
+            return;
+        }
+        for peer in self.peers.values_mut() {
+            let rate = take(&mut peer.acked_bytes) as f64 / secs;
+            peer.rate = Some(peer.rate.map_or(rate, |prev| {
+                (rate - prev).mul_add(PEER_RATE_SMOOTHING, prev)
+            }));
+        }
+    }
+
+    /// Returns smoothed acknowledged bytes per second for the peer.
+    pub fn peer_rate(&self, peer_id: &PeerId) -> Option<f64> {
+        self.peers.get(peer_id).and_then(|peer| peer.rate)
+    }
+
+    /// Returns the maximum number of bytes that can be sent to the peer without acknowledgement.
+    pub fn peer_congestion_window(&self, peer_id: &PeerId) -> Option<u64> {
+        self.peers.get(peer_id).map(|peer| peer.congestion_window)
+    }
+
+    /// Returns the number of bytes sent to the peer and not acknowledged yet.
+    pub fn peer_in_flight_bytes(&self, peer_id: &PeerId) -> Option<u64> {
+        self.peers.get(peer_id).map(|peer| peer.in_flight_bytes)
+    }
+
+    /// Records that sending to the peer failed because its buffer was filled.
+    ///
+    /// Diagnostic only, the earliest time is kept until `mark_peer_send_unblocked` is called.
+    pub fn mark_peer_send_blocked(&mut self, peer_id: &PeerId, time: T) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            let _: &mut T = peer.send_blocked_since.get_or_insert(time);
+        }
+    }
+
+    pub fn mark_peer_send_unblocked(&mut self, peer_id: &PeerId) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.send_blocked_since = None;
+        }
+    }
+
+    /// Returns the time since which sending to the peer is blocked by a filled buffer.
+    pub fn peer_send_blocked_since(&self, peer_id: &PeerId) -> Option<&T> {
+        self.peers
+            .get(peer_id)
+            .and_then(|peer| peer.send_blocked_since.as_ref())
+    }
+
+    /// Returns peers sending to which is blocked by a filled buffer and the time since when.
+    pub fn send_blocked_peers(&self) -> impl Iterator<Item = (&PeerId, &T)> {
+        self.peers.iter().filter_map(|(peer_id, peer)| {
+            peer.send_blocked_since
+                .as_ref()
+                .map(|blocked_since| (peer_id, blocked_since))
+        })
+    }
+
+    // Returns selection weights ordered as `shared_peers_order` or `None` if no rates are measured.
+    fn peer_weights(&self) -> Option<Vec<f64>> {
+        let rates: Vec<f64> = self
+            .shared_peers_order
+            .iter()
+            .map(|peer_id| self.peers[peer_id].rate.unwrap_or(0.0))
+            .collect();
+        let max_rate = rates.iter().copied().fold(0.0, f64::max);
+        if max_rate > 0.0 {
+            Some(
+                rates
+                    .into_iter()
+                    .map(|rate| rate.max(max_rate * MIN_PEER_RATE_RATIO))
+                    .collect(),
+            )
+        } else {
+            None
         }
-        Err(SharedFileSelectPiecePeerError::PieceIsAlreadyOwned)
     }
 
     pub fn mark_peer_piece_as_received_by_remote(
         &mut self,
         peer_id: &PeerId,
         piece_idx: FilePieceIdx,
-    ) -> Result<SharedFileMarkStatus, SharedFileMarkError> {
+    ) -> Result<SharedFileMarkStatus, SharedFileMarkError>
+    where
+        C: FileChunk,
+    {
         use crate::FileStateSetStatus;
 
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::MarkPeerPieceAsReceivedByRemote {
+            peer_id: *peer_id,
+            piece_idx,
+        });
+
         let num_pieces = self.num_pieces();
         let (state, piece_idx) =
             mark_peer_state_with_piece_idx(&mut self.peers, peer_id, piece_idx, num_pieces)?;
@@ -438,18 +1389,44 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         }
         let possible = state.possible.set(&piece_idx).unwrap();
 
-        if !self.file.has_piece(&piece_idx).unwrap() {
+        // Pieces possibly owned but not confirmed by the peer are the ones sent to it.
+        if possible == FileStateSetStatus::AlreadySet {
+            let piece_len = self.file.borrow().piece_len(&piece_idx) as u64;
+            self.peers
+                .get_mut(peer_id)
+                .unwrap()
+                .on_piece_acked(piece_len);
+        }
+
+        if !self.file.borrow().has_piece(&piece_idx).unwrap()
+            || self.file.borrow().is_piece_released(&piece_idx)
+        {
             return Ok(SharedFileMarkStatus::JustMarked);
         }
 
+        // Only pieces that have been sent to the peer are counted in its rate.
+        if possible == FileStateSetStatus::AlreadySet {
+            let piece_len = self.file.borrow().piece_len(&piece_idx) as u64;
+            self.peers.get_mut(peer_id).unwrap().acked_bytes += piece_len;
+            self.uploaded_bytes += piece_len;
+        }
+
         let mut piece = self.piece_queues.remove(&piece_idx).unwrap();
         piece.num_confirmed_owners.0 += 1;
         if possible == FileStateSetStatus::JustSet {
             piece.num_possible_owners.0 += 1;
+        } else {
+            piece.send_attempts = piece.send_attempts.saturating_sub(1);
         }
         assert!(piece.num_confirmed_owners.0 <= piece.num_possible_owners.0);
 
-        insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
+        if piece.num_confirmed_owners.0 == self.shared_peers_order.len() {
+            // the piece is present on all remote peers and no longer needs to be shared
+            let _: FileStateSetStatus = self.confirmed_remote_state.set(&piece_idx).unwrap();
+            self.release_chunk_if_confirmed(&piece_idx);
+        } else {
+            insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
+        }
         Ok(SharedFileMarkStatus::JustMarked)
     }
 
@@ -457,6 +1434,20 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         &mut self,
         peer_id: &PeerId,
         piece_idx: FilePieceIdx,
+    ) -> Result<SharedFileMarkForResendStatus, SharedFileMarkError> {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::MarkForResendIfNotSent {
+            peer_id: *peer_id,
+            piece_idx,
+        });
+
+        self.mark_for_resend(peer_id, piece_idx)
+    }
+
+    fn mark_for_resend(
+        &mut self,
+        peer_id: &PeerId,
+        piece_idx: FilePieceIdx,
     ) -> Result<SharedFileMarkForResendStatus, SharedFileMarkError> {
         use crate::FileStateUnsetStatus;
 
@@ -472,12 +1463,27 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
         if possible == FileStateUnsetStatus::AlreadyUnset {
             return Ok(SharedFileMarkForResendStatus::AlreadyMarked);
         }
+        let peer = self.peers.get_mut(peer_id).unwrap();
+        peer.in_flight_bytes = peer
+            .in_flight_bytes
+            .saturating_sub(self.file.borrow().piece_len(&piece_idx) as u64);
         let mut piece = self.piece_queues.remove(&piece_idx).unwrap();
         piece.num_possible_owners.0 -= 1;
         insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
         Ok(SharedFileMarkForResendStatus::JustMarked)
     }
 
+    /// Returns `true` if all peers except blocked ones have received the local state.
+    pub fn is_local_state_received(&self) -> bool {
+        self.peers.values().all(|peer| {
+            peer.blocked
+                || matches!(
+                    peer.local_state_status,
+                    SharedFileLocalStateStatus::Received
+                )
+        })
+    }
+
     pub fn local_state_status(
         &mut self,
         peer_id: &PeerId,
@@ -501,6 +1507,373 @@ impl<C, T, const CHUNK_SIZE: usize> SharedFile<C, T, CHUNK_SIZE> {
 
         Ok(&mut peer.local_state_status)
     }
+
+    /// Returns the sequence number for the next local state message.
+    /// Returns the number of bytes sent to peers and acknowledged by them.
+    pub fn uploaded_bytes(&self) -> u64 {
+        self.uploaded_bytes
+    }
+
+    /// Returns the number of bytes of pieces added to the file, i.e. received from peers.
+    pub fn downloaded_bytes(&self) -> u64 {
+        self.downloaded_bytes
+    }
+
+    pub fn next_state_seq(&mut self) -> u64 {
+        let seq = self.next_state_seq;
+        self.next_state_seq += 1;
+        seq
+    }
+
+    /// Records the sequence number of a received peer state message.
+    ///
+    /// Messages with a sequence number lower than the last accepted one are outdated
+    /// and should be ignored, the same number is accepted for every chunk of a state.
+    /// Stops sending pieces and the local state to the peer
+    /// without removing its state, unlike `remove_peer`.
+    pub fn block_peer(&mut self, peer_id: &PeerId) -> Result<(), SharedFileBlockPeerError> {
+        self.set_peer_blocked(peer_id, true)
+    }
+
+    /// Resumes sending pieces and the local state to the blocked peer.
+    pub fn unblock_peer(&mut self, peer_id: &PeerId) -> Result<(), SharedFileBlockPeerError> {
+        self.set_peer_blocked(peer_id, false)
+    }
+
+    fn set_peer_blocked(
+        &mut self,
+        peer_id: &PeerId,
+        blocked: bool,
+    ) -> Result<(), SharedFileBlockPeerError> {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::SetPeerBlocked {
+            peer_id: *peer_id,
+            blocked,
+        });
+
+        let peer = self
+            .peers
+            .get_mut(peer_id)
+            .ok_or(SharedFileBlockPeerError::PeerIsNotAdded)?;
+        peer.blocked = blocked;
+        Ok(())
+    }
+
+    pub fn is_peer_blocked(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).is_some_and(|peer| peer.blocked)
+    }
+
+    /// Stops or resumes pushing pieces to the peer, pulling peers request pieces themselves.
+    pub fn set_peer_pulls_pieces(
+        &mut self,
+        peer_id: &PeerId,
+        pulls_pieces: bool,
+    ) -> Result<(), SharedFileBlockPeerError> {
+        let peer = self
+            .peers
+            .get_mut(peer_id)
+            .ok_or(SharedFileBlockPeerError::PeerIsNotAdded)?;
+        if peer.pulls_pieces != pulls_pieces {
+            #[cfg(feature = "selection-trace")]
+            self.record(|| SelectionEvent::SetPeerPullsPieces {
+                peer_id: *peer_id,
+                pulls_pieces,
+            });
+            self.peers.get_mut(peer_id).unwrap().pulls_pieces = pulls_pieces;
+        }
+        Ok(())
+    }
+
+    pub fn peer_pulls_pieces(&self, peer_id: &PeerId) -> bool {
+        self.peers
+            .get(peer_id)
+            .is_some_and(|peer| peer.pulls_pieces)
+    }
+
+    /// Queues the piece requested by the peer,
+    /// queued requests are served by `serve_piece_requests` within the upload budget.
+    ///
+    /// Requests of blocked peers are refused, repeated requests are queued once
+    /// and requests of pieces already sent to the peer are ignored until they are resent.
+    pub fn queue_piece_request(
+        &mut self,
+        peer_id: &PeerId,
+        piece_idx: FilePieceIdx,
+    ) -> Result<(), SharedFileServePieceRequestError>
+    where
+        C: FileChunk,
+    {
+        use crate::MAX_PENDING_PIECE_REQUESTS;
+
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::QueuePieceRequest {
+            peer_id: *peer_id,
+            piece_idx,
+        });
+
+        let piece_idx = check_piece_idx(piece_idx, self.num_pieces())
+            .ok_or(SharedFileServePieceRequestError::PieceIndexOutOfRange)?;
+        let peer = self
+            .peers
+            .get(peer_id)
+            .ok_or(SharedFileServePieceRequestError::PeerIsNotAdded)?;
+        if peer.blocked {
+            return Err(SharedFileServePieceRequestError::PeerIsBlocked);
+        }
+        {
+            let file = self.file.borrow();
+            if !file.has_piece(&piece_idx).unwrap() || file.is_piece_released(&piece_idx) {
+                return Err(SharedFileServePieceRequestError::PieceIsMissing { piece_idx });
+            }
+        }
+        let is_sent = peer
+            .state
+            .as_ref()
+            .is_some_and(|state| state.possible.has(&piece_idx).unwrap());
+        if is_sent || self.queued_piece_requests.contains(&(*peer_id, piece_idx)) {
+            return Ok(());
+        }
+        // Well-behaved peers never have more requests pending.
+        let num_peer_requests = self
+            .queued_piece_requests
+            .iter()
+            .filter(|(requested_peer_id, _)| requested_peer_id == peer_id)
+            .count();
+        if num_peer_requests >= MAX_PENDING_PIECE_REQUESTS {
+            return Err(SharedFileServePieceRequestError::TooManyRequests);
+        }
+        self.queued_piece_requests.push_back((*peer_id, piece_idx));
+        Ok(())
+    }
+
+    pub fn has_queued_piece_requests(&self) -> bool {
+        !self.queued_piece_requests.is_empty()
+    }
+
+    /// Reads up to `max_pieces` pieces of queued requests in the order they were received
+    /// and marks them as possibly owned by the requesting peers,
+    /// so that they are not pushed to the peers and their acknowledgements count as uploaded.
+    ///
+    /// Served pieces count against peer congestion windows and are resent the same way
+    /// as selected ones, requests of peers with full windows are kept queued.
+    pub fn serve_piece_requests(
+        &mut self,
+        max_pieces: usize,
+        time: T,
+    ) -> Vec<(PeerId, FilePieceIdx, Box<[u8]>)>
+    where
+        C: FileChunk,
+        T: Clone + Ord,
+    {
+        use crate::FileStateSetStatus;
+        use core::mem::take;
+
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::ServePieceRequests {
+            max_pieces,
+            time: time.clone(),
+        });
+
+        let mut served = Vec::new();
+        let mut requests = take(&mut self.queued_piece_requests);
+        while served.len() < max_pieces {
+            let (peer_id, piece_idx) = match requests.pop_front() {
+                Some(request) => request,
+                None => break,
+            };
+            let piece_len = self.file.borrow().piece_len(&piece_idx) as u64;
+            // Peers could have been removed or blocked since their requests were queued.
+            let peer = match self.peers.get_mut(&peer_id) {
+                Some(peer) if !peer.blocked => peer,
+                Some(_) | None => continue,
+            };
+            if !peer.has_window_for(piece_len) {
+                self.queued_piece_requests.push_back((peer_id, piece_idx));
+                continue;
+            }
+            let bytes = match self.read_piece(&piece_idx) {
+                Ok(Some(bytes)) => bytes,
+                Ok(None) | Err(_) => continue,
+            };
+
+            // Queued pieces are available locally and are not confirmed by some of the peers.
+            let peer = self.peers.get_mut(&peer_id).unwrap();
+            if let Some(state) = &mut peer.state {
+                if !state.confirmed.has(&piece_idx).unwrap()
+                    && state.possible.set(&piece_idx).unwrap() == FileStateSetStatus::JustSet
+                {
+                    peer.in_flight_bytes += piece_len;
+                    if let Ok(mut piece) = self.piece_queues.remove(&piece_idx) {
+                        piece.num_possible_owners.0 += 1;
+                        insert_piece(&mut self.piece_queues, &self.peers, piece_idx, piece);
+                    }
+                    self.sent_pieces
+                        .entry(time.clone())
+                        .or_default()
+                        .push((peer_id, piece_idx));
+                }
+            }
+            served.push((peer_id, piece_idx, bytes));
+        }
+        self.queued_piece_requests.extend(requests);
+        served
+    }
+
+    /// Selects pieces to be requested from `pull_peers` so that at most `max_pending_requests`
+    /// requests are pending, the rarest pieces first and from the least loaded owners.
+    ///
+    /// Pieces are requested only from peers that have announced them in their states.
+    pub fn select_piece_requests(
+        &mut self,
+        pull_peers: &HashSet<PeerId>,
+        max_pending_requests: usize,
+        time: T,
+    ) -> Vec<(PeerId, FilePieceIdx)>
+    where
+        T: Clone,
+    {
+        if self.piece_requests.len() >= max_pending_requests {
+            return Vec::new();
+        }
+
+        let num_copies = self.piece_num_copies();
+        let mut pieces: Vec<_> = self
+            .file
+            .borrow()
+            .state()
+            .raw()
+            .iter_zeros()
+            .filter(|&piece_idx| num_copies[piece_idx] > 0)
+            .map(FilePieceIdx)
+            .filter(|piece_idx| !self.piece_requests.contains_key(piece_idx))
+            .collect();
+        pieces.sort_by_key(|piece_idx| num_copies[piece_idx.0]);
+
+        let mut num_peer_requests: HashMap<PeerId, usize> = HashMap::new();
+        for (peer_id, _) in self.piece_requests.values() {
+            *num_peer_requests.entry(*peer_id).or_default() += 1;
+        }
+
+        let mut requests = Vec::new();
+        for piece_idx in pieces {
+            if self.piece_requests.len() >= max_pending_requests {
+                break;
+            }
+            let owner = pull_peers
+                .iter()
+                .filter(|peer_id| {
+                    self.peers
+                        .get(peer_id)
+                        .filter(|peer| !peer.unresponsive)
+                        .and_then(|peer| peer.state.as_ref())
+                        .is_some_and(|state| state.confirmed.has(&piece_idx).unwrap())
+                })
+                .min_by_key(|peer_id| {
+                    let num_requests = num_peer_requests.get(peer_id).copied().unwrap_or(0);
+                    (num_requests, peer_id.0)
+                });
+            if let Some(&peer_id) = owner {
+                *num_peer_requests.entry(peer_id).or_default() += 1;
+                let _: Option<_> = self
+                    .piece_requests
+                    .insert(piece_idx, (peer_id, time.clone()));
+                requests.push((peer_id, piece_idx));
+            }
+        }
+        requests
+    }
+
+    /// Drops piece requests sent before `time`, e.g. lost ones,
+    /// so that their pieces are requested again, possibly from other peers.
+    pub fn expire_piece_requests_before(&mut self, time: &T)
+    where
+        T: Ord,
+    {
+        self.piece_requests
+            .retain(|_, (_, requested_at)| &*requested_at >= time);
+    }
+
+    pub fn num_piece_requests(&self) -> usize {
+        self.piece_requests.len()
+    }
+
+    /// Records that the peer answered a liveness check, unresponsive peers become responsive.
+    pub fn mark_peer_seen(
+        &mut self,
+        peer_id: &PeerId,
+        time: T,
+    ) -> Result<(), SharedFileBlockPeerError>
+    where
+        T: Clone,
+    {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::MarkPeerSeen {
+            peer_id: *peer_id,
+            time: time.clone(),
+        });
+
+        let peer = self
+            .peers
+            .get_mut(peer_id)
+            .ok_or(SharedFileBlockPeerError::PeerIsNotAdded)?;
+        peer.last_seen = Some(time);
+        peer.unresponsive = false;
+        Ok(())
+    }
+
+    /// Marks peers last seen before `time` as unresponsive and returns newly marked ones.
+    ///
+    /// Peers that have never answered a liveness check may not support it,
+    /// so they are never marked as unresponsive.
+    pub fn mark_peers_unresponsive_before(&mut self, time: &T) -> Vec<PeerId>
+    where
+        T: Clone + Ord,
+    {
+        #[cfg(feature = "selection-trace")]
+        self.record(|| SelectionEvent::MarkPeersUnresponsiveBefore { time: time.clone() });
+
+        let mut peer_ids = Vec::new();
+        for (peer_id, peer) in &mut self.peers {
+            let is_outdated = peer
+                .last_seen
+                .as_ref()
+                .is_some_and(|last_seen| last_seen < time);
+            if is_outdated && !peer.unresponsive {
+                peer.unresponsive = true;
+                peer_ids.push(*peer_id);
+            }
+        }
+        peer_ids
+    }
+
+    pub fn is_peer_unresponsive(&self, peer_id: &PeerId) -> bool {
+        self.peers
+            .get(peer_id)
+            .is_some_and(|peer| peer.unresponsive)
+    }
+
+    pub fn peer_last_seen(&self, peer_id: &PeerId) -> Option<&T> {
+        self.peers
+            .get(peer_id)
+            .and_then(|peer| peer.last_seen.as_ref())
+    }
+
+    pub fn accept_peer_state_seq(
+        &mut self,
+        peer_id: &PeerId,
+        seq: u64,
+    ) -> Result<SharedFileStateSeqStatus, SharedFileAcceptStateSeqError> {
+        let peer = self
+            .peers
+            .get_mut(peer_id)
+            .ok_or(SharedFileAcceptStateSeqError::PeerIsNotAdded)?;
+
+        if matches!(peer.state_seq, Some(last_seq) if seq < last_seq) {
+            return Ok(SharedFileStateSeqStatus::Outdated);
+        }
+        peer.state_seq = Some(seq);
+        Ok(SharedFileStateSeqStatus::Accepted)
+    }
 }
 
 fn insert_piece<T>(
@@ -554,7 +1927,7 @@ fn mark_peer_state_with_piece_idx<'a, T, C>(
     num_pieces: usize,
 ) -> Result<(&'a mut SharedFilePeerState, C), SharedFileMarkError>
 where
-    C: Borrow<FilePieceIdx>,
+    C: core::borrow::Borrow<FilePieceIdx>,
 {
     let piece_idx =
         check_piece_idx(piece_idx, num_pieces).ok_or(SharedFileMarkError::PieceIndexOutOfRange)?;
@@ -573,7 +1946,7 @@ where
 
 fn check_piece_idx<C>(piece_idx: C, num_pieces: usize) -> Option<C>
 where
-    C: Borrow<FilePieceIdx>,
+    C: core::borrow::Borrow<FilePieceIdx>,
 {
     if piece_idx.borrow().0 < num_pieces {
         Some(piece_idx)
@@ -620,12 +1993,32 @@ pub enum SharedFileSetPeerStateError {
     PeerInvalidStateLen { peer_len: usize, local_len: usize },
 }
 
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SharedFileSetPeerStateChunkError {
+    #[error("peer is not added to SharedFile")]
+    PeerIsNotAdded,
+    #[error("peer state chunk offset: {offset}, expected: {expected}")]
+    UnexpectedChunkOffset { offset: usize, expected: usize },
+    #[error("peer state chunk end: {end}, exceeds peer state length: {num_pieces}")]
+    ChunkOutOfRange { end: usize, num_pieces: usize },
+    #[error("peer state length: {peer_len}, not matched local state length: {local_len}")]
+    PeerInvalidStateLen { peer_len: usize, local_len: usize },
+}
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SharedFileStateChunkStatus {
+    Partial,
+    Complete,
+}
+
 #[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
 pub enum SharedFileSelectPiecePeerError {
     #[error("piece index out of range")]
     PieceIndexOutOfRange,
     #[error("piece is already owned by all peers")]
     PieceIsAlreadyOwned,
+    #[error("piece is missing only on excluded peers")]
+    AllPeersAreExcluded,
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -657,37 +2050,103 @@ pub enum SharedFileAddLocalPieceError {
     SetPiece(#[from] FileSetPieceError),
     #[error("piece is already set")]
     PieceIsAlreadySet,
+    #[error("chunk {chunk_idx} hash mismatch, chunk pieces are dropped")]
+    ChunkHashMismatch { chunk_idx: usize },
 }
 
 #[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
-pub enum LocalStateStatusError {
+pub enum SharedFilePeerMissingPiecesError {
     #[error("peer is not added to SharedFile")]
     PeerIsNotAdded,
+    #[error("peer state is not added to SharedFile")]
+    PeerStateIsNotAdded,
 }
 
-#[test]
-fn send_shared_file_to_single_receiver() {
-    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
-    use bitvec::bits;
-    use bitvec::store::BitStore;
-    use tracker_protocol::FileSha256;
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SharedFilePrioritizePiecesError {
+    #[error("piece index out of range")]
+    PieceIndexOutOfRange,
+}
 
-    const NUM_PIECES: usize = 16;
-    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SharedFileInvalidatePiecesError {
+    #[error("piece index out of range")]
+    PieceIndexOutOfRange,
+    #[error("piece {} is missing", piece_idx.0)]
+    PieceIsMissing { piece_idx: FilePieceIdx },
+    #[error("piece {} is released", piece_idx.0)]
+    PieceIsReleased { piece_idx: FilePieceIdx },
+    #[error("piece {} is not owned by any peer", piece_idx.0)]
+    PieceIsNotOwnedByPeers { piece_idx: FilePieceIdx },
+}
 
-    let metadata = FileMetadata::new(
-        FileSha256(Default::default()),
-        "filename".to_owned(),
-        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
-    );
-    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
-    let mut shared_file = SharedFile::new(file);
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum LocalStateStatusError {
+    #[error("peer is not added to SharedFile")]
+    PeerIsNotAdded,
+}
 
-    assert_eq!(shared_file.file().state().raw(), bits![0; NUM_PIECES]);
-    assert_eq!(shared_file.file().state().num_missing(), NUM_PIECES);
-    assert_eq!(shared_file.file().state().num_available(), 0);
-    assert_eq!(shared_file.file().state().is_missing(), true);
-    assert_eq!(shared_file.file().state().is_complete(), false);
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum SharedFileStateSeqStatus {
+    Accepted,
+    Outdated,
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SharedFileAcceptStateSeqError {
+    #[error("peer is not added to SharedFile")]
+    PeerIsNotAdded,
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SharedFileNewError {
+    #[error("stored files can not be shared, their pieces are read asynchronously")]
+    FileIsStored,
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SharedFileBlockPeerError {
+    #[error("peer is not added to SharedFile")]
+    PeerIsNotAdded,
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum SharedFileServePieceRequestError {
+    #[error("peer is not added to SharedFile")]
+    PeerIsNotAdded,
+    #[error("piece index out of range")]
+    PieceIndexOutOfRange,
+    #[error("requested piece {} is missing", piece_idx.0)]
+    PieceIsMissing { piece_idx: FilePieceIdx },
+    #[error("peer is blocked, its piece requests are refused")]
+    PeerIsBlocked,
+    #[error("peer has too many piece requests queued")]
+    TooManyRequests,
+}
+
+#[test]
+fn send_shared_file_to_single_receiver() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use bitvec::bits;
+    use bitvec::store::BitStore;
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 16;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+    let mut shared_file = SharedFile::new(file).unwrap();
+
+    assert_eq!(shared_file.file().state().raw(), bits![0; NUM_PIECES]);
+    assert_eq!(shared_file.file().state().num_missing(), NUM_PIECES);
+    assert_eq!(shared_file.file().state().num_available(), 0);
+    assert_eq!(shared_file.file().state().is_missing(), true);
+    assert_eq!(shared_file.file().state().is_complete(), false);
     assert_eq!(shared_file.num_pieces(), NUM_PIECES);
     assert_eq!(shared_file.peer_ids().count(), 0);
     assert_eq!(shared_file.piece_queues().next_queue(), None);
@@ -800,7 +2259,7 @@ fn send_shared_file_to_multiple_receivers() {
         FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
     );
     let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
-    let mut shared_file: SharedFile<_, i32, CHUNK_LEN> = SharedFile::new(file);
+    let mut shared_file: SharedFile<_, i32, CHUNK_LEN> = SharedFile::new(file).unwrap();
 
     for j in 0..NUM_PIECES {
         shared_file
@@ -855,7 +2314,7 @@ fn send_shared_file_to_multiple_receivers() {
     assert_eq!(get_queue(&shared_file), &[0, 1, 2, 3]);
 
     let peer_id = shared_file.select_piece_peer(FilePieceIdx(1), 0).unwrap();
-    assert_eq!(peer_id, PeerId(5));
+    assert_eq!(peer_id, PeerId(2));
     assert_eq!(get_queue_num_owners(&shared_file), 0);
     assert_eq!(get_queue(&shared_file), &[0, 3, 2]);
 
@@ -880,7 +2339,7 @@ fn send_shared_file_to_multiple_receivers() {
     assert_eq!(get_queue(&shared_file), &[1, 0, 3]);
 
     let peer_id = shared_file.select_piece_peer(FilePieceIdx(1), 0).unwrap();
-    assert_eq!(peer_id, PeerId(1));
+    assert_eq!(peer_id, PeerId(7));
     assert_eq!(get_queue_num_owners(&shared_file), 1);
     assert_eq!(get_queue(&shared_file), &[3, 0]);
 
@@ -894,3 +2353,1501 @@ fn send_shared_file_to_multiple_receivers() {
     assert_eq!(get_queue_num_owners(&shared_file), 2);
     assert_eq!(get_queue(&shared_file), &[2, 1, 3, 0]);
 }
+
+#[test]
+fn set_peer_state_from_chunks() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use bitvec::bitvec;
+    use bitvec::order::Lsb0;
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 10;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, CHUNK_LEN> = SharedFile::new(file).unwrap();
+    shared_file.add_peer(PeerId(1)).unwrap();
+
+    let state = bitvec![1, 0, 1, 1, 0, 0, 1, 0, 1, 1];
+    assert_eq!(
+        shared_file.set_peer_state_chunk(PeerId(1), NUM_PIECES, 4, &state[4..8]),
+        Err(SharedFileSetPeerStateChunkError::UnexpectedChunkOffset {
+            offset: 4,
+            expected: 0
+        })
+    );
+    assert_eq!(
+        shared_file.set_peer_state_chunk(PeerId(1), NUM_PIECES, 0, &state[0..4]),
+        Ok(SharedFileStateChunkStatus::Partial)
+    );
+    assert_eq!(
+        shared_file.set_peer_state_chunk(PeerId(1), NUM_PIECES, 4, &state[4..8]),
+        Ok(SharedFileStateChunkStatus::Partial)
+    );
+    assert_eq!(
+        shared_file.num_peers_with_state(),
+        PieceNumPossibleOwners(0)
+    );
+    assert_eq!(
+        shared_file.set_peer_state_chunk(PeerId(1), NUM_PIECES, 8, &state[8..10]),
+        Ok(SharedFileStateChunkStatus::Complete)
+    );
+    assert_eq!(
+        shared_file.num_peers_with_state(),
+        PieceNumPossibleOwners(1)
+    );
+
+    assert_eq!(
+        shared_file.set_peer_state_chunk(PeerId(1), 4, 0, &state[0..8]),
+        Err(SharedFileSetPeerStateChunkError::ChunkOutOfRange {
+            end: 8,
+            num_pieces: 4
+        })
+    );
+    assert_eq!(
+        shared_file.set_peer_state_chunk(PeerId(1), 4, 0, &state[0..4]),
+        Err(SharedFileSetPeerStateChunkError::PeerInvalidStateLen {
+            peer_len: 4,
+            local_len: NUM_PIECES
+        })
+    );
+}
+
+#[test]
+fn drop_corrupted_chunk_pieces() {
+    use crate::{FileLen, FileMetadata, FILE_CHUNK_SIZE, FILE_PIECE_SIZE};
+    use sha2::{Digest, Sha256};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES_IN_CHUNK: usize = FILE_CHUNK_SIZE / FILE_PIECE_SIZE;
+    const NUM_PIECES: usize = NUM_PIECES_IN_CHUNK + 10;
+
+    let bytes: Vec<u8> = (0..NUM_PIECES * FILE_PIECE_SIZE)
+        .map(|j| (j % 251) as u8)
+        .collect();
+    let chunk_hashes = bytes
+        .chunks(FILE_CHUNK_SIZE)
+        .map(|chunk| FileSha256(Sha256::digest(chunk).into()))
+        .collect();
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen(bytes.len() as u64),
+    )
+    .with_chunk_hashes(chunk_hashes);
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+
+    shared_file.add_peer(PeerId(1)).unwrap();
+    *shared_file.local_state_status_mut(&PeerId(1)).unwrap() = SharedFileLocalStateStatus::Received;
+
+    let corrupted_piece = FilePieceIdx(3);
+    for (j, piece) in bytes.chunks(FILE_PIECE_SIZE).enumerate().rev() {
+        let piece_idx = FilePieceIdx(j);
+        let result = if piece_idx == corrupted_piece {
+            shared_file.add_local_piece(piece_idx, &vec![0; piece.len()])
+        } else {
+            shared_file.add_local_piece(piece_idx, piece)
+        };
+        if j == 0 {
+            assert_eq!(
+                result,
+                Err(SharedFileAddLocalPieceError::ChunkHashMismatch { chunk_idx: 0 })
+            );
+        } else {
+            assert_eq!(result, Ok(()));
+        }
+    }
+
+    // The second chunk is verified, all pieces of the first one are requested again.
+    assert_eq!(shared_file.file().state().num_available(), 10);
+    assert_eq!(
+        shared_file.take_recently_added_pieces().len(),
+        NUM_PIECES - NUM_PIECES_IN_CHUNK
+    );
+    assert_eq!(
+        shared_file.local_state_status(&PeerId(1)).unwrap(),
+        &SharedFileLocalStateStatus::NotSent
+    );
+
+    for (j, piece) in bytes.chunks(FILE_PIECE_SIZE).enumerate() {
+        if j < NUM_PIECES_IN_CHUNK {
+            shared_file.add_local_piece(FilePieceIdx(j), piece).unwrap();
+        }
+    }
+    assert!(shared_file.file().state().is_complete());
+}
+
+#[test]
+fn select_faster_peer_more_often() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use std::collections::VecDeque;
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 1000;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+    const NUM_CYCLES: usize = 50;
+    const NUM_PIECES_PER_CYCLE: usize = 10;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, usize, CHUNK_LEN> = SharedFile::new(file).unwrap();
+    for j in 0..NUM_PIECES {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+
+    // Peers acknowledge up to their capacity of pieces per cycle.
+    let peers = [(PeerId(1), 8), (PeerId(2), 2)];
+    let mut pending: HashMap<PeerId, VecDeque<FilePieceIdx>> = HashMap::new();
+    let mut num_selections: HashMap<PeerId, usize> = HashMap::new();
+    for (peer_id, _) in peers {
+        shared_file.add_peer(peer_id).unwrap();
+        shared_file.set_peer_file_missing(peer_id).unwrap();
+    }
+
+    for cycle in 0..NUM_CYCLES {
+        for _ in 0..NUM_PIECES_PER_CYCLE {
+            let piece_idx = shared_file.piece_queues().next_queue().unwrap().1[0];
+            let peer_id = shared_file.select_piece_peer(piece_idx, cycle).unwrap();
+            pending.entry(peer_id).or_default().push_back(piece_idx);
+            *num_selections.entry(peer_id).or_default() += 1;
+        }
+        for (peer_id, capacity) in peers {
+            let pending = pending.entry(peer_id).or_default();
+            for piece_idx in pending.drain(..capacity.min(pending.len())) {
+                let _: SharedFileMarkStatus = shared_file
+                    .mark_peer_piece_as_received_by_remote(&peer_id, piece_idx)
+                    .unwrap();
+            }
+        }
+        shared_file.update_peer_rates(Duration::from_secs(1));
+    }
+
+    let fast_rate = shared_file.peer_rate(&PeerId(1)).unwrap();
+    let slow_rate = shared_file.peer_rate(&PeerId(2)).unwrap();
+    assert!(fast_rate > 2.0 * slow_rate);
+    assert!(num_selections[&PeerId(1)] > 2 * num_selections[&PeerId(2)]);
+    assert!(num_selections[&PeerId(2)] >= NUM_CYCLES * NUM_PIECES_PER_CYCLE / 10);
+}
+
+#[test]
+fn grow_and_shrink_congestion_window() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 1000;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, usize, CHUNK_LEN> = SharedFile::new(file).unwrap();
+    for j in 0..NUM_PIECES {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    let peer_id = PeerId(1);
+    shared_file.add_peer(peer_id).unwrap();
+    shared_file.set_peer_file_missing(peer_id).unwrap();
+
+    let select_pieces = |shared_file: &mut SharedFile<_, _, CHUNK_LEN>, time| {
+        let mut pieces = Vec::new();
+        loop {
+            let piece_idx = shared_file.piece_queues().next_queue().unwrap().1[0];
+            match shared_file.select_piece_peer(piece_idx, time) {
+                Ok(_) => pieces.push(piece_idx),
+                Err(SharedFileSelectPiecePeerError::AllPeersAreExcluded) => return pieces,
+                Err(err) => panic!("{}", err),
+            }
+        }
+    };
+
+    // Pieces are not assigned once the window is full.
+    let pieces = select_pieces(&mut shared_file, 0);
+    assert_eq!(
+        pieces.len() as u64,
+        INITIAL_CONGESTION_WINDOW / FILE_PIECE_SIZE as u64
+    );
+    assert_eq!(
+        shared_file.peer_in_flight_bytes(&peer_id),
+        Some(INITIAL_CONGESTION_WINDOW)
+    );
+
+    for piece_idx in pieces {
+        let _: SharedFileMarkStatus = shared_file
+            .mark_peer_piece_as_received_by_remote(&peer_id, piece_idx)
+            .unwrap();
+    }
+    let grown_window = shared_file.peer_congestion_window(&peer_id).unwrap();
+    assert!(grown_window > INITIAL_CONGESTION_WINDOW);
+    assert_eq!(shared_file.peer_in_flight_bytes(&peer_id), Some(0));
+
+    // Pieces are not acknowledged and the window is halved once they are resent.
+    let pieces = select_pieces(&mut shared_file, 1);
+    assert_eq!(pieces.len() as u64, grown_window / FILE_PIECE_SIZE as u64);
+    shared_file.mark_pieces_for_resend_before(2).unwrap();
+    assert_eq!(
+        shared_file.peer_congestion_window(&peer_id),
+        Some(grown_window / 2)
+    );
+    assert_eq!(shared_file.peer_in_flight_bytes(&peer_id), Some(0));
+
+    for time in 2..10 {
+        let _ = select_pieces(&mut shared_file, time);
+        shared_file.mark_pieces_for_resend_before(time + 1).unwrap();
+    }
+    assert_eq!(
+        shared_file.peer_congestion_window(&peer_id),
+        Some(MIN_CONGESTION_WINDOW)
+    );
+}
+
+#[test]
+fn seed_half_complete_state() {
+    use crate::{
+        FileLen, FileMetadata, FileReplaceStateError, FileStateSetStatus, FileStateUnsetStatus,
+        FILE_CHUNK_SIZE, FILE_PIECE_SIZE,
+    };
+    use sha2::{Digest, Sha256};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES_IN_CHUNK: usize = FILE_CHUNK_SIZE / FILE_PIECE_SIZE;
+    const NUM_PIECES: usize = NUM_PIECES_IN_CHUNK * 2 + 10;
+
+    let bytes: Vec<u8> = (0..NUM_PIECES * FILE_PIECE_SIZE)
+        .map(|j| (j % 251) as u8)
+        .collect();
+    let chunk_hashes = bytes
+        .chunks(FILE_CHUNK_SIZE)
+        .map(|chunk| FileSha256(Sha256::digest(chunk).into()))
+        .collect();
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen(bytes.len() as u64),
+    )
+    .with_chunk_hashes(chunk_hashes);
+    let new_file = |bytes: &[u8]| {
+        let mut file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata.clone()).unwrap();
+        for (j, piece) in bytes.chunks(FILE_PIECE_SIZE).enumerate() {
+            let _: FileStateSetStatus = file.set_piece(&FilePieceIdx(j), piece).unwrap();
+        }
+        file
+    };
+
+    // The first chunk is complete, the second one is partially available.
+    let mut state = FileState::from_missing(NUM_PIECES);
+    for j in (0..NUM_PIECES_IN_CHUNK).chain(NUM_PIECES_IN_CHUNK..NUM_PIECES_IN_CHUNK + 5) {
+        let _: FileStateSetStatus = state.set(&FilePieceIdx(j)).unwrap();
+    }
+    let num_available = state.num_available();
+
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> =
+        SharedFile::with_state(new_file(&bytes), state.clone()).unwrap();
+    assert_eq!(shared_file.file().state(), &state);
+    assert_eq!(
+        shared_file
+            .file()
+            .has_piece(&FilePieceIdx(NUM_PIECES_IN_CHUNK + 5)),
+        Ok(false)
+    );
+
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file.set_peer_file_missing(PeerId(1)).unwrap();
+    assert_eq!(
+        shared_file.piece_queues().next_queue().unwrap().1.len(),
+        num_available
+    );
+
+    // Only complete chunks can be verified, so corrupted partial chunks are accepted.
+    let mut corrupted_bytes = bytes.clone();
+    corrupted_bytes[FILE_PIECE_SIZE * 3] ^= 1;
+    corrupted_bytes[FILE_CHUNK_SIZE + FILE_PIECE_SIZE * 7] ^= 1;
+    let result: Result<SharedFile<_, i32, FILE_CHUNK_SIZE>, _> =
+        SharedFile::with_state(new_file(&corrupted_bytes), state.clone());
+    assert_eq!(
+        result.err(),
+        Some(FileReplaceStateError::ChunkHashMismatch { chunk_idx: 0 })
+    );
+
+    let mut second_chunk_state = state.clone();
+    for j in 0..NUM_PIECES_IN_CHUNK {
+        let _: FileStateUnsetStatus = second_chunk_state.unset(&FilePieceIdx(j)).unwrap();
+    }
+    let result: Result<SharedFile<_, i32, FILE_CHUNK_SIZE>, _> =
+        SharedFile::with_state(new_file(&corrupted_bytes), second_chunk_state);
+    assert!(result.is_ok());
+
+    let result: Result<SharedFile<_, i32, FILE_CHUNK_SIZE>, _> =
+        SharedFile::with_state(new_file(&bytes), FileState::from_missing(NUM_PIECES - 1));
+    assert_eq!(
+        result.err(),
+        Some(FileReplaceStateError::InvalidStateLen {
+            len: NUM_PIECES - 1,
+            expected: NUM_PIECES
+        })
+    );
+}
+
+#[test]
+fn compute_file_availability() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 4;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, CHUNK_LEN> = SharedFile::new(file).unwrap();
+    assert_eq!(shared_file.availability(), 0.0);
+    assert_eq!(shared_file.min_availability(), 0);
+
+    for j in 0..2 {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    assert_eq!(shared_file.availability(), 0.5);
+
+    // Copies per piece: 3, 2, 1 and 0.
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file
+        .set_peer_state(PeerId(1), FileState::from(bitbox![1, 1, 0, 0]))
+        .unwrap();
+    shared_file.add_peer(PeerId(2)).unwrap();
+    shared_file
+        .set_peer_state(PeerId(2), FileState::from(bitbox![1, 0, 1, 0]))
+        .unwrap();
+    assert_eq!(shared_file.availability(), 1.5);
+    assert_eq!(shared_file.min_availability(), 0);
+
+    shared_file.add_peer(PeerId(3)).unwrap();
+    shared_file.set_peer_file_complete(PeerId(3)).unwrap();
+    assert_eq!(shared_file.availability(), 2.5);
+    assert_eq!(shared_file.min_availability(), 1);
+}
+
+#[test]
+fn downgrade_complete_peer_to_partial() {
+    use crate::{FileLen, FileMetadata, PieceNumConfirmedOwners, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 8;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+
+    let new_shared_file = || {
+        let metadata = FileMetadata::new(
+            FileSha256(Default::default()),
+            "filename".to_owned(),
+            FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+        );
+        let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+        let mut shared_file: SharedFile<_, i32, CHUNK_LEN> = SharedFile::new(file).unwrap();
+        for j in 0..NUM_PIECES {
+            shared_file
+                .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+                .unwrap();
+        }
+        shared_file
+    };
+    let queued_pieces = |shared_file: &SharedFile<_, i32, CHUNK_LEN>| {
+        let mut pieces: Vec<_> = shared_file
+            .piece_queues()
+            .iter_pieces()
+            .map(|(piece_idx, piece)| {
+                assert_eq!(piece.num_possible_owners.0, piece.num_confirmed_owners.0);
+                (piece_idx.0, piece.num_confirmed_owners)
+            })
+            .collect();
+        pieces.sort_unstable_by_key(|(piece_idx, _)| *piece_idx);
+        pieces
+    };
+    let partial_state = FileState::from_indices(NUM_PIECES, (0..4).map(FilePieceIdx)).unwrap();
+    let shrunk_state = FileState::from_indices(NUM_PIECES, [0, 5].map(FilePieceIdx)).unwrap();
+
+    let mut shared_file = new_shared_file();
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file.set_peer_file_complete(PeerId(1)).unwrap();
+    shared_file.add_peer(PeerId(2)).unwrap();
+    shared_file
+        .set_peer_state(PeerId(2), partial_state.clone())
+        .unwrap();
+    assert_eq!(shared_file.remote_state().raw(), partial_state.raw());
+    assert_eq!(
+        queued_pieces(&shared_file),
+        (4..NUM_PIECES)
+            .map(|j| (j, PieceNumConfirmedOwners(1)))
+            .collect::<Vec<_>>()
+    );
+
+    // The peer dropped pieces, e.g. after releasing chunks under memory pressure.
+    shared_file
+        .set_peer_state(PeerId(1), shrunk_state.clone())
+        .unwrap();
+    assert_eq!(
+        shared_file
+            .remote_state()
+            .raw()
+            .iter_ones()
+            .collect::<Vec<_>>(),
+        [0]
+    );
+    assert_eq!(
+        queued_pieces(&shared_file),
+        [(1, 1), (2, 1), (3, 1), (4, 0), (5, 1), (6, 0), (7, 0)]
+            .map(|(j, num_owners)| (j, PieceNumConfirmedOwners(num_owners)))
+    );
+    assert_eq!(
+        shared_file.ownership_matrix(),
+        [
+            (PeerId(1), shrunk_state.clone()),
+            (PeerId(2), partial_state.clone())
+        ]
+    );
+
+    // The shrunk state is indistinguishable from the same state received at once.
+    let mut expected_file = new_shared_file();
+    expected_file.add_peer(PeerId(2)).unwrap();
+    expected_file
+        .set_peer_state(PeerId(2), partial_state)
+        .unwrap();
+    expected_file.add_peer(PeerId(1)).unwrap();
+    expected_file
+        .set_peer_state(PeerId(1), shrunk_state)
+        .unwrap();
+    assert_eq!(queued_pieces(&shared_file), queued_pieces(&expected_file));
+    assert_eq!(
+        shared_file.remote_state().raw(),
+        expected_file.remote_state().raw()
+    );
+
+    // Upgrading the peer back to complete confirms the pieces the other peer has again.
+    shared_file.set_peer_file_complete(PeerId(1)).unwrap();
+    assert_eq!(shared_file.remote_state().raw().iter_ones().count(), 4);
+    assert_eq!(queued_pieces(&shared_file).len(), NUM_PIECES - 4);
+}
+
+#[test]
+fn release_confirmed_chunks() {
+    use crate::{FileGetPieceError, FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES_IN_CHUNK: usize = FILE_CHUNK_SIZE / FILE_PIECE_SIZE;
+    const NUM_PIECES: usize = NUM_PIECES_IN_CHUNK + 1;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file.set_release_confirmed_chunks(true);
+
+    for j in 0..NUM_PIECES {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    // Chunks are never released without peers to confirm them.
+    assert!(!shared_file.is_chunk_releasable(0));
+
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file.set_peer_file_missing(PeerId(1)).unwrap();
+    for j in 0..NUM_PIECES_IN_CHUNK - 1 {
+        let _: SharedFileMarkStatus = shared_file
+            .mark_peer_piece_as_received_by_remote(&PeerId(1), FilePieceIdx(j))
+            .unwrap();
+    }
+    assert!(!shared_file.file().is_chunk_released(0));
+
+    let _: SharedFileMarkStatus = shared_file
+        .mark_peer_piece_as_received_by_remote(&PeerId(1), FilePieceIdx(NUM_PIECES_IN_CHUNK - 1))
+        .unwrap();
+    assert!(shared_file.file().is_chunk_released(0));
+    assert!(!shared_file.file().is_chunk_released(1));
+    assert!(!shared_file.is_chunk_releasable(0));
+    assert!(!shared_file.is_chunk_releasable(1));
+    assert!(shared_file.file().state().is_complete());
+    assert_eq!(
+        shared_file.file().get_piece(&FilePieceIdx(0)),
+        Err(FileGetPieceError::ChunkIsReleased { chunk_idx: 0 })
+    );
+
+    // Released pieces are not queued for peers that join later.
+    shared_file.add_peer(PeerId(2)).unwrap();
+    shared_file.set_peer_file_missing(PeerId(2)).unwrap();
+    let queued: Vec<_> = shared_file
+        .piece_queues()
+        .next_queue()
+        .map(|(_, pieces)| pieces.to_vec())
+        .unwrap_or_default();
+    assert_eq!(queued, [FilePieceIdx(NUM_PIECES_IN_CHUNK)]);
+}
+
+#[test]
+fn keep_confirmed_chunks_by_default() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen(FILE_PIECE_SIZE as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file
+        .add_local_piece(FilePieceIdx(0), &[0; FILE_PIECE_SIZE])
+        .unwrap();
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file.set_peer_file_complete(PeerId(1)).unwrap();
+
+    assert!(shared_file.remote_state().is_complete());
+    assert!(!shared_file.is_chunk_releasable(0));
+    assert!(!shared_file.file().is_chunk_released(0));
+}
+
+#[test]
+fn list_peer_missing_pieces() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((6 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    for j in 0..6 {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+
+    shared_file.add_peer(PeerId(1)).unwrap();
+    assert_eq!(
+        shared_file.peer_missing_pieces(&PeerId(1)),
+        Err(SharedFilePeerMissingPiecesError::PeerStateIsNotAdded)
+    );
+    assert_eq!(
+        shared_file.peer_missing_pieces(&PeerId(2)),
+        Err(SharedFilePeerMissingPiecesError::PeerIsNotAdded)
+    );
+
+    shared_file
+        .set_peer_state(PeerId(1), FileState::from(bitbox![1, 0, 1, 0, 0, 1]))
+        .unwrap();
+    assert_eq!(
+        shared_file.peer_missing_pieces(&PeerId(1)),
+        Ok(vec![FilePieceIdx(1), FilePieceIdx(3), FilePieceIdx(4)])
+    );
+
+    // Sent pieces are no longer missing until they are marked for resend.
+    assert_eq!(
+        shared_file.select_piece_peer(FilePieceIdx(3), 0),
+        Ok(PeerId(1))
+    );
+    assert_eq!(
+        shared_file.peer_missing_pieces(&PeerId(1)),
+        Ok(vec![FilePieceIdx(1), FilePieceIdx(4)])
+    );
+}
+
+#[test]
+fn invalidate_pieces_and_receive_them_again() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 8;
+
+    let new_shared_file = || {
+        let metadata = FileMetadata::new(
+            FileSha256(Default::default()),
+            "filename".to_owned(),
+            FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+        );
+        let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+        let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+        for j in 0..NUM_PIECES {
+            shared_file
+                .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+                .unwrap();
+        }
+        let _ = shared_file.take_recently_added_pieces();
+        shared_file
+    };
+
+    let mut seeder = new_shared_file();
+    seeder.add_peer(PeerId(2)).unwrap();
+    seeder.set_peer_file_complete(PeerId(2)).unwrap();
+
+    let mut receiver = new_shared_file();
+    receiver.add_peer(PeerId(1)).unwrap();
+    *receiver.local_state_status_mut(&PeerId(1)).unwrap() = SharedFileLocalStateStatus::Received;
+
+    // Pieces that no peer can supply are not invalidated.
+    assert_eq!(
+        receiver.invalidate_pieces(&[FilePieceIdx(3)]),
+        Err(SharedFileInvalidatePiecesError::PieceIsNotOwnedByPeers {
+            piece_idx: FilePieceIdx(3)
+        })
+    );
+    assert!(receiver.file().state().is_complete());
+
+    receiver.set_peer_file_complete(PeerId(1)).unwrap();
+    assert_eq!(
+        receiver.invalidate_pieces(&[FilePieceIdx(NUM_PIECES)]),
+        Err(SharedFileInvalidatePiecesError::PieceIndexOutOfRange)
+    );
+    assert_eq!(receiver.invalidate_pieces(&[FilePieceIdx(3)]), Ok(()));
+    assert_eq!(receiver.file().has_piece(&FilePieceIdx(3)), Ok(false));
+    assert_eq!(
+        receiver.local_state_status(&PeerId(1)).unwrap(),
+        &SharedFileLocalStateStatus::NotSent
+    );
+    assert_eq!(
+        receiver.invalidate_pieces(&[FilePieceIdx(3)]),
+        Err(SharedFileInvalidatePiecesError::PieceIsMissing {
+            piece_idx: FilePieceIdx(3)
+        })
+    );
+
+    // The seeder offers the piece again once it receives the updated receiver state.
+    seeder
+        .set_peer_state(PeerId(2), receiver.file().state().clone())
+        .unwrap();
+    assert_eq!(seeder.select_piece_peer(FilePieceIdx(3), 0), Ok(PeerId(2)));
+
+    receiver
+        .add_local_piece(FilePieceIdx(3), &[0; FILE_PIECE_SIZE])
+        .unwrap();
+    assert!(receiver.file().state().is_complete());
+    assert_eq!(receiver.take_recently_added_pieces(), vec![FilePieceIdx(3)]);
+}
+
+#[cfg(feature = "selection-trace")]
+#[test]
+fn replay_selection_trace() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 40;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 4;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let new_file = || -> File<Box<[u8]>, CHUNK_LEN> { File::new(metadata.clone()).unwrap() };
+    let selection_state = |file: &SharedFile<Box<[u8]>, usize, CHUNK_LEN>| {
+        let peers: BTreeMap<_, _> = file
+            .peers
+            .iter()
+            .map(|(peer_id, peer)| {
+                let state = peer.state.as_ref().map(|state| {
+                    (
+                        state.peer_idx,
+                        state.confirmed.raw().to_bitvec(),
+                        state.possible.raw().to_bitvec(),
+                    )
+                });
+                (
+                    peer_id.0,
+                    (
+                        state,
+                        peer.acked_bytes,
+                        peer.rate,
+                        peer.credit,
+                        peer.in_flight_bytes,
+                        peer.congestion_window,
+                        peer.blocked,
+                    ),
+                )
+            })
+            .collect();
+        let pieces: Vec<_> = file
+            .piece_queues
+            .iter_pieces()
+            .map(|(piece_idx, piece)| {
+                (
+                    piece_idx,
+                    piece.peer_shift,
+                    piece.num_confirmed_owners,
+                    piece.num_possible_owners,
+                )
+            })
+            .collect();
+        (
+            file.file().state().raw().to_bitvec(),
+            file.confirmed_remote_state.raw().to_bitvec(),
+            peers,
+            file.shared_peers_order.clone(),
+            pieces,
+            file.sent_pieces.clone(),
+        )
+    };
+
+    let mut shared_file = SharedFile::new(new_file()).unwrap();
+    shared_file.start_trace();
+    for j in 0..NUM_PIECES {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    for peer_id in [PeerId(1), PeerId(2), PeerId(3)] {
+        shared_file.add_peer(peer_id).unwrap();
+        shared_file.set_peer_file_missing(peer_id).unwrap();
+    }
+    for cycle in 0..5 {
+        match cycle {
+            2 => shared_file.block_peer(&PeerId(2)).unwrap(),
+            4 => shared_file.unblock_peer(&PeerId(2)).unwrap(),
+            _ => {}
+        }
+        for _ in 0..6 {
+            let piece_idx = shared_file.piece_queues().next_queue().unwrap().1[0];
+            let peer_id = shared_file.select_piece_peer(piece_idx, cycle).unwrap();
+            if peer_id != PeerId(3) {
+                let _: SharedFileMarkStatus = shared_file
+                    .mark_peer_piece_as_received_by_remote(&peer_id, piece_idx)
+                    .unwrap();
+            }
+        }
+        shared_file.update_peer_rates(Duration::from_secs(1));
+        shared_file.mark_pieces_for_resend_before(cycle).unwrap();
+    }
+    shared_file.remove_peer(&PeerId(3)).unwrap();
+
+    let trace = shared_file.take_trace();
+    assert!(!trace.is_empty());
+    assert!(shared_file.take_trace().is_empty());
+
+    let replayed = SharedFile::replay(new_file(), &trace).unwrap();
+    assert_eq!(selection_state(&replayed), selection_state(&shared_file));
+}
+
+#[test]
+fn track_peer_send_blocked_time() {
+    use crate::{FileLen, FileMetadata, PeerConnectionSendError, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((4 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_PIECE_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, usize, FILE_PIECE_SIZE> = SharedFile::new(file).unwrap();
+    for peer_id in [PeerId(1), PeerId(2)] {
+        shared_file.add_peer(peer_id).unwrap();
+    }
+    assert_eq!(shared_file.send_blocked_peers().count(), 0);
+
+    let mut on_send_result =
+        |peer_id, result: Result<(), PeerConnectionSendError>, time| match result {
+            Ok(()) => shared_file.mark_peer_send_unblocked(&peer_id),
+            Err(PeerConnectionSendError::BufferIsFilled) => {
+                shared_file.mark_peer_send_blocked(&peer_id, time);
+            }
+            Err(
+                PeerConnectionSendError::PieceKeyIsNotConfirmed
+                | PeerConnectionSendError::PeerError(_),
+            ) => {}
+        };
+    on_send_result(PeerId(1), Err(PeerConnectionSendError::BufferIsFilled), 10);
+    on_send_result(PeerId(2), Ok(()), 10);
+    on_send_result(PeerId(1), Err(PeerConnectionSendError::BufferIsFilled), 20);
+    on_send_result(PeerId(3), Err(PeerConnectionSendError::BufferIsFilled), 20);
+
+    // The first blocked time is kept while sends keep failing.
+    assert_eq!(shared_file.peer_send_blocked_since(&PeerId(1)), Some(&10));
+    assert_eq!(shared_file.peer_send_blocked_since(&PeerId(2)), None);
+    assert_eq!(shared_file.peer_send_blocked_since(&PeerId(3)), None);
+    assert_eq!(
+        shared_file.send_blocked_peers().collect::<Vec<_>>(),
+        vec![(&PeerId(1), &10)]
+    );
+
+    shared_file.mark_peer_send_unblocked(&PeerId(1));
+    assert_eq!(shared_file.peer_send_blocked_since(&PeerId(1)), None);
+    assert_eq!(shared_file.send_blocked_peers().count(), 0);
+}
+
+#[test]
+fn skip_blocked_peer() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 64;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_PIECE_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, usize, FILE_PIECE_SIZE> = SharedFile::new(file).unwrap();
+    for j in 0..NUM_PIECES {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    for peer_id in [PeerId(1), PeerId(2), PeerId(3)] {
+        shared_file.add_peer(peer_id).unwrap();
+        shared_file.set_peer_file_missing(peer_id).unwrap();
+        *shared_file.local_state_status_mut(&peer_id).unwrap() =
+            SharedFileLocalStateStatus::Received;
+    }
+    assert_eq!(
+        shared_file.block_peer(&PeerId(4)),
+        Err(SharedFileBlockPeerError::PeerIsNotAdded)
+    );
+    shared_file.block_peer(&PeerId(2)).unwrap();
+    assert!(shared_file.is_peer_blocked(&PeerId(2)));
+
+    // The local state is not expected to be received by the blocked peer.
+    *shared_file.local_state_status_mut(&PeerId(2)).unwrap() = SharedFileLocalStateStatus::NotSent;
+    assert!(shared_file.is_local_state_received());
+
+    let mut selected = HashSet::new();
+    for j in 0..NUM_PIECES {
+        let peer_id = shared_file.select_piece_peer(FilePieceIdx(j), 0).unwrap();
+        assert_ne!(peer_id, PeerId(2));
+        let _: bool = selected.insert(peer_id);
+    }
+    assert_eq!(selected, HashSet::from([PeerId(1), PeerId(3)]));
+
+    // The blocked peer keeps its state and is selected again once unblocked.
+    assert_eq!(shared_file.peer_ids().count(), 3);
+    shared_file.unblock_peer(&PeerId(2)).unwrap();
+    assert!(!shared_file.is_peer_blocked(&PeerId(2)));
+    assert!(!shared_file.is_local_state_received());
+    let piece_idx = shared_file.piece_queues().next_queue().unwrap().1[0];
+    let excluded_peers = HashSet::from([PeerId(1), PeerId(3)]);
+    assert_eq!(
+        shared_file.select_piece_peer_excluding(piece_idx, 1, &excluded_peers),
+        Ok(PeerId(2))
+    );
+}
+
+#[test]
+fn cache_read_pieces_until_invalidated() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((4 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    for j in 0..4 {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[j as u8; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file.set_peer_file_complete(PeerId(1)).unwrap();
+
+    for _ in 0..3 {
+        assert_eq!(
+            shared_file.read_piece(&FilePieceIdx(2)).unwrap().as_deref(),
+            Some(&[2; FILE_PIECE_SIZE][..])
+        );
+    }
+    assert_eq!(shared_file.piece_cache().num_misses(), 1);
+    assert_eq!(shared_file.piece_cache().num_hits(), 2);
+
+    // Only two pieces fit into the budget.
+    shared_file.set_piece_cache_max_bytes(2 * FILE_PIECE_SIZE);
+    for j in 0..3 {
+        let _: Option<Box<[u8]>> = shared_file.read_piece(&FilePieceIdx(j)).unwrap();
+    }
+    assert!(shared_file.piece_cache().num_bytes() <= 2 * FILE_PIECE_SIZE);
+    assert!(!shared_file.piece_cache().contains(&FilePieceIdx(0)));
+    assert!(shared_file.piece_cache().contains(&FilePieceIdx(2)));
+
+    assert_eq!(shared_file.invalidate_pieces(&[FilePieceIdx(2)]), Ok(()));
+    assert!(!shared_file.piece_cache().contains(&FilePieceIdx(2)));
+    assert_eq!(shared_file.read_piece(&FilePieceIdx(2)), Ok(None));
+}
+
+#[test]
+fn spread_pieces_evenly_between_peers() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 700;
+    const NUM_PEERS: u32 = 7;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    for j in 0..NUM_PIECES {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    for j in 1..=NUM_PEERS {
+        shared_file.add_peer(PeerId(j)).unwrap();
+        shared_file.set_peer_file_missing(PeerId(j)).unwrap();
+    }
+
+    // Each sending cycle assigns at most one piece to a peer, like `max_pieces_per_peer` does,
+    // and starts piece peer orders from the next peer.
+    let mut num_selections: HashMap<PeerId, usize> = HashMap::new();
+    for cycle in 0..NUM_PIECES / NUM_PEERS as usize {
+        let mut assigned = HashSet::new();
+        for j in 0..NUM_PEERS as usize {
+            let piece_idx = FilePieceIdx(cycle * NUM_PEERS as usize + j);
+            let peer_id = shared_file
+                .select_piece_peer_excluding(piece_idx, 0, &assigned)
+                .unwrap();
+            let _: bool = assigned.insert(peer_id);
+            *num_selections.entry(peer_id).or_default() += 1;
+        }
+        shared_file.advance_peer_cursor();
+    }
+
+    let mean = NUM_PIECES as f64 / f64::from(NUM_PEERS);
+    let variance = (1..=NUM_PEERS)
+        .map(|j| {
+            let num_selections = num_selections.get(&PeerId(j)).copied().unwrap_or(0);
+            (num_selections as f64 - mean).powi(2)
+        })
+        .sum::<f64>()
+        / f64::from(NUM_PEERS);
+    assert!(variance < 1.0, "variance {} is too large", variance);
+}
+
+#[test]
+fn rotate_piece_peer_order_start_per_cycle() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PEERS: u32 = 5;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen(FILE_PIECE_SIZE as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut first: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    first.set_verify_chunks(false);
+    first
+        .add_local_piece(FilePieceIdx(0), &[0; FILE_PIECE_SIZE])
+        .unwrap();
+    let mut second: SharedFile<_, i32, FILE_CHUNK_SIZE> =
+        SharedFile::from_shared(Rc::clone(first.shared_file())).unwrap();
+    for j in 1..=NUM_PEERS {
+        for shared_file in [&mut first, &mut second] {
+            shared_file.add_peer(PeerId(j)).unwrap();
+            shared_file.set_peer_file_missing(PeerId(j)).unwrap();
+        }
+    }
+
+    // The same piece starts from the next peer in the next cycle.
+    second.advance_peer_cursor();
+    let first_peer_id = first.select_piece_peer(FilePieceIdx(0), 0).unwrap();
+    let second_peer_id = second.select_piece_peer(FilePieceIdx(0), 0).unwrap();
+    assert_eq!(second_peer_id, PeerId(first_peer_id.0 % NUM_PEERS + 1));
+
+    // The cursor is not advanced by selections within a cycle.
+    let first_peer_id = first.select_piece_peer(FilePieceIdx(0), 0).unwrap();
+    let second_peer_id = second.select_piece_peer(FilePieceIdx(0), 0).unwrap();
+    assert_eq!(second_peer_id, PeerId(first_peer_id.0 % NUM_PEERS + 1));
+}
+
+#[test]
+fn export_ownership_matrix() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((4 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    assert_eq!(shared_file.ownership_matrix(), []);
+
+    let states = [
+        (PeerId(3), FileState::from(bitbox![0, 1, 1, 0])),
+        (PeerId(1), FileState::from(bitbox![1, 1, 0, 0])),
+        (PeerId(2), FileState::from(bitbox![0, 0, 0, 1])),
+    ];
+    for (peer_id, state) in &states {
+        shared_file.add_peer(*peer_id).unwrap();
+        shared_file.set_peer_state(*peer_id, state.clone()).unwrap();
+    }
+    // Peers without known states are not exported.
+    shared_file.add_peer(PeerId(4)).unwrap();
+
+    assert_eq!(
+        shared_file.ownership_matrix(),
+        [states[1].clone(), states[2].clone(), states[0].clone()]
+    );
+}
+
+#[test]
+fn select_prioritized_pieces_first() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use bitvec::bitbox;
+    use bitvec::order::Lsb0;
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((8 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    for j in 0..8 {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    shared_file.add_peer(PeerId(1)).unwrap();
+    shared_file.set_peer_file_missing(PeerId(1)).unwrap();
+    shared_file.add_peer(PeerId(2)).unwrap();
+    shared_file
+        .set_peer_state(PeerId(2), FileState::from(bitbox![1, 0, 0, 0, 0, 0, 0, 1]))
+        .unwrap();
+
+    // The first and the last pieces are not the rarest ones.
+    let (num_possible_owners, pieces) = shared_file.next_pieces().unwrap();
+    assert_eq!(num_possible_owners, PieceNumPossibleOwners(0));
+    assert!(!pieces.contains(&FilePieceIdx(0)));
+    assert!(!pieces.contains(&FilePieceIdx(7)));
+
+    assert_eq!(
+        shared_file.prioritize_pieces(&[FilePieceIdx(8)]),
+        Err(SharedFilePrioritizePiecesError::PieceIndexOutOfRange)
+    );
+    shared_file
+        .prioritize_pieces(&[FilePieceIdx(7), FilePieceIdx(0), FilePieceIdx(7)])
+        .unwrap();
+    assert_eq!(
+        shared_file.prioritized_pieces(),
+        [FilePieceIdx(7), FilePieceIdx(0)]
+    );
+
+    for piece_idx in [FilePieceIdx(7), FilePieceIdx(0)] {
+        let (_, pieces) = shared_file.next_pieces().unwrap();
+        assert_eq!(pieces[0], piece_idx);
+        assert_eq!(shared_file.select_piece_peer(piece_idx, 0), Ok(PeerId(1)));
+    }
+
+    // Priorities are cleared once pieces are selected for all peers.
+    assert_eq!(shared_file.prioritized_pieces(), []);
+    assert_eq!(
+        shared_file.next_pieces().unwrap().1.as_ref(),
+        shared_file.piece_queues().next_queue().unwrap().1
+    );
+}
+
+#[test]
+fn estimate_completion_from_recent_arrivals() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    let secs = Duration::from_secs;
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((100 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, Duration, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    assert_eq!(shared_file.estimated_completion(secs(0)), None);
+
+    // Ten pieces arrive every second.
+    for j in 0..40 {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+        if j % 10 == 9 {
+            shared_file.stamp_piece_arrivals(secs(j as u64 / 10 + 1));
+        }
+    }
+    // Nothing arrived since the last stamp.
+    shared_file.stamp_piece_arrivals(secs(5));
+
+    // Sixty missing pieces take six more seconds.
+    assert_eq!(shared_file.estimated_completion(secs(4)), Some(secs(10)));
+
+    // No recent progress.
+    assert_eq!(
+        shared_file.estimated_completion(secs(4) + ETA_STALL_TIMEOUT + secs(1)),
+        None
+    );
+}
+
+#[test]
+fn track_piece_send_attempts() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((4 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    for j in 0..4 {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[0; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    for peer_id in [PeerId(1), PeerId(2)] {
+        shared_file.add_peer(peer_id).unwrap();
+        shared_file.set_peer_file_missing(peer_id).unwrap();
+    }
+    let piece_idx = FilePieceIdx(0);
+    let send_attempts = |shared_file: &SharedFile<_, _, FILE_CHUNK_SIZE>| {
+        shared_file
+            .piece_queues()
+            .get(piece_idx)
+            .unwrap()
+            .send_attempts
+    };
+
+    let first_peer = shared_file.select_piece_peer(piece_idx, 0).unwrap();
+    let second_peer = shared_file.select_piece_peer(piece_idx, 0).unwrap();
+    assert_ne!(first_peer, second_peer);
+    assert_eq!(send_attempts(&shared_file), 2);
+
+    let _: SharedFileMarkStatus = shared_file
+        .mark_peer_piece_as_received_by_remote(&first_peer, piece_idx)
+        .unwrap();
+    assert_eq!(send_attempts(&shared_file), 1);
+
+    // Resent pieces keep their previous attempts.
+    for _ in 0..3 {
+        let _: SharedFileMarkForResendStatus = shared_file
+            .mark_for_resend_if_not_sent(&second_peer, piece_idx)
+            .unwrap();
+        assert_eq!(shared_file.select_piece_peer(piece_idx, 0), Ok(second_peer));
+    }
+    assert_eq!(send_attempts(&shared_file), 4);
+    assert_eq!(
+        shared_file.excessively_sent_pieces(3).collect::<Vec<_>>(),
+        [piece_idx]
+    );
+    assert_eq!(shared_file.excessively_sent_pieces(4).count(), 0);
+
+    // Pieces confirmed by all peers are no longer queued.
+    let _: SharedFileMarkStatus = shared_file
+        .mark_peer_piece_as_received_by_remote(&second_peer, piece_idx)
+        .unwrap();
+    assert_eq!(shared_file.excessively_sent_pieces(0).count(), 0);
+}
+
+#[test]
+fn share_file_between_shared_files() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((2 * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> = File::new(metadata).unwrap();
+    let mut first: SharedFile<_, i32, FILE_CHUNK_SIZE> = SharedFile::new(file).unwrap();
+    let mut second: SharedFile<_, i32, FILE_CHUNK_SIZE> =
+        SharedFile::from_shared(Rc::clone(first.shared_file())).unwrap();
+    first.set_verify_chunks(false);
+    second.set_verify_chunks(false);
+    assert!(Rc::ptr_eq(first.shared_file(), second.shared_file()));
+
+    first
+        .add_local_piece(FilePieceIdx(0), &[1; FILE_PIECE_SIZE])
+        .unwrap();
+    assert_eq!(
+        second.read_piece(&FilePieceIdx(0)),
+        Ok(Some([1; FILE_PIECE_SIZE].into()))
+    );
+    assert_eq!(
+        second.add_local_piece(FilePieceIdx(0), &[1; FILE_PIECE_SIZE]),
+        Err(SharedFileAddLocalPieceError::PieceIsAlreadySet)
+    );
+
+    // Peers and piece queues are tracked separately.
+    second.add_peer(PeerId(1)).unwrap();
+    second.set_peer_file_missing(PeerId(1)).unwrap();
+    assert_eq!(first.num_peers_with_state(), PieceNumPossibleOwners(0));
+    assert_eq!(
+        second.peer_missing_pieces(&PeerId(1)),
+        Ok(vec![FilePieceIdx(0)])
+    );
+
+    // Chunks confirmed by peers of one shared file are kept for peers of the other one.
+    first.set_release_confirmed_chunks(true);
+    first.add_peer(PeerId(2)).unwrap();
+    first.set_peer_file_complete(PeerId(2)).unwrap();
+    first
+        .add_local_piece(FilePieceIdx(1), &[1; FILE_PIECE_SIZE])
+        .unwrap();
+    assert!(!first.is_chunk_releasable(0));
+    assert!(!first.file().is_chunk_released(0));
+
+    drop(second);
+    assert!(first.is_chunk_releasable(0));
+}
+
+#[test]
+fn select_rarest_piece_requests_first() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 6;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, CHUNK_LEN> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    shared_file
+        .add_local_piece(FilePieceIdx(0), &[0; FILE_PIECE_SIZE])
+        .unwrap();
+
+    // Piece 5 is owned by a single peer, pieces 3 and 4 by two, pieces 1 and 2 by all three.
+    let states = [
+        [1, 2, 3, 4, 5].as_slice(),
+        [1, 2, 3, 4].as_slice(),
+        [1, 2].as_slice(),
+    ];
+    for (j, pieces) in states.into_iter().enumerate() {
+        let peer_id = PeerId(j as u32 + 1);
+        shared_file.add_peer(peer_id).unwrap();
+        let state =
+            FileState::from_indices(NUM_PIECES, pieces.iter().copied().map(FilePieceIdx)).unwrap();
+        shared_file.set_peer_state(peer_id, state).unwrap();
+    }
+
+    // Peer 3 does not pull pieces, requests are spread over the least loaded owners.
+    let pull_peers: HashSet<_> = [PeerId(1), PeerId(2)].into_iter().collect();
+    assert_eq!(
+        shared_file.select_piece_requests(&pull_peers, 3, 0),
+        [
+            (PeerId(1), FilePieceIdx(5)),
+            (PeerId(2), FilePieceIdx(3)),
+            (PeerId(1), FilePieceIdx(4)),
+        ]
+    );
+    assert_eq!(shared_file.select_piece_requests(&pull_peers, 3, 1), []);
+    assert_eq!(
+        shared_file.select_piece_requests(&pull_peers, 10, 2),
+        [(PeerId(2), FilePieceIdx(1)), (PeerId(1), FilePieceIdx(2))]
+    );
+    assert_eq!(shared_file.num_piece_requests(), 5);
+
+    // Received and expired requests are dropped, pieces of expired ones are requested again.
+    shared_file
+        .add_local_piece(FilePieceIdx(5), &[0; FILE_PIECE_SIZE])
+        .unwrap();
+    shared_file.expire_piece_requests_before(&1);
+    assert_eq!(shared_file.num_piece_requests(), 2);
+    assert_eq!(
+        shared_file.select_piece_requests(&pull_peers, 10, 3),
+        [(PeerId(1), FilePieceIdx(3)), (PeerId(2), FilePieceIdx(4))]
+    );
+
+    shared_file.remove_peer(&PeerId(1)).unwrap();
+    assert_eq!(shared_file.num_piece_requests(), 2);
+}
+
+#[test]
+fn serve_queued_piece_requests_within_budget() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const NUM_PIECES: usize = 6;
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen((NUM_PIECES * FILE_PIECE_SIZE) as u64),
+    );
+    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, CHUNK_LEN> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    for j in 0..NUM_PIECES - 1 {
+        shared_file
+            .add_local_piece(FilePieceIdx(j), &[j as u8; FILE_PIECE_SIZE])
+            .unwrap();
+    }
+    for peer_id in [PeerId(1), PeerId(2)] {
+        shared_file.add_peer(peer_id).unwrap();
+        shared_file.set_peer_file_missing(peer_id).unwrap();
+        shared_file.set_peer_pulls_pieces(&peer_id, true).unwrap();
+    }
+
+    for piece_idx in [0, 1, 2, 1] {
+        shared_file
+            .queue_piece_request(&PeerId(1), FilePieceIdx(piece_idx))
+            .unwrap();
+    }
+    shared_file
+        .queue_piece_request(&PeerId(2), FilePieceIdx(3))
+        .unwrap();
+    assert_eq!(
+        shared_file.queue_piece_request(&PeerId(2), FilePieceIdx(5)),
+        Err(SharedFileServePieceRequestError::PieceIsMissing {
+            piece_idx: FilePieceIdx(5)
+        })
+    );
+
+    // Requests are served in order within the budget, repeated ones are served once.
+    let served = |shared_file: &mut SharedFile<_, _, CHUNK_LEN>, max_pieces, time| {
+        shared_file
+            .serve_piece_requests(max_pieces, time)
+            .into_iter()
+            .map(|(peer_id, piece_idx, bytes)| {
+                assert_eq!(bytes[0], piece_idx.0 as u8);
+                (peer_id, piece_idx)
+            })
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        served(&mut shared_file, 2, 0),
+        [(PeerId(1), FilePieceIdx(0)), (PeerId(1), FilePieceIdx(1))]
+    );
+    assert_eq!(
+        shared_file.peer_in_flight_bytes(&PeerId(1)),
+        Some(2 * FILE_PIECE_SIZE as u64)
+    );
+
+    // Requests of sent pieces are ignored until the pieces are resent.
+    shared_file
+        .queue_piece_request(&PeerId(1), FilePieceIdx(0))
+        .unwrap();
+
+    // Blocked peers can not request pieces and their queued requests are dropped.
+    shared_file.block_peer(&PeerId(1)).unwrap();
+    assert_eq!(
+        shared_file.queue_piece_request(&PeerId(1), FilePieceIdx(4)),
+        Err(SharedFileServePieceRequestError::PeerIsBlocked)
+    );
+    assert_eq!(
+        served(&mut shared_file, 10, 1),
+        [(PeerId(2), FilePieceIdx(3))]
+    );
+    assert!(!shared_file.has_queued_piece_requests());
+
+    // Lost pieces are served again once they are marked for resend.
+    shared_file.unblock_peer(&PeerId(1)).unwrap();
+    shared_file.mark_pieces_for_resend_before(1).unwrap();
+    shared_file
+        .queue_piece_request(&PeerId(1), FilePieceIdx(0))
+        .unwrap();
+    assert_eq!(
+        served(&mut shared_file, 10, 2),
+        [(PeerId(1), FilePieceIdx(0))]
+    );
+}
+
+#[test]
+fn count_bytes_remaining_with_short_last_piece() {
+    use crate::{FileLen, FileMetadata, FILE_PIECE_SIZE};
+    use tracker_protocol::FileSha256;
+
+    const CHUNK_LEN: usize = FILE_PIECE_SIZE * 2;
+    const LAST_PIECE_LEN: usize = FILE_PIECE_SIZE / 3;
+    const FILE_LEN: u64 = (2 * FILE_PIECE_SIZE + LAST_PIECE_LEN) as u64;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen(FILE_LEN),
+    );
+    let file: File<Box<[u8]>, CHUNK_LEN> = File::new(metadata).unwrap();
+    let mut shared_file: SharedFile<_, i32, CHUNK_LEN> = SharedFile::new(file).unwrap();
+    shared_file.set_verify_chunks(false);
+    assert_eq!(shared_file.bytes_remaining(), FILE_LEN);
+    assert_eq!(shared_file.bytes_available(), 0);
+
+    shared_file
+        .add_local_piece(FilePieceIdx(2), &[0; LAST_PIECE_LEN])
+        .unwrap();
+    assert_eq!(shared_file.bytes_remaining(), 2 * FILE_PIECE_SIZE as u64);
+    assert_eq!(shared_file.bytes_available(), LAST_PIECE_LEN as u64);
+
+    shared_file
+        .add_local_piece(FilePieceIdx(0), &[0; FILE_PIECE_SIZE])
+        .unwrap();
+    shared_file
+        .add_local_piece(FilePieceIdx(1), &[0; FILE_PIECE_SIZE])
+        .unwrap();
+    assert_eq!(shared_file.bytes_remaining(), 0);
+    assert_eq!(shared_file.bytes_available(), FILE_LEN);
+}
+
+#[test]
+fn refuse_stored_file() {
+    use crate::file_storage::NativeFileStorage;
+    use crate::{FileLen, FileMetadata};
+    use tracker_protocol::FileSha256;
+
+    let metadata = FileMetadata::new(
+        FileSha256(Default::default()),
+        "filename".to_owned(),
+        FileLen(FILE_PIECE_SIZE as u64),
+    );
+    let storage = NativeFileStorage::temp("refuse-stored-shared-file");
+    let file: File<Box<[u8]>, FILE_CHUNK_SIZE> =
+        File::new_with_backend(metadata, Box::new(storage)).unwrap();
+    assert_eq!(
+        SharedFile::<_, u32, FILE_CHUNK_SIZE>::new(file).err(),
+        Some(SharedFileNewError::FileIsStored)
+    );
+}
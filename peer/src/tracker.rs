@@ -40,8 +40,9 @@ impl Tracker {
         use wasm_bindgen::JsCast;
 
         let closure = Closure::with_callback(move |ev| {
-            let message = Self::parse(&ev);
-            callback(message)
+            if let Some(message) = Self::parse(&ev) {
+                callback(message);
+            }
         });
         self.websocket
             .set_onmessage(Some(closure.as_ref().unchecked_ref()));
@@ -55,16 +56,19 @@ impl Tracker {
         self.websocket.send_with_u8_array(&request).unwrap();
     }
 
-    fn parse(message: &MessageEvent) -> TrackerPeerMessage {
+    // Messages unknown to this protocol version are logged and skipped.
+    fn parse(message: &MessageEvent) -> Option<TrackerPeerMessage> {
         use bincode::deserialize;
         use js_sys::{ArrayBuffer, Uint8Array};
         use wasm_bindgen::JsCast;
 
+        use crate::OkOrLog;
+
         let array_buffer: ArrayBuffer = message.data().dyn_into().unwrap();
         let data = Uint8Array::new(&array_buffer).to_vec();
-        let message = deserialize(&data).unwrap();
+        let message = deserialize(&data).ok_or_log()?;
         log::debug!("{:?}", message);
-        message
+        Some(message)
     }
 }
 
@@ -0,0 +1,81 @@
+use core::fmt::Debug;
+
+use tracker_protocol::{FileSha256, PeerId, PeerTrackerMessage, TrackerPeerMessage};
+
+use crate::{
+    FilePieceIdx, PeerConnectionSendError, PeerError, PeerPeerMessage, PieceTransfer, RemotePeer,
+    Tracker,
+};
+
+/// Peer-to-peer message channel to a single remote peer.
+pub trait PeerTransport {
+    fn peer_id(&self) -> PeerId;
+    fn is_ready(&self) -> bool;
+    fn send(&self, message: PeerPeerMessage) -> Result<(), PeerError>;
+    /// Sends file pieces batched, compressed or encrypted the way the remote peer expects them.
+    ///
+    /// If `max_buffer_bytes` is set, remaining pieces are not sent once the send buffer is filled.
+    fn send_file_pieces(
+        &self,
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+        compress: bool,
+        max_buffer_bytes: Option<u64>,
+    ) -> Result<(), PeerConnectionSendError>;
+    /// Returns piece transfer modes negotiated with the remote peer.
+    fn piece_transfer(&self) -> PieceTransfer;
+    /// Returns `true` if `PeerPeerMessage::Pong` was received since the previous call.
+    fn take_pong(&self) -> bool;
+}
+
+/// Peer-to-tracker message channel.
+///
+/// `Tracker` uses a WebSocket, other implementations allow signaling
+/// where WebSockets are blocked, e.g. via HTTP long polling or a `postMessage` bridge.
+pub trait TrackerTransport: Debug {
+    fn send(&self, message: PeerTrackerMessage);
+    /// Sets the handler called for every message received from the tracker.
+    fn set_handler(&self, handler: Box<dyn FnMut(TrackerPeerMessage)>);
+}
+
+impl<T> PeerTransport for RemotePeer<T> {
+    fn peer_id(&self) -> PeerId {
+        Self::peer_id(self)
+    }
+
+    fn is_ready(&self) -> bool {
+        Self::is_ready(self)
+    }
+
+    fn send(&self, message: PeerPeerMessage) -> Result<(), PeerError> {
+        Self::send(self, message)
+    }
+
+    fn send_file_pieces(
+        &self,
+        sha256: FileSha256,
+        pieces: Vec<(FilePieceIdx, Box<[u8]>)>,
+        compress: bool,
+        max_buffer_bytes: Option<u64>,
+    ) -> Result<(), PeerConnectionSendError> {
+        Self::send_file_pieces(self, sha256, pieces, compress, max_buffer_bytes)
+    }
+
+    fn piece_transfer(&self) -> PieceTransfer {
+        Self::piece_transfer(self)
+    }
+
+    fn take_pong(&self) -> bool {
+        Self::take_pong(self)
+    }
+}
+
+impl TrackerTransport for Tracker {
+    fn send(&self, message: PeerTrackerMessage) {
+        Self::send(self, message);
+    }
+
+    fn set_handler(&self, handler: Box<dyn FnMut(TrackerPeerMessage)>) {
+        Self::set_handler(self, handler);
+    }
+}
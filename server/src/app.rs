@@ -8,20 +8,71 @@ use clap::{AppSettings, Clap};
 )]
 #[clap(setting = AppSettings::ColoredHelp)]
 struct Options {
-    /// IP address to bind
-    #[clap(short, long, default_value = "0.0.0.0")]
-    address: String,
+    /// IP address to bind, may be repeated to bind both IPv4 and IPv6 addresses
+    #[clap(short, long, default_value = "0.0.0.0", multiple_occurrences = true)]
+    address: Vec<String>,
     /// Port number
     #[clap(short, long, default_value = "9010")]
     port: String,
+    /// Maximum incoming WebSocket message size in bytes
+    #[clap(long, default_value = "1048576")]
+    max_message_size: usize,
+    /// Maximum relayed session description length in bytes
+    #[clap(long, default_value = "524288")]
+    max_sdp_len: usize,
+    /// Maximum relayed ICE candidate length in bytes
+    #[clap(long, default_value = "4096")]
+    max_ice_candidate_len: usize,
+    /// PEM encoded TLS certificate chain file, sockets are accepted as `wss://` if set
+    #[clap(long, requires = "tls-key")]
+    tls_cert: Option<String>,
+    /// PEM encoded TLS private key file
+    #[clap(long, requires = "tls-cert")]
+    tls_key: Option<String>,
+    /// Token required by tracker debug queries, queries are refused if not set
+    #[cfg(feature = "debug-query")]
+    #[clap(long)]
+    debug_token: Option<String>,
 }
 
 pub async fn app() -> anyhow::Result<()> {
-    use tracker::Tracker;
+    use tracker::{RelayLimits, Tracker, TrackerTls};
 
     env_logger::init();
     let opts: Options = Options::parse();
-    let addr = format!("{}:{}", opts.address, opts.port);
-    Tracker::new(addr).await?.run().await;
+    let addrs = opts
+        .address
+        .iter()
+        .map(|address| bind_address(address, &opts.port));
+    let tracker = Tracker::with_addresses(addrs)
+        .await?
+        .with_max_message_size(opts.max_message_size)
+        .with_relay_limits(
+            RelayLimits::new()
+                .with_max_sdp_len(opts.max_sdp_len)
+                .with_max_ice_candidate_len(opts.max_ice_candidate_len),
+        );
+    let tracker = match (opts.tls_cert, opts.tls_key) {
+        (Some(cert_path), Some(key_path)) => {
+            tracker.with_tls(TrackerTls::from_pem_files(cert_path, key_path)?)
+        }
+        _ => tracker,
+    };
+    #[cfg(feature = "debug-query")]
+    let tracker = match opts.debug_token {
+        Some(debug_token) => tracker.with_debug_token(debug_token),
+        None => tracker,
+    };
+    tracker.run().await;
     Ok(())
 }
+
+// IPv6 addresses are enclosed in brackets so that the port is not parsed as a part of them.
+fn bind_address(address: &str, port: &str) -> String {
+    use std::net::IpAddr;
+
+    match address.parse() {
+        Ok(IpAddr::V6(address)) => format!("[{}]:{}", address, port),
+        _ => format!("{}:{}", address, port),
+    }
+}
@@ -14,6 +14,16 @@ use core::fmt;
 
 use serde::{Deserialize, Serialize};
 
+/// Room used by peers that do not specify one.
+pub const DEFAULT_ROOM: &str = "";
+
+/// Tracker protocol version, incremented on incompatible message changes.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// WebSocket subprotocol selecting JSON text messages instead of bincode binary messages,
+/// e.g. for non-Rust clients and readable traffic captures.
+pub const JSON_SUBPROTOCOL: &str = "tracker-json";
+
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum SdpType {
     Offer,
@@ -36,7 +46,9 @@ pub struct IceCandidate {
     pub username_fragment: Option<String>,
 }
 
-#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+/// File hash, serialized as an uppercase hex string in human-readable formats like JSON
+/// and as raw bytes otherwise.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub struct FileSha256(pub [u8; 32]);
 
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
@@ -45,9 +57,11 @@ pub struct PeerId(pub u32);
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum PeerTrackerMessage {
     RequestOffers {
+        room: String,
         file_sha256: FileSha256,
     },
     RemoveFile {
+        room: String,
         file_sha256: FileSha256,
     },
     SendOffer {
@@ -65,12 +79,28 @@ pub enum PeerTrackerMessage {
     AllIceCandidatesSent {
         peer_id: PeerId,
     },
+    SetLabel {
+        label: String,
+    },
+    /// Requests the number of peers sharing the file without joining its swarm.
+    QuerySwarm {
+        room: String,
+        file_sha256: FileSha256,
+    },
+    /// Inspects the tracker state, ignored unless the token matches the tracker debug token.
+    #[cfg(feature = "debug-query")]
+    Debug {
+        token: String,
+        query: DebugQuery,
+    },
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum TrackerPeerMessage {
+    /// The first message sent by the tracker.
     PeerIdAssigned {
         peer_id: PeerId,
+        protocol_version: u16,
     },
     RequestOffer {
         peer_id: PeerId,
@@ -91,6 +121,108 @@ pub enum TrackerPeerMessage {
     PeerAllIceCandidatesSent {
         peer_id: PeerId,
     },
+    /// Replies to `PeerTrackerMessage::QuerySwarm`, the querying peer is not counted.
+    SwarmInfo {
+        file_sha256: FileSha256,
+        peer_count: usize,
+    },
+    /// Replies to `PeerTrackerMessage::Debug`.
+    #[cfg(feature = "debug-query")]
+    DebugReport {
+        report: DebugReport,
+    },
+}
+
+#[cfg(feature = "debug-query")]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum DebugQuery {
+    ListFiles,
+    ListPeers,
+    SwarmOf(FileSha256),
+}
+
+#[cfg(feature = "debug-query")]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum DebugReport {
+    Files(Vec<DebugFileInfo>),
+    Peers(Vec<DebugPeerInfo>),
+    /// File swarms in every room the file is shared in.
+    Swarm(Vec<DebugSwarmInfo>),
+}
+
+#[cfg(feature = "debug-query")]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct DebugFileInfo {
+    pub room: String,
+    pub file_sha256: FileSha256,
+    pub peer_count: usize,
+}
+
+#[cfg(feature = "debug-query")]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct DebugPeerInfo {
+    pub peer_id: PeerId,
+    pub label: Option<String>,
+    /// The peer socket is still open.
+    pub is_connected: bool,
+}
+
+#[cfg(feature = "debug-query")]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct DebugSwarmInfo {
+    pub room: String,
+    pub peers: Vec<PeerId>,
+}
+
+// Keeps the binary encoding of the derived implementation.
+#[derive(Deserialize, Serialize)]
+#[serde(rename = "FileSha256")]
+struct RawFileSha256([u8; 32]);
+
+impl Serialize for FileSha256 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&self.to_string())
+        } else {
+            RawFileSha256(self.0).serialize(serializer)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FileSha256 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use hex::FromHex;
+        use serde::de::Error;
+
+        if deserializer.is_human_readable() {
+            let hex = String::deserialize(deserializer)?;
+            <[u8; 32]>::from_hex(hex)
+                .map(Self)
+                .map_err(D::Error::custom)
+        } else {
+            RawFileSha256::deserialize(deserializer).map(|raw| Self(raw.0))
+        }
+    }
+}
+
+impl PeerTrackerMessage {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+}
+
+impl TrackerPeerMessage {
+    pub fn to_json(&self) -> Result<String, serde_json::Error> {
+        serde_json::to_string(self)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(json)
+    }
 }
 
 impl fmt::Display for FileSha256 {
@@ -104,3 +236,212 @@ impl fmt::Display for PeerId {
         write!(f, "{}", self.0)
     }
 }
+
+#[test]
+fn ice_candidate_roundtrip() {
+    let candidate = IceCandidate {
+        candidate: "candidate:842163049 1 udp 1677729535 192.0.2.1 46154 typ srflx".to_owned(),
+        sdp_mid: Some("0".to_owned()),
+        sdp_mline_index: Some(0),
+        username_fragment: Some("EsAw".to_owned()),
+    };
+    let message = PeerTrackerMessage::SendIceCandidate {
+        peer_id: PeerId(3),
+        candidate: candidate.clone(),
+    };
+
+    let bytes = bincode::serialize(&message).unwrap();
+    let message: PeerTrackerMessage = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(
+        message,
+        PeerTrackerMessage::SendIceCandidate {
+            peer_id: PeerId(3),
+            candidate,
+        }
+    );
+}
+
+#[test]
+fn swarm_query_roundtrip() {
+    let query = PeerTrackerMessage::QuerySwarm {
+        room: "room".to_owned(),
+        file_sha256: FileSha256([7; 32]),
+    };
+    let bytes = bincode::serialize(&query).unwrap();
+    let message: PeerTrackerMessage = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(message, query);
+
+    let info = TrackerPeerMessage::SwarmInfo {
+        file_sha256: FileSha256([7; 32]),
+        peer_count: 12,
+    };
+    let bytes = bincode::serialize(&info).unwrap();
+    let message: TrackerPeerMessage = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(message, info);
+}
+
+#[cfg(feature = "debug-query")]
+#[test]
+fn debug_query_roundtrip() {
+    let query = PeerTrackerMessage::Debug {
+        token: "token".to_owned(),
+        query: DebugQuery::SwarmOf(FileSha256([7; 32])),
+    };
+    let bytes = bincode::serialize(&query).unwrap();
+    let message: PeerTrackerMessage = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(message, query);
+
+    let report = TrackerPeerMessage::DebugReport {
+        report: DebugReport::Swarm(vec![DebugSwarmInfo {
+            room: "room".to_owned(),
+            peers: vec![PeerId(1), PeerId(2)],
+        }]),
+    };
+    let bytes = bincode::serialize(&report).unwrap();
+    let message: TrackerPeerMessage = bincode::deserialize(&bytes).unwrap();
+    assert_eq!(message, report);
+}
+
+#[test]
+fn encode_and_decode_every_message() {
+    let file_sha256 = FileSha256([7; 32]);
+    let description = SessionDescription {
+        sdp_type: SdpType::Offer,
+        sdp: "v=0\r\n".to_owned(),
+    };
+    let candidate = IceCandidate {
+        candidate: "candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host".to_owned(),
+        sdp_mid: Some("0".to_owned()),
+        sdp_mline_index: None,
+        username_fragment: None,
+    };
+
+    #[allow(unused_mut)]
+    let mut peer_tracker_messages = vec![
+        PeerTrackerMessage::RequestOffers {
+            room: "room".to_owned(),
+            file_sha256,
+        },
+        PeerTrackerMessage::RemoveFile {
+            room: "room".to_owned(),
+            file_sha256,
+        },
+        PeerTrackerMessage::SendOffer {
+            peer_id: PeerId(1),
+            offer: description.clone(),
+        },
+        PeerTrackerMessage::SendAnswer {
+            peer_id: PeerId(2),
+            answer: description.clone(),
+        },
+        PeerTrackerMessage::SendIceCandidate {
+            peer_id: PeerId(3),
+            candidate: candidate.clone(),
+        },
+        PeerTrackerMessage::AllIceCandidatesSent { peer_id: PeerId(4) },
+        PeerTrackerMessage::SetLabel {
+            label: "label".to_owned(),
+        },
+        PeerTrackerMessage::QuerySwarm {
+            room: DEFAULT_ROOM.to_owned(),
+            file_sha256,
+        },
+    ];
+    #[cfg(feature = "debug-query")]
+    peer_tracker_messages.push(PeerTrackerMessage::Debug {
+        token: "token".to_owned(),
+        query: DebugQuery::ListPeers,
+    });
+
+    #[allow(unused_mut)]
+    let mut tracker_peer_messages = vec![
+        TrackerPeerMessage::PeerIdAssigned {
+            peer_id: PeerId(5),
+            protocol_version: PROTOCOL_VERSION,
+        },
+        TrackerPeerMessage::RequestOffer {
+            peer_id: PeerId(6),
+            file_sha256,
+        },
+        TrackerPeerMessage::PeerOffer {
+            peer_id: PeerId(7),
+            offer: description.clone(),
+        },
+        TrackerPeerMessage::PeerAnswer {
+            peer_id: PeerId(8),
+            answer: description,
+        },
+        TrackerPeerMessage::PeerIceCandidate {
+            peer_id: PeerId(9),
+            candidate,
+        },
+        TrackerPeerMessage::PeerAllIceCandidatesSent {
+            peer_id: PeerId(10),
+        },
+        TrackerPeerMessage::SwarmInfo {
+            file_sha256,
+            peer_count: 11,
+        },
+    ];
+    #[cfg(feature = "debug-query")]
+    tracker_peer_messages.push(TrackerPeerMessage::DebugReport {
+        report: DebugReport::Files(vec![DebugFileInfo {
+            room: "room".to_owned(),
+            file_sha256,
+            peer_count: 12,
+        }]),
+    });
+
+    for message in peer_tracker_messages {
+        let bytes = bincode::serialize(&message).unwrap();
+        assert_eq!(
+            bincode::deserialize::<PeerTrackerMessage>(&bytes).unwrap(),
+            message
+        );
+        let json = message.to_json().unwrap();
+        assert_eq!(PeerTrackerMessage::from_json(&json).unwrap(), message);
+    }
+    for message in tracker_peer_messages {
+        let bytes = bincode::serialize(&message).unwrap();
+        assert_eq!(
+            bincode::deserialize::<TrackerPeerMessage>(&bytes).unwrap(),
+            message
+        );
+        let json = message.to_json().unwrap();
+        assert_eq!(TrackerPeerMessage::from_json(&json).unwrap(), message);
+    }
+}
+
+#[test]
+fn encode_file_sha256_canonically() {
+    let message = TrackerPeerMessage::RequestOffer {
+        peer_id: PeerId(6),
+        file_sha256: FileSha256([0xAB; 32]),
+    };
+    assert_eq!(
+        message.to_json().unwrap(),
+        format!(
+            r#"{{"RequestOffer":{{"peer_id":6,"file_sha256":"{}"}}}}"#,
+            "AB".repeat(32)
+        )
+    );
+    // Lowercase hashes are accepted too.
+    assert_eq!(
+        TrackerPeerMessage::from_json(&format!(
+            r#"{{"RequestOffer":{{"peer_id":6,"file_sha256":"{}"}}}}"#,
+            "ab".repeat(32)
+        ))
+        .unwrap(),
+        message
+    );
+    assert!(
+        TrackerPeerMessage::from_json(r#"{"RequestOffer":{"peer_id":6,"file_sha256":"AB"}}"#)
+            .is_err()
+    );
+
+    // The binary encoding is the same as the one of the raw hash bytes.
+    assert_eq!(
+        bincode::serialize(&FileSha256([0xAB; 32])).unwrap(),
+        bincode::serialize(&[0xAB_u8; 32]).unwrap()
+    );
+}
@@ -10,15 +10,26 @@
     unused_results
 )]
 
+mod message_encoding;
+mod message_limits;
 mod socket;
 mod socket_receiver;
 mod socket_sender;
+mod socket_stream;
 mod state;
+mod tls;
 mod tracker;
 
+use message_limits::{validate_message, MessageLimitError};
 use socket::Socket;
 use socket_receiver::{SocketMessageReceiveError, SocketReceiver};
 use socket_sender::{SocketMessageSendError, SocketSender};
+use socket_stream::SocketStream;
 use state::{State, StateAddFilePeerError, StateRemoveFilePeerError};
 
+pub use message_encoding::MessageEncoding;
+pub use message_limits::{RelayLimits, MAX_ICE_CANDIDATE_LEN, MAX_SDP_LEN, MAX_SHORT_STRING_LEN};
+pub use socket::MAX_MESSAGE_SIZE;
+pub use socket_sender::SEND_QUEUE_LEN;
+pub use tls::{TrackerTls, TrackerTlsError};
 pub use tracker::Tracker;
@@ -0,0 +1,90 @@
+use async_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+/// Encoding of messages sent through a socket, selected per connection during the handshake.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum MessageEncoding {
+    /// Binary messages encoded with bincode.
+    #[default]
+    Bincode,
+    /// Text messages encoded with JSON.
+    Json,
+}
+
+impl MessageEncoding {
+    /// Selects the encoding requested by the client either with the `tracker-json` subprotocol
+    /// or with the `encoding=json` query parameter, bincode is used otherwise.
+    ///
+    /// The requested subprotocol is confirmed in the response as the WebSocket protocol requires.
+    pub fn negotiate(request: &Request, response: Response) -> (Self, Response) {
+        use async_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+        use async_tungstenite::tungstenite::http::HeaderValue;
+        use tracker_protocol::JSON_SUBPROTOCOL;
+
+        let mut response = response;
+        if is_json_subprotocol_requested(request) {
+            let _: Option<HeaderValue> = response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(JSON_SUBPROTOCOL),
+            );
+            (Self::Json, response)
+        } else if is_json_query_requested(request) {
+            (Self::Json, response)
+        } else {
+            (Self::Bincode, response)
+        }
+    }
+}
+
+fn is_json_subprotocol_requested(request: &Request) -> bool {
+    use async_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+    use tracker_protocol::JSON_SUBPROTOCOL;
+
+    request
+        .headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == JSON_SUBPROTOCOL)
+}
+
+fn is_json_query_requested(request: &Request) -> bool {
+    request
+        .uri()
+        .query()
+        .is_some_and(|query| query.split('&').any(|pair| pair == "encoding=json"))
+}
+
+#[test]
+fn negotiate_message_encoding() {
+    use async_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+    use tracker_protocol::JSON_SUBPROTOCOL;
+
+    let negotiate = |uri: &str, protocol: Option<&str>| {
+        let mut request = Request::builder().uri(uri);
+        if let Some(protocol) = protocol {
+            request = request.header(SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+        let (encoding, response) =
+            MessageEncoding::negotiate(&request.body(()).unwrap(), Response::default());
+        let protocol = response
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .map(|value| value.to_str().unwrap().to_owned());
+        (encoding, protocol)
+    };
+
+    assert_eq!(negotiate("/", None), (MessageEncoding::Bincode, None));
+    assert_eq!(
+        negotiate("/?encoding=bincode", Some("chat")),
+        (MessageEncoding::Bincode, None)
+    );
+    assert_eq!(
+        negotiate("/?room=a&encoding=json", None),
+        (MessageEncoding::Json, None)
+    );
+    assert_eq!(
+        negotiate("/", Some("chat, tracker-json")),
+        (MessageEncoding::Json, Some(JSON_SUBPROTOCOL.to_owned()))
+    );
+}
@@ -0,0 +1,187 @@
+use thiserror::Error;
+use tracker_protocol::{IceCandidate, PeerTrackerMessage, SessionDescription};
+
+/// Maximum session description length in bytes, real descriptions are a few kilobytes.
+pub const MAX_SDP_LEN: usize = 512 * 1024;
+
+/// Maximum ICE candidate length in bytes.
+pub const MAX_ICE_CANDIDATE_LEN: usize = 4096;
+
+/// Maximum length of rooms, labels and other short strings in bytes.
+pub const MAX_SHORT_STRING_LEN: usize = 256;
+
+/// Rejects messages with fields that no legitimate peer sends.
+pub fn validate_message(message: &PeerTrackerMessage) -> Result<(), MessageLimitError> {
+    match message {
+        PeerTrackerMessage::RequestOffers { room, .. }
+        | PeerTrackerMessage::RemoveFile { room, .. }
+        | PeerTrackerMessage::QuerySwarm { room, .. } => check_len(room, MAX_SHORT_STRING_LEN),
+        PeerTrackerMessage::SendOffer { offer: sdp, .. }
+        | PeerTrackerMessage::SendAnswer { answer: sdp, .. } => validate_sdp(sdp),
+        PeerTrackerMessage::SendIceCandidate { candidate, .. } => validate_candidate(candidate),
+        PeerTrackerMessage::AllIceCandidatesSent { .. } => Ok(()),
+        PeerTrackerMessage::SetLabel { label } => check_len(label, MAX_SHORT_STRING_LEN),
+        #[cfg(feature = "debug-query")]
+        PeerTrackerMessage::Debug { token, .. } => check_len(token, MAX_SHORT_STRING_LEN),
+    }
+}
+
+fn validate_sdp(sdp: &SessionDescription) -> Result<(), MessageLimitError> {
+    check_len(&sdp.sdp, MAX_SDP_LEN)
+}
+
+fn validate_candidate(candidate: &IceCandidate) -> Result<(), MessageLimitError> {
+    check_len(&candidate.candidate, MAX_ICE_CANDIDATE_LEN)?;
+    for field in [&candidate.sdp_mid, &candidate.username_fragment]
+        .into_iter()
+        .flatten()
+    {
+        check_len(field, MAX_SHORT_STRING_LEN)?;
+    }
+    Ok(())
+}
+
+fn check_len(value: &str, max_len: usize) -> Result<(), MessageLimitError> {
+    if value.len() > max_len {
+        Err(MessageLimitError::StringIsTooLong {
+            len: value.len(),
+            max_len,
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Limits of session descriptions and ICE candidates relayed to other peers.
+///
+/// Unlike the message limits, exceeding them does not close the socket,
+/// oversized signaling messages are dropped instead of being forwarded.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct RelayLimits {
+    max_sdp_len: usize,
+    max_ice_candidate_len: usize,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        Self {
+            max_sdp_len: MAX_SDP_LEN,
+            max_ice_candidate_len: MAX_ICE_CANDIDATE_LEN,
+        }
+    }
+}
+
+impl RelayLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_max_sdp_len(self, max_sdp_len: usize) -> Self {
+        Self {
+            max_sdp_len,
+            ..self
+        }
+    }
+
+    pub fn with_max_ice_candidate_len(self, max_ice_candidate_len: usize) -> Self {
+        Self {
+            max_ice_candidate_len,
+            ..self
+        }
+    }
+
+    pub fn max_sdp_len(&self) -> usize {
+        self.max_sdp_len
+    }
+
+    pub fn max_ice_candidate_len(&self) -> usize {
+        self.max_ice_candidate_len
+    }
+
+    pub fn check_sdp(&self, sdp: &SessionDescription) -> Result<(), MessageLimitError> {
+        check_len(&sdp.sdp, self.max_sdp_len)
+    }
+
+    pub fn check_ice_candidate(&self, candidate: &IceCandidate) -> Result<(), MessageLimitError> {
+        check_len(&candidate.candidate, self.max_ice_candidate_len)
+    }
+}
+
+#[derive(Clone, Copy, Error, Debug, Eq, PartialEq)]
+pub enum MessageLimitError {
+    #[error("message string of {len} bytes exceeds the limit of {max_len} bytes")]
+    StringIsTooLong { len: usize, max_len: usize },
+}
+
+#[test]
+fn reject_messages_with_long_strings() {
+    use tracker_protocol::{FileSha256, PeerId, SdpType};
+
+    let offer = |len| PeerTrackerMessage::SendOffer {
+        peer_id: PeerId(1),
+        offer: SessionDescription {
+            sdp_type: SdpType::Offer,
+            sdp: "v".repeat(len),
+        },
+    };
+    assert_eq!(validate_message(&offer(MAX_SDP_LEN)), Ok(()));
+    assert_eq!(
+        validate_message(&offer(MAX_SDP_LEN + 1)),
+        Err(MessageLimitError::StringIsTooLong {
+            len: MAX_SDP_LEN + 1,
+            max_len: MAX_SDP_LEN
+        })
+    );
+
+    let request = PeerTrackerMessage::RequestOffers {
+        room: "r".repeat(MAX_SHORT_STRING_LEN + 1),
+        file_sha256: FileSha256([0; 32]),
+    };
+    assert!(validate_message(&request).is_err());
+
+    let candidate = PeerTrackerMessage::SendIceCandidate {
+        peer_id: PeerId(1),
+        candidate: IceCandidate {
+            candidate: "candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host".to_owned(),
+            sdp_mid: Some("0".to_owned()),
+            sdp_mline_index: Some(0),
+            username_fragment: Some("u".repeat(MAX_SHORT_STRING_LEN + 1)),
+        },
+    };
+    assert!(validate_message(&candidate).is_err());
+}
+
+#[test]
+fn check_relay_limits() {
+    use tracker_protocol::SdpType;
+
+    let sdp = |len| SessionDescription {
+        sdp_type: SdpType::Answer,
+        sdp: "v".repeat(len),
+    };
+    let candidate = |len| IceCandidate {
+        candidate: "c".repeat(len),
+        sdp_mid: None,
+        sdp_mline_index: None,
+        username_fragment: None,
+    };
+
+    let limits = RelayLimits::new();
+    assert_eq!(limits.check_sdp(&sdp(MAX_SDP_LEN)), Ok(()));
+    assert_eq!(
+        limits.check_ice_candidate(&candidate(MAX_ICE_CANDIDATE_LEN)),
+        Ok(())
+    );
+
+    let limits = limits.with_max_sdp_len(100).with_max_ice_candidate_len(10);
+    assert_eq!(limits.check_sdp(&sdp(100)), Ok(()));
+    assert_eq!(
+        limits.check_sdp(&sdp(101)),
+        Err(MessageLimitError::StringIsTooLong {
+            len: 101,
+            max_len: 100
+        })
+    );
+    assert_eq!(limits.check_ice_candidate(&candidate(10)), Ok(()));
+    assert!(limits.check_ice_candidate(&candidate(11)).is_err());
+}
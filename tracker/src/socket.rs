@@ -1,70 +1,128 @@
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use async_std::net::TcpStream;
 use async_std::sync::Mutex;
 use async_tungstenite::tungstenite;
+use async_tungstenite::tungstenite::protocol::WebSocketConfig;
 use thiserror::Error;
-use tracker_protocol::{PeerId, TrackerPeerMessage};
+use tracker_protocol::{PeerId, PeerTrackerMessage, TrackerPeerMessage};
 
 use crate::{
-    SocketMessageReceiveError, SocketMessageSendError, SocketReceiver, SocketSender, State,
-    StateAddFilePeerError, StateRemoveFilePeerError,
+    MessageEncoding, RelayLimits, SocketMessageReceiveError, SocketMessageSendError,
+    SocketReceiver, SocketSender, SocketStream, State, StateAddFilePeerError,
+    StateRemoveFilePeerError,
 };
 
+pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
+
 #[derive(Debug)]
 pub struct Socket {
     sender: Arc<Mutex<SocketSender>>,
     receiver: SocketReceiver,
     addr: SocketAddr,
     state: Arc<State>,
+    relay_limits: RelayLimits,
 }
 
 impl Socket {
     pub async fn new(
-        stream: TcpStream,
+        stream: SocketStream,
         addr: SocketAddr,
         state: Arc<State>,
+        max_message_size: usize,
     ) -> Result<Self, NewSocketError> {
-        use async_tungstenite::accept_async;
+        use crate::SEND_QUEUE_LEN;
+        use async_tungstenite::accept_hdr_async_with_config;
+        use async_tungstenite::tungstenite::handshake::server::{Request, Response};
         use futures::StreamExt;
 
-        let stream = accept_async(stream).await?;
+        let config = websocket_config(max_message_size);
+        let mut encoding = MessageEncoding::default();
+        #[allow(clippy::result_large_err)] // the error response type is defined by `tungstenite`
+        let negotiate = |request: &Request, response: Response| {
+            let (negotiated, response) = MessageEncoding::negotiate(request, response);
+            encoding = negotiated;
+            Ok(response)
+        };
+        let stream = accept_hdr_async_with_config(stream, negotiate, Some(config)).await?;
         let (sender, receiver) = stream.split();
-        let sender = Arc::new(Mutex::new(SocketSender::new(sender)));
-        let receiver = SocketReceiver::new(receiver);
+        let sender = Arc::new(Mutex::new(SocketSender::new(
+            sender,
+            SEND_QUEUE_LEN,
+            encoding,
+        )));
+        let receiver = SocketReceiver::new(receiver, encoding);
 
         Ok(Self {
             sender,
             receiver,
             addr,
             state,
+            relay_limits: RelayLimits::default(),
         })
     }
 
-    pub async fn run(mut self) -> Result<(), SocketRunError> {
-        use tracker_protocol::PeerTrackerMessage;
+    pub fn with_relay_limits(self, relay_limits: RelayLimits) -> Self {
+        Self {
+            relay_limits,
+            ..self
+        }
+    }
 
+    pub async fn run(mut self) -> Result<(), SocketRunError> {
         let addr = self.addr;
         log::info!("socket {} opened", addr);
 
         let peer_id = self.state.new_peer(&self.sender).await;
         log::info!("socket {} peer id assigned", peer_id);
+        let result = self.run_peer(peer_id).await;
+
+        log::info!(
+            "socket {} closed, peer {}",
+            addr,
+            self.state.peer_name(peer_id).await
+        );
+        // Labels are removed even if the socket is closed with an error.
+        self.state.remove_peer_label(peer_id).await;
+        result
+    }
+
+    async fn run_peer(&mut self, peer_id: PeerId) -> Result<(), SocketRunError> {
+        use tracker_protocol::PROTOCOL_VERSION;
+
+        let addr = self.addr;
         self.sender
             .lock()
             .await
-            .send(TrackerPeerMessage::PeerIdAssigned { peer_id })
-            .await?;
+            .send(TrackerPeerMessage::PeerIdAssigned {
+                peer_id,
+                protocol_version: PROTOCOL_VERSION,
+            })?;
 
-        while let Some(message) = self.receiver.recv().await? {
-            log::debug!("peer {}: recv {:?}", peer_id, message);
+        while let Some(message) = self.recv().await? {
+            if self.sender.lock().await.is_disconnected() {
+                log::warn!("socket {} disconnected, peer {}", addr, peer_id);
+                break;
+            }
+            log::debug!(
+                "peer {}: recv {:?}",
+                self.state.peer_name(peer_id).await,
+                message
+            );
 
             match message {
-                PeerTrackerMessage::RequestOffers { file_sha256 } => {
-                    let peer_list = self
+                PeerTrackerMessage::RequestOffers { room, file_sha256 } => {
+                    let peer_list = match self
                         .state
-                        .add_file_peer_and_get_file_peer_list(file_sha256, peer_id)
-                        .await?;
+                        .add_file_peer_and_get_file_peer_list(room.clone(), file_sha256, peer_id)
+                        .await
+                    {
+                        Ok(peer_list) => peer_list,
+                        // Peers without connections request offers again to find new peers.
+                        Err(StateAddFilePeerError::FileIsAlreadyAdded(_)) => {
+                            self.state.get_file_peer_list(room, file_sha256).await
+                        }
+                    };
 
                     for other_peer_id in peer_list {
                         if peer_id == other_peer_id {
@@ -81,13 +139,37 @@ impl Socket {
                         .await?;
                     }
                 }
-                PeerTrackerMessage::RemoveFile { file_sha256 } => {
-                    self.state.remove_file_peer(file_sha256, peer_id).await?;
+                PeerTrackerMessage::SetLabel { label } => {
+                    log::info!("peer {} label set to {}", peer_id, label);
+                    self.state.set_peer_label(peer_id, label).await;
+                }
+                PeerTrackerMessage::QuerySwarm { room, file_sha256 } => {
+                    let peer_count = self
+                        .state
+                        .count_other_file_peers(room, file_sha256, peer_id)
+                        .await;
+                    self.send_to_peer(
+                        peer_id,
+                        TrackerPeerMessage::SwarmInfo {
+                            file_sha256,
+                            peer_count,
+                        },
+                    )
+                    .await?;
+                }
+                PeerTrackerMessage::RemoveFile { room, file_sha256 } => {
+                    self.state
+                        .remove_file_peer(room, file_sha256, peer_id)
+                        .await?;
                 }
                 PeerTrackerMessage::SendOffer {
                     peer_id: other_peer_id,
                     offer,
                 } => {
+                    if let Err(err) = self.relay_limits.check_sdp(&offer) {
+                        log::warn!("peer {} offer dropped: {}", peer_id, err);
+                        continue;
+                    }
                     self.send_to_peer(
                         other_peer_id,
                         TrackerPeerMessage::PeerOffer { peer_id, offer },
@@ -98,6 +180,10 @@ impl Socket {
                     peer_id: other_peer_id,
                     answer,
                 } => {
+                    if let Err(err) = self.relay_limits.check_sdp(&answer) {
+                        log::warn!("peer {} answer dropped: {}", peer_id, err);
+                        continue;
+                    }
                     self.send_to_peer(
                         other_peer_id,
                         TrackerPeerMessage::PeerAnswer { peer_id, answer },
@@ -108,12 +194,26 @@ impl Socket {
                     peer_id: other_peer_id,
                     candidate,
                 } => {
+                    if let Err(err) = self.relay_limits.check_ice_candidate(&candidate) {
+                        log::warn!("peer {} ICE candidate dropped: {}", peer_id, err);
+                        continue;
+                    }
                     self.send_to_peer(
                         other_peer_id,
                         TrackerPeerMessage::PeerIceCandidate { peer_id, candidate },
                     )
                     .await?;
                 }
+                #[cfg(feature = "debug-query")]
+                PeerTrackerMessage::Debug { token, query } => {
+                    if !self.state.is_debug_token(&token) {
+                        log::warn!("peer {} debug query refused", peer_id);
+                        continue;
+                    }
+                    let report = self.state.debug_report(query).await;
+                    self.send_to_peer(peer_id, TrackerPeerMessage::DebugReport { report })
+                        .await?;
+                }
                 PeerTrackerMessage::AllIceCandidatesSent {
                     peer_id: other_peer_id,
                 } => {
@@ -126,24 +226,73 @@ impl Socket {
             }
         }
 
-        log::info!("socket {} closed", addr);
         Ok(())
     }
 
+    // Oversized and invalid messages close the socket with an explanatory close code.
+    async fn recv(&mut self) -> Result<Option<PeerTrackerMessage>, SocketMessageReceiveError> {
+        use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+        let err = match self.receiver.recv().await {
+            Ok(message) => return Ok(message),
+            Err(err) => err,
+        };
+        let code = match err.close_code() {
+            Some(code) => code,
+            None => return Err(err),
+        };
+        let reason = if code == CloseCode::Size {
+            "message is too large"
+        } else {
+            "invalid message"
+        };
+        if let Err(close_err) = self.sender.lock().await.close(code, reason) {
+            log::debug!("socket {} close error: {}", self.addr, close_err);
+        }
+        Err(err)
+    }
+
     async fn send_to_peer(
         &self,
         peer_id: PeerId,
         message: TrackerPeerMessage,
     ) -> Result<(), SocketMessageSendError> {
-        log::debug!("peer {}: send {:?}", peer_id, message);
+        log::debug!(
+            "peer {}: send {:?}",
+            self.state.peer_name(peer_id).await,
+            message
+        );
         let sender = self.state.get_peer_sender(peer_id).await;
         if let Some(sender) = sender {
-            sender.lock().await.send(message).await?;
+            // Slow and disconnected peers do not stop the sending socket.
+            match sender.lock().await.send(message) {
+                Ok(()) => {}
+                Err(SocketMessageSendError::QueueOverflow) => {
+                    log::warn!("peer {} disconnected: send queue overflow", peer_id);
+                }
+                Err(SocketMessageSendError::QueueClosed) => {
+                    log::debug!("peer {} send queue is closed", peer_id);
+                }
+                Err(
+                    err @ (SocketMessageSendError::SerializationError(_)
+                    | SocketMessageSendError::JsonSerializationError(_)),
+                ) => return Err(err),
+            }
         }
         Ok(())
     }
 }
 
+// Signaling messages are small, so message size is limited more strictly than by default.
+// `permessage-deflate` is not supported by `tungstenite`, so messages are sent uncompressed.
+pub fn websocket_config(max_message_size: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(max_message_size),
+        max_frame_size: Some(max_message_size),
+        ..WebSocketConfig::default()
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum NewSocketError {
     #[error(transparent)]
@@ -161,3 +310,265 @@ pub enum SocketRunError {
     #[error(transparent)]
     StateRemoveFilePeerError(#[from] StateRemoveFilePeerError),
 }
+
+#[test]
+fn send_large_message_through_configured_socket() {
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::{block_on, spawn};
+    use async_tungstenite::tungstenite::Message;
+    use async_tungstenite::{accept_async_with_config, client_async_with_config};
+    use futures::{SinkExt, StreamExt};
+    use tracker_protocol::{PeerTrackerMessage, SdpType, SessionDescription};
+
+    let message = PeerTrackerMessage::SendOffer {
+        peer_id: PeerId(1),
+        offer: SessionDescription {
+            sdp_type: SdpType::Offer,
+            sdp: "a=candidate:1 1 udp 2122260223 192.0.2.1 54321 typ host\r\n".repeat(4096),
+        },
+    };
+    let bytes = bincode::serialize(&message).unwrap();
+    assert!(bytes.len() > 128 * 1024 && bytes.len() < MAX_MESSAGE_SIZE);
+
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let stream = accept_async_with_config(
+                SocketStream::Plain(stream),
+                Some(websocket_config(MAX_MESSAGE_SIZE)),
+            )
+            .await
+            .unwrap();
+            let (_, receiver) = stream.split();
+            SocketReceiver::new(receiver, MessageEncoding::Bincode)
+                .recv()
+                .await
+                .unwrap()
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, _) = client_async_with_config(
+            format!("ws://{}", addr),
+            stream,
+            Some(websocket_config(MAX_MESSAGE_SIZE)),
+        )
+        .await
+        .unwrap();
+        client.send(Message::Binary(bytes)).await.unwrap();
+
+        assert_eq!(server.await, Some(message));
+    });
+}
+
+#[test]
+fn close_socket_on_oversized_message() {
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::{block_on, spawn};
+    use async_tungstenite::client_async;
+    use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use async_tungstenite::tungstenite::Message;
+    use futures::{SinkExt, StreamExt};
+
+    const MAX_TEST_MESSAGE_SIZE: usize = 1024;
+
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let state = Arc::new(State::new());
+            Socket::new(
+                SocketStream::Plain(stream),
+                addr,
+                state,
+                MAX_TEST_MESSAGE_SIZE,
+            )
+            .await
+            .unwrap()
+            .run()
+            .await
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut client, _) = client_async(format!("ws://{}", addr), stream)
+            .await
+            .unwrap();
+        // Skip the assigned peer id.
+        let _: Message = client.next().await.unwrap().unwrap();
+        client
+            .send(Message::Binary(vec![0; MAX_TEST_MESSAGE_SIZE + 1]))
+            .await
+            .unwrap();
+
+        assert!(matches!(
+            server.await,
+            Err(SocketRunError::MessageReceiveError(
+                SocketMessageReceiveError::WebSocketReceiveError(tungstenite::Error::Capacity(_))
+            ))
+        ));
+        match client.next().await.unwrap().unwrap() {
+            Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Size),
+            message => panic!("unexpected message {:?}", message),
+        }
+    });
+}
+
+#[test]
+fn drop_oversized_sdp_instead_of_relaying() {
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::{block_on, spawn};
+    use async_tungstenite::client_async;
+    use async_tungstenite::tungstenite::Message;
+    use futures::{SinkExt, StreamExt};
+    use tracker_protocol::{SdpType, SessionDescription};
+
+    const MAX_TEST_SDP_LEN: usize = 1024;
+
+    let offer = |len| SessionDescription {
+        sdp_type: SdpType::Offer,
+        sdp: "v".repeat(len),
+    };
+    let decode = |message| match message {
+        Message::Binary(data) => bincode::deserialize::<TrackerPeerMessage>(&data).unwrap(),
+        message => panic!("unexpected message {:?}", message),
+    };
+
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(State::new());
+        let _server = spawn(async move {
+            loop {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let stream = SocketStream::Plain(stream);
+                let socket = Socket::new(stream, addr, Arc::clone(&state), MAX_MESSAGE_SIZE)
+                    .await
+                    .unwrap()
+                    .with_relay_limits(RelayLimits::new().with_max_sdp_len(MAX_TEST_SDP_LEN));
+                // Sockets run until the test ends.
+                drop(spawn(socket.run()));
+            }
+        });
+
+        let mut clients = Vec::new();
+        let mut peer_ids = Vec::new();
+        for _ in 0..2 {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (mut client, _) = client_async(format!("ws://{}", addr), stream)
+                .await
+                .unwrap();
+            match decode(client.next().await.unwrap().unwrap()) {
+                TrackerPeerMessage::PeerIdAssigned { peer_id, .. } => peer_ids.push(peer_id),
+                message => panic!("unexpected message {:?}", message),
+            }
+            clients.push(client);
+        }
+
+        for len in [MAX_TEST_SDP_LEN + 1, MAX_TEST_SDP_LEN] {
+            let message = PeerTrackerMessage::SendOffer {
+                peer_id: peer_ids[1],
+                offer: offer(len),
+            };
+            clients[0]
+                .send(Message::Binary(bincode::serialize(&message).unwrap()))
+                .await
+                .unwrap();
+        }
+
+        // The oversized offer is dropped and the socket is kept open.
+        assert_eq!(
+            decode(clients[1].next().await.unwrap().unwrap()),
+            TrackerPeerMessage::PeerOffer {
+                peer_id: peer_ids[0],
+                offer: offer(MAX_TEST_SDP_LEN)
+            }
+        );
+    });
+}
+
+#[test]
+fn relay_between_json_and_bincode_sockets() {
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::{block_on, spawn};
+    use async_tungstenite::client_async;
+    use async_tungstenite::tungstenite::client::IntoClientRequest;
+    use async_tungstenite::tungstenite::http::header::SEC_WEBSOCKET_PROTOCOL;
+    use async_tungstenite::tungstenite::http::HeaderValue;
+    use async_tungstenite::tungstenite::Message;
+    use futures::{SinkExt, StreamExt};
+    use tracker_protocol::{SdpType, SessionDescription, JSON_SUBPROTOCOL};
+
+    let offer = SessionDescription {
+        sdp_type: SdpType::Offer,
+        sdp: "v=0\r\n".to_owned(),
+    };
+
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(State::new());
+        let _server = spawn(async move {
+            loop {
+                let (stream, addr) = listener.accept().await.unwrap();
+                let stream = SocketStream::Plain(stream);
+                let socket = Socket::new(stream, addr, Arc::clone(&state), MAX_MESSAGE_SIZE)
+                    .await
+                    .unwrap();
+                // Sockets run until the test ends.
+                drop(spawn(socket.run()));
+            }
+        });
+
+        let mut request = format!("ws://{}", addr).into_client_request().unwrap();
+        let _: Option<HeaderValue> = request.headers_mut().insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static(JSON_SUBPROTOCOL),
+        );
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut json_client, response) = client_async(request, stream).await.unwrap();
+        assert_eq!(
+            response.headers().get(SEC_WEBSOCKET_PROTOCOL).unwrap(),
+            JSON_SUBPROTOCOL
+        );
+        let json_peer_id = match json_client.next().await.unwrap().unwrap() {
+            Message::Text(text) => match TrackerPeerMessage::from_json(&text).unwrap() {
+                TrackerPeerMessage::PeerIdAssigned { peer_id, .. } => peer_id,
+                message => panic!("unexpected message {:?}", message),
+            },
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let (mut bincode_client, _) = client_async(format!("ws://{}", addr), stream)
+            .await
+            .unwrap();
+        let bincode_peer_id = match bincode_client.next().await.unwrap().unwrap() {
+            Message::Binary(data) => match bincode::deserialize(&data).unwrap() {
+                TrackerPeerMessage::PeerIdAssigned { peer_id, .. } => peer_id,
+                message => panic!("unexpected message {:?}", message),
+            },
+            message => panic!("unexpected message {:?}", message),
+        };
+
+        let message = PeerTrackerMessage::SendOffer {
+            peer_id: bincode_peer_id,
+            offer: offer.clone(),
+        };
+        json_client
+            .send(Message::Text(message.to_json().unwrap()))
+            .await
+            .unwrap();
+
+        // Each socket encodes messages as negotiated by its own peer.
+        let expected = TrackerPeerMessage::PeerOffer {
+            peer_id: json_peer_id,
+            offer,
+        };
+        assert_eq!(
+            bincode_client.next().await.unwrap().unwrap(),
+            Message::Binary(bincode::serialize(&expected).unwrap())
+        );
+    });
+}
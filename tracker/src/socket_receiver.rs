@@ -1,34 +1,47 @@
-use async_std::net::TcpStream;
 use async_tungstenite::tungstenite;
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use async_tungstenite::tungstenite::protocol::Message;
 use async_tungstenite::WebSocketStream;
 use futures::stream::SplitStream;
 use thiserror::Error;
 use tracker_protocol::PeerTrackerMessage;
 
+use crate::{MessageEncoding, MessageLimitError, SocketStream};
+
 #[derive(Debug)]
-pub struct SocketReceiver(SplitStream<WebSocketStream<TcpStream>>);
+pub struct SocketReceiver {
+    receiver: SplitStream<WebSocketStream<SocketStream>>,
+    encoding: MessageEncoding,
+}
 
 #[allow(single_use_lifetimes)] // false positive
 impl SocketReceiver {
-    pub fn new(receiver: SplitStream<WebSocketStream<TcpStream>>) -> Self {
-        Self(receiver)
+    pub fn new(
+        receiver: SplitStream<WebSocketStream<SocketStream>>,
+        encoding: MessageEncoding,
+    ) -> Self {
+        Self { receiver, encoding }
     }
 
     pub async fn recv(&mut self) -> Result<Option<PeerTrackerMessage>, SocketMessageReceiveError> {
-        use bincode::deserialize;
+        use crate::validate_message;
         use futures::StreamExt;
 
         let message = self
-            .0
+            .receiver
             .next()
             .await
             .ok_or(SocketMessageReceiveError::UnexpectedEndOfStream)??;
-        match message {
-            Message::Binary(data) => Ok(Some(deserialize(&data[..])?)),
-            Message::Close(_) => Ok(None),
-            message => Err(SocketMessageReceiveError::InvalidWebSocketMessage(message)),
-        }
+        let message = match (self.encoding, message) {
+            (MessageEncoding::Bincode, Message::Binary(data)) => bincode::deserialize(&data[..])?,
+            (MessageEncoding::Json, Message::Text(text)) => PeerTrackerMessage::from_json(&text)?,
+            (_, Message::Close(_)) => return Ok(None),
+            (_, message) => {
+                return Err(SocketMessageReceiveError::InvalidWebSocketMessage(message))
+            }
+        };
+        validate_message(&message)?;
+        Ok(Some(message))
     }
 }
 
@@ -38,8 +51,32 @@ pub enum SocketMessageReceiveError {
     UnexpectedEndOfStream,
     #[error("message deserialization error: {0}")]
     DeserializationError(#[from] bincode::Error),
+    #[error("message JSON deserialization error: {0}")]
+    JsonDeserializationError(#[from] serde_json::Error),
     #[error("WebSocket receive message error: {0}")]
     WebSocketReceiveError(#[from] tungstenite::Error),
     #[error("invalid WebSocket message: {0}")]
     InvalidWebSocketMessage(Message),
+    #[error(transparent)]
+    MessageLimitError(#[from] MessageLimitError),
+}
+
+impl SocketMessageReceiveError {
+    /// Returns the code the socket is closed with because of the error,
+    /// `None` if the connection is already broken.
+    pub fn close_code(&self) -> Option<CloseCode> {
+        use async_tungstenite::tungstenite::error::CapacityError;
+
+        match self {
+            Self::UnexpectedEndOfStream => None,
+            Self::DeserializationError(_)
+            | Self::JsonDeserializationError(_)
+            | Self::InvalidWebSocketMessage(_) => Some(CloseCode::Invalid),
+            Self::WebSocketReceiveError(tungstenite::Error::Capacity(
+                CapacityError::MessageTooLong { .. },
+            ))
+            | Self::MessageLimitError(_) => Some(CloseCode::Size),
+            Self::WebSocketReceiveError(_) => None,
+        }
+    }
 }
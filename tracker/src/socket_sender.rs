@@ -1,29 +1,113 @@
-use async_std::net::TcpStream;
-use async_tungstenite::tungstenite;
+use async_std::channel::Sender;
+use async_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
 use async_tungstenite::tungstenite::protocol::Message;
 use async_tungstenite::WebSocketStream;
 use futures::stream::SplitSink;
 use thiserror::Error;
 use tracker_protocol::TrackerPeerMessage;
 
+use crate::{MessageEncoding, SocketStream};
+
+/// Number of outgoing messages queued per peer before the peer is disconnected.
+pub const SEND_QUEUE_LEN: usize = 256;
+
+/// Sends messages through a bounded queue flushed by a background task,
+/// so that a slow peer does not block senders of other peers holding the sender lock.
 #[derive(Debug)]
-pub struct SocketSender(SplitSink<WebSocketStream<TcpStream>, Message>);
+pub struct SocketSender {
+    queue: Sender<Message>,
+    disconnect: Sender<()>,
+    encoding: MessageEncoding,
+}
 
 impl SocketSender {
-    pub fn new(sender: SplitSink<WebSocketStream<TcpStream>, Message>) -> Self {
-        Self(sender)
+    pub fn new(
+        sender: SplitSink<WebSocketStream<SocketStream>, Message>,
+        queue_len: usize,
+        encoding: MessageEncoding,
+    ) -> Self {
+        use async_std::channel::bounded;
+        use async_std::task::{spawn, JoinHandle};
+
+        let (queue, queue_receiver) = bounded(queue_len);
+        let (disconnect, disconnect_receiver) = bounded(1);
+        let _: JoinHandle<()> = spawn(async move {
+            use futures::future::{pending, select};
+            use futures::SinkExt;
+
+            let mut sender = sender;
+            let flush = Box::pin(async {
+                while let Ok(message) = queue_receiver.recv().await {
+                    if let Err(err) = sender.send(message).await {
+                        log::debug!("socket send error: {}", err);
+                        return;
+                    }
+                }
+                if let Err(err) = sender.close().await {
+                    log::debug!("socket close error: {}", err);
+                }
+            });
+            // Dropping the sender only stops the queue, the queued messages are still sent.
+            // A disconnect also drops the queued messages and the message being sent.
+            let disconnected = Box::pin(async {
+                if disconnect_receiver.recv().await.is_err() {
+                    pending::<()>().await;
+                }
+            });
+            let _: futures::future::Either<_, _> = select(flush, disconnected).await;
+        });
+        Self {
+            queue,
+            disconnect,
+            encoding,
+        }
     }
 
-    pub async fn send(
+    /// Queues the message without waiting for it to be sent.
+    ///
+    /// The peer is disconnected if its queue is full.
+    pub fn send(&mut self, message: TrackerPeerMessage) -> Result<(), SocketMessageSendError> {
+        let message = match self.encoding {
+            MessageEncoding::Bincode => Message::Binary(bincode::serialize(&message)?),
+            MessageEncoding::Json => Message::Text(message.to_json()?),
+        };
+        self.push(message)
+    }
+
+    /// Queues the close frame, the socket is closed after the queued messages are sent.
+    pub fn close(
         &mut self,
-        message: TrackerPeerMessage,
+        code: CloseCode,
+        reason: &'static str,
     ) -> Result<(), SocketMessageSendError> {
-        use bincode::serialize;
-        use futures::SinkExt;
+        use async_tungstenite::tungstenite::protocol::CloseFrame;
+
+        let result = self.push(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })));
+        let _: bool = self.queue.close();
+        result
+    }
+
+    /// Returns `true` if the peer is disconnected, e.g. because of the queue overflow.
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnect.is_closed()
+    }
+
+    fn push(&mut self, message: Message) -> Result<(), SocketMessageSendError> {
+        use async_std::channel::TrySendError;
 
-        let message: Vec<u8> = serialize(&message)?;
-        self.0.send(Message::Binary(message)).await?;
-        Ok(())
+        match self.queue.try_send(message) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                let _: bool = self.queue.close();
+                let _: Result<(), _> = self.disconnect.try_send(());
+                let _: bool = self.disconnect.close();
+                Err(SocketMessageSendError::QueueOverflow)
+            }
+            Err(TrySendError::Closed(_)) => Err(SocketMessageSendError::QueueClosed),
+        }
     }
 }
 
@@ -31,6 +115,87 @@ impl SocketSender {
 pub enum SocketMessageSendError {
     #[error("message serialization error: {0}")]
     SerializationError(#[from] bincode::Error),
-    #[error("WebSocket send message error: {0}")]
-    WebSocketSendError(#[from] tungstenite::Error),
+    #[error("message JSON serialization error: {0}")]
+    JsonSerializationError(#[from] serde_json::Error),
+    #[error("send queue overflow, the peer is disconnected")]
+    QueueOverflow,
+    #[error("send queue is closed")]
+    QueueClosed,
+}
+
+#[test]
+fn disconnect_slow_peer_without_blocking_other_peers() {
+    use core::time::Duration;
+
+    use async_std::future::timeout;
+    use async_std::net::{TcpListener, TcpStream};
+    use async_std::task::block_on;
+    use async_tungstenite::{accept_async, client_async};
+    use futures::StreamExt;
+    use tracker_protocol::{PeerId, SdpType, SessionDescription};
+
+    const TEST_QUEUE_LEN: usize = 4;
+
+    let offer = TrackerPeerMessage::PeerOffer {
+        peer_id: PeerId(1),
+        offer: SessionDescription {
+            sdp_type: SdpType::Offer,
+            sdp: "v".repeat(64 * 1024),
+        },
+    };
+
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut senders = Vec::new();
+        // Receivers are kept, so that sockets are not closed.
+        let mut receivers = Vec::new();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let client_stream = TcpStream::connect(addr).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let (stream, client) = futures::join!(
+                accept_async(SocketStream::Plain(stream)),
+                client_async(format!("ws://{}", addr), client_stream)
+            );
+            let (sender, receiver) = stream.unwrap().split();
+            senders.push(SocketSender::new(
+                sender,
+                TEST_QUEUE_LEN,
+                MessageEncoding::Bincode,
+            ));
+            receivers.push(receiver);
+            clients.push(client.unwrap().0);
+        }
+
+        // The slow client never reads, so its queue overflows at the latest once the socket buffers are full.
+        let overflow = timeout(Duration::from_secs(10), async {
+            loop {
+                match senders[0].send(offer.clone()) {
+                    Ok(()) => async_std::task::yield_now().await,
+                    Err(err) => return err,
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(overflow, SocketMessageSendError::QueueOverflow));
+        assert!(senders[0].is_disconnected());
+        assert!(matches!(
+            senders[0].send(offer.clone()),
+            Err(SocketMessageSendError::QueueClosed)
+        ));
+
+        senders[1].send(offer.clone()).unwrap();
+        let message = timeout(Duration::from_secs(10), clients[1].next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            message,
+            Message::Binary(bincode::serialize(&offer).unwrap())
+        );
+        assert!(!senders[1].is_disconnected());
+    });
 }
@@ -1,18 +1,34 @@
+use core::fmt;
 use core::sync::atomic::AtomicU32;
 use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Weak};
 
 use async_std::sync::{Mutex, RwLock};
 use thiserror::Error;
+#[cfg(feature = "debug-query")]
+use tracker_protocol::{DebugQuery, DebugReport};
 use tracker_protocol::{FileSha256, PeerId};
 
 use crate::SocketSender;
 
+type FilePeers = Arc<RwLock<HashSet<PeerId>>>;
+
 #[derive(Debug)]
 pub struct State {
     peers_senders: RwLock<HashMap<PeerId, Weak<Mutex<SocketSender>>>>,
-    files_senders: RwLock<HashMap<FileSha256, Arc<RwLock<HashSet<PeerId>>>>>,
+    files_senders: RwLock<HashMap<(String, FileSha256), FilePeers>>,
+    peers_labels: RwLock<HashMap<PeerId, String>>,
     next_peer_id: AtomicU32,
+    /// Token required by debug queries, all queries are refused if it is not set.
+    #[cfg(feature = "debug-query")]
+    debug_token: Option<String>,
+}
+
+/// Peer id with an optional human-readable label used in logs.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerName {
+    pub peer_id: PeerId,
+    pub label: Option<String>,
 }
 
 impl State {
@@ -20,7 +36,78 @@ impl State {
         Self {
             peers_senders: RwLock::new(HashMap::new()),
             files_senders: RwLock::new(HashMap::new()),
+            peers_labels: RwLock::new(HashMap::new()),
             next_peer_id: AtomicU32::new(0),
+            #[cfg(feature = "debug-query")]
+            debug_token: None,
+        }
+    }
+
+    #[cfg(feature = "debug-query")]
+    pub fn with_debug_token(self, debug_token: String) -> Self {
+        Self {
+            debug_token: Some(debug_token),
+            ..self
+        }
+    }
+
+    #[cfg(feature = "debug-query")]
+    pub fn is_debug_token(&self, token: &str) -> bool {
+        self.debug_token.as_deref() == Some(token)
+    }
+
+    /// Returns the report on the tracker state, lists are sorted to make them easier to read.
+    #[cfg(feature = "debug-query")]
+    pub async fn debug_report(&self, query: DebugQuery) -> DebugReport {
+        use tracker_protocol::{DebugFileInfo, DebugPeerInfo, DebugSwarmInfo};
+
+        match query {
+            DebugQuery::ListFiles => {
+                let mut files = Vec::new();
+                for ((room, file_sha256), file_peers) in self.files_senders.read().await.iter() {
+                    files.push(DebugFileInfo {
+                        room: room.clone(),
+                        file_sha256: *file_sha256,
+                        peer_count: file_peers.read().await.len(),
+                    });
+                }
+                files.sort_by(|lhs, rhs| {
+                    (&lhs.room, lhs.file_sha256.0).cmp(&(&rhs.room, rhs.file_sha256.0))
+                });
+                DebugReport::Files(files)
+            }
+            DebugQuery::ListPeers => {
+                let labels = self.peers_labels.read().await;
+                let mut peers: Vec<_> = self
+                    .peers_senders
+                    .read()
+                    .await
+                    .iter()
+                    .map(|(peer_id, sender)| DebugPeerInfo {
+                        peer_id: *peer_id,
+                        label: labels.get(peer_id).cloned(),
+                        is_connected: sender.strong_count() > 0,
+                    })
+                    .collect();
+                peers.sort_by_key(|peer| peer.peer_id.0);
+                DebugReport::Peers(peers)
+            }
+            DebugQuery::SwarmOf(file_sha256) => {
+                let mut swarms = Vec::new();
+                for ((room, sha256), file_peers) in self.files_senders.read().await.iter() {
+                    if *sha256 != file_sha256 {
+                        continue;
+                    }
+                    let mut peers: Vec<_> = file_peers.read().await.iter().copied().collect();
+                    peers.sort_by_key(|peer_id| peer_id.0);
+                    swarms.push(DebugSwarmInfo {
+                        room: room.clone(),
+                        peers,
+                    });
+                }
+                swarms.sort_by(|lhs, rhs| lhs.room.cmp(&rhs.room));
+                DebugReport::Swarm(swarms)
+            }
         }
     }
 
@@ -47,12 +134,33 @@ impl State {
             .and_then(Weak::upgrade)
     }
 
+    pub async fn set_peer_label(&self, peer_id: PeerId, label: String) {
+        let _: Option<_> = self.peers_labels.write().await.insert(peer_id, label);
+    }
+
+    pub async fn remove_peer_label(&self, peer_id: PeerId) {
+        let _: Option<_> = self.peers_labels.write().await.remove(&peer_id);
+    }
+
+    pub async fn peer_label(&self, peer_id: PeerId) -> Option<String> {
+        self.peers_labels.read().await.get(&peer_id).cloned()
+    }
+
+    pub async fn peer_name(&self, peer_id: PeerId) -> PeerName {
+        PeerName {
+            peer_id,
+            label: self.peer_label(peer_id).await,
+        }
+    }
+
+    /// Peers are only discovered by other peers in the same room.
     pub async fn add_file_peer_and_get_file_peer_list(
         &self,
+        room: String,
         file_sha256: FileSha256,
         peer_id: PeerId,
     ) -> Result<Vec<PeerId>, StateAddFilePeerError> {
-        let file_peers = self.get_or_insert_empty_file_peers(file_sha256).await;
+        let file_peers = self.get_or_insert_empty_file_peers(room, file_sha256).await;
         let mut file_peers = file_peers.write().await;
 
         let is_inserted = file_peers.insert(peer_id);
@@ -63,12 +171,43 @@ impl State {
         }
     }
 
+    pub async fn get_file_peer_list(&self, room: String, file_sha256: FileSha256) -> Vec<PeerId> {
+        let file_peers = self.get_or_insert_empty_file_peers(room, file_sha256).await;
+        let file_peers = file_peers.read().await;
+        file_peers.iter().copied().collect()
+    }
+
+    /// Returns the number of peers sharing the file, other than `peer_id`.
+    ///
+    /// Unlike other file queries, the file swarm is not created if it does not exist.
+    pub async fn count_other_file_peers(
+        &self,
+        room: String,
+        file_sha256: FileSha256,
+        peer_id: PeerId,
+    ) -> usize {
+        let file_peers = self
+            .files_senders
+            .read()
+            .await
+            .get(&(room, file_sha256))
+            .map(Arc::clone);
+        match file_peers {
+            Some(file_peers) => {
+                let file_peers = file_peers.read().await;
+                file_peers.len() - usize::from(file_peers.contains(&peer_id))
+            }
+            None => 0,
+        }
+    }
+
     pub async fn remove_file_peer(
         &self,
+        room: String,
         file_sha256: FileSha256,
         peer_id: PeerId,
     ) -> Result<(), StateRemoveFilePeerError> {
-        let file_peers = self.get_or_insert_empty_file_peers(file_sha256).await;
+        let file_peers = self.get_or_insert_empty_file_peers(room, file_sha256).await;
         let mut file_peers = file_peers.write().await;
 
         let is_present_before = file_peers.remove(&peer_id);
@@ -81,15 +220,17 @@ impl State {
 
     async fn get_or_insert_empty_file_peers(
         &self,
+        room: String,
         file_sha256: FileSha256,
-    ) -> Arc<RwLock<HashSet<PeerId>>> {
+    ) -> FilePeers {
+        let key = (room, file_sha256);
         let mut files_peers = self.files_senders.write().await;
-        let file_peers = files_peers.get(&file_sha256);
+        let file_peers = files_peers.get(&key);
         match file_peers {
             Some(file_peers) => Arc::clone(file_peers),
             None => {
                 let file_peers = Arc::new(RwLock::new(HashSet::new()));
-                let prev = files_peers.insert(file_sha256, Arc::clone(&file_peers));
+                let prev = files_peers.insert(key, Arc::clone(&file_peers));
                 assert!(prev.is_none());
                 file_peers
             }
@@ -97,6 +238,15 @@ impl State {
     }
 }
 
+impl fmt::Display for PeerName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "{} ({})", self.peer_id, label),
+            None => write!(f, "{}", self.peer_id),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 pub enum StateAddFilePeerError {
     #[error("file {0} is already added")]
@@ -108,3 +258,192 @@ pub enum StateRemoveFilePeerError {
     #[error("file {0} is not added before")]
     FileIsNotAddedBefore(FileSha256),
 }
+
+#[test]
+fn set_peer_label() {
+    use async_std::task::block_on;
+
+    let state = State::new();
+    block_on(async {
+        assert_eq!(state.peer_label(PeerId(5)).await, None);
+        assert_eq!(state.peer_name(PeerId(5)).await.to_string(), "5");
+
+        state.set_peer_label(PeerId(5), "alice".to_owned()).await;
+        assert_eq!(state.peer_label(PeerId(5)).await.as_deref(), Some("alice"));
+        assert_eq!(state.peer_label(PeerId(6)).await, None);
+        assert_eq!(state.peer_name(PeerId(5)).await.to_string(), "5 (alice)");
+
+        state.set_peer_label(PeerId(5), "bob".to_owned()).await;
+        assert_eq!(state.peer_name(PeerId(5)).await.to_string(), "5 (bob)");
+
+        state.remove_peer_label(PeerId(5)).await;
+        assert_eq!(state.peer_label(PeerId(5)).await, None);
+        assert_eq!(state.peer_name(PeerId(5)).await.to_string(), "5");
+    });
+}
+
+#[test]
+fn separate_file_peers_by_room() {
+    use async_std::task::block_on;
+
+    let state = State::new();
+    let sha256 = FileSha256([1; 32]);
+    block_on(async {
+        let add = |room: &str, peer_id| {
+            state.add_file_peer_and_get_file_peer_list(room.to_owned(), sha256, peer_id)
+        };
+        assert_eq!(add("first", PeerId(1)).await.unwrap(), [PeerId(1)]);
+        assert_eq!(add("second", PeerId(2)).await.unwrap(), [PeerId(2)]);
+
+        let mut peers = add("first", PeerId(3)).await.unwrap();
+        peers.sort_by_key(|peer_id| peer_id.0);
+        assert_eq!(peers, [PeerId(1), PeerId(3)]);
+
+        state
+            .remove_file_peer("second".to_owned(), sha256, PeerId(2))
+            .await
+            .unwrap();
+        assert!(state
+            .remove_file_peer("first".to_owned(), sha256, PeerId(2))
+            .await
+            .is_err());
+
+        let mut peers = state.get_file_peer_list("first".to_owned(), sha256).await;
+        peers.sort_by_key(|peer_id| peer_id.0);
+        assert_eq!(peers, [PeerId(1), PeerId(3)]);
+        assert!(add("first", PeerId(3)).await.is_err());
+    });
+}
+
+#[test]
+fn count_other_file_peers() {
+    use async_std::task::block_on;
+
+    let state = State::new();
+    let sha256 = FileSha256([1; 32]);
+    block_on(async {
+        let count =
+            |room: &str, peer_id| state.count_other_file_peers(room.to_owned(), sha256, peer_id);
+        assert_eq!(count("room", PeerId(1)).await, 0);
+        assert!(state.files_senders.read().await.is_empty());
+
+        for peer_id in [PeerId(1), PeerId(2), PeerId(3)] {
+            let _: Vec<_> = state
+                .add_file_peer_and_get_file_peer_list("room".to_owned(), sha256, peer_id)
+                .await
+                .unwrap();
+        }
+        assert_eq!(count("room", PeerId(4)).await, 3);
+        assert_eq!(count("room", PeerId(2)).await, 2);
+        assert_eq!(count("other", PeerId(4)).await, 0);
+
+        // Querying does not add the peer to the swarm.
+        assert_eq!(
+            state
+                .get_file_peer_list("room".to_owned(), sha256)
+                .await
+                .len(),
+            3
+        );
+    });
+}
+
+#[cfg(feature = "debug-query")]
+#[test]
+fn refuse_debug_queries_without_token() {
+    assert!(!State::new().is_debug_token(""));
+
+    let state = State::new().with_debug_token("secret".to_owned());
+    assert!(state.is_debug_token("secret"));
+    assert!(!state.is_debug_token("guess"));
+}
+
+#[cfg(feature = "debug-query")]
+#[test]
+fn report_debug_queries() {
+    use async_std::task::block_on;
+    use tracker_protocol::{DebugFileInfo, DebugPeerInfo, DebugSwarmInfo};
+
+    let state = State::new();
+    let first = FileSha256([1; 32]);
+    let second = FileSha256([2; 32]);
+    block_on(async {
+        for (room, sha256, peer_id) in [
+            ("room", first, PeerId(2)),
+            ("room", first, PeerId(1)),
+            ("other", first, PeerId(3)),
+            ("room", second, PeerId(1)),
+        ] {
+            let _: Vec<_> = state
+                .add_file_peer_and_get_file_peer_list(room.to_owned(), sha256, peer_id)
+                .await
+                .unwrap();
+        }
+        for peer_id in [PeerId(2), PeerId(1)] {
+            let _: Option<_> = state
+                .peers_senders
+                .write()
+                .await
+                .insert(peer_id, Weak::new());
+        }
+        state.set_peer_label(PeerId(1), "alice".to_owned()).await;
+
+        assert_eq!(
+            state.debug_report(DebugQuery::ListFiles).await,
+            DebugReport::Files(vec![
+                DebugFileInfo {
+                    room: "other".to_owned(),
+                    file_sha256: first,
+                    peer_count: 1,
+                },
+                DebugFileInfo {
+                    room: "room".to_owned(),
+                    file_sha256: first,
+                    peer_count: 2,
+                },
+                DebugFileInfo {
+                    room: "room".to_owned(),
+                    file_sha256: second,
+                    peer_count: 1,
+                },
+            ])
+        );
+
+        // Peers without open sockets are still listed until the tracker forgets them.
+        assert_eq!(
+            state.debug_report(DebugQuery::ListPeers).await,
+            DebugReport::Peers(vec![
+                DebugPeerInfo {
+                    peer_id: PeerId(1),
+                    label: Some("alice".to_owned()),
+                    is_connected: false,
+                },
+                DebugPeerInfo {
+                    peer_id: PeerId(2),
+                    label: None,
+                    is_connected: false,
+                },
+            ])
+        );
+
+        assert_eq!(
+            state.debug_report(DebugQuery::SwarmOf(first)).await,
+            DebugReport::Swarm(vec![
+                DebugSwarmInfo {
+                    room: "other".to_owned(),
+                    peers: vec![PeerId(3)],
+                },
+                DebugSwarmInfo {
+                    room: "room".to_owned(),
+                    peers: vec![PeerId(1), PeerId(2)],
+                },
+            ])
+        );
+        assert_eq!(
+            state
+                .debug_report(DebugQuery::SwarmOf(FileSha256([3; 32])))
+                .await,
+            DebugReport::Swarm(vec![])
+        );
+    });
+}
@@ -0,0 +1,168 @@
+use core::fmt;
+use std::io;
+use std::path::Path;
+
+use async_std::net::TcpStream;
+use async_tls::TlsAcceptor;
+use thiserror::Error;
+
+use crate::SocketStream;
+
+/// TLS termination of accepted sockets, so that the tracker serves `wss://` directly
+/// and browsers on HTTPS pages are able to connect to it.
+#[derive(Clone)]
+pub struct TrackerTls {
+    acceptor: TlsAcceptor,
+}
+
+impl TrackerTls {
+    /// Loads the PEM encoded certificate chain and private key.
+    pub fn from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<Self, TrackerTlsError> {
+        use rustls::{Certificate, ServerConfig};
+
+        let certs: Vec<_> = rustls_pemfile::certs(&mut &*cert_pem)
+            .map_err(TrackerTlsError::CertificateReadError)?
+            .into_iter()
+            .map(Certificate)
+            .collect();
+        if certs.is_empty() {
+            return Err(TrackerTlsError::MissingCertificate);
+        }
+        let key = read_private_key(key_pem)?;
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)?;
+        Ok(Self {
+            acceptor: TlsAcceptor::from(config),
+        })
+    }
+
+    pub fn from_pem_files<CertPath: AsRef<Path>, KeyPath: AsRef<Path>>(
+        cert_path: CertPath,
+        key_path: KeyPath,
+    ) -> Result<Self, TrackerTlsError> {
+        use std::fs::read;
+
+        let cert_pem = read(cert_path).map_err(TrackerTlsError::CertificateReadError)?;
+        let key_pem = read(key_path).map_err(TrackerTlsError::PrivateKeyReadError)?;
+        Self::from_pem(&cert_pem, &key_pem)
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<SocketStream> {
+        let stream = self.acceptor.accept(stream).await?;
+        Ok(SocketStream::Tls(Box::new(stream)))
+    }
+}
+
+impl fmt::Debug for TrackerTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TrackerTls").finish_non_exhaustive()
+    }
+}
+
+// The first private key is used regardless of its encoding.
+fn read_private_key(key_pem: &[u8]) -> Result<rustls::PrivateKey, TrackerTlsError> {
+    use rustls::PrivateKey;
+    use rustls_pemfile::Item;
+
+    let mut reader = key_pem;
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(TrackerTlsError::PrivateKeyReadError)? {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => {
+                return Ok(PrivateKey(key))
+            }
+            Some(_) => {}
+            None => return Err(TrackerTlsError::MissingPrivateKey),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TrackerTlsError {
+    #[error("TLS certificate read error: {0}")]
+    CertificateReadError(io::Error),
+    #[error("TLS private key read error: {0}")]
+    PrivateKeyReadError(io::Error),
+    #[error("no TLS certificate found")]
+    MissingCertificate,
+    #[error("no TLS private key found")]
+    MissingPrivateKey,
+    #[error("TLS config error: {0}")]
+    ConfigError(#[from] rustls::Error),
+}
+
+#[test]
+fn complete_websocket_handshake_over_tls() {
+    use std::sync::Arc;
+
+    use async_std::net::TcpListener;
+    use async_std::task::{block_on, spawn};
+    use async_tls::TlsConnector;
+    use async_tungstenite::client_async;
+    use async_tungstenite::tungstenite::Message;
+    use futures::StreamExt;
+    use rustls::{Certificate, ClientConfig, RootCertStore};
+    use tracker_protocol::TrackerPeerMessage;
+
+    use crate::{Socket, State, MAX_MESSAGE_SIZE};
+
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let tls = TrackerTls::from_pem(
+        cert.serialize_pem().unwrap().as_bytes(),
+        cert.serialize_private_key_pem().as_bytes(),
+    )
+    .unwrap();
+
+    let mut roots = RootCertStore::empty();
+    roots
+        .add(&Certificate(cert.serialize_der().unwrap()))
+        .unwrap();
+    let connector = TlsConnector::from(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    );
+
+    block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _server = spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            let stream = tls.accept(stream).await.unwrap();
+            let socket = Socket::new(stream, addr, Arc::new(State::new()), MAX_MESSAGE_SIZE)
+                .await
+                .unwrap();
+            socket.run().await
+        });
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let stream = connector.connect("localhost", stream).await.unwrap();
+        let (mut client, _) = client_async(format!("wss://localhost:{}", addr.port()), stream)
+            .await
+            .unwrap();
+        let message = match client.next().await.unwrap().unwrap() {
+            Message::Binary(data) => bincode::deserialize(&data).unwrap(),
+            message => panic!("unexpected message {:?}", message),
+        };
+        assert!(matches!(message, TrackerPeerMessage::PeerIdAssigned { .. }));
+    });
+}
+
+#[test]
+fn reject_invalid_tls_pem() {
+    let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let cert_pem = cert.serialize_pem().unwrap();
+    let key_pem = cert.serialize_private_key_pem();
+
+    assert!(matches!(
+        TrackerTls::from_pem(b"", key_pem.as_bytes()),
+        Err(TrackerTlsError::MissingCertificate)
+    ));
+    assert!(matches!(
+        TrackerTls::from_pem(cert_pem.as_bytes(), cert_pem.as_bytes()),
+        Err(TrackerTlsError::MissingPrivateKey)
+    ));
+    assert!(TrackerTls::from_pem(cert_pem.as_bytes(), key_pem.as_bytes()).is_ok());
+}
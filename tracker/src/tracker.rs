@@ -1,55 +1,246 @@
 use std::io;
+use std::net::SocketAddr;
 use std::sync::Arc;
 
 use async_std::net::TcpListener;
 use thiserror::Error;
 
-use crate::State;
+use crate::{RelayLimits, State, TrackerTls, MAX_MESSAGE_SIZE};
 
 #[derive(Debug)]
 pub struct Tracker {
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
     state: Arc<State>,
+    max_message_size: usize,
+    relay_limits: RelayLimits,
+    tls: Option<TrackerTls>,
 }
 
 impl Tracker {
     pub async fn new<Address: AsRef<str>>(addr: Address) -> Result<Self, NewServerError> {
-        let listener = TcpListener::bind(addr.as_ref()).await?;
+        Self::with_addresses([addr]).await
+    }
+
+    /// Binds every address, e.g. both an IPv4 and an IPv6 one,
+    /// sockets accepted on all of them share the same state.
+    ///
+    /// IPv6 addresses are bound as IPv6-only,
+    /// so that `0.0.0.0` and `[::]` can be bound to the same port.
+    pub async fn with_addresses<Addresses, Address>(
+        addrs: Addresses,
+    ) -> Result<Self, NewServerError>
+    where
+        Addresses: IntoIterator<Item = Address>,
+        Address: AsRef<str>,
+    {
+        let mut listeners = Vec::new();
+        for addr in addrs {
+            listeners.push(bind_listener(addr.as_ref()).await?);
+            log::info!("started on address: {}", addr.as_ref());
+        }
+        if listeners.is_empty() {
+            return Err(NewServerError::NoAddresses);
+        }
         let state = Arc::new(State::new());
 
-        log::info!("started on address: {}", addr.as_ref());
+        Ok(Self {
+            listeners,
+            state,
+            max_message_size: MAX_MESSAGE_SIZE,
+            relay_limits: RelayLimits::default(),
+            tls: None,
+        })
+    }
 
-        Ok(Self { listener, state })
+    /// Sets the maximum incoming WebSocket message and frame size in bytes.
+    pub fn with_max_message_size(self, max_message_size: usize) -> Self {
+        Self {
+            max_message_size,
+            ..self
+        }
     }
 
+    /// Sets the limits of relayed session descriptions and ICE candidates.
+    pub fn with_relay_limits(self, relay_limits: RelayLimits) -> Self {
+        Self {
+            relay_limits,
+            ..self
+        }
+    }
+
+    /// Enables TLS termination, so that sockets are accepted as `wss://` instead of `ws://`.
+    pub fn with_tls(self, tls: TrackerTls) -> Self {
+        Self {
+            tls: Some(tls),
+            ..self
+        }
+    }
+
+    /// Sets the token required by `PeerTrackerMessage::Debug` queries.
+    #[cfg(feature = "debug-query")]
+    pub fn with_debug_token(self, debug_token: String) -> Self {
+        // No sockets are accepted before `run`, so the state is still empty.
+        Self {
+            state: Arc::new(State::new().with_debug_token(debug_token)),
+            ..self
+        }
+    }
+
+    /// Returns bound addresses, e.g. to find out ports assigned to addresses with port 0.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners.iter().map(TcpListener::local_addr).collect()
+    }
+
+    /// Accepts sockets on all bound addresses until all listeners fail.
     pub async fn run(self) {
-        use crate::Socket;
-        use async_std::task::{spawn, JoinHandle};
-
-        while let Ok((stream, addr)) = self.listener.accept().await {
-            let state = Arc::clone(&self.state);
-            let _: JoinHandle<()> = spawn(async move {
-                let socket = Socket::new(stream, addr, state).await;
-                let socket = match socket {
-                    Ok(socket) => socket,
+        use futures::future::join_all;
+
+        let Self {
+            listeners,
+            state,
+            max_message_size,
+            relay_limits,
+            tls,
+        } = self;
+        let _: Vec<()> = join_all(listeners.into_iter().map(|listener| {
+            accept_sockets(
+                listener,
+                Arc::clone(&state),
+                max_message_size,
+                relay_limits,
+                tls.clone(),
+            )
+        }))
+        .await;
+    }
+}
+
+async fn accept_sockets(
+    listener: TcpListener,
+    state: Arc<State>,
+    max_message_size: usize,
+    relay_limits: RelayLimits,
+    tls: Option<TrackerTls>,
+) {
+    use crate::{Socket, SocketStream};
+    use async_std::task::{spawn, JoinHandle};
+
+    while let Ok((stream, addr)) = listener.accept().await {
+        let state = Arc::clone(&state);
+        let tls = tls.clone();
+        let _: JoinHandle<()> = spawn(async move {
+            // The TLS handshake is done in the socket task, so slow clients do not block accepts.
+            let stream = match tls {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => stream,
                     Err(err) => {
-                        log::error!("new socket {} error: {}", addr, err);
+                        log::error!("socket {} TLS handshake error: {}", addr, err);
                         return;
                     }
-                };
-                match socket.run().await {
-                    Ok(()) => {}
-                    Err(err) => {
-                        log::error!("socket {} run error: {}", addr, err);
-                    }
+                },
+                None => SocketStream::Plain(stream),
+            };
+            let socket = Socket::new(stream, addr, state, max_message_size)
+                .await
+                .map(|socket| socket.with_relay_limits(relay_limits));
+            let socket = match socket {
+                Ok(socket) => socket,
+                Err(err) => {
+                    log::error!("new socket {} error: {}", addr, err);
+                    return;
                 }
-            });
+            };
+            match socket.run().await {
+                Ok(()) => {}
+                Err(err) => {
+                    log::error!("socket {} run error: {}", addr, err);
+                }
+            }
+        });
+    }
+}
+
+// Resolved addresses are tried in order until one is bound, like `TcpListener::bind` does.
+async fn bind_listener(addr: &str) -> io::Result<TcpListener> {
+    use async_std::net::ToSocketAddrs;
+
+    let mut last_err = None;
+    for addr in addr.to_socket_addrs().await? {
+        match bind_socket_addr(addr) {
+            Ok(listener) => return Ok(listener),
+            Err(err) => last_err = Some(err),
         }
     }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+fn bind_socket_addr(addr: SocketAddr) -> io::Result<TcpListener> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(TcpListener::from(std::net::TcpListener::from(socket)))
 }
 
 #[derive(Error, Debug)]
 pub enum NewServerError {
     #[error("TcpListener bind error: {0}")]
     TcpListenerBindError(#[from] io::Error),
+    #[error("no addresses to bind")]
+    NoAddresses,
+}
+
+#[test]
+fn accept_sockets_on_ipv4_and_ipv6_addresses() {
+    use async_std::net::TcpStream;
+    use async_std::task::{block_on, spawn};
+    use async_tungstenite::client_async;
+    use async_tungstenite::tungstenite::Message;
+    use futures::StreamExt;
+    use tracker_protocol::TrackerPeerMessage;
+
+    block_on(async {
+        let tracker = Tracker::with_addresses(["127.0.0.1:0", "[::1]:0"])
+            .await
+            .unwrap();
+        let addrs = tracker.local_addrs().unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert!(addrs[0].is_ipv4() && addrs[1].is_ipv6());
+        let _server = spawn(tracker.run());
+
+        let mut peer_ids = Vec::new();
+        for addr in addrs {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let (mut client, _) = client_async(format!("ws://{}", addr), stream)
+                .await
+                .unwrap();
+            let message = match client.next().await.unwrap().unwrap() {
+                Message::Binary(data) => bincode::deserialize(&data).unwrap(),
+                message => panic!("unexpected message {:?}", message),
+            };
+            match message {
+                TrackerPeerMessage::PeerIdAssigned { peer_id, .. } => peer_ids.push(peer_id),
+                message => panic!("unexpected message {:?}", message),
+            }
+        }
+        // Peer ids are assigned by the shared state, so they are unique across listeners.
+        assert_eq!(peer_ids.len(), 2);
+        assert_ne!(peer_ids[0], peer_ids[1]);
+    });
+
+    assert!(matches!(
+        block_on(Tracker::with_addresses(Vec::<String>::new())),
+        Err(NewServerError::NoAddresses)
+    ));
 }